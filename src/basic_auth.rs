@@ -0,0 +1,89 @@
+use anyhow::ensure;
+
+use crate::{digest, Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+/// Paths that need credentials.
+const PROTECTED: &str = "/files";
+
+/// HTTP Basic authentication (RFC 7617) for the files routes: a request for them needs an
+/// `Authorization` header with one of the configured user and password pairs.
+#[derive(Debug, Clone)]
+pub struct BasicAuth {
+    /// `user:password`, as clients encode them.
+    credentials: Vec<String>,
+}
+
+impl BasicAuth {
+    pub fn new(credentials: Vec<String>) -> anyhow::Result<Self> {
+        for credential in &credentials {
+            ensure!(credential.contains(':'), "ERROR: --auth {credential} is not USER:PASSWORD");
+        }
+        Ok(BasicAuth { credentials })
+    }
+
+    /// The 401 for `request`, if it is for the files routes and doesn't carry valid
+    /// credentials.
+    pub fn response(&self, request: &HttpRequest) -> Option<HttpResponseBuilder> {
+        let path = request.route.split_once('?').map_or(request.route.as_str(), |(path, _)| path);
+        let protected = path.strip_prefix(PROTECTED).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'));
+        if !protected || self.authorized(request) {
+            return None;
+        }
+        Some(HttpResponseBuilder {
+            status_code: HttpStatusCode::Other(401, "Unauthorized".to_string()),
+            version: request.version.clone(),
+            headers: HeaderMap::from([("WWW-Authenticate".to_string(), r#"Basic realm="files", charset="UTF-8""#.to_string())]),
+            content: Content::Empty,
+        })
+    }
+
+    fn authorized(&self, request: &HttpRequest) -> bool {
+        let Some((scheme, encoded)) = request.header("Authorization").and_then(|value| value.trim().split_once(' ')) else {
+            return false;
+        };
+        let Some(decoded) = digest::base64_decode(encoded.trim()).filter(|_| scheme.eq_ignore_ascii_case("Basic")) else {
+            return false;
+        };
+        // every pair is compared, so the time taken doesn't tell which user exists
+        self.credentials.iter()
+            .fold(false, |found, credential| digest::constant_time_eq(credential.as_bytes(), &decoded) | found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpMethod;
+
+    fn request(route: &str, authorization: Option<&str>) -> HttpRequest {
+        HttpRequest {
+            method: HttpMethod::Get,
+            route: route.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: authorization.map(|value| ("Authorization".to_string(), value.to_string())).into_iter().collect::<HeaderMap>(),
+            body: None,
+            remote_addr: None,
+            forwarded: None,
+            spooled: None,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn only_files_need_credentials() {
+        let auth = BasicAuth::new(vec!["Aladdin:open sesame".to_string()]).unwrap();
+        let status = |route, authorization| {
+            auth.response(&request(route, authorization)).map(|response| response.status_code.code_and_phrase().0)
+        };
+
+        assert_eq!(status("/files/a", None), Some(401));
+        assert_eq!(status("/files/a", Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ==")), None);
+        assert_eq!(status("/files", Some("basic  QWxhZGRpbjpvcGVuIHNlc2FtZQ==")), None);
+        assert_eq!(status("/files/a", Some("Basic QWxhZGRpbjpvcGVuIHNlc2FtRQ==")), Some(401));
+        assert_eq!(status("/files/a", Some("Bearer QWxhZGRpbjpvcGVuIHNlc2FtZQ==")), Some(401));
+        assert_eq!(status("/files/a", Some("Basic not base64")), Some(401));
+        assert_eq!(status("/filesystem", None), None);
+        assert_eq!(status("/echo/files", None), None);
+        assert!(BasicAuth::new(vec!["nopassword".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::clock::{self, Clock};
+use crate::log::{log_debug, log_error};
+use crate::{Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+#[derive(Debug, Clone)]
+pub struct CgiConfig {
+    pub directory: PathBuf,
+    pub timeout: Duration,
+}
+
+pub struct CgiOutput {
+    status_code: HttpStatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl CgiOutput {
+    pub fn into_response(self, version: String) -> HttpResponseBuilder {
+        HttpResponseBuilder {
+            status_code: self.status_code,
+            version,
+            headers: self.headers,
+            content: Content::Bytes(self.body),
+        }
+    }
+}
+
+/// Runs `script` from the configured CGI directory and turns its output into a response.
+/// Failures to run the script are reported to the client as 502, timeouts as 504.
+pub async fn handle(request: &HttpRequest, config: &CgiConfig, clock: &dyn Clock, script: &str, path_info: &[&str]) -> HttpResponseBuilder {
+    let failure = |status_code| HttpResponseBuilder {
+        status_code,
+        version: request.version.clone(),
+        headers: HeaderMap::new(),
+        content: Content::Empty,
+    };
+
+    if script.is_empty() || script.starts_with('.') {
+        return failure(HttpStatusCode::NotFound404);
+    }
+    let script_path = config.directory.join(script);
+    if !script_path.is_file() {
+        log_debug!("no cgi script at {}", script_path.display());
+        return failure(HttpStatusCode::NotFound404);
+    }
+
+    let run = run_script(request, &script_path, script, path_info);
+    match clock::timeout(clock, config.timeout, run).await {
+        Ok(Ok(output)) => output.into_response(request.version.clone()),
+        Ok(Err(err)) => {
+            log_error!("cgi script {} failed, error: {err:#}", script_path.display());
+            failure(HttpStatusCode::BadGateway502)
+        }
+        Err(_) => {
+            log_error!("cgi script {} timed out", script_path.display());
+            failure(HttpStatusCode::GatewayTimeout504)
+        }
+    }
+}
+
+async fn run_script(request: &HttpRequest, script_path: &PathBuf, script: &str, path_info: &[&str]) -> anyhow::Result<CgiOutput> {
+    let path_info = if path_info.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path_info.join("/"))
+    };
+
+    let mut command = Command::new(script_path);
+    command
+        .env_clear()
+        .envs(environment(request, &format!("/cgi-bin/{script}"), &path_info))
+        .current_dir(script_path.parent().unwrap_or(script_path))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true);
+
+    let mut child = command.spawn().context("spawning cgi script")?;
+
+    // feed the body from a separate task so a script that writes before reading can't deadlock us
+    let mut stdin = child.stdin.take().context("cgi stdin unavailable")?;
+    let body = request.body.clone().unwrap_or_default();
+    let feeder = tokio::spawn(async move {
+        // the script is free to exit without reading its input
+        let _ = stdin.write_all(&body).await;
+    });
+
+    let output = child.wait_with_output().await.context("waiting for cgi script")?;
+    feeder.abort();
+    if !output.status.success() {
+        bail!("cgi script exited with {}", output.status);
+    }
+
+    parse_output(&output.stdout)
+}
+
+/// The standard CGI/1.1 meta-variables for `request`, shared with the FastCGI client.
+pub fn environment(request: &HttpRequest, script_name: &str, path_info: &str) -> Vec<(String, String)> {
+    let (_, query) = request.route.split_once('?').unwrap_or((&request.route, ""));
+
+    let mut env = vec![
+        ("GATEWAY_INTERFACE".to_string(), "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE".to_string(), "http-server".to_string()),
+        ("SERVER_PROTOCOL".to_string(), request.version.clone()),
+        ("REQUEST_METHOD".to_string(), request.method.as_str().to_string()),
+        ("SCRIPT_NAME".to_string(), script_name.to_string()),
+        ("PATH_INFO".to_string(), path_info.to_string()),
+        ("REQUEST_URI".to_string(), request.route.clone()),
+        ("QUERY_STRING".to_string(), query.to_string()),
+    ];
+    if let Ok(path) = std::env::var("PATH") {
+        env.push(("PATH".to_string(), path));
+    }
+    if let Some(ip) = request.client_ip() {
+        env.push(("REMOTE_ADDR".to_string(), ip.to_string()));
+    }
+    // the peer's port says nothing about a client behind a proxy
+    if let (Some(addr), None) = (request.remote_addr, &request.forwarded) {
+        env.push(("REMOTE_PORT".to_string(), addr.port().to_string()));
+    }
+    env.push(("REQUEST_SCHEME".to_string(), request.scheme().to_string()));
+    if request.scheme() == "https" {
+        env.push(("HTTPS".to_string(), "on".to_string()));
+    }
+    if let Some(host) = request.headers.get("Host") {
+        let (name, port) = host.split_once(':').unwrap_or((host, "80"));
+        env.push(("SERVER_NAME".to_string(), name.to_string()));
+        env.push(("SERVER_PORT".to_string(), port.to_string()));
+    }
+    if let Some(body) = &request.body {
+        env.push(("CONTENT_LENGTH".to_string(), body.len().to_string()));
+    }
+
+    for (name, value) in &request.headers {
+        let name = name.to_ascii_uppercase().replace('-', "_");
+        match name.as_str() {
+            "CONTENT_TYPE" => env.push((name, value.clone())),
+            // already described by CONTENT_LENGTH, and not to be trusted over the body we read
+            "CONTENT_LENGTH" => {}
+            // HTTP_PROXY would be taken for the proxy setting by the script's HTTP clients (httpoxy)
+            "PROXY" => {}
+            _ => env.push((format!("HTTP_{name}"), value.clone())),
+        }
+    }
+    env
+}
+
+/// Headers about the connection or how the body is framed, which the server decides itself
+/// for the body it actually sends.
+const FRAMING: &[&str] = &[
+    "Connection", "Keep-Alive", "Proxy-Connection", "TE", "Trailer", "Transfer-Encoding", "Upgrade", "Content-Length",
+];
+
+pub fn parse_output(stdout: &[u8]) -> anyhow::Result<CgiOutput> {
+    let (head, body) = split_head(stdout).context("cgi output has no header section")?;
+    let head = std::str::from_utf8(head).context("cgi headers are not utf8")?;
+
+    let mut status_code = None;
+    let mut headers = HeaderMap::new();
+    for line in head.lines() {
+        let (name, value) = line.split_once(':')
+            .with_context(|| format!("malformed cgi header line {line:?}"))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("Status") {
+            let (code, phrase) = value.split_once(' ').unwrap_or((value, ""));
+            let code = code.parse().with_context(|| format!("invalid cgi status {value:?}"))?;
+            status_code = Some(HttpStatusCode::Other(code, phrase.to_string()));
+        } else if FRAMING.iter().any(|framing| name.eq_ignore_ascii_case(framing)) {
+            log_debug!("dropping {name} from cgi output");
+        } else {
+            headers.append(name.to_string(), value.to_string());
+        }
+    }
+
+    let status_code = status_code.unwrap_or_else(|| {
+        let is_redirect = headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Location"));
+        if is_redirect {
+            HttpStatusCode::Other(302, "Found".to_string())
+        } else {
+            HttpStatusCode::Ok200
+        }
+    });
+
+    Ok(CgiOutput {
+        status_code,
+        headers,
+        body: body.to_vec(),
+    })
+}
+
+/// Splits script output at the first empty line; scripts commonly use bare LF line endings.
+fn split_head(output: &[u8]) -> Option<(&[u8], &[u8])> {
+    let crlf = output.windows(4).position(|w| w == b"\r\n\r\n").map(|i| (i, i + 4));
+    let lf = output.windows(2).position(|w| w == b"\n\n").map(|i| (i, i + 2));
+    let (end, body_start) = match (crlf, lf) {
+        (Some(crlf), Some(lf)) => if crlf.0 < lf.0 { crlf } else { lf },
+        (Some(split), None) | (None, Some(split)) => split,
+        (None, None) => return None,
+    };
+    Some((&output[..end], &output[body_start..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpMethod;
+
+    #[test]
+    fn proxy_header_is_not_passed_on() {
+        let request = HttpRequest {
+            method: HttpMethod::Get,
+            route: "/cgi-bin/a".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: [("Proxy", "http://evil"), ("X-Thing", "1")].into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HeaderMap>(),
+            body: None,
+            remote_addr: None,
+            forwarded: None,
+            spooled: None,
+            session: None,
+        };
+        let env = environment(&request, "/cgi-bin/a", "");
+        assert!(env.iter().any(|(name, _)| name == "HTTP_X_THING"));
+        assert!(!env.iter().any(|(name, _)| name == "HTTP_PROXY"));
+    }
+
+    #[test]
+    fn framing_headers_are_dropped_from_output() {
+        let output = parse_output(b"Content-Type: text/plain\nContent-Length: 99\ntransfer-encoding: chunked\nConnection: close\n\nhi").unwrap();
+        assert_eq!(output.headers, HeaderMap::from([("Content-Type".to_string(), "text/plain".to_string())]));
+        assert_eq!(output.body, b"hi");
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::{httpdate, Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+/// Caps how many responses a single client IP has in flight at once, so one aggressive
+/// client can't tie up every worker while everyone else waits.
+#[derive(Debug)]
+pub struct ClientLimit {
+    max: usize,
+    in_flight: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// One of a client's in-flight responses; the count goes back down when it is dropped.
+#[derive(Debug)]
+pub struct Slot {
+    limit: Arc<ClientLimit>,
+    ip: IpAddr,
+}
+
+impl ClientLimit {
+    pub fn new(max: usize) -> Self {
+        ClientLimit { max, in_flight: Mutex::default() }
+    }
+
+    /// Takes a slot for `ip`, or `None` if it already has `max` responses in flight.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<Slot> {
+        let ip = ip.to_canonical();
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let count = in_flight.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(Slot { limit: self.clone(), ip })
+    }
+
+    /// The 429 for a client over its limit.
+    pub fn rejection(&self, request: &HttpRequest, now: SystemTime) -> HttpResponseBuilder {
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Other(429, "Too Many Requests".to_string()),
+            version: request.version.clone(),
+            headers: HeaderMap::from([
+                ("Retry-After".to_string(), "1".to_string()),
+                ("Date".to_string(), httpdate::format(now)),
+            ]),
+            content: Content::Empty,
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut in_flight = self.limit.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.ip) {
+            *count -= 1;
+            // idle clients don't stay in the map
+            if *count == 0 {
+                in_flight.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_counted_per_client() {
+        let limit = Arc::new(ClientLimit::new(2));
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = limit.acquire(client).unwrap();
+        let _second = limit.acquire(client).unwrap();
+        assert!(limit.acquire(client).is_none());
+        // the same client over a dual-stack socket
+        assert!(limit.acquire("::ffff:192.0.2.1".parse().unwrap()).is_none());
+        assert!(limit.acquire("192.0.2.2".parse().unwrap()).is_some());
+
+        drop(first);
+        assert!(limit.acquire(client).is_some());
+        assert!(!limit.in_flight.lock().unwrap().contains_key(&"192.0.2.2".parse::<IpAddr>().unwrap()));
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, SystemTime};
+
+use crate::storage::BoxFuture;
+
+/// Source of time for everything time-dependent (Date headers, timeouts), so that tests can
+/// substitute a [`MockClock`] instead of sleeping.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[derive(Debug)]
+pub struct Elapsed;
+
+/// Like `tokio::time::timeout`, but measured on `clock`.
+pub async fn timeout<F: Future>(clock: &dyn Clock, duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    tokio::select! {
+        output = future => Ok(output),
+        _ = clock.sleep(duration) => Err(Elapsed),
+    }
+}
+
+/// A clock that only moves when told to; sleepers wake once it's advanced past their deadline.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now: tokio::sync::watch::Sender<SystemTime>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(start: SystemTime) -> Self {
+        MockClock { now: tokio::sync::watch::channel(start).0 }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.borrow()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let deadline = self.now() + duration;
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            // the sender lives as long as the clock, so an error means nobody can wake us
+            if now.wait_for(|now| *now >= deadline).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn timeout_follows_the_mock_clock() {
+        let clock = std::sync::Arc::new(MockClock::new(UNIX_EPOCH));
+
+        let waiting = tokio::spawn({
+            let clock = clock.clone();
+            async move { timeout(&*clock, Duration::from_secs(30), std::future::pending::<()>()).await }
+        });
+        tokio::task::yield_now().await;
+
+        clock.advance(Duration::from_secs(29));
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(waiting.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn timeout_passes_output_through() {
+        let clock = MockClock::new(UNIX_EPOCH);
+        let output = timeout(&clock, Duration::from_secs(1), async { 42 }).await;
+        assert_eq!(output.unwrap(), 42);
+    }
+}
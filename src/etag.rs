@@ -0,0 +1,85 @@
+use std::time::UNIX_EPOCH;
+
+use crate::digest;
+use crate::storage::Metadata;
+
+/// How entity tags for stored files are made.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Strategy {
+    /// Hash of the content: changes exactly when the bytes do, but every file has to be read
+    /// and hashed once (digests are cached until the file changes).
+    #[default]
+    Strong,
+    /// Length and modification time, known without reading the file. Two versions written
+    /// within the same second with the same length share a tag, hence weak.
+    Weak,
+}
+
+impl Strategy {
+    /// The tag for a file with this `metadata`; `digest` is only called for strong tags.
+    pub fn tag(self, metadata: &Metadata, digest: impl FnOnce() -> [u8; 32]) -> String {
+        match self {
+            Strategy::Strong => digest::etag(&digest()),
+            Strategy::Weak => {
+                let modified = metadata.modified
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |modified| modified.as_secs());
+                format!("W/\"{:x}-{modified:x}\"", metadata.len)
+            }
+        }
+    }
+}
+
+/// The tag for another representation of the same content, such as its gzipped variant.
+pub fn variant(tag: &str, suffix: &str) -> String {
+    format!("{}-{suffix}\"", tag.trim_end_matches('"'))
+}
+
+/// The strong comparison If-Match and If-Range use (RFC 9110 section 8.8.3.2): both tags
+/// must be strong and identical. `*` matches any current representation.
+pub fn matches_strongly(field: &str, tag: &str) -> bool {
+    field.trim() == "*"
+        || (!is_weak(tag) && field.split(',').map(str::trim).any(|candidate| !is_weak(candidate) && candidate == tag))
+}
+
+/// The weak comparison If-None-Match uses: identical once any `W/` is ignored.
+pub fn matches_weakly(field: &str, tag: &str) -> bool {
+    field.trim() == "*"
+        || field.split(',').map(str::trim).any(|candidate| opaque(candidate) == opaque(tag))
+}
+
+fn is_weak(tag: &str) -> bool {
+    tag.starts_with("W/")
+}
+
+fn opaque(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparisons_follow_rfc_9110() {
+        // the example table in RFC 9110 section 8.8.3.2
+        assert!(!matches_strongly(r#"W/"1""#, r#"W/"1""#));
+        assert!(matches_weakly(r#"W/"1""#, r#"W/"1""#));
+        assert!(!matches_strongly(r#"W/"1""#, r#"W/"2""#));
+        assert!(!matches_weakly(r#"W/"1""#, r#"W/"2""#));
+        assert!(!matches_strongly(r#"W/"1""#, r#""1""#));
+        assert!(matches_weakly(r#"W/"1""#, r#""1""#));
+        assert!(matches_strongly(r#""1""#, r#""1""#));
+        assert!(matches_weakly(r#""1""#, r#""1""#));
+
+        assert!(matches_strongly(r#""a", "b""#, r#""b""#));
+        assert!(matches_weakly("*", r#"W/"1""#));
+    }
+
+    #[test]
+    fn weak_tags_come_from_metadata() {
+        let metadata = Metadata { len: 255, modified: Some(UNIX_EPOCH + std::time::Duration::from_secs(16)), is_dir: false };
+        assert_eq!(Strategy::Weak.tag(&metadata, || unreachable!()), r#"W/"ff-10""#);
+        assert_eq!(variant(r#"W/"ff-10""#, "gzip"), r#"W/"ff-10-gzip""#);
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{ensure, Context};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+
+use crate::{cgi, glob};
+use crate::clock::{self, Clock};
+use crate::log::{log_debug, log_error};
+use crate::{Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+/// Frames larger than this are treated as a broken handler rather than allocated.
+const MAX_FRAME: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ExternalConfig {
+    /// Glob over request paths, like `--fastcgi-pattern`.
+    pub pattern: String,
+    /// Run with `sh -c`, so it may carry arguments.
+    pub command: String,
+    pub timeout: Duration,
+}
+
+struct Process {
+    // kept so the handler is killed when the process is dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+/// A long-lived subprocess that selected routes are dispatched to, for logic in other
+/// languages without a FastCGI library.
+///
+/// The protocol is one exchange at a time over the child's stdin and stdout. Each message
+/// is a 4 byte big-endian length followed by that many bytes: the server sends the raw HTTP
+/// request and the handler answers with CGI-style output (header lines, `Status:` for the
+/// status, a blank line, the body). The child is started on the first request and restarted
+/// after it fails or times out.
+pub struct ExternalHandler {
+    config: ExternalConfig,
+    clock: Arc<dyn Clock>,
+    process: Mutex<Option<Process>>,
+}
+
+impl fmt::Debug for ExternalHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExternalHandler").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+impl ExternalHandler {
+    pub fn new(config: ExternalConfig, clock: Arc<dyn Clock>) -> Self {
+        ExternalHandler { config, clock, process: Mutex::new(None) }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        glob::matches(&self.config.pattern, path)
+    }
+
+    /// Failures of the handler are reported to the client as 502, timeouts as 504.
+    pub async fn handle(&self, request: &HttpRequest) -> HttpResponseBuilder {
+        let failure = |status_code| HttpResponseBuilder {
+            status_code,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        };
+
+        // requests queue here, the protocol has no way to interleave them
+        let mut process = self.process.lock().await;
+        let result = clock::timeout(&*self.clock, self.config.timeout, self.forward(&mut process, request)).await;
+        let output = match result {
+            Ok(Ok(output)) => output,
+            Ok(Err(err)) => {
+                log_error!("external handler {:?} failed, error: {err:#}", self.config.command);
+                *process = None;
+                return failure(HttpStatusCode::BadGateway502);
+            }
+            Err(_) => {
+                log_error!("external handler {:?} timed out", self.config.command);
+                *process = None;
+                return failure(HttpStatusCode::GatewayTimeout504);
+            }
+        };
+        drop(process);
+
+        match cgi::parse_output(&output) {
+            Ok(output) => output.into_response(request.version.clone()),
+            Err(err) => {
+                log_error!("external handler {:?} answered garbage, error: {err:#}", self.config.command);
+                failure(HttpStatusCode::BadGateway502)
+            }
+        }
+    }
+
+    async fn forward(&self, process: &mut Option<Process>, request: &HttpRequest) -> anyhow::Result<Vec<u8>> {
+        let process = match process {
+            Some(process) => process,
+            None => process.insert(self.spawn()?),
+        };
+        exchange(&mut process.stdout, &mut process.stdin, &request.to_bytes()).await
+    }
+
+    fn spawn(&self) -> anyhow::Result<Process> {
+        log_debug!("starting external handler {:?}", self.config.command);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.config.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("starting external handler {:?}", self.config.command))?;
+        let stdin = child.stdin.take().context("external handler has no stdin")?;
+        let stdout = child.stdout.take().context("external handler has no stdout")?;
+        Ok(Process { _child: child, stdin, stdout })
+    }
+}
+
+/// Sends one length-prefixed request frame and reads back one response frame.
+async fn exchange<R, W>(reader: &mut R, writer: &mut W, request: &[u8]) -> anyhow::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let len = u32::try_from(request.len()).context("request too large to frame")?;
+    writer.write_all(&len.to_be_bytes()).await.context("writing request")?;
+    writer.write_all(request).await.context("writing request")?;
+    writer.flush().await.context("writing request")?;
+
+    let len = reader.read_u32().await.context("reading response length")? as usize;
+    ensure!(len <= MAX_FRAME, "response frame of {len} bytes is over the {MAX_FRAME} byte limit");
+    let mut response = vec![0; len];
+    reader.read_exact(&mut response).await.context("reading response")?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::test_client::TestClient;
+    use crate::ServerConfig;
+
+    #[tokio::test]
+    async fn frames_are_length_prefixed() {
+        let (mut server, mut handler) = tokio::io::duplex(1024);
+        let fake_handler = tokio::spawn(async move {
+            let len = handler.read_u32().await.unwrap() as usize;
+            let mut request = vec![0; len];
+            handler.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, b"GET / HTTP/1.1\r\n\r\n");
+
+            let response = b"Status: 204 No Content\r\n\r\n";
+            handler.write_u32(response.len() as u32).await.unwrap();
+            handler.write_all(response).await.unwrap();
+        });
+
+        let (mut reader, mut writer) = tokio::io::split(&mut server);
+        let response = exchange(&mut reader, &mut writer, b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        assert_eq!(response, b"Status: 204 No Content\r\n\r\n");
+        fake_handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dead_handler_is_a_bad_gateway() {
+        let handler = ExternalHandler::new(
+            ExternalConfig { pattern: "/ext/*".to_string(), command: "exit 1".to_string(), timeout: Duration::from_secs(5) },
+            Arc::new(MockClock::new(UNIX_EPOCH)),
+        );
+        let client = TestClient::new(ServerConfig { external: vec![Arc::new(handler)], ..Default::default() });
+
+        assert_eq!(client.get("/ext/anything").send().await.status, 502);
+        assert_eq!(client.get("/other").send().await.status, 404);
+    }
+}
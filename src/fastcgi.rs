@@ -0,0 +1,345 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::Mutex;
+
+use crate::clock::{self, Clock};
+use crate::log::{log_debug, log_error};
+use crate::{cgi, glob, percent, Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+const VERSION_1: u8 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+const ROLE_RESPONDER: u16 = 1;
+const FLAG_KEEP_CONN: u8 = 1;
+
+// every request on a connection uses the same id, connections are never multiplexed
+const REQUEST_ID: u16 = 1;
+const MAX_RECORD_CONTENT: usize = 0xffff;
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+type Connection = BufStream<Box<dyn Stream>>;
+
+#[derive(Debug, Clone)]
+pub struct FastCgiConfig {
+    /// `host:port`, or `unix:/path/to/socket`
+    pub address: String,
+    /// glob matched against the request path, e.g. `*.php`
+    pub pattern: String,
+    /// directory the backend resolves SCRIPT_FILENAME against
+    pub document_root: PathBuf,
+    pub timeout: Duration,
+}
+
+/// Forwards matching requests to a FastCGI responder, keeping idle connections around for reuse.
+pub struct FastCgiClient {
+    config: FastCgiConfig,
+    clock: Arc<dyn Clock>,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl fmt::Debug for FastCgiClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FastCgiClient").field("config", &self.config).finish()
+    }
+}
+
+impl FastCgiClient {
+    pub fn new(config: FastCgiConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            config,
+            clock,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        glob::matches(&self.config.pattern, path)
+    }
+
+    pub async fn handle(&self, request: &HttpRequest, path: &str) -> HttpResponseBuilder {
+        let failure = |status_code| HttpResponseBuilder {
+            status_code,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        };
+
+        let Some(script_filename) = self.script_filename(path) else {
+            log_debug!("refusing fastcgi path {path:?}");
+            return failure(HttpStatusCode::NotFound404);
+        };
+        let forward = self.forward(request, path, &script_filename);
+        match clock::timeout(&*self.clock, self.config.timeout, forward).await {
+            Ok(Ok(output)) => output.into_response(request.version.clone()),
+            Ok(Err(err)) => {
+                log_error!("fastcgi request to {} failed, error: {err:#}", self.config.address);
+                failure(HttpStatusCode::BadGateway502)
+            }
+            Err(_) => {
+                log_error!("fastcgi request to {} timed out", self.config.address);
+                failure(HttpStatusCode::GatewayTimeout504)
+            }
+        }
+    }
+
+    async fn forward(&self, request: &HttpRequest, path: &str, script_filename: &Path) -> anyhow::Result<cgi::CgiOutput> {
+        let params = self.params(request, path, script_filename);
+        let stdin = request.body.as_deref().unwrap_or_default();
+
+        // an idle connection may have been closed by the backend in the meantime; those
+        // found closed are dropped before anything is sent on them
+        let pooled = loop {
+            let Some(mut connection) = self.idle.lock().await.pop() else {
+                break None;
+            };
+            if is_open(&mut connection).await {
+                break Some(connection);
+            }
+            log_debug!("dropping closed fastcgi connection");
+        };
+        let (output, connection) = match pooled {
+            Some(connection) => match exchange(connection, &params, stdin).await {
+                Ok(result) => result,
+                // the backend may have acted on the request already, so only a request that
+                // changes nothing is sent again; scripts can't be trusted to keep PUT and
+                // DELETE idempotent
+                Err(err) if request.method.is_safe() => {
+                    log_debug!("pooled fastcgi connection failed ({err:#}), reconnecting");
+                    exchange(self.connect().await?, &params, stdin).await?
+                }
+                Err(err) => return Err(err),
+            },
+            None => exchange(self.connect().await?, &params, stdin).await?,
+        };
+
+        let mut idle = self.idle.lock().await;
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(connection);
+        }
+        drop(idle);
+
+        cgi::parse_output(&output)
+    }
+
+    async fn connect(&self) -> anyhow::Result<Connection> {
+        let stream: Box<dyn Stream> = match self.config.address.strip_prefix("unix:") {
+            Some(path) => Box::new(UnixStream::connect(path).await
+                .with_context(|| format!("connecting to fastcgi socket {path}"))?),
+            None => Box::new(TcpStream::connect(&self.config.address).await
+                .with_context(|| format!("connecting to fastcgi backend {}", self.config.address))?),
+        };
+        Ok(BufStream::new(stream))
+    }
+
+    /// The file under the document root that `path` names, or `None` if it is malformed or
+    /// climbs out of the root, which the backend would otherwise happily run.
+    fn script_filename(&self, path: &str) -> Option<PathBuf> {
+        let decoded = percent::decode(path)?;
+        if decoded.split(['/', '\\']).any(|segment| segment == "..") || decoded.contains('\0') {
+            return None;
+        }
+        Some(self.config.document_root.join(decoded.trim_start_matches('/')))
+    }
+
+    fn params(&self, request: &HttpRequest, path: &str, script_filename: &Path) -> Vec<(String, String)> {
+        let root = &self.config.document_root;
+        let mut params = cgi::environment(request, path, "");
+        params.push(("SCRIPT_FILENAME".to_string(), script_filename.display().to_string()));
+        params.push(("DOCUMENT_ROOT".to_string(), root.display().to_string()));
+        params
+    }
+}
+
+/// Whether an idle connection is still open: the backend hasn't closed it, nor sent
+/// anything unasked. Doesn't wait for the backend.
+async fn is_open(connection: &mut Connection) -> bool {
+    std::future::poll_fn(|cx| Poll::Ready(Pin::new(&mut *connection).poll_fill_buf(cx).is_pending())).await
+}
+
+/// Runs one request/response exchange, handing the connection back for reuse.
+async fn exchange(mut connection: Connection, params: &[(String, String)], stdin: &[u8]) -> anyhow::Result<(Vec<u8>, Connection)> {
+    let mut begin = Vec::with_capacity(8);
+    begin.extend_from_slice(&ROLE_RESPONDER.to_be_bytes());
+    begin.push(FLAG_KEEP_CONN);
+    begin.extend_from_slice(&[0; 5]);
+    write_record(&mut connection, BEGIN_REQUEST, &begin).await?;
+
+    let mut encoded = Vec::new();
+    for (name, value) in params {
+        encode_length(&mut encoded, name.len());
+        encode_length(&mut encoded, value.len());
+        encoded.extend_from_slice(name.as_bytes());
+        encoded.extend_from_slice(value.as_bytes());
+    }
+    write_stream(&mut connection, PARAMS, &encoded).await?;
+    write_stream(&mut connection, STDIN, stdin).await?;
+    connection.flush().await?;
+
+    let mut stdout = Vec::new();
+    loop {
+        let (record_type, content) = read_record(&mut connection).await?;
+        match record_type {
+            STDOUT => stdout.extend_from_slice(&content),
+            STDERR => log_error!("fastcgi stderr: {}", String::from_utf8_lossy(&content).trim_end()),
+            END_REQUEST => {
+                let protocol_status = content.get(4).copied().unwrap_or_default();
+                if protocol_status != 0 {
+                    bail!("fastcgi backend rejected the request, protocol status {protocol_status}");
+                }
+                return Ok((stdout, connection));
+            }
+            other => log_debug!("ignoring fastcgi record type {other}"),
+        }
+    }
+}
+
+/// Writes `content` as a stream of records, terminated by the empty record.
+async fn write_stream(connection: &mut Connection, record_type: u8, content: &[u8]) -> anyhow::Result<()> {
+    for chunk in content.chunks(MAX_RECORD_CONTENT) {
+        write_record(connection, record_type, chunk).await?;
+    }
+    write_record(connection, record_type, &[]).await
+}
+
+async fn write_record(connection: &mut Connection, record_type: u8, content: &[u8]) -> anyhow::Result<()> {
+    let padding = (8 - content.len() % 8) % 8;
+    let mut header = [VERSION_1, record_type, 0, 0, 0, 0, padding as u8, 0];
+    header[2..4].copy_from_slice(&REQUEST_ID.to_be_bytes());
+    header[4..6].copy_from_slice(&(content.len() as u16).to_be_bytes());
+
+    connection.write_all(&header).await?;
+    connection.write_all(content).await?;
+    connection.write_all(&[0; 8][..padding]).await?;
+    Ok(())
+}
+
+async fn read_record(connection: &mut Connection) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 8];
+    connection.read_exact(&mut header).await.context("reading fastcgi record header")?;
+    if header[0] != VERSION_1 {
+        bail!("unsupported fastcgi version {}", header[0]);
+    }
+    let content_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding = header[6] as usize;
+
+    let mut content = vec![0; content_length + padding];
+    connection.read_exact(&mut content).await.context("reading fastcgi record content")?;
+    content.truncate(content_length);
+    Ok((header[1], content))
+}
+
+fn encode_length(buffer: &mut Vec<u8>, length: usize) {
+    if length < 0x80 {
+        buffer.push(length as u8);
+    } else {
+        buffer.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::clock::{MockClock, SystemClock};
+    use crate::request::parse_http_request;
+
+    #[test]
+    fn script_filename_stays_under_the_root() {
+        let client = FastCgiClient::new(FastCgiConfig {
+            address: "127.0.0.1:9000".to_string(),
+            pattern: "*.php".to_string(),
+            document_root: PathBuf::from("/srv/www"),
+            timeout: Duration::from_secs(1),
+        }, Arc::new(MockClock::new(std::time::UNIX_EPOCH)));
+
+        assert_eq!(client.script_filename("/a/index.php"), Some(PathBuf::from("/srv/www/a/index.php")));
+        assert_eq!(client.script_filename("/with%20space.php"), Some(PathBuf::from("/srv/www/with space.php")));
+        assert_eq!(client.script_filename("/../../etc/x.php"), None);
+        assert_eq!(client.script_filename("/a/%2e%2e/%2E%2E/x.php"), None);
+        assert_eq!(client.script_filename("/a/..%5cx.php"), None);
+        assert_eq!(client.script_filename("/a%00.php"), None);
+    }
+
+    /// A backend answering every request on a connection but the `drop_at`th overall, after
+    /// which it hangs up without a word; returns its address and how many it received.
+    async fn backend(drop_at: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut connection: Connection = BufStream::new(Box::new(stream));
+                let counter = counter.clone();
+                tokio::spawn(async move {
+                    loop {
+                        // a request ends with its empty STDIN record
+                        loop {
+                            let Ok((record_type, content)) = read_record(&mut connection).await else {
+                                return;
+                            };
+                            if record_type == STDIN && content.is_empty() {
+                                break;
+                            }
+                        }
+                        if counter.fetch_add(1, Ordering::SeqCst) + 1 == drop_at {
+                            return;
+                        }
+                        write_stream(&mut connection, STDOUT, b"Content-Type: text/plain\r\n\r\nok").await.unwrap();
+                        write_record(&mut connection, END_REQUEST, &[0; 8]).await.unwrap();
+                        connection.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+        (address, received)
+    }
+
+    #[tokio::test]
+    async fn only_safe_requests_are_sent_again() {
+        for (method, status, received) in [("GET", 200, 3), ("POST", 502, 2)] {
+            let (address, count) = backend(2).await;
+            let client = FastCgiClient::new(FastCgiConfig {
+                address,
+                pattern: "*.php".to_string(),
+                document_root: PathBuf::from("/srv/www"),
+                timeout: Duration::from_secs(5),
+            }, Arc::new(SystemClock));
+            let status_of = |raw: String| {
+                let client = &client;
+                async move {
+                    let (_, request) = parse_http_request(&raw).unwrap();
+                    client.handle(&request, "/a.php").await.status_code.code_and_phrase().0
+                }
+            };
+
+            assert_eq!(status_of("GET /a.php HTTP/1.1\r\nHost: a\r\n\r\n".to_string()).await, 200);
+            // the backend takes this one in on the pooled connection, then hangs up
+            let raw = format!("{method} /a.php HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\n\r\n");
+            assert_eq!(status_of(raw).await, status, "{method}");
+            assert_eq!(count.load(Ordering::SeqCst), received, "{method}");
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+
+use crate::HttpRequest;
+
+/// An address block such as `10.0.0.0/8` or `2001:db8::/32`; a bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (address, prefix) = s.split_once('/').map_or((s, None), |(address, prefix)| (address, Some(prefix)));
+        let network: IpAddr = address.parse().with_context(|| format!("{address:?} is not an IP address"))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= max)
+                .with_context(|| format!("{prefix:?} is not a prefix length up to {max}"))?,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // an IPv4 peer accepted on a dual-stack socket shows up as ::ffff:a.b.c.d
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What trusted proxies reported about the client they forwarded a request for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Forwarded {
+    /// `None` when the client hop was obfuscated (`for=unknown`) or unparsable.
+    pub client_ip: Option<IpAddr>,
+    pub proto: Option<String>,
+}
+
+/// Reads `Forwarded`, or failing that `X-Forwarded-For` and `X-Forwarded-Proto`, but only
+/// when the peer itself is a trusted proxy; anyone else could claim to be anyone.
+///
+/// The chain is walked from the nearest hop outwards, skipping trusted proxies, so a client
+/// can't spoof its address by sending the headers itself: its made-up entries sit left of
+/// the one the first trusted proxy appended.
+pub fn resolve(request: &HttpRequest, trusted: &[Cidr]) -> Option<Forwarded> {
+    let peer = request.remote_addr?.ip();
+    if !trusted.iter().any(|cidr| cidr.contains(peer)) {
+        return None;
+    }
+
+    let hops = match request.header("Forwarded") {
+        Some(forwarded) => parse_forwarded(forwarded).ok()?,
+        None => {
+            let addresses = split_list(request.header("X-Forwarded-For")?);
+            let protos = request.header("X-Forwarded-Proto").map(split_list).unwrap_or_default();
+            let aligned = protos.len() == addresses.len();
+            addresses.iter().enumerate()
+                .map(|(index, address)| {
+                    let proto = if aligned { protos.get(index) } else { protos.last() };
+                    (parse_node(address), proto.map(|proto| proto.to_string()))
+                })
+                .collect()
+        }
+    };
+
+    let (client_ip, proto) = hops.iter().rev()
+        .find(|(ip, _)| !ip.is_some_and(|ip| trusted.iter().any(|cidr| cidr.contains(ip))))
+        .or(hops.first())?
+        .clone();
+    Some(Forwarded { client_ip, proto })
+}
+
+fn split_list(value: &str) -> Vec<&str> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).collect()
+}
+
+/// The `for` and `proto` of each element of an RFC 7239 `Forwarded` value, client first.
+fn parse_forwarded(value: &str) -> anyhow::Result<Vec<(Option<IpAddr>, Option<String>)>> {
+    split_list(value).into_iter()
+        .map(|element| {
+            let (mut client, mut proto) = (None, None);
+            for pair in element.split(';').map(str::trim).filter(|pair| !pair.is_empty()) {
+                let Some((name, value)) = pair.split_once('=') else {
+                    bail!("{pair:?} in Forwarded is not name=value");
+                };
+                let value = value.trim_matches('"');
+                if name.eq_ignore_ascii_case("for") {
+                    client = parse_node(value);
+                } else if name.eq_ignore_ascii_case("proto") {
+                    proto = Some(value.to_ascii_lowercase());
+                }
+            }
+            Ok((client, proto))
+        })
+        .collect()
+}
+
+/// An address as proxies write them: bare, with a port, or bracketed IPv6 with or without one.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{HeaderMap, HttpMethod};
+
+    fn request(peer: &str, headers: &[(&str, &str)]) -> HttpRequest {
+        HttpRequest {
+            method: HttpMethod::Get,
+            route: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<HeaderMap>(),
+            body: None,
+            remote_addr: Some(peer.parse().unwrap()),
+            forwarded: None,
+            spooled: None,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn cidrs_match_their_block() {
+        let block: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(block.contains("10.1.200.3".parse().unwrap()));
+        assert!(block.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!block.contains("10.2.0.1".parse().unwrap()));
+        assert!("::/0".parse::<Cidr>().unwrap().contains("2001:db8::1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains("192.0.2.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn only_trusted_peers_are_believed() {
+        let trusted = ["10.0.0.0/8".parse().unwrap()];
+        let spoofed = request("198.51.100.7:4000", &[("X-Forwarded-For", "192.0.2.1")]);
+        assert_eq!(resolve(&spoofed, &trusted), None);
+
+        // the client's own X-Forwarded-For entry is left of what the proxies appended
+        let proxied = request("10.0.0.2:4000", &[
+            ("X-Forwarded-For", "203.0.113.9, 192.0.2.1, 10.0.0.1"),
+            ("X-Forwarded-Proto", "https"),
+        ]);
+        assert_eq!(resolve(&proxied, &trusted), Some(Forwarded {
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            proto: Some("https".to_string()),
+        }));
+
+        let standard = request("10.0.0.2:4000", &[
+            ("Forwarded", r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.1;proto=http"#),
+        ]);
+        assert_eq!(resolve(&standard, &trusted), Some(Forwarded {
+            client_ip: Some("2001:db8::1".parse().unwrap()),
+            proto: Some("https".to_string()),
+        }));
+    }
+}
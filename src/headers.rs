@@ -0,0 +1,142 @@
+use std::ops::Index;
+
+/// Header fields by name, which compare case-insensitively (RFC 9110 section 5.1), so
+/// `content-length` from one client and `Content-Length` from another are the same field.
+/// Fields keep the order and the spelling they were first given in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMap(Vec<(String, String)>);
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        HeaderMap::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.position(name).map(|i| self.0[i].1.as_str())
+    }
+
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    /// Sets field `name` to `value`, returning the value it replaces.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) -> Option<String> {
+        let name = name.into();
+        match self.position(&name) {
+            Some(i) => Some(std::mem::replace(&mut self.0[i].1, value.into())),
+            None => {
+                self.0.push((name, value.into()));
+                None
+            }
+        }
+    }
+
+    /// Adds `value` to field `name`: a repeated field is the same as one field listing all
+    /// the values (RFC 9110 section 5.3). `Cookie` pairs are joined with "; " instead (RFC 6265
+    /// section 5.4), and `Set-Cookie`, whose values can't be combined, stays a field of its own.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        match self.position(&name).filter(|_| !name.eq_ignore_ascii_case("Set-Cookie")) {
+            Some(i) => {
+                let values = &mut self.0[i].1;
+                values.push_str(if name.eq_ignore_ascii_case("Cookie") { "; " } else { ", " });
+                values.push_str(&value.into());
+            }
+            None => self.0.push((name, value.into())),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        self.position(name).map(|i| self.0.remove(i).1)
+    }
+
+    /// Keeps only the fields `keep` returns true for.
+    pub fn retain(&mut self, keep: impl FnMut(&(String, String)) -> bool) {
+        self.0.retain(keep);
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (String, String)> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.0.iter().position(|(field, _)| field.eq_ignore_ascii_case(name))
+    }
+}
+
+impl Index<&str> for HeaderMap {
+    type Output = String;
+
+    fn index(&self, name: &str) -> &String {
+        let i = self.position(name).unwrap_or_else(|| panic!("no {name} header"));
+        &self.0[i].1
+    }
+}
+
+impl FromIterator<(String, String)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (String, String)>>(fields: I) -> Self {
+        let mut headers = HeaderMap::new();
+        for (name, value) in fields {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+impl<const N: usize> From<[(String, String); N]> for HeaderMap {
+    fn from(fields: [(String, String); N]) -> Self {
+        fields.into_iter().collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = &'a (String, String);
+    type IntoIter = std::slice::Iter<'a, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_compare_case_insensitively() {
+        let mut headers: HeaderMap = [
+            ("Content-Length".to_string(), "5".to_string()),
+            ("accept".to_string(), "text/html".to_string()),
+            ("ACCEPT".to_string(), "*/*".to_string()),
+        ].into();
+        assert_eq!(headers.get("content-length"), Some("5"));
+        assert_eq!(headers["Accept"], "text/html, */*");
+        assert_eq!(headers.len(), 2);
+
+        assert_eq!(headers.insert("CONTENT-LENGTH", "7"), Some("5".to_string()));
+        assert_eq!(headers.iter().next(), Some(&("Content-Length".to_string(), "7".to_string())));
+        assert_eq!(headers.remove("Accept"), Some("text/html, */*".to_string()));
+        assert!(!headers.contains_key("accept"));
+    }
+
+    #[test]
+    fn cookies_are_combined_their_own_way() {
+        let mut headers = HeaderMap::new();
+        headers.append("Cookie", "a=1");
+        headers.append("cookie", "b=2");
+        assert_eq!(headers["Cookie"], "a=1; b=2");
+
+        headers.append("Set-Cookie", "a=1; Path=/");
+        headers.append("Set-Cookie", "b=2; Expires=Wed, 21 Oct 2015 07:28:00 GMT");
+        let set_cookies = headers.iter().filter(|(name, _)| name == "Set-Cookie").count();
+        assert_eq!(set_cookies, 2);
+    }
+}
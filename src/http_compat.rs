@@ -0,0 +1,244 @@
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context};
+use bytes::Bytes;
+
+use crate::storage::BoxFuture;
+use crate::{forwarded, session, upload, Content, HeaderMap, HttpMethod, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+fn version_from_str(version: &str) -> anyhow::Result<http::Version> {
+    match version {
+        "HTTP/0.9" => Ok(http::Version::HTTP_09),
+        "HTTP/1.0" => Ok(http::Version::HTTP_10),
+        "HTTP/1.1" => Ok(http::Version::HTTP_11),
+        "HTTP/2.0" => Ok(http::Version::HTTP_2),
+        "HTTP/3.0" => Ok(http::Version::HTTP_3),
+        other => bail!("unsupported http version {other:?}"),
+    }
+}
+
+fn version_to_string(version: http::Version) -> String {
+    // the Debug representation is the protocol string, e.g. "HTTP/1.1"
+    format!("{version:?}")
+}
+
+/// A streamed body or a protocol switch, which a `Bytes` body can't hold. It travels in the
+/// response extensions instead, and is put back when the response is converted back.
+#[derive(Clone)]
+struct Passthrough(Arc<Mutex<Option<Content>>>);
+
+/// The peer address travels in the request extensions, which is where hyper-style code looks,
+/// and so does what was already worked out about the request: the resolved proxy chain, a
+/// spooled body and the session.
+impl TryFrom<http::Request<Bytes>> for HttpRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(request: http::Request<Bytes>) -> anyhow::Result<Self> {
+        let (parts, body) = request.into_parts();
+        let method = match parts.method {
+            http::Method::GET => HttpMethod::Get,
+            http::Method::HEAD => HttpMethod::Head,
+            http::Method::POST => HttpMethod::Post,
+            http::Method::PUT => HttpMethod::Put,
+            http::Method::DELETE => HttpMethod::Delete,
+            http::Method::OPTIONS => HttpMethod::Options,
+            other => bail!("unsupported method {other}"),
+        };
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &parts.headers {
+            let value = value.to_str().with_context(|| format!("header {name} is not visible ascii"))?;
+            headers.append(name.as_str(), value);
+        }
+
+        let body = if body.is_empty() && !headers.contains_key("content-length") {
+            None
+        } else {
+            Some(body.to_vec())
+        };
+
+        Ok(HttpRequest {
+            method,
+            route: parts.uri.path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string(),
+            version: version_to_string(parts.version),
+            headers,
+            body,
+            remote_addr: parts.extensions.get::<SocketAddr>().copied(),
+            forwarded: parts.extensions.get::<forwarded::Forwarded>().cloned(),
+            spooled: parts.extensions.get::<Arc<upload::SpooledBody>>().cloned(),
+            session: parts.extensions.get::<Arc<session::Session>>().cloned(),
+        })
+    }
+}
+
+impl TryFrom<HttpRequest> for http::Request<Bytes> {
+    type Error = anyhow::Error;
+
+    fn try_from(request: HttpRequest) -> anyhow::Result<Self> {
+        let mut builder = http::Request::builder()
+            .method(request.method.as_str())
+            .uri(&request.route)
+            .version(version_from_str(&request.version)?);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(remote_addr) = request.remote_addr {
+            builder = builder.extension(remote_addr);
+        }
+        if let Some(forwarded) = request.forwarded {
+            builder = builder.extension(forwarded);
+        }
+        if let Some(spooled) = request.spooled {
+            builder = builder.extension(spooled);
+        }
+        if let Some(session) = request.session {
+            builder = builder.extension(session);
+        }
+        builder.body(request.body.map(Bytes::from).unwrap_or_default())
+            .map_err(|err| anyhow!("invalid request: {err}"))
+    }
+}
+
+impl TryFrom<HttpResponseBuilder> for http::Response<Bytes> {
+    type Error = anyhow::Error;
+
+    fn try_from(mut response: HttpResponseBuilder) -> anyhow::Result<Self> {
+        let passthrough = matches!(response.content, Content::Stream(..) | Content::Upgrade(_))
+            .then(|| Passthrough(Arc::new(Mutex::new(Some(std::mem::replace(&mut response.content, Content::Empty))))));
+        let (status_code, version, mut headers, body) = response.into_parts();
+        if passthrough.is_some() {
+            // framed once the content is back
+            headers.remove("Content-Length");
+        }
+        let mut builder = http::Response::builder()
+            .status(status_code.code_and_phrase().0)
+            .version(version_from_str(&version)?);
+        for (name, value) in &headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(passthrough) = passthrough {
+            builder = builder.extension(passthrough);
+        }
+        builder.body(body.map(Bytes::from).unwrap_or_default())
+            .map_err(|err| anyhow!("invalid response: {err}"))
+    }
+}
+
+impl From<http::Response<Bytes>> for HttpResponseBuilder {
+    fn from(response: http::Response<Bytes>) -> Self {
+        let (parts, body) = response.into_parts();
+        let status = parts.status;
+        let phrase = status.canonical_reason().unwrap_or_default().to_string();
+
+        let headers = parts.headers.iter()
+            // recomputed from the body when the response is written
+            .filter(|(name, _)| *name != http::header::CONTENT_LENGTH)
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect();
+
+        let passthrough = parts.extensions.get::<Passthrough>().and_then(|passthrough| passthrough.0.lock().unwrap().take());
+        let content = match passthrough {
+            Some(content) => content,
+            None if body.is_empty() => Content::Empty,
+            None => Content::Bytes(body.to_vec()),
+        };
+
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Other(status.as_u16(), phrase),
+            version: version_to_string(parts.version),
+            headers,
+            content,
+        }
+    }
+}
+
+type HandlerFn = dyn Fn(http::Request<Bytes>) -> BoxFuture<'static, http::Response<Bytes>> + Send + Sync;
+
+/// A hyper-style handler, taking and returning the http crate's types, mounted under a path
+/// prefix so existing handler code runs inside this server unchanged.
+#[derive(Clone)]
+pub struct Mount {
+    prefix: String,
+    handler: Arc<HandlerFn>,
+}
+
+impl fmt::Debug for Mount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mount").field("prefix", &self.prefix).finish_non_exhaustive()
+    }
+}
+
+impl Mount {
+    #[allow(dead_code)] // the binary mounts nothing yet, handlers come from embedding code
+    pub fn new<F, Fut>(prefix: impl Into<String>, handler: F) -> Self
+    where
+        F: Fn(http::Request<Bytes>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = http::Response<Bytes>> + Send + 'static,
+    {
+        Mount {
+            prefix: prefix.into().trim_end_matches('/').to_string(),
+            handler: Arc::new(move |request| Box::pin(handler(request))),
+        }
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(&self.prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    pub async fn handle(&self, request: &HttpRequest) -> anyhow::Result<HttpResponseBuilder> {
+        let request = http::Request::try_from(request.clone())?;
+        Ok((self.handler)(request).await.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::test_client::TestClient;
+    use crate::ServerConfig;
+
+    #[test]
+    fn request_round_trips_through_http_types() {
+        let request = HttpRequest {
+            method: HttpMethod::Post,
+            route: "/files/a?raw=1".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: HeaderMap::from([("content-length".to_string(), "2".to_string())]),
+            body: Some(b"hi".to_vec()),
+            remote_addr: Some("127.0.0.1:5000".parse().unwrap()),
+            forwarded: None,
+            spooled: None,
+            session: None,
+        };
+
+        let converted = http::Request::try_from(request.clone()).unwrap();
+        assert_eq!(converted.uri().query(), Some("raw=1"));
+        let back = HttpRequest::try_from(converted).unwrap();
+        assert_eq!(format!("{back:?}"), format!("{request:?}"));
+    }
+
+    #[tokio::test]
+    async fn mounted_handler_answers_under_its_prefix() {
+        let mount = Mount::new("/hyper/", |request: http::Request<Bytes>| async move {
+            http::Response::builder()
+                .status(http::StatusCode::ACCEPTED)
+                .header("x-path", request.uri().path())
+                .body(Bytes::from_static(b"from hyper"))
+                .unwrap()
+        });
+        let client = TestClient::new(ServerConfig { mounts: vec![mount], ..Default::default() });
+
+        let response = client.get("/hyper/hello").send().await;
+        assert_eq!(response.status, 202);
+        assert_eq!(response.header("x-path"), Some("/hyper/hello"));
+        assert_eq!(response.text(), "from hyper");
+
+        assert_eq!(client.get("/hyperactive").send().await.status, 404);
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt::{self, Write};
+
+/// Just enough JSON to write documents without pulling in a serializer.
+pub enum Json {
+    Str(String),
+    Num(u64),
+    Bool(bool),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::Str(value.to_string())
+    }
+}
+
+pub fn obj<const N: usize>(entries: [(&str, Json); N]) -> Json {
+    Json::Obj(entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+fn write_str(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Str(s) => write_str(f, s),
+            Json::Num(n) => write!(f, "{n}"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::Arr(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_char(']')
+            }
+            Json::Obj(entries) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_str(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// Checks that `text` is one well-formed JSON value (RFC 8259), returning the keys of the
+/// top-level object if it is one, or a description of the first problem.
+pub fn top_level_keys(text: &str) -> Result<Vec<String>, String> {
+    let mut parser = Parser { bytes: text.as_bytes(), at: 0, keys: Vec::new() };
+    parser.value(0)?;
+    parser.whitespace();
+    if parser.at != parser.bytes.len() {
+        return Err(format!("unexpected content at byte {}", parser.at));
+    }
+    Ok(parser.keys)
+}
+
+/// Nesting deeper than this is refused rather than risking the stack.
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    keys: Vec<String>,
+}
+
+impl Parser<'_> {
+    fn whitespace(&mut self) {
+        while self.bytes.get(self.at).is_some_and(|b| matches!(b, b' ' | b'\t' | b'\n' | b'\r')) {
+            self.at += 1;
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.whitespace();
+        if self.bytes.get(self.at) != Some(&byte) {
+            return Err(format!("expected {:?} at byte {}", byte as char, self.at));
+        }
+        self.at += 1;
+        Ok(())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<(), String> {
+        if depth > MAX_DEPTH {
+            return Err("nested too deeply".to_string());
+        }
+        self.whitespace();
+        match self.bytes.get(self.at) {
+            Some(b'{') => self.object(depth),
+            Some(b'[') => self.array(depth),
+            Some(b'"') => self.string().map(drop),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => ["true", "false", "null"].iter()
+                .find(|literal| self.bytes[self.at..].starts_with(literal.as_bytes()))
+                .map(|literal| self.at += literal.len())
+                .ok_or_else(|| format!("expected a value at byte {}", self.at)),
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<(), String> {
+        self.at += 1;
+        self.whitespace();
+        if self.bytes.get(self.at) == Some(&b'}') {
+            self.at += 1;
+            return Ok(());
+        }
+        loop {
+            self.whitespace();
+            if self.bytes.get(self.at) != Some(&b'"') {
+                return Err(format!("expected a key at byte {}", self.at));
+            }
+            let key = self.string()?;
+            if depth == 0 {
+                self.keys.push(key);
+            }
+            self.expect(b':')?;
+            self.value(depth + 1)?;
+            self.whitespace();
+            match self.bytes.get(self.at) {
+                Some(b',') => self.at += 1,
+                Some(b'}') => {
+                    self.at += 1;
+                    return Ok(());
+                }
+                _ => return Err(format!("expected ',' or '}}' at byte {}", self.at)),
+            }
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<(), String> {
+        self.at += 1;
+        self.whitespace();
+        if self.bytes.get(self.at) == Some(&b']') {
+            self.at += 1;
+            return Ok(());
+        }
+        loop {
+            self.value(depth + 1)?;
+            self.whitespace();
+            match self.bytes.get(self.at) {
+                Some(b',') => self.at += 1,
+                Some(b']') => {
+                    self.at += 1;
+                    return Ok(());
+                }
+                _ => return Err(format!("expected ',' or ']' at byte {}", self.at)),
+            }
+        }
+    }
+
+    /// A string, unescaped only as far as keys need: escapes are checked but kept as written.
+    fn string(&mut self) -> Result<String, String> {
+        let start = self.at;
+        self.at += 1;
+        loop {
+            match self.bytes.get(self.at) {
+                None => return Err(format!("unterminated string at byte {start}")),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    match self.bytes.get(self.at + 1) {
+                        Some(b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => self.at += 2,
+                        Some(b'u') if self.bytes.get(self.at + 2..self.at + 6).is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) => self.at += 6,
+                        _ => return Err(format!("invalid escape at byte {}", self.at)),
+                    }
+                }
+                Some(b) if *b < 0x20 => return Err(format!("control character in string at byte {}", self.at)),
+                Some(_) => self.at += 1,
+            }
+        }
+        self.at += 1;
+        // the input is a str, and quotes are never inside a multi-byte character
+        Ok(String::from_utf8_lossy(&self.bytes[start + 1..self.at - 1]).into_owned())
+    }
+
+    fn number(&mut self) -> Result<(), String> {
+        let start = self.at;
+        let digits = |parser: &mut Self| {
+            let from = parser.at;
+            while parser.bytes.get(parser.at).is_some_and(u8::is_ascii_digit) {
+                parser.at += 1;
+            }
+            parser.at > from
+        };
+        if self.bytes.get(self.at) == Some(&b'-') {
+            self.at += 1;
+        }
+        if self.bytes.get(self.at) == Some(&b'0') {
+            self.at += 1;
+        } else if !digits(self) {
+            return Err(format!("invalid number at byte {start}"));
+        }
+        if self.bytes.get(self.at) == Some(&b'.') {
+            self.at += 1;
+            if !digits(self) {
+                return Err(format!("invalid number at byte {start}"));
+            }
+        }
+        if matches!(self.bytes.get(self.at), Some(b'e' | b'E')) {
+            self.at += 1;
+            if matches!(self.bytes.get(self.at), Some(b'+' | b'-')) {
+                self.at += 1;
+            }
+            if !digits(self) {
+                return Err(format!("invalid number at byte {start}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(Json::from("a \"quoted\"\n\u{1}").to_string(), r#""a \"quoted\"\n\u0001""#);
+    }
+
+    #[test]
+    fn well_formed_json_is_told_apart() {
+        assert_eq!(top_level_keys(r#" {"name": "ada", "tags": [1, -2.5e3, true, null, {"x": "é"}]} "#).unwrap(), ["name", "tags"]);
+        assert_eq!(top_level_keys("[]").unwrap(), Vec::<String>::new());
+        for malformed in ["", "{", r#"{"a" 1}"#, "[1,]", "01", r#""\x""#, "nul", "{} {}", &"[".repeat(100)] {
+            assert!(top_level_keys(malformed).is_err(), "{malformed}");
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+
+use crate::percent;
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value: Vec<u8>,
+    expires: Option<SystemTime>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PutError {
+    /// The value alone is over the store's whole budget.
+    TooLarge,
+    /// There is no room left, even after dropping expired entries.
+    Full,
+}
+
+/// A scratch key-value store shared by every connection, backing `/kv/{key}`. Entries may
+/// expire; keys and values together are kept under `max_bytes`.
+#[derive(Debug)]
+pub struct KvStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    max_bytes: usize,
+}
+
+impl KvStore {
+    pub fn new(max_bytes: usize) -> Self {
+        KvStore { entries: Mutex::default(), max_bytes }
+    }
+
+    pub fn get(&self, key: &str, now: SystemTime) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires.is_some_and(|expires| expires <= now) => {
+                entries.remove(key);
+                None
+            }
+            entry => entry.map(|entry| entry.value.clone()),
+        }
+    }
+
+    /// Stores `value` under `key`, returning whether it replaced a live entry.
+    pub fn put(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>, now: SystemTime) -> Result<bool, PutError> {
+        let size = key.len() + value.len();
+        if size > self.max_bytes {
+            return Err(PutError::TooLarge);
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires.is_none_or(|expires| expires > now));
+        let used: usize = entries.iter()
+            .filter(|(existing, _)| *existing != key)
+            .map(|(key, entry)| key.len() + entry.value.len())
+            .sum();
+        if used + size > self.max_bytes {
+            return Err(PutError::Full);
+        }
+        let entry = Entry { value, expires: ttl.map(|ttl| now + ttl) };
+        Ok(entries.insert(key.to_string(), entry).is_some())
+    }
+
+    /// Removes `key`, returning whether it held a live entry.
+    pub fn delete(&self, key: &str, now: SystemTime) -> bool {
+        let removed = self.entries.lock().unwrap().remove(key);
+        removed.is_some_and(|entry| entry.expires.is_none_or(|expires| expires > now))
+    }
+
+    /// Writes the live entries to `path`, one `key<TAB>expiry<TAB>value` line each with the key
+    /// percent-encoded, the expiry in Unix seconds or `-`, and the value in hex.
+    pub fn save(&self, path: &Path, now: SystemTime) -> anyhow::Result<()> {
+        let mut dump = String::new();
+        for (key, entry) in self.entries.lock().unwrap().iter() {
+            let expires = match entry.expires {
+                Some(expires) if expires <= now => continue,
+                Some(expires) => expires.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs().to_string(),
+                None => "-".to_string(),
+            };
+            let value: String = entry.value.iter().map(|b| format!("{b:02x}")).collect();
+            dump.push_str(&format!("{}\t{expires}\t{value}\n", percent::encode(key)));
+        }
+        // written aside and renamed, so a crash mid-save leaves the previous dump
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, dump).with_context(|| format!("ERROR: writing {}", partial.display()))?;
+        std::fs::rename(&partial, path).with_context(|| format!("ERROR: replacing {}", path.display()))
+    }
+
+    /// Adds the entries saved in `path`, if it exists.
+    pub fn load(&self, path: &Path) -> anyhow::Result<()> {
+        let dump = match std::fs::read_to_string(path) {
+            Ok(dump) => dump,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("ERROR: reading {}", path.display())),
+        };
+        let mut entries = self.entries.lock().unwrap();
+        for (number, line) in dump.lines().enumerate() {
+            let parse = || -> Option<(String, Entry)> {
+                let mut fields = line.split('\t');
+                let key = percent::decode(fields.next()?)?;
+                let expires = match fields.next()? {
+                    "-" => None,
+                    secs => Some(UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?)),
+                };
+                let hex = fields.next()?;
+                let value = (0..hex.len()).step_by(2)
+                    .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                    .collect::<Option<Vec<u8>>>()?;
+                Some((key, Entry { value, expires }))
+            };
+            let (key, entry) = parse().with_context(|| format!("ERROR: {} line {} is malformed", path.display(), number + 1))?;
+            entries.insert(key, entry);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_client::temp_dir;
+
+    #[test]
+    fn entries_expire_and_respect_the_budget() {
+        let store = KvStore::new(16);
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        assert_eq!(store.put("a", b"12345".to_vec(), Some(Duration::from_secs(10)), now), Ok(false));
+        assert_eq!(store.put("b", b"1234567".to_vec(), None, now), Ok(false));
+        assert_eq!(store.put("c", b"123".to_vec(), None, now), Err(PutError::Full));
+        assert_eq!(store.put("d", vec![0; 16], None, now), Err(PutError::TooLarge));
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(store.get("a", later), None);
+        // the expired entry made room
+        assert_eq!(store.put("c", b"123".to_vec(), None, later), Ok(false));
+        assert_eq!(store.put("c", b"456".to_vec(), None, later), Ok(true));
+        assert!(store.delete("c", later));
+        assert!(!store.delete("c", later));
+    }
+
+    #[test]
+    fn dumps_round_trip() {
+        let path = temp_dir("kv").join("kv.tsv");
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+        let store = KvStore::new(1024);
+        store.put("greeting\tkey", b"hello\n\x00".to_vec(), None, now).unwrap();
+        store.put("session", b"x".to_vec(), Some(Duration::from_secs(60)), now).unwrap();
+        store.save(&path, now).unwrap();
+
+        let restored = KvStore::new(1024);
+        restored.load(&path).unwrap();
+        assert_eq!(restored.get("greeting\tkey", now), Some(b"hello\n\x00".to_vec()));
+        assert_eq!(restored.get("session", now + Duration::from_secs(61)), None);
+    }
+}
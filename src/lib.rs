@@ -0,0 +1,59 @@
+pub mod basic_auth;
+pub mod cgi;
+pub mod client_limit;
+pub mod clock;
+pub mod digest;
+pub mod etag;
+pub mod external;
+pub mod fastcgi;
+pub mod forwarded;
+pub mod glob;
+pub mod headers;
+pub mod httpdate;
+pub mod json;
+pub mod kv;
+pub mod log;
+pub mod maintenance;
+#[cfg(feature = "http")]
+pub mod http_compat;
+pub mod markdown;
+pub mod metrics;
+pub mod middleware;
+pub mod mime;
+pub mod openapi;
+pub mod percent;
+pub mod precompress;
+pub mod range;
+pub mod record;
+pub mod request;
+pub mod response;
+pub mod router;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod selftest;
+pub mod server;
+pub mod session;
+pub mod signed_url;
+pub mod slowlog;
+pub mod sse;
+pub mod statsd;
+pub mod storage;
+pub mod template;
+pub mod upload;
+pub mod validate;
+pub mod watch;
+pub mod websocket;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "tower")]
+pub mod tower_compat;
+pub mod wire;
+#[cfg(test)]
+mod test_client;
+
+pub use headers::HeaderMap;
+pub use request::{HttpMethod, HttpRequest};
+pub use response::{Content, HttpResponseBuilder, HttpStatusCode};
+pub use server::{ServerConfig, Service};
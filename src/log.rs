@@ -0,0 +1,398 @@
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use tracing::field::{Field, Visit};
+use tracing::{span, Event, Subscriber};
+use tracing_subscriber::layer::{self, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+use crate::clock::Clock;
+use crate::httpdate;
+
+/// Logs an error, formatted like `eprintln!`, along with the fields of the spans it happens in.
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        ::tracing::error!($($arg)*)
+    };
+}
+pub use log_error;
+
+/// Logs what the server is doing, if the log filter lets info through.
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        ::tracing::info!($($arg)*)
+    };
+}
+pub use log_info;
+
+/// Logs details for debugging, if the log filter lets them through; the message isn't even
+/// formatted otherwise.
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        ::tracing::debug!($($arg)*)
+    };
+}
+pub use log_debug;
+
+/// Facility daemon, as in RFC 5424 section 6.2.1.
+const FACILITY_DAEMON: u8 = 3;
+const SEVERITY_ERROR: u8 = 3;
+const SEVERITY_INFO: u8 = 6;
+const SEVERITY_DEBUG: u8 = 7;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Access,
+    Error,
+    Info,
+    Debug,
+}
+
+#[derive(Debug)]
+enum Transport {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+#[derive(Debug)]
+enum Sink {
+    Stderr,
+    Stdout,
+    File(Mutex<File>),
+    Syslog(Transport),
+}
+
+impl Sink {
+    /// Parses a `--log` value: `stderr`, `stdout`, `file:PATH`, `syslog+udp://HOST:PORT` or
+    /// `syslog+unix:PATH`.
+    fn parse(spec: &str) -> anyhow::Result<Self> {
+        if spec == "stderr" {
+            return Ok(Sink::Stderr);
+        }
+        if spec == "stdout" {
+            return Ok(Sink::Stdout);
+        }
+        if let Some(path) = spec.strip_prefix("file:") {
+            let file = OpenOptions::new().create(true).append(true).open(path)
+                .with_context(|| format!("ERROR: opening log file {path}"))?;
+            return Ok(Sink::File(Mutex::new(file)));
+        }
+        if let Some(address) = spec.strip_prefix("syslog+udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0").context("ERROR: binding syslog socket")?;
+            socket.connect(address).with_context(|| format!("ERROR: connecting to syslog at {address}"))?;
+            return Ok(Sink::Syslog(Transport::Udp(socket)));
+        }
+        if let Some(path) = spec.strip_prefix("syslog+unix:") {
+            let socket = UnixDatagram::unbound().context("ERROR: creating syslog socket")?;
+            socket.connect(PathBuf::from(path)).with_context(|| format!("ERROR: connecting to syslog at {path}"))?;
+            return Ok(Sink::Syslog(Transport::Unix(socket)));
+        }
+        bail!("ERROR: unknown log sink {spec:?}, expected stderr, stdout, file:PATH, syslog+udp://HOST:PORT or syslog+unix:PATH")
+    }
+
+    fn write(&self, kind: Kind, message: &str, syslog: impl FnOnce() -> String) {
+        let line = match kind {
+            Kind::Access => message.to_string(),
+            Kind::Error => format!("ERROR: {message}"),
+            Kind::Info => format!("INFO: {message}"),
+            Kind::Debug => format!("DEBUG: {message}"),
+        };
+        match self {
+            Sink::Stderr => eprintln!("{line}"),
+            Sink::Stdout => println!("{line}"),
+            Sink::File(file) => {
+                let _ = writeln!(file.lock().unwrap(), "{line}");
+            }
+            // a lost datagram is a lost log line, there is nowhere better to report it
+            Sink::Syslog(Transport::Udp(socket)) => {
+                let _ = socket.send(syslog().as_bytes());
+            }
+            Sink::Syslog(Transport::Unix(socket)) => {
+                let _ = socket.send(syslog().as_bytes());
+            }
+        }
+    }
+}
+
+/// How access log lines are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AccessFormat {
+    /// Common Log Format: client, time, request line, status and body bytes sent.
+    #[default]
+    Common,
+    /// Combined Log Format, which adds the `Referer` and `User-Agent`, followed by the time
+    /// taken in microseconds as Apache's `%D` writes it.
+    Combined,
+}
+
+/// One response, as the access log records it.
+#[derive(Debug, Clone)]
+pub struct Access<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub request_line: &'a str,
+    pub status: u16,
+    /// Of the body only, the head isn't counted.
+    pub bytes: usize,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    /// From the request being read to the response being written.
+    pub duration: Duration,
+}
+
+impl AccessFormat {
+    fn line(self, access: &Access, now: SystemTime) -> String {
+        let host = access.client_ip.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_string());
+        let request_line = access.request_line.replace('"', "\\\"");
+        // no body is logged as -, not 0
+        let bytes = match access.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let common = format!("{host} - - [{}] \"{request_line}\" {} {bytes}", httpdate::common_log(now), access.status);
+        match self {
+            AccessFormat::Common => common,
+            AccessFormat::Combined => {
+                let quoted = |value: Option<&str>| value.map_or("-".to_string(), |value| value.replace('"', "\\\""));
+                format!(
+                    "{common} \"{}\" \"{}\" {}",
+                    quoted(access.referer),
+                    quoted(access.user_agent),
+                    access.duration.as_micros(),
+                )
+            }
+        }
+    }
+}
+
+/// Where access and error logs go. Installed once at startup with [`init`]; until then
+/// nothing is logged.
+#[derive(Debug)]
+pub struct Logger {
+    sinks: Vec<Sink>,
+    access_format: AccessFormat,
+    clock: Arc<dyn Clock>,
+    hostname: String,
+}
+
+impl Logger {
+    pub fn new<'a>(specs: impl IntoIterator<Item = &'a String>, access_format: AccessFormat, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        let sinks = specs.into_iter().map(|spec| Sink::parse(spec)).collect::<anyhow::Result<_>>()?;
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_string())
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "-".to_string());
+        Ok(Logger { sinks, access_format, clock, hostname })
+    }
+
+    fn log(&self, kind: Kind, message: &str) {
+        for sink in &self.sinks {
+            sink.write(kind, message, || self.syslog_message(kind, message));
+        }
+    }
+
+    /// An RFC 5424 message, without structured data.
+    fn syslog_message(&self, kind: Kind, message: &str) -> String {
+        let (severity, msgid) = match kind {
+            Kind::Access => (SEVERITY_INFO, "access"),
+            Kind::Error => (SEVERITY_ERROR, "error"),
+            Kind::Info => (SEVERITY_INFO, "info"),
+            Kind::Debug => (SEVERITY_DEBUG, "debug"),
+        };
+        format!(
+            "<{}>1 {} {} http-server {} {msgid} - {message}",
+            FACILITY_DAEMON * 8 + severity,
+            httpdate::rfc3339(self.clock.now()),
+            self.hostname,
+            std::process::id(),
+        )
+    }
+}
+
+/// Installs `logger`, and routes the events `filter` lets through to it. The filter takes
+/// `RUST_LOG` syntax, e.g. `debug` or `info,http_server_starter_rust::router=debug`.
+pub fn init(logger: Logger, filter: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(filter).with_context(|| format!("ERROR: invalid log filter {filter:?}"))?;
+    let _ = LOGGER.set(logger);
+    tracing_subscriber::registry().with(filter).with(SinkLayer(log)).try_init().context("ERROR: installing the logger")
+}
+
+fn log(kind: Kind, message: &str) {
+    if let Some(logger) = LOGGER.get() {
+        logger.log(kind, message);
+    }
+}
+
+/// Hands events to `F` as log lines, prefixed by the spans they happen in and followed by
+/// their other fields, e.g. `connection{peer=127.0.0.1:5000}:request{method=GET path=/a}:
+/// reading file a`.
+struct SinkLayer<F>(F);
+
+impl<S, F> Layer<S> for SinkLayer<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    F: Fn(Kind, &str) + 'static,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: layer::Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<Fields>() {
+                values.record(fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: layer::Context<'_, S>) {
+        let mut line = String::new();
+        for span in ctx.event_scope(event).into_iter().flat_map(|scope| scope.from_root()) {
+            line.push_str(span.name());
+            if let Some(fields) = span.extensions().get::<Fields>().filter(|fields| !fields.rest.is_empty()) {
+                let _ = write!(line, "{{{}}}", fields.rest);
+            }
+            line.push(':');
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        line.push_str(&fields.message);
+        if !fields.rest.is_empty() {
+            let _ = write!(line, " {}", fields.rest);
+        }
+        // warnings are logged as errors, and nothing is finer than debug
+        let kind = match *event.metadata().level() {
+            tracing::Level::ERROR | tracing::Level::WARN => Kind::Error,
+            tracing::Level::INFO => Kind::Info,
+            _ => Kind::Debug,
+        };
+        (self.0)(kind, &line);
+    }
+}
+
+/// The fields of a span or event, the message apart from the rest.
+#[derive(Debug, Default)]
+struct Fields {
+    message: String,
+    rest: String,
+}
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{value}"));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+            return;
+        }
+        if !self.rest.is_empty() {
+            self.rest.push(' ');
+        }
+        let _ = write!(self.rest, "{}={value:?}", field.name());
+    }
+}
+
+/// Logs a response in the configured [`AccessFormat`].
+pub fn access(access: &Access, now: SystemTime) {
+    let format = LOGGER.get().map_or(AccessFormat::default(), |logger| logger.access_format);
+    log(Kind::Access, &format.line(access, now));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[test]
+    fn syslog_messages_use_rfc5424_framing() {
+        let logger = Logger {
+            sinks: Vec::new(),
+            access_format: AccessFormat::Common,
+            clock: Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(784_111_777))),
+            hostname: "web1".to_string(),
+        };
+        let message = logger.syslog_message(Kind::Error, "disk full");
+        let pid = std::process::id();
+        assert_eq!(message, format!("<27>1 1994-11-06T08:49:37.000Z web1 http-server {pid} error - disk full"));
+    }
+
+    #[test]
+    fn access_lines_follow_the_format() {
+        let access = Access {
+            client_ip: Some("192.0.2.7".parse().unwrap()),
+            request_line: "GET /echo/abc HTTP/1.1",
+            status: 200,
+            bytes: 128,
+            referer: None,
+            user_agent: Some("curl/8.5.0 \"test\""),
+            duration: Duration::from_micros(1500),
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        let common = r#"192.0.2.7 - - [06/Nov/1994:08:49:37 +0000] "GET /echo/abc HTTP/1.1" 200 128"#;
+        assert_eq!(AccessFormat::Common.line(&access, now), common);
+        assert_eq!(AccessFormat::Combined.line(&access, now), format!(r#"{common} "-" "curl/8.5.0 \"test\"" 1500"#));
+
+        let empty = Access { status: 204, bytes: 0, ..access };
+        assert!(AccessFormat::Common.line(&empty, now).ends_with("\" 204 -"));
+    }
+
+    #[test]
+    fn events_carry_their_spans_and_fields() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let capture = {
+            let lines = lines.clone();
+            move |kind: Kind, line: &str| lines.lock().unwrap().push(format!("{kind:?} {line}"))
+        };
+        let subscriber = tracing_subscriber::registry().with(EnvFilter::new("info")).with(SinkLayer(capture));
+        tracing::subscriber::with_default(subscriber, || {
+            let _connection = tracing::info_span!("connection", peer = "127.0.0.1:5000").entered();
+            let _request = tracing::info_span!("request", method = "GET", path = "/a").entered();
+            tracing::warn!(bytes = 12, "short read of {}", "a");
+            tracing::debug!("filtered out");
+        });
+        assert_eq!(*lines.lock().unwrap(), [
+            "Error connection{peer=127.0.0.1:5000}:request{method=GET path=/a}: short read of a bytes=12",
+        ]);
+        assert!(EnvFilter::try_new("info,[").is_err());
+    }
+
+    #[test]
+    fn udp_sink_ships_messages() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let spec = format!("syslog+udp://{}", receiver.local_addr().unwrap());
+        let logger = Logger::new([&spec], AccessFormat::Common, Arc::new(MockClock::new(UNIX_EPOCH))).unwrap();
+
+        logger.log(Kind::Access, "127.0.0.1 - - [...] \"GET / HTTP/1.1\" 200 0");
+
+        let mut buf = [0; 512];
+        let n = receiver.recv(&mut buf).unwrap();
+        let received = String::from_utf8_lossy(&buf[..n]);
+        assert!(received.starts_with("<30>1 1970-01-01T00:00:00.000Z "), "{received}");
+        assert!(received.ends_with(" access - 127.0.0.1 - - [...] \"GET / HTTP/1.1\" 200 0"), "{received}");
+    }
+}
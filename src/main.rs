@@ -1,352 +1,924 @@
-use std::borrow::Borrow;
-use std::collections::HashMap;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Context;
 use clap::{Arg, Command};
-use nom::bytes::complete::{tag, take_while1};
-use nom::character::complete::{char, crlf, space0};
-use nom::IResult;
-use nom::multi::many1;
-use nom::sequence::{pair, terminated};
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::net::tcp::ReadHalf;
+use tokio::net::TcpListener;
+
+use http_server_starter_rust::log::{log_debug, log_error, log_info};
+use http_server_starter_rust::request::{LineEndings, ParserConfig};
+use http_server_starter_rust::router::DEFAULT_ROBOTS_TXT;
+use http_server_starter_rust::server::{respond, serve, serve_until, EarlyHint, HeaderRule, RouteTimeout};
+use http_server_starter_rust::{basic_auth, cgi, client_limit, clock, etag, external, fastcgi, kv, log, maintenance, precompress};
+use http_server_starter_rust::{record, selftest, session, signed_url, slowlog, statsd, storage, validate, watch};
+use http_server_starter_rust::{HttpMethod, ServerConfig, Service};
+#[cfg(feature = "s3")]
+use http_server_starter_rust::s3;
+#[cfg(feature = "scripting")]
+use http_server_starter_rust::scripting;
+#[cfg(feature = "tower")]
+use http_server_starter_rust::tower_compat;
+#[cfg(feature = "wasm")]
+use http_server_starter_rust::wasm;
 
-enum Content {
-    Empty,
-    Text(String),
-    OctetStream(String),
-}
-
-#[derive(Debug)]
-enum HttpStatusCode {
-    Ok200,
-    Created201,
-    NotFound404,
-    InternalError500,
-}
-
-#[derive(Debug)]
-enum HttpMethod {
-    Get,
-    Post,
-}
-
-#[derive(Debug)]
-struct HttpRequest {
-    method: HttpMethod,
-    route: String,
-    version: String,
-    headers: HashMap<String, String>,
-    body: Option<String>,
-}
-
-struct HttpResponseBuilder {
-    status_code: HttpStatusCode,
-    version: String,
-    content: Content,
-}
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let command = Command::new("http-server")
+        .arg(
+            Arg::new("directory")
+                .long("directory")
+                .required(false)
+        )
+        .arg(
+            Arg::new("line-endings")
+                .long("line-endings")
+                .help("strict answers request heads with bare LF line endings with 400, lenient accepts them")
+                .value_parser(["strict", "lenient"])
+                .default_value("lenient")
+        )
+        .arg(
+            Arg::new("max-headers")
+                .long("max-headers")
+                .help("Header fields a request may carry before it is answered with 431")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("100")
+        )
+        .arg(
+            Arg::new("max-header-size")
+                .long("max-header-size")
+                .help("Bytes all header fields of a request may take together before it is answered with 431")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("65536")
+        )
+        .arg(
+            Arg::new("max-line-length")
+                .long("max-line-length")
+                .help("Longest request line, answered with 414 beyond it, or header field, answered with 431, in bytes")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("8192")
+        )
+        .arg(
+            Arg::new("max-body-size")
+                .long("max-body-size")
+                .help("Longest request body accepted, in bytes, answered with 413 beyond it")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("104857600")
+        )
+        .arg(
+            Arg::new("handler-timeout")
+                .long("handler-timeout")
+                .help("Seconds a request handler may run before it is cancelled and answered with 503")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("60")
+        )
+        .arg(
+            Arg::new("route-timeout")
+                .long("route-timeout")
+                .help("PATTERN=SECONDS, a handler timeout for request paths matching the glob PATTERN instead of --handler-timeout; repeat for several, the first match wins")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("upload-spool-threshold")
+                .long("upload-spool-threshold")
+                .help("Bytes above which /files uploads are streamed through a temporary file instead of held in memory")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1048576")
+        )
+        .arg(
+            Arg::new("upload-spool-dir")
+                .long("upload-spool-dir")
+                .help("Directory for spooled uploads, the system temporary directory by default")
+        )
+        .arg(
+            Arg::new("write-timeout")
+                .long("write-timeout")
+                .help("Seconds a client may take to accept a response before it is disconnected")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("30")
+        )
+        .arg(
+            Arg::new("header-timeout")
+                .long("header-timeout")
+                .help("Seconds a client may take to send a request's head, answered with 408 beyond it")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("10")
+        )
+        .arg(
+            Arg::new("body-timeout")
+                .long("body-timeout")
+                .help("Seconds a client may take to send a request's body, answered with 408 beyond it")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("60")
+        )
+        .arg(
+            Arg::new("keep-alive-timeout")
+                .long("keep-alive-timeout")
+                .help("Seconds a connection may sit idle between requests before it is closed")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("5")
+        )
+        .arg(
+            Arg::new("shutdown-grace")
+                .long("shutdown-grace")
+                .help("Seconds open connections get to finish their response after SIGINT or SIGTERM")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("10")
+        )
+        .arg(
+            Arg::new("cgi-dir")
+                .long("cgi-dir")
+                .help("Directory of CGI scripts served under /cgi-bin/")
+                .required(false)
+        )
+        .arg(
+            Arg::new("cgi-timeout")
+                .long("cgi-timeout")
+                .help("Seconds a CGI script may run before it is killed")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("30")
+        )
+        .arg(
+            Arg::new("fastcgi-pass")
+                .long("fastcgi-pass")
+                .help("FastCGI backend address, host:port or unix:/path/to/socket")
+                .required(false)
+        )
+        .arg(
+            Arg::new("fastcgi-pattern")
+                .long("fastcgi-pattern")
+                .help("Request paths forwarded to the FastCGI backend")
+                .default_value("*.php")
+        )
+        .arg(
+            Arg::new("fastcgi-root")
+                .long("fastcgi-root")
+                .help("Document root passed to the FastCGI backend, defaults to --directory")
+                .required(false)
+        )
+        .arg(
+            Arg::new("fastcgi-timeout")
+                .long("fastcgi-timeout")
+                .help("Seconds to wait for a FastCGI response")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("30")
+        )
+        .arg(
+            Arg::new("external-handler")
+                .long("external-handler")
+                .help("PATTERN=COMMAND, dispatch request paths matching the glob PATTERN to a long-lived COMMAND speaking length-prefixed frames on stdin/stdout; repeat for several")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("external-timeout")
+                .long("external-timeout")
+                .help("Seconds to wait for an external handler's response")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("30")
+        )
+        .arg(
+            Arg::new("early-hint")
+                .long("early-hint")
+                .help("PATTERN=LINK, send a 103 Early Hints response with the Link header LINK (e.g. '</style.css>; rel=preload; as=style') before answering request paths matching the glob PATTERN; repeat for several")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("add-header")
+                .long("add-header")
+                .help("'PATTERN=Name: value', set this header on responses to request paths matching the glob PATTERN, e.g. '/assets/*=Access-Control-Allow-Origin: *'; repeat for several")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("maintenance")
+                .long("maintenance")
+                .help("Start in maintenance mode, answering everything but /health and /healthz with 503; SIGUSR1 toggles it at runtime")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("maintenance-retry-after")
+                .long("maintenance-retry-after")
+                .help("Seconds sent as Retry-After with maintenance responses")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("300")
+        )
+        .arg(
+            Arg::new("maintenance-page")
+                .long("maintenance-page")
+                .help("HTML file sent as the body of maintenance responses")
+        )
+        .arg(
+            Arg::new("trusted-proxy")
+                .long("trusted-proxy")
+                .help("CIDR (or single address) of a reverse proxy whose Forwarded/X-Forwarded-For/X-Forwarded-Proto headers decide the client address and scheme; repeat for several")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("validate-body")
+                .long("validate-body")
+                .help("'PATTERN=RULE,RULE...', check bodies of requests to paths matching the glob PATTERN before handling them; rules are max-size:BYTES (413), content-type:TYPE (415), json (400) and required:KEY (422), e.g. '/kv/*=max-size:4096,json'; repeat for several")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("method-override")
+                .long("method-override")
+                .help("Method, e.g. DELETE, that a POST may ask to be handled as with X-HTTP-Method-Override or a _method form field; repeat for several")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("max-connections")
+                .long("max-connections")
+                .help("Serve at most this many connections at once; further clients wait until one closes")
+                .value_parser(clap::value_parser!(u64).range(1..))
+        )
+        .arg(
+            Arg::new("max-responses-per-client")
+                .long("max-responses-per-client")
+                .help("Answer 429 to a client IP that already has this many responses in flight")
+                .value_parser(clap::value_parser!(usize))
+        )
+        .arg(
+            Arg::new("https-redirect-listen")
+                .long("https-redirect-listen")
+                .help("ADDR:PORT of an extra plaintext listener answering every request, except ACME challenges, with a 301 to the same URL on https://")
+        )
+        .arg(
+            Arg::new("https-port")
+                .long("https-port")
+                .help("Port the HTTPS redirects point at")
+                .value_parser(clap::value_parser!(u16))
+                .default_value("443")
+        )
+        .arg(
+            Arg::new("precompress-dir")
+                .long("precompress-dir")
+                .help("Gzip the compressible files in --directory into this directory at startup and after uploads, and serve them to clients accepting gzip")
+        )
+        .arg(
+            Arg::new("precompress-min-size")
+                .long("precompress-min-size")
+                .help("Bytes a file needs to have to get a gzipped variant")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("1024")
+        )
+        .arg(
+            Arg::new("session-key-file")
+                .long("session-key-file")
+                .help("File holding the key that signs session cookies; enables sessions and the /session/{key} routes")
+        )
+        .arg(
+            Arg::new("session-ttl")
+                .long("session-ttl")
+                .help("Seconds a session is kept after the client was last seen")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("3600")
+        )
+        .arg(
+            Arg::new("kv")
+                .long("kv")
+                .help("Serve an in-memory key-value store at /kv/{key}; PUT takes an optional Kv-Ttl header in seconds")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("kv-max-bytes")
+                .long("kv-max-bytes")
+                .help("Bytes of keys and values the /kv store holds at most")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("16777216")
+        )
+        .arg(
+            Arg::new("kv-persist")
+                .long("kv-persist")
+                .help("File the /kv store is loaded from at startup and saved to periodically, e.g. one in --directory")
+                .requires("kv")
+        )
+        .arg(
+            Arg::new("kv-persist-interval")
+                .long("kv-persist-interval")
+                .help("Seconds between saves of the /kv store to --kv-persist")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("60")
+        )
+        .arg(
+            Arg::new("url-signing-key-file")
+                .long("url-signing-key-file")
+                .help("File holding the key that signs /files download links, see the sign-url subcommand; links signed with it are checked")
+        )
+        .arg(
+            Arg::new("require-signed-downloads")
+                .long("require-signed-downloads")
+                .help("Refuse /files downloads without a valid signed link")
+                .requires("url-signing-key-file")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("etag")
+                .long("etag")
+                .help("strong tags files with a hash of their content, weak with their length and modification time, which needs no reading")
+                .value_parser(["strong", "weak"])
+                .default_value("strong")
+        )
+        .arg(
+            Arg::new("storage")
+                .long("storage")
+                .help("Where /files keeps its content: local is --directory, memory is lost when the server stops, s3 is the --s3-bucket (with the s3 feature)")
+                .value_parser(["local", "memory", "s3"])
+                .default_value("local")
+        )
+        .arg(
+            Arg::new("content-addressed")
+                .long("content-addressed")
+                .help("Accept POST /files, storing each upload once under its SHA-256 and serving files named so as immutable")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("auth")
+                .long("auth")
+                .help("USER:PASSWORD a client must send with HTTP Basic authentication to use /files; repeat for several users")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("allow-delete")
+                .long("allow-delete")
+                .help("Answer DELETE /files/{name} by deleting the file")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("autoindex")
+                .long("autoindex")
+                .help("List the stored files at GET /files/, as HTML or, with Accept: application/json, JSON")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("watch")
+                .long("watch")
+                .help("Watch --directory for changes made outside the server and refresh cached digests and gzipped variants")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("favicon")
+                .long("favicon")
+                .help("Icon file served at /favicon.ico, which otherwise answers 204")
+        )
+        .arg(
+            Arg::new("robots-txt")
+                .long("robots-txt")
+                .help("File served at /robots.txt instead of the built-in one allowing all crawlers")
+        )
+        .arg(
+            Arg::new("render-markdown")
+                .long("render-markdown")
+                .help("Serve .md files under /files as HTML, unless ?raw=1 is given")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("swagger-ui")
+                .long("swagger-ui")
+                .help("Serve a Swagger UI page for /openapi.json at /docs")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .help("Directory to dump every raw request into, for later replay")
+                .required(false)
+        )
+        .arg(
+            Arg::new("record-responses")
+                .long("record-responses")
+                .help("Dump raw responses next to the recorded requests")
+                .requires("record")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("log")
+                .long("log")
+                .help("Where access and error logs go: stderr, stdout, file:PATH, syslog+udp://HOST:PORT or syslog+unix:PATH; repeat for several")
+                .action(clap::ArgAction::Append)
+                .default_value("stderr")
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .help("What to log besides access lines, in RUST_LOG syntax, e.g. debug or info,http_server_starter_rust::router=debug; defaults to RUST_LOG, then info")
+        )
+        .arg(
+            Arg::new("access-log-format")
+                .long("access-log-format")
+                .help("Access log lines in Common Log Format, or combined, which adds the referer, user agent and microseconds taken")
+                .value_parser(["common", "combined"])
+                .default_value("common")
+        )
+        .arg(
+            Arg::new("slow-request-ms")
+                .long("slow-request-ms")
+                .help("Log requests taking at least this many milliseconds, with where the time went, and list the slowest at /admin/slow-requests")
+                .value_parser(clap::value_parser!(u64))
+        )
+        .arg(
+            Arg::new("slow-requests-kept")
+                .long("slow-requests-kept")
+                .help("How many of the slowest requests /admin/slow-requests lists")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("20")
+        )
+        .arg(
+            Arg::new("metrics")
+                .long("metrics")
+                .help("Serve request counts, open connections and bytes sent at /metrics for Prometheus")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("statsd")
+                .long("statsd")
+                .help("host:port of a StatsD agent to push request counters and timers to")
+                .required(false)
+        )
+        .arg(
+            Arg::new("statsd-prefix")
+                .long("statsd-prefix")
+                .help("Prefix of every StatsD metric name")
+                .default_value("http_server")
+        )
+        .arg(
+            Arg::new("statsd-tag")
+                .long("statsd-tag")
+                .help("key:value tag sent with every metric, requires --dogstatsd; repeat for several")
+                .requires("dogstatsd")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("dogstatsd")
+                .long("dogstatsd")
+                .help("Send DogStatsD tags with every metric, including the request method and status")
+                .requires("statsd")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("trace-wire")
+                .long("trace-wire")
+                .help("Log hexdumps of the bytes read and written on every connection, at trace level whatever --log-level says")
+                .action(clap::ArgAction::SetTrue)
+        )
+        .arg(
+            Arg::new("trace-wire-limit")
+                .long("trace-wire-limit")
+                .help("Bytes dumped per connection and direction before --trace-wire goes quiet")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("4096")
+        )
+        .subcommand(
+            Command::new("respond")
+                .about("Run one raw HTTP request through the router and print the raw response")
+                .arg(
+                    Arg::new("in")
+                        .long("in")
+                        .help("File holding the raw request, with CRLF line endings; - or absent reads stdin")
+                        .required(false)
+                )
+        )
+        .subcommand(
+            Command::new("sign-url")
+                .about("Print a signed link to a path under /files, valid for a while, using --url-signing-key-file")
+                .arg(
+                    Arg::new("path")
+                        .help("Path to sign, e.g. /files/report.pdf")
+                        .required(true)
+                )
+                .arg(
+                    Arg::new("expires-in")
+                        .long("expires-in")
+                        .help("Seconds the link stays valid")
+                        .value_parser(clap::value_parser!(u64))
+                        .default_value("3600")
+                )
+        )
+        .subcommand(
+            Command::new("self-test")
+                .about("Start the server on an ephemeral port, run protocol checks against it and exit nonzero on failure")
+        )
+        .subcommand(
+            Command::new("replay")
+                .about("Run recorded requests through the router, checking any recorded responses")
+                .arg(
+                    Arg::new("dir")
+                        .help("Directory written by --record")
+                        .required(true)
+                )
+        );
+    #[cfg(feature = "tower")]
+    let command = command
+        .arg(
+            Arg::new("request-timeout")
+                .long("request-timeout")
+                .help("Seconds a request may take before it is answered with 503")
+                .value_parser(clap::value_parser!(u64))
+                .required(false)
+        )
+        .arg(
+            Arg::new("concurrency-limit")
+                .long("concurrency-limit")
+                .help("Requests handled at once; the rest wait their turn")
+                .value_parser(clap::value_parser!(usize))
+                .required(false)
+        );
+    #[cfg(feature = "wasm")]
+    let command = command
+        .arg(
+            Arg::new("wasm-plugin")
+                .long("wasm-plugin")
+                .help("PREFIX=PATH, serve requests under PREFIX with the WebAssembly module at PATH; repeat for several")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("wasm-fuel")
+                .long("wasm-fuel")
+                .help("Fuel a WebAssembly plugin may burn per request before it is stopped")
+                .value_parser(clap::value_parser!(u64))
+                .default_value("100000000")
+        );
+    #[cfg(feature = "scripting")]
+    let command = command
+        .arg(
+            Arg::new("script")
+                .long("script")
+                .help("PATTERN=PATH, handle request paths matching the glob PATTERN with the rhai script at PATH; repeat for several")
+                .action(clap::ArgAction::Append)
+        )
+        .arg(
+            Arg::new("script-root")
+                .long("script-root")
+                .help("Directory scripts may read and write files in, defaults to --directory")
+                .required(false)
+        );
+    #[cfg(feature = "s3")]
+    let command = command
+        .arg(
+            Arg::new("s3-endpoint")
+                .long("s3-endpoint")
+                .help("HOST:PORT of the S3-compatible service for --storage s3, spoken to over plain HTTP; credentials come from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY")
+        )
+        .arg(
+            Arg::new("s3-bucket")
+                .long("s3-bucket")
+                .help("Bucket holding the files for --storage s3")
+        )
+        .arg(
+            Arg::new("s3-region")
+                .long("s3-region")
+                .help("Region requests to --s3-endpoint are signed for")
+                .default_value("us-east-1")
+        );
+    let matches = command.get_matches();
 
-impl Into<String> for HttpResponseBuilder {
-    fn into(self) -> String {
-        let (code, phrase) = match self.status_code {
-            HttpStatusCode::Ok200 => (200, "Ok"),
-            HttpStatusCode::Created201 => (201, "Created"),
-            HttpStatusCode::NotFound404 => (404, "NotFound"),
-            HttpStatusCode::InternalError500 => (500, "InternalError"),
-        };
-        let mut response = format!("{} {} {}\r\n", self.version, code, phrase);
-        match self.content {
-            Content::Empty => {
-                response.push_str("\r\n");
-            }
-            Content::Text(content) => {
-                response.push_str(&format!("Content-Type: text/plain\r\n"));
-                response.push_str(&format!("Content-Length: {}\r\n", content.len()));
-                response.push_str("\r\n");
-                response.push_str(&content);
-            }
-            Content::OctetStream(content) => {
-                response.push_str(&format!("Content-Type: application/octet-stream\r\n"));
-                response.push_str(&format!("Content-Length: {}\r\n", content.len()));
-                response.push_str("\r\n");
-                response.push_str(&content);
-            }
-        }
+    let directory = matches
+        .get_one::<String>("directory");
 
-        response
+    let clock: Arc<dyn clock::Clock> = Arc::new(clock::SystemClock);
+    let access_format = match matches.get_one::<String>("access-log-format").unwrap().as_str() {
+        "combined" => log::AccessFormat::Combined,
+        _ => log::AccessFormat::Common,
+    };
+    let mut filter = match matches.get_one::<String>("log-level") {
+        Some(filter) => filter.clone(),
+        None => std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_string()),
+    };
+    if matches.get_flag("trace-wire") {
+        filter.push_str(",http_server_starter_rust::wire=trace");
     }
-}
-
-async fn reader_request(reader: &mut BufReader<&mut ReadHalf<'_>>) -> anyhow::Result<HttpRequest> {
-    let mut request_content = String::new();
-
-    // read until empty line
-    let mut temp_line = String::new();
-    while let Ok(n) = reader.read_line(&mut temp_line).await {
-        if n == 0 || temp_line.trim().is_empty() {
-            break;
+    log::init(log::Logger::new(matches.get_many::<String>("log").unwrap(), access_format, clock.clone())?, &filter)?;
+    log_debug!("directory {:?}", directory);
+
+    let cgi = matches.get_one::<String>("cgi-dir").map(|dir| cgi::CgiConfig {
+        directory: PathBuf::from(dir),
+        timeout: Duration::from_secs(*matches.get_one::<u64>("cgi-timeout").unwrap()),
+    });
+
+    let fastcgi = matches.get_one::<String>("fastcgi-pass").map(|address| {
+        let document_root = matches.get_one::<String>("fastcgi-root")
+            .or(directory)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."));
+        Arc::new(fastcgi::FastCgiClient::new(fastcgi::FastCgiConfig {
+            address: address.clone(),
+            pattern: matches.get_one::<String>("fastcgi-pattern").unwrap().clone(),
+            document_root,
+            timeout: Duration::from_secs(*matches.get_one::<u64>("fastcgi-timeout").unwrap()),
+        }, clock.clone()))
+    });
+
+    let external = matches.get_many::<String>("external-handler").unwrap_or_default()
+        .map(|spec| {
+            let (pattern, command) = spec.split_once('=')
+                .with_context(|| format!("ERROR: --external-handler {spec} is not PATTERN=COMMAND"))?;
+            Ok(Arc::new(external::ExternalHandler::new(external::ExternalConfig {
+                pattern: pattern.to_string(),
+                command: command.to_string(),
+                timeout: Duration::from_secs(*matches.get_one::<u64>("external-timeout").unwrap()),
+            }, clock.clone())))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    let early_hints = matches.get_many::<String>("early-hint").unwrap_or_default()
+        .map(|spec| {
+            let (pattern, link) = spec.split_once('=')
+                .with_context(|| format!("ERROR: --early-hint {spec} is not PATTERN=LINK"))?;
+            Ok(EarlyHint { pattern: pattern.to_string(), link: link.to_string() })
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    let maintenance_page = match matches.get_one::<String>("maintenance-page") {
+        Some(path) => Some(std::fs::read_to_string(path)
+            .with_context(|| format!("ERROR: reading maintenance page {path}"))?),
+        None => None,
+    };
+    let maintenance = Arc::new(maintenance::Maintenance::new(
+        matches.get_flag("maintenance"),
+        Duration::from_secs(*matches.get_one::<u64>("maintenance-retry-after").unwrap()),
+        maintenance_page,
+    ));
+
+    let storage: Option<Arc<dyn storage::Storage>> = match matches.get_one::<String>("storage").unwrap().as_str() {
+        "memory" => Some(Arc::new(storage::MemoryStorage::default())),
+        #[cfg(feature = "s3")]
+        "s3" => {
+            let required = |name: &str| matches.get_one::<String>(name).cloned()
+                .with_context(|| format!("ERROR: --storage s3 needs --{name}"));
+            let credential = |name: &str| std::env::var(name)
+                .with_context(|| format!("ERROR: --storage s3 needs {name} in the environment"));
+            Some(Arc::new(s3::S3Storage::new(s3::S3Config {
+                endpoint: required("s3-endpoint")?,
+                bucket: required("s3-bucket")?,
+                region: matches.get_one::<String>("s3-region").unwrap().clone(),
+                access_key: credential("AWS_ACCESS_KEY_ID")?,
+                secret_key: credential("AWS_SECRET_ACCESS_KEY")?,
+            })))
         }
-        request_content.push_str(&temp_line); // Append the non-empty line
-        temp_line.clear();
-    }
-
-    println!("DEBUG: content {request_content}");
-
-    // parse request
-    let (_left, mut request) = parse_http_request(&request_content)
-        .map_err(|e| e.to_owned())?;
-
-
-    // read body
-    let body = if let Some(length) = request.headers.get("Content-Length") {
-        println!("here!!");
-        let length = length.parse()
-            .context("ERROR: content length is not a valid number")?;
-        println!("DEBUG: content length - {length}");
-
-        let mut buffer = vec![0; length];
-        reader.read_exact(&mut buffer).await
-            .context("ERROR: reading request content")?;
-        println!("DEBUG: buffer {buffer:?}");
-
-        let x = String::from_utf8(buffer)
-            .context("ERROR: request content is not utf8")?;
-        println!("DEBUG: extracted content: {x}");
-        Some(x)
-    } else {
-        println!("there");
-        None
+        #[cfg(not(feature = "s3"))]
+        "s3" => anyhow::bail!("ERROR: --storage s3 needs the server built with the s3 feature"),
+        _ => directory.map(|dir| Arc::new(storage::LocalStorage::new(dir)) as Arc<dyn storage::Storage>),
     };
+    if matches.get_flag("content-addressed") && storage.is_none() {
+        anyhow::bail!("ERROR: --content-addressed needs --directory or another --storage");
+    }
+    if matches.get_flag("allow-delete") && storage.is_none() {
+        anyhow::bail!("ERROR: --allow-delete needs --directory or another --storage");
+    }
+    if matches.get_flag("autoindex") && storage.is_none() {
+        anyhow::bail!("ERROR: --autoindex needs --directory or another --storage");
+    }
+    if matches.contains_id("auth") && storage.is_none() {
+        anyhow::bail!("ERROR: --auth needs --directory or another --storage");
+    }
 
-    request.body = body;
-    Ok(request)
-}
-
-fn non_whitespace(input: &str) -> IResult<&str, &str> {
-    take_while1(|c: char| !c.is_whitespace())(input)
-}
-
-fn parse_http_request(content: &str) -> IResult<&str, HttpRequest> {
-    let (input, method) = terminated(non_whitespace, space0)(content)?;
-    let (input, route) = terminated(non_whitespace, space0)(input)?;
-    let (input, version) = terminated(non_whitespace, crlf)(input)?;
-
-    let (input, headers) = many1(pair(
-        terminated(take_while1(|c: char| c != ':'), tag(": ")),
-        terminated(non_whitespace, crlf),
-    ))(input)?;
-
-    let method = match method {
-        "GET" => HttpMethod::Get,
-        "POST" => HttpMethod::Post,
-        _ => { panic!(); }
+    let precompressed = match (matches.get_one::<String>("precompress-dir"), directory) {
+        (Some(cache_dir), Some(root)) => {
+            let precompressed = precompress::Precompressed::new(
+                root,
+                cache_dir,
+                *matches.get_one::<u64>("precompress-min-size").unwrap(),
+            );
+            let written = precompressed.build_all()
+                .with_context(|| format!("ERROR: precompressing {root} into {cache_dir}"))?;
+            log_info!("precompressed {written} files into {cache_dir}");
+            Some(precompressed)
+        }
+        (Some(_), None) => anyhow::bail!("ERROR: --precompress-dir needs --directory"),
+        (None, _) => None,
     };
 
-    let headers: HashMap<String, String> = headers.into_iter()
-        .map(|(n, v)| (n.to_string(), v.to_string()))
-        .collect();
-
-    Ok(
-        (input, HttpRequest {
-            method,
-            headers,
-            route: route.to_string(),
-            version: version.to_string(),
-            body: None,
+    let sessions = match matches.get_one::<String>("session-key-file") {
+        Some(path) => {
+            let key = std::fs::read(path).with_context(|| format!("ERROR: reading session key {path}"))?;
+            let key = key.trim_ascii().to_vec();
+            anyhow::ensure!(!key.is_empty(), "ERROR: session key {path} is empty");
+            let ttl = Duration::from_secs(*matches.get_one::<u64>("session-ttl").unwrap());
+            Some(session::Sessions::new(key, ttl, Arc::new(session::MemorySessionStore::default())))
         }
-        )
-    )
-}
+        None => None,
+    };
 
-async fn route_request(request: &HttpRequest, directory: Option<String>) -> anyhow::Result<HttpResponseBuilder> {
-    let route = request.route.split('/').skip(1).collect::<Vec<&str>>();
-    println!("DEBUG: route {route:?}");
-    let response = match (&request.method, route.as_slice()) {
-        (HttpMethod::Get, [""]) => {
-            let content = Content::Empty;
-            Ok(
-                HttpResponseBuilder {
-                    status_code: HttpStatusCode::Ok200,
-                    version: request.version.clone(),
-                    content,
-                }
-            )
-        }
-        (HttpMethod::Get, ["echo", val @ ..]) => {
-            let content = Content::Text(val.join("/").to_string());
-            Ok(
-                HttpResponseBuilder {
-                    status_code: HttpStatusCode::Ok200,
-                    version: request.version.clone(),
-                    content,
-                }
-            )
-        }
-        (HttpMethod::Get, ["user-agent"]) => {
-            let user_agent = request.headers.get("User-Agent");
-            match user_agent {
-                Some(user_agent) => {
-                    let user_agent = user_agent.clone();
-                    let content = Content::Text(user_agent);
-                    Ok(
-                        HttpResponseBuilder {
-                            status_code: HttpStatusCode::Ok200,
-                            version: request.version.clone(),
-                            content,
-                        }
-                    )
-                }
-                None => {
-                    Ok(
-                        HttpResponseBuilder {
-                            status_code: HttpStatusCode::NotFound404,
-                            version: request.version.clone(),
-                            content: Content::Empty,
-                        }
-                    )
+    let kv = matches.get_flag("kv")
+        .then(|| Arc::new(kv::KvStore::new(*matches.get_one::<usize>("kv-max-bytes").unwrap())));
+    if let (Some(store), Some(path)) = (&kv, matches.get_one::<String>("kv-persist")) {
+        let path = PathBuf::from(path);
+        store.load(&path)?;
+        let (store, clock) = (store.clone(), clock.clone());
+        let interval = Duration::from_secs(*matches.get_one::<u64>("kv-persist-interval").unwrap());
+        tokio::spawn(async move {
+            loop {
+                clock.sleep(interval).await;
+                if let Err(err) = store.save(&path, clock.now()) {
+                    log_error!("couldn't save the /kv store, error: {err:#}");
                 }
             }
-        }
-        (HttpMethod::Get, ["files", filename]) => {
-            let file_path = match directory {
-                None => {
-                    return Ok(HttpResponseBuilder {
-                        status_code: HttpStatusCode::NotFound404,
-                        version: request.version.clone(),
-                        content: Content::Empty,
-                    });
-                }
-                Some(directory) => {
-                    let dir = Path::new(&directory);
-                    dir.join(filename)
-                }
-            };
-
-            println!("DEBUG: {}", file_path.display());
-
-            let mut file = match File::open(&file_path).await {
-                Err(err) => {
-                    eprintln!("ERROR: couldn't open path {}, error: {err}", file_path.display());
-                    return Ok(HttpResponseBuilder {
-                        status_code: HttpStatusCode::NotFound404,
-                        version: request.version.clone(),
-                        content: Content::Empty,
-                    });
-                }
-                Ok(file) => file
-            };
+        });
+    }
 
-            let mut file_content = String::new();
-            match file.read_to_string(&mut file_content).await {
-                Ok(_) => {
-                    let content = Content::OctetStream(file_content);
-                    Ok(
-                        HttpResponseBuilder {
-                            status_code: HttpStatusCode::Ok200,
-                            version: request.version.clone(),
-                            content,
-                        }
-                    )
-                }
-                Err(err) => {
-                    eprintln!("ERROR: couldn't read file, error: {err}");
-                    Ok(HttpResponseBuilder {
-                        status_code: HttpStatusCode::NotFound404,
-                        version: request.version.clone(),
-                        content: Content::Empty,
-                    })
-                }
-            }
+    let url_signer = match matches.get_one::<String>("url-signing-key-file") {
+        Some(path) => {
+            let key = std::fs::read(path).with_context(|| format!("ERROR: reading URL signing key {path}"))?;
+            let key = key.trim_ascii().to_vec();
+            anyhow::ensure!(!key.is_empty(), "ERROR: URL signing key {path} is empty");
+            Some(signed_url::UrlSigner::new(key, matches.get_flag("require-signed-downloads")))
         }
-        (HttpMethod::Post, ["files", filename]) => {
-            let content = request.body.clone().context("Error: got no content")?;
-            let file_path = match directory {
-                None => {
-                    return Ok(HttpResponseBuilder {
-                        status_code: HttpStatusCode::NotFound404,
-                        version: request.version.clone(),
-                        content: Content::Empty,
-                    });
-                }
-                Some(directory) => {
-                    let dir = Path::new(&directory);
-                    dir.join(filename)
-                }
-            };
+        None => None,
+    };
+    if let Some(sign_matches) = matches.subcommand_matches("sign-url") {
+        let signer = url_signer.as_ref().context("ERROR: sign-url needs --url-signing-key-file")?;
+        let expires_in = Duration::from_secs(*sign_matches.get_one::<u64>("expires-in").unwrap());
+        println!("{}", signer.sign(sign_matches.get_one::<String>("path").unwrap(), clock.now() + expires_in));
+        return Ok(());
+    }
 
-            println!("DEBUG: {}", file_path.display());
-            let mut file = File::create(&file_path).await?;
-            file.write_all(content.as_bytes()).await?;
-            Ok(
-                HttpResponseBuilder {
-                    status_code: HttpStatusCode::Created201,
-                    version: request.version.clone(),
-                    content: Content::Empty,
-                }
-            )
-        }
-        _ => Ok(HttpResponseBuilder {
-            status_code: HttpStatusCode::NotFound404,
-            version: request.version.clone(),
-            content: Content::Empty,
-        }),
+    let trusted_proxies = matches.get_many::<String>("trusted-proxy").unwrap_or_default()
+        .map(|cidr| cidr.parse().with_context(|| format!("ERROR: --trusted-proxy {cidr} is not a CIDR")))
+        .collect::<anyhow::Result<_>>()?;
+
+    let statsd = match matches.get_one::<String>("statsd") {
+        Some(address) => Some(Arc::new(statsd::StatsdClient::new(statsd::StatsdConfig {
+            address: address.clone(),
+            prefix: matches.get_one::<String>("statsd-prefix").unwrap().clone(),
+            tags: matches.get_many::<String>("statsd-tag").unwrap_or_default().cloned().collect(),
+            dogstatsd: matches.get_flag("dogstatsd"),
+        })?)),
+        None => None,
     };
-    response
-}
 
-async fn stream_handler(mut stream: TcpStream, directory: Option<String>) -> anyhow::Result<()> {
-    let (mut reader, mut writer) = stream.split();
-    let mut reader = BufReader::new(&mut reader);
-    let request = reader_request(&mut reader).await?;
-    println!("DEBUG: request {:?}", request);
+    let config = ServerConfig {
+        clock: clock.clone(),
+        parser: ParserConfig {
+            line_endings: match matches.get_one::<String>("line-endings").unwrap().as_str() {
+                "strict" => LineEndings::Strict,
+                _ => LineEndings::Lenient,
+            },
+            max_headers: *matches.get_one::<usize>("max-headers").unwrap(),
+            max_line_len: *matches.get_one::<usize>("max-line-length").unwrap(),
+            max_header_size: *matches.get_one::<usize>("max-header-size").unwrap(),
+            header_timeout: Duration::from_secs(*matches.get_one::<u64>("header-timeout").unwrap()),
+            body_timeout: Duration::from_secs(*matches.get_one::<u64>("body-timeout").unwrap()),
+            max_body_size: *matches.get_one::<u64>("max-body-size").unwrap(),
+            spool_threshold: *matches.get_one::<usize>("upload-spool-threshold").unwrap(),
+            spool_dir: matches.get_one::<String>("upload-spool-dir").map_or_else(std::env::temp_dir, PathBuf::from),
+        },
+        write_timeout: Duration::from_secs(*matches.get_one::<u64>("write-timeout").unwrap()),
+        keep_alive_timeout: Duration::from_secs(*matches.get_one::<u64>("keep-alive-timeout").unwrap()),
+        handler_timeout: Some(Duration::from_secs(*matches.get_one::<u64>("handler-timeout").unwrap())),
+        route_timeouts: matches.get_many::<String>("route-timeout").unwrap_or_default()
+            .map(|spec| {
+                let (pattern, secs) = spec.split_once('=')
+                    .with_context(|| format!("ERROR: --route-timeout {spec} is not PATTERN=SECONDS"))?;
+                let secs = secs.parse()
+                    .with_context(|| format!("ERROR: --route-timeout {spec} is not PATTERN=SECONDS"))?;
+                Ok(RouteTimeout { pattern: pattern.to_string(), timeout: Duration::from_secs(secs) })
+            })
+            .collect::<anyhow::Result<_>>()?,
+        storage,
+        precompressed,
+        digests: Arc::default(),
+        sessions,
+        kv: kv.clone(),
+        basic_auth: matches.get_many::<String>("auth")
+            .map(|credentials| basic_auth::BasicAuth::new(credentials.cloned().collect()))
+            .transpose()?,
+        url_signer,
+        content_addressed: matches.get_flag("content-addressed"),
+        allow_delete: matches.get_flag("allow-delete"),
+        autoindex: matches.get_flag("autoindex"),
+        etags: match matches.get_one::<String>("etag").unwrap().as_str() {
+            "weak" => etag::Strategy::Weak,
+            _ => etag::Strategy::Strong,
+        },
+        file_changes: Arc::default(),
+        cgi,
+        fastcgi,
+        external,
+        early_hints,
+        header_rules: matches.get_many::<String>("add-header").unwrap_or_default()
+            .map(|spec| HeaderRule::parse(spec).with_context(|| format!("ERROR: --add-header {spec}")))
+            .collect::<anyhow::Result<_>>()?,
+        body_validators: matches.get_many::<String>("validate-body").unwrap_or_default()
+            .map(|spec| validate::BodyValidator::parse(spec).with_context(|| format!("ERROR: --validate-body {spec}")))
+            .collect::<anyhow::Result<_>>()?,
+        method_overrides: matches.get_many::<String>("method-override").unwrap_or_default()
+            .map(|method| HttpMethod::parse(&method.to_ascii_uppercase())
+                .with_context(|| format!("ERROR: --method-override {method} is not a method this server handles")))
+            .collect::<anyhow::Result<_>>()?,
+        maintenance,
+        trusted_proxies,
+        max_connections: matches.get_one::<u64>("max-connections").map(|max| *max as usize),
+        client_limit: matches.get_one::<usize>("max-responses-per-client")
+            .map(|max| Arc::new(client_limit::ClientLimit::new(*max))),
+        favicon: match matches.get_one::<String>("favicon") {
+            Some(path) => Some(std::fs::read(path).with_context(|| format!("ERROR: reading favicon {path}"))?),
+            None => None,
+        },
+        robots_txt: match matches.get_one::<String>("robots-txt") {
+            Some(path) => std::fs::read_to_string(path).with_context(|| format!("ERROR: reading robots.txt {path}"))?,
+            None => DEFAULT_ROBOTS_TXT.to_string(),
+        },
+        render_markdown: matches.get_flag("render-markdown"),
+        swagger_ui: matches.get_flag("swagger-ui"),
+        statsd,
+        metrics: matches.get_flag("metrics").then(Arc::default),
+        slow_log: matches.get_one::<u64>("slow-request-ms").map(|millis| {
+            Arc::new(slowlog::SlowLog::new(Duration::from_millis(*millis), *matches.get_one::<usize>("slow-requests-kept").unwrap()))
+        }),
+        record: matches.get_one::<String>("record").map(|dir| record::RecordConfig {
+            directory: PathBuf::from(dir),
+            responses: matches.get_flag("record-responses"),
+        }),
+        #[cfg(feature = "http")]
+        mounts: Vec::new(),
+        #[cfg(feature = "wasm")]
+        plugins: matches.get_many::<String>("wasm-plugin").unwrap_or_default()
+            .map(|spec| {
+                let (prefix, path) = spec.split_once('=')
+                    .with_context(|| format!("ERROR: --wasm-plugin {spec} is not PREFIX=PATH"))?;
+                wasm::Plugin::load(prefix, Path::new(path), *matches.get_one::<u64>("wasm-fuel").unwrap())
+            })
+            .collect::<anyhow::Result<_>>()?,
+        #[cfg(feature = "scripting")]
+        scripts: matches.get_many::<String>("script").unwrap_or_default()
+            .map(|spec| {
+                let (pattern, path) = spec.split_once('=')
+                    .with_context(|| format!("ERROR: --script {spec} is not PATTERN=PATH"))?;
+                let root = matches.get_one::<String>("script-root").or(directory).map(Path::new);
+                scripting::Script::load(pattern, Path::new(path), root)
+            })
+            .collect::<anyhow::Result<_>>()?,
+        #[cfg(feature = "tower")]
+        tower_layers: tower_compat::TowerLayers {
+            timeout: matches.get_one::<u64>("request-timeout").map(|secs| Duration::from_secs(*secs)),
+            concurrency_limit: matches.get_one::<usize>("concurrency-limit").copied(),
+        },
+    };
 
-    let response = route_request(&request, directory).await.unwrap_or_else(
-        |_| HttpResponseBuilder {
-            status_code: HttpStatusCode::InternalError500,
-            version: request.version.clone(),
-            content: Content::Empty,
-        }
-    );
-    let response_string: String = response.into();
+    // kept alive for as long as the server runs
+    let _watcher = match (matches.get_flag("watch"), directory) {
+        (true, Some(root)) => Some(watch::watch(Path::new(root), config.digests.clone(), config.precompressed.clone())?),
+        (true, None) => anyhow::bail!("ERROR: --watch needs --directory"),
+        (false, _) => None,
+    };
 
-    println!("DEBUG: {response_string}");
-    writer.write_all(response_string.as_bytes()).await?;
+    let trace_wire = matches.get_flag("trace-wire")
+        .then(|| *matches.get_one::<usize>("trace-wire-limit").unwrap());
 
-    Ok(())
-}
+    if matches.subcommand_matches("self-test").is_some() {
+        return selftest::run(config, trace_wire).await;
+    }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let matches = Command::new("http-server")
-        .arg(
-            Arg::new("directory")
-                .long("directory")
-                .required(false)
-        )
-        .get_matches();
+    let service = Service::new(config);
+    if let Some(respond_matches) = matches.subcommand_matches("respond") {
+        return respond(&service, respond_matches.get_one::<String>("in")).await;
+    }
+    if let Some(replay_matches) = matches.subcommand_matches("replay") {
+        let directory = replay_matches.get_one::<String>("dir").unwrap();
+        return record::replay(&service, Path::new(directory)).await;
+    }
 
-    let directory = matches
-        .get_one::<String>("directory");
+    if let Some(redirect_addr) = matches.get_one::<String>("https-redirect-listen") {
+        let listener = TcpListener::bind(redirect_addr).await
+            .with_context(|| format!("ERROR: binding HTTPS redirect listener {redirect_addr}"))?;
+        log_info!("redirecting {redirect_addr} to HTTPS");
+        let service = service.redirecting_to_https(*matches.get_one::<u16>("https-port").unwrap());
+        tokio::spawn(async move {
+            if let Err(err) = serve(listener, service, trace_wire).await {
+                log_error!("HTTPS redirect listener failed: {err:#}");
+            }
+        });
+    }
 
-    println!("DEBUG: directory {:?}", directory);
+    #[cfg(unix)]
+    {
+        let maintenance = service.config.maintenance.clone();
+        let mut toggles = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())
+            .context("ERROR: listening for SIGUSR1")?;
+        tokio::spawn(async move {
+            while toggles.recv().await.is_some() {
+                let state = if maintenance.toggle() { "on" } else { "off" };
+                log_info!("maintenance mode {state}");
+            }
+        });
+    }
 
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .context("ERROR: listening for SIGTERM")?;
+    let shutdown = async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+        log_info!("shutting down");
+    };
+    let grace = Duration::from_secs(*matches.get_one::<u64>("shutdown-grace").unwrap());
 
     let addr = "127.0.0.1:4221";
     let listener = TcpListener::bind(addr).await?;
-    println!("INFO: listening {addr}");
+    log_info!("listening {addr}");
 
-    loop {
-        let (stream, _) = listener.accept().await?;
-        let directory = directory.cloned();
-        tokio::spawn(
-            async move {
-                if let Err(err) = stream_handler(stream, directory).await {
-                    eprintln!("ERROR: connection ended with {err}")
-                }
-            }
-        );
-    }
+    serve_until(listener, service, trace_wire, shutdown, grace).await
 }
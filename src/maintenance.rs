@@ -0,0 +1,42 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::{Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+/// Paths answered even in maintenance, so load balancers don't take the server out of rotation.
+const HEALTH_PATHS: &[&str] = &["/health", "/healthz"];
+
+/// While enabled, every request but health checks is answered with 503. It is only consulted
+/// when a request comes in, so requests already being handled finish normally.
+#[derive(Debug, Default)]
+pub struct Maintenance {
+    enabled: AtomicBool,
+    retry_after: Duration,
+    /// HTML sent with the 503 instead of an empty body.
+    page: Option<String>,
+}
+
+impl Maintenance {
+    pub fn new(enabled: bool, retry_after: Duration, page: Option<String>) -> Self {
+        Maintenance { enabled: AtomicBool::new(enabled), retry_after, page }
+    }
+
+    /// Flips maintenance on or off, returning whether it is now on.
+    pub fn toggle(&self) -> bool {
+        !self.enabled.fetch_xor(true, Ordering::SeqCst)
+    }
+
+    /// The 503 for `request`, if maintenance is on and it isn't a health check.
+    pub fn response(&self, request: &HttpRequest) -> Option<HttpResponseBuilder> {
+        let path = request.route.split_once('?').map_or(request.route.as_str(), |(path, _)| path);
+        if !self.enabled.load(Ordering::SeqCst) || HEALTH_PATHS.contains(&path) {
+            return None;
+        }
+        Some(HttpResponseBuilder {
+            status_code: HttpStatusCode::Other(503, "Service Unavailable".to_string()),
+            version: request.version.clone(),
+            headers: HeaderMap::from([("Retry-After".to_string(), self.retry_after.as_secs().to_string())]),
+            content: self.page.clone().map_or(Content::Empty, Content::Html),
+        })
+    }
+}
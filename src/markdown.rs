@@ -0,0 +1,174 @@
+use crate::template::escape_html;
+
+/// Converts the common subset of Markdown to HTML: ATX headings, paragraphs, bullet and
+/// numbered lists, block quotes, fenced code, rules, and inline code, emphasis and links.
+/// Everything from the source is escaped, so documents can't inject markup.
+pub fn to_html(source: &str) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut list: Option<&str> = None;
+    let mut lines = source.lines();
+
+    while let Some(line) = lines.next() {
+        let trimmed = line.trim();
+
+        let item = list_item(trimmed);
+        if list.is_some() && item.map(|(tag, _)| tag) != list {
+            html.push_str(&format!("</{}>\n", list.take().unwrap()));
+        }
+
+        if trimmed.is_empty() {
+            flush_paragraph(&mut html, &mut paragraph);
+        } else if let Some(fence) = trimmed.strip_prefix("```") {
+            flush_paragraph(&mut html, &mut paragraph);
+            let language = fence.trim();
+            if language.is_empty() {
+                html.push_str("<pre><code>");
+            } else {
+                html.push_str(&format!("<pre><code class=\"language-{}\">", escape_html(language)));
+            }
+            for code in lines.by_ref().take_while(|code| !code.trim_start().starts_with("```")) {
+                html.push_str(&escape_html(code));
+                html.push('\n');
+            }
+            html.push_str("</code></pre>\n");
+        } else if let Some((level, text)) = heading(trimmed) {
+            flush_paragraph(&mut html, &mut paragraph);
+            html.push_str(&format!("<h{level}>{}</h{level}>\n", inline(text)));
+        } else if is_rule(trimmed) {
+            flush_paragraph(&mut html, &mut paragraph);
+            html.push_str("<hr>\n");
+        } else if let Some((tag, text)) = item {
+            flush_paragraph(&mut html, &mut paragraph);
+            if list.is_none() {
+                html.push_str(&format!("<{tag}>\n"));
+                list = Some(tag);
+            }
+            html.push_str(&format!("<li>{}</li>\n", inline(text)));
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            flush_paragraph(&mut html, &mut paragraph);
+            html.push_str(&format!("<blockquote><p>{}</p></blockquote>\n", inline(quote.trim())));
+        } else {
+            paragraph.push(trimmed);
+        }
+    }
+
+    flush_paragraph(&mut html, &mut paragraph);
+    if let Some(tag) = list {
+        html.push_str(&format!("</{tag}>\n"));
+    }
+    html
+}
+
+fn flush_paragraph(html: &mut String, paragraph: &mut Vec<&str>) {
+    if !paragraph.is_empty() {
+        html.push_str(&format!("<p>{}</p>\n", inline(&paragraph.join("\n"))));
+        paragraph.clear();
+    }
+}
+
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let text = line[level..].strip_prefix(' ')?;
+    (1..=6).contains(&level).then(|| (level, text.trim().trim_end_matches('#').trim_end()))
+}
+
+fn is_rule(line: &str) -> bool {
+    ['-', '*', '_'].iter().any(|&c| {
+        line.chars().filter(|&l| l == c).count() >= 3 && line.chars().all(|l| l == c || l == ' ')
+    })
+}
+
+fn list_item(line: &str) -> Option<(&'static str, &str)> {
+    if let Some(text) = line.strip_prefix("- ").or_else(|| line.strip_prefix("* ")).or_else(|| line.strip_prefix("+ ")) {
+        return Some(("ul", text));
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let text = line[digits..].strip_prefix(". ")?;
+    (digits > 0).then_some(("ol", text))
+}
+
+/// Inline markup: `code`, **strong**, *emphasis* and [links](url).
+fn inline(text: &str) -> String {
+    let mut html = String::new();
+    let mut rest = text;
+    let mut previous = ' ';
+    while let Some(c) = rest.chars().next() {
+        if c == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                html.push_str(&format!("<code>{}</code>", escape_html(&rest[1..end + 1])));
+                rest = &rest[end + 2..];
+                continue;
+            }
+        } else if let Some(inner) = rest.strip_prefix("**") {
+            if let Some(end) = inner.find("**") {
+                html.push_str(&format!("<strong>{}</strong>", inline(&inner[..end])));
+                rest = &inner[end + 2..];
+                continue;
+            }
+        } else if c == '*' || (c == '_' && !previous.is_alphanumeric()) {
+            if let Some(end) = rest[1..].find(c).filter(|&end| end > 0) {
+                html.push_str(&format!("<em>{}</em>", inline(&rest[1..end + 1])));
+                rest = &rest[end + 2..];
+                continue;
+            }
+        } else if c == '[' {
+            if let Some((label, url, len)) = link(rest) {
+                html.push_str(&format!("<a href=\"{}\">{}</a>", escape_html(url), inline(label)));
+                rest = &rest[len..];
+                continue;
+            }
+        }
+        html.push_str(&escape_html(&rest[..c.len_utf8()]));
+        rest = &rest[c.len_utf8()..];
+        previous = c;
+    }
+    html
+}
+
+fn link(text: &str) -> Option<(&str, &str, usize)> {
+    let label_end = text.find("](")?;
+    let url_end = text[label_end + 2..].find(')')? + label_end + 2;
+    let url = &text[label_end + 2..url_end];
+    is_safe_url(url).then(|| (&text[1..label_end], url, url_end + 1))
+}
+
+/// Whether `url` is http, https, mailto or relative. Checked the way browsers read it, with
+/// tabs and newlines removed and leading spaces and control characters skipped, so
+/// `java\tscript:` is seen for what it is.
+fn is_safe_url(url: &str) -> bool {
+    let url: String = url.chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .skip_while(|c| c.is_ascii_control() || *c == ' ')
+        .collect();
+    match url.find([':', '/', '?', '#']) {
+        Some(colon) if url[colon..].starts_with(':') => {
+            ["http", "https", "mailto"].iter().any(|scheme| url[..colon].eq_ignore_ascii_case(scheme))
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_in_the_source_is_escaped() {
+        assert_eq!(to_html("# Title <b>\n\n*a* & `<i>`"), "<h1>Title &lt;b&gt;</h1>\n<p><em>a</em> &amp; <code>&lt;i&gt;</code></p>\n");
+        assert_eq!(to_html("```html\n<script>\n```"), "<pre><code class=\"language-html\">&lt;script&gt;\n</code></pre>\n");
+    }
+
+    #[test]
+    fn links_keep_to_safe_schemes() {
+        assert_eq!(to_html("[home](https://example.com/?a=1&b=\"2\")"), "<p><a href=\"https://example.com/?a=1&amp;b=&quot;2&quot;\">home</a></p>\n");
+        assert_eq!(to_html("[mail](MAILTO:me@example.com)"), "<p><a href=\"MAILTO:me@example.com\">mail</a></p>\n");
+        assert_eq!(to_html("[up](../notes.md#top)"), "<p><a href=\"../notes.md#top\">up</a></p>\n");
+        assert_eq!(to_html("[odd](a/b:c)"), "<p><a href=\"a/b:c\">odd</a></p>\n");
+
+        for url in ["javascript:alert(1)", " JavaScript:alert(1)", "java\tscript:alert(1)", "\u{1}javascript:x", "data:text/html,x", "vbscript:x"] {
+            let html = to_html(&format!("[x]({url})"));
+            assert!(!html.contains("<a"), "{url:?} became {html}");
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Counters served at `GET /metrics` in the Prometheus text exposition format, for setups
+/// that scrape rather than have metrics pushed to them.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Responses by method, route pattern and status.
+    responses: Mutex<BTreeMap<(String, String, u16), u64>>,
+    open_connections: AtomicUsize,
+    bytes_sent: AtomicU64,
+}
+
+/// Counts a connection as open until dropped.
+#[derive(Debug)]
+pub struct OpenConnection<'a>(&'a Metrics);
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub fn connection(&self) -> OpenConnection<'_> {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self)
+    }
+
+    /// Counts a response of `bytes`, head included. `route` is the pattern that matched,
+    /// never the path itself, which would make a series of every file name.
+    pub fn response(&self, method: &str, route: &str, status: u16, bytes: usize) {
+        *self.responses.lock().unwrap().entry((method.to_string(), route.to_string(), status)).or_default() += 1;
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        text.push_str("# HELP http_requests_total Responses sent, by request method, route and status.\n");
+        text.push_str("# TYPE http_requests_total counter\n");
+        for ((method, route, status), count) in self.responses.lock().unwrap().iter() {
+            let _ = writeln!(
+                text,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{status}\"}} {count}",
+                escape(method),
+                escape(route),
+            );
+        }
+        text.push_str("# HELP http_open_connections Connections currently open.\n");
+        text.push_str("# TYPE http_open_connections gauge\n");
+        let _ = writeln!(text, "http_open_connections {}", self.open_connections.load(Ordering::Relaxed));
+        text.push_str("# HELP http_response_bytes_total Bytes of responses sent, heads included.\n");
+        text.push_str("# TYPE http_response_bytes_total counter\n");
+        let _ = writeln!(text, "http_response_bytes_total {}", self.bytes_sent.load(Ordering::Relaxed));
+        text
+    }
+}
+
+/// A label value, with what the exposition format reserves escaped.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_render_in_text_format() {
+        let metrics = Metrics::default();
+        let connection = metrics.connection();
+        metrics.response("GET", "/files/:name", 200, 100);
+        metrics.response("GET", "/files/:name", 200, 50);
+        metrics.response("PUT", "/files/:name", 201, 20);
+        let text = metrics.render();
+        assert!(text.contains("http_requests_total{method=\"GET\",route=\"/files/:name\",status=\"200\"} 2\n"), "{text}");
+        assert!(text.contains("http_requests_total{method=\"PUT\",route=\"/files/:name\",status=\"201\"} 1\n"), "{text}");
+        assert!(text.contains("http_open_connections 1\n"), "{text}");
+        assert!(text.contains("http_response_bytes_total 170\n"), "{text}");
+
+        drop(connection);
+        assert!(metrics.render().contains("http_open_connections 0\n"));
+        assert_eq!(escape("a\"b\\c"), "a\\\"b\\\\c");
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::storage::BoxFuture;
+use crate::{HttpRequest, HttpResponseBuilder, Service};
+
+/// Wraps every handler: gets the request and the rest of the chain, and decides whether and
+/// with what request to run it and what to make of its response.
+pub type Middleware = Arc<dyn for<'a> Fn(&'a HttpRequest, Next<'a>) -> BoxFuture<'a, HttpResponseBuilder> + Send + Sync>;
+
+/// The middleware registered on a service, outermost first.
+#[derive(Clone, Default)]
+pub struct Chain(Vec<Middleware>);
+
+impl Chain {
+    pub fn push(&mut self, middleware: Middleware) {
+        self.0.push(middleware);
+    }
+
+    /// Runs `request` through the chain and then the service's handler.
+    pub async fn run(&self, service: &Service, request: &HttpRequest) -> HttpResponseBuilder {
+        Next { service, chain: &self.0 }.run(request).await
+    }
+}
+
+impl fmt::Debug for Chain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Chain({} middleware)", self.0.len())
+    }
+}
+
+/// What is left of the chain after the running middleware, down to the handler.
+pub struct Next<'a> {
+    service: &'a Service,
+    chain: &'a [Middleware],
+}
+
+impl<'a> Next<'a> {
+    pub fn run<'b>(self, request: &'b HttpRequest) -> BoxFuture<'b, HttpResponseBuilder>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            match self.chain.split_first() {
+                Some((middleware, chain)) => middleware(request, Next { service: self.service, chain }).await,
+                None => self.service.route(request).await,
+            }
+        })
+    }
+}
@@ -0,0 +1,55 @@
+use std::path::Path;
+
+/// Media types by file extension, for the types browsers display or run themselves.
+const TYPES: &[(&str, &str)] = &[
+    ("css", "text/css; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("gif", "image/gif"),
+    ("htm", "text/html; charset=utf-8"),
+    ("html", "text/html; charset=utf-8"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("wasm", "application/wasm"),
+    ("webm", "video/webm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("zip", "application/zip"),
+];
+
+/// The `Content-Type` of the file `name`, going by its extension. Anything unknown is sent as
+/// opaque bytes, which browsers download rather than guess at.
+pub fn for_name(name: &str) -> &'static str {
+    let extension = Path::new(name).extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    extension
+        .and_then(|extension| TYPES.iter().find(|(known, _)| *known == extension))
+        .map_or("application/octet-stream", |(_, media_type)| media_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn types_go_by_extension() {
+        assert_eq!(for_name("index.html"), "text/html; charset=utf-8");
+        assert_eq!(for_name("LOGO.PNG"), "image/png");
+        assert_eq!(for_name("archive.tar.gz"), "application/octet-stream");
+        assert_eq!(for_name("README"), "application/octet-stream");
+        assert_eq!(for_name(".css"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,161 @@
+use crate::json::{obj, Json};
+use crate::router::Router;
+
+#[derive(Debug, Clone, Copy)]
+pub enum In {
+    Path,
+    Query,
+    Header,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Param {
+    pub name: &'static str,
+    pub location: In,
+    pub description: &'static str,
+}
+
+/// What the document says about a route, attached where the route is registered with
+/// [`Router::describe`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Operation {
+    pub summary: &'static str,
+    pub params: &'static [Param],
+    /// Content type of the request body, if the route takes one.
+    pub request_body: Option<&'static str>,
+    /// Status, description and content type of each response.
+    pub responses: &'static [(u16, &'static str, Option<&'static str>)],
+}
+
+fn operation(method: &str, operation: &Operation) -> Json {
+    let mut fields = Vec::new();
+    if !operation.summary.is_empty() {
+        fields.push(("summary".to_string(), Json::from(operation.summary)));
+    }
+
+    if !operation.params.is_empty() {
+        let params = operation.params.iter()
+            .map(|param| {
+                let location = match param.location {
+                    In::Path => "path",
+                    In::Query => "query",
+                    In::Header => "header",
+                };
+                obj([
+                    ("name", param.name.into()),
+                    ("in", location.into()),
+                    ("description", param.description.into()),
+                    ("required", Json::Bool(matches!(param.location, In::Path))),
+                    ("schema", obj([("type", "string".into())])),
+                ])
+            })
+            .collect();
+        fields.push(("parameters".to_string(), Json::Arr(params)));
+    }
+
+    // routes answering every method only take bodies where they mean something
+    if let Some(content_type) = operation.request_body.filter(|_| matches!(method, "post" | "put")) {
+        fields.push(("requestBody".to_string(), obj([
+            ("required", Json::Bool(true)),
+            ("content", Json::Obj(vec![(content_type.to_string(), obj([]))])),
+        ])));
+    }
+
+    let mut responses: Vec<(String, Json)> = operation.responses.iter()
+        .map(|(status, description, content_type)| {
+            let mut response = vec![("description".to_string(), Json::from(*description))];
+            if let Some(content_type) = content_type {
+                response.push(("content".to_string(), Json::Obj(vec![(content_type.to_string(), obj([]))])));
+            }
+            (status.to_string(), Json::Obj(response))
+        })
+        .collect();
+    // an undescribed route still gets a valid operation
+    if responses.is_empty() {
+        responses.push(("default".to_string(), obj([("description", "Undocumented".into())])));
+    }
+    fields.push(("responses".to_string(), Json::Obj(responses)));
+
+    Json::Obj(fields)
+}
+
+/// The OpenAPI 3 document for the routes of `router`, in the order they were registered.
+pub fn document(router: &Router) -> String {
+    let mut paths: Vec<(String, Json)> = Vec::new();
+    for (method, path, described) in router.operations() {
+        let entry = (method.clone(), operation(&method, described));
+        match paths.iter_mut().find(|(existing, _)| *existing == path) {
+            Some((_, Json::Obj(methods))) => methods.push(entry),
+            _ => paths.push((path, Json::Obj(vec![entry]))),
+        }
+    }
+
+    obj([
+        ("openapi", "3.0.3".into()),
+        ("info", obj([
+            ("title", "http-server".into()),
+            ("version", env!("CARGO_PKG_VERSION").into()),
+        ])),
+        ("paths", Json::Obj(paths)),
+    ]).to_string()
+}
+
+/// Swagger UI pointed at /openapi.json. The UI's assets are loaded from unpkg rather than
+/// served from here, so the page needs the browser to have internet access.
+pub const SWAGGER_UI_PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>http-server API</title>
+<link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+<script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::router::routes;
+    use crate::storage::MemoryStorage;
+    use crate::ServerConfig;
+
+    #[test]
+    fn document_lists_only_enabled_routes() {
+        let bare = document(&routes(&ServerConfig::default()));
+        assert!(bare.starts_with(r#"{"openapi":"3.0.3","#));
+        assert!(bare.contains(r#""/echo/{rest}":{"get":{"summary":"Echo the rest of the path back","parameters":[{"name":"rest","in":"path""#));
+        assert!(!bare.contains("/files/{name}"));
+        assert!(!bare.contains("/docs"));
+
+        let config = ServerConfig { storage: Some(Arc::new(MemoryStorage::default())), swagger_ui: true, ..Default::default() };
+        let full = document(&routes(&config));
+        // both methods share one path item
+        assert_eq!(full.matches(r#""/files/{name}":"#).count(), 1);
+        assert!(full.contains(r#""post":{"summary":"Upload a file""#));
+        assert!(full.contains(r#""/docs""#));
+    }
+
+    #[test]
+    fn every_registered_route_is_documented() {
+        let mut router = Router::default();
+        router
+            .get("/a/:id/*", |_| unreachable!())
+            .describe(Operation { summary: "A thing", ..Default::default() })
+            .any("/b", |_| unreachable!())
+            .describe(Operation { request_body: Some("text/plain"), ..Default::default() })
+            .put("/c", |_| unreachable!());
+        let document = document(&router);
+        assert!(document.contains(r#""/a/{id}/{rest}":{"get":{"summary":"A thing","responses":{"default""#), "{document}");
+        // a body only where the method takes one
+        assert!(document.contains(r#""/b":{"get":{"responses""#), "{document}");
+        assert!(document.contains(r#""post":{"requestBody""#), "{document}");
+        assert!(document.contains(r#""/c":{"put":{"responses":{"default":{"description":"Undocumented"}}}"#), "{document}");
+    }
+}
@@ -0,0 +1,68 @@
+/// Bytes left alone by [`encode`]: RFC 3986 unreserved characters.
+fn is_unreserved(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~')
+}
+
+/// Decodes `%XX` escapes. Fails on malformed escapes and on results that aren't utf8.
+pub fn decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut rest = input.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Escapes everything but unreserved characters, so the result is safe as a path segment.
+pub fn encode(input: &str) -> String {
+    let mut encoded = String::with_capacity(input.len());
+    for b in input.bytes() {
+        if is_unreserved(b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Decodes a /files path segment into a file name, if it is one the server will store:
+/// non-empty, at most 255 bytes, not `.` or `..`, and without separators or control
+/// characters.
+pub fn decode_file_name(segment: &str) -> Option<String> {
+    let name = decode(segment)?;
+    let safe = !name.is_empty()
+        && name.len() <= 255
+        && name != "."
+        && name != ".."
+        && !name.chars().any(|c| c == '/' || c == '\\' || c.is_control());
+    safe.then_some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_unicode_and_reserved_characters() {
+        for name in ["plain.txt", "with space.txt", "über ✓.md", "a+b=c&d?#.txt", "100%"] {
+            assert_eq!(decode(&encode(name)).as_deref(), Some(name));
+        }
+        assert_eq!(encode("über ✓"), "%C3%BCber%20%E2%9C%93");
+    }
+
+    #[test]
+    fn file_names_follow_the_policy() {
+        assert_eq!(decode_file_name("my%20notes.txt").as_deref(), Some("my notes.txt"));
+        for unsafe_name in ["", "%2e%2E", ".", "a%2Fb", "a%5Cb", "a%00b", "a%0D%0Ab", "%zz", "%C3", &"a".repeat(256)] {
+            assert_eq!(decode_file_name(unsafe_name), None, "{unsafe_name:?}");
+        }
+    }
+}
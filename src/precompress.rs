@@ -0,0 +1,132 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Extensions of files worth compressing; images, archives and the like already are.
+const COMPRESSIBLE: &[&str] = &["css", "csv", "htm", "html", "js", "json", "md", "mjs", "svg", "txt", "wasm", "xml"];
+
+/// Gzipped copies of the files in the served directory, kept in `cache_dir` as `<name>.gz`
+/// so downloads by clients accepting gzip never compress on the request path.
+#[derive(Debug, Clone)]
+pub struct Precompressed {
+    root: PathBuf,
+    cache_dir: PathBuf,
+    /// Files smaller than this aren't worth the header overhead.
+    min_size: u64,
+}
+
+impl Precompressed {
+    pub fn new(root: impl Into<PathBuf>, cache_dir: impl Into<PathBuf>, min_size: u64) -> Self {
+        Precompressed { root: root.into(), cache_dir: cache_dir.into(), min_size }
+    }
+
+    /// Compresses every eligible file that has no up to date variant yet, returning how many
+    /// were written.
+    pub fn build_all(&self) -> io::Result<usize> {
+        std::fs::create_dir_all(&self.cache_dir)?;
+        let mut written = 0;
+        for entry in std::fs::read_dir(&self.root)? {
+            let entry = entry?;
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if self.refresh(&name)? {
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    /// Brings the variant of `name` in line with the file, after it was written or deleted.
+    /// Returns whether a new variant was written.
+    pub fn refresh(&self, name: &str) -> io::Result<bool> {
+        let source = self.root.join(name);
+        let variant = self.variant_path(name);
+        let metadata = match std::fs::metadata(&source) {
+            Ok(metadata) if metadata.is_file() && metadata.len() >= self.min_size && is_compressible(name) => metadata,
+            _ => {
+                // gone, or no longer worth it
+                return match std::fs::remove_file(&variant) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                    _ => Ok(false),
+                };
+            }
+        };
+        if is_fresh(&variant, metadata.modified().ok()) {
+            return Ok(false);
+        }
+
+        let content = std::fs::read(&source)?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&content)?;
+        // written aside and renamed, so readers never see half a variant
+        let partial = variant.with_extension("gz.partial");
+        std::fs::write(&partial, encoder.finish()?)?;
+        std::fs::rename(&partial, &variant)?;
+        Ok(true)
+    }
+
+    /// The gzipped content of `name`, if there is a variant at least as new as `modified`.
+    pub async fn gzipped(&self, name: &str, modified: Option<SystemTime>) -> Option<Vec<u8>> {
+        let variant = self.variant_path(name);
+        let variant_modified = tokio::fs::metadata(&variant).await.ok()?.modified().ok();
+        if variant_modified < modified {
+            return None;
+        }
+        tokio::fs::read(&variant).await.ok()
+    }
+
+    fn variant_path(&self, name: &str) -> PathBuf {
+        self.cache_dir.join(format!("{name}.gz"))
+    }
+}
+
+/// Whether `name` is of a type worth gzipping.
+pub fn is_compressible(name: &str) -> bool {
+    Path::new(name).extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| COMPRESSIBLE.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+fn is_fresh(variant: &Path, source_modified: Option<SystemTime>) -> bool {
+    let variant_modified = std::fs::metadata(variant).and_then(|metadata| metadata.modified()).ok();
+    variant_modified.is_some() && variant_modified >= source_modified
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::test_client::temp_dir;
+
+    #[test]
+    fn only_compressible_files_above_the_threshold_get_variants() {
+        let root = temp_dir("precompress-root");
+        let cache = root.join("cache");
+        let text = "all work and no play ".repeat(100);
+        std::fs::write(root.join("big.txt"), &text).unwrap();
+        std::fs::write(root.join("small.txt"), "tiny").unwrap();
+        std::fs::write(root.join("photo.jpg"), &text).unwrap();
+
+        let precompressed = Precompressed::new(&root, &cache, 100);
+        assert_eq!(precompressed.build_all().unwrap(), 1);
+        // already up to date
+        assert_eq!(precompressed.build_all().unwrap(), 0);
+
+        let mut unzipped = String::new();
+        GzDecoder::new(std::fs::File::open(cache.join("big.txt.gz")).unwrap()).read_to_string(&mut unzipped).unwrap();
+        assert_eq!(unzipped, text);
+        assert!(!cache.join("small.txt.gz").exists());
+        assert!(!cache.join("photo.jpg.gz").exists());
+
+        std::fs::remove_file(root.join("big.txt")).unwrap();
+        precompressed.refresh("big.txt").unwrap();
+        assert!(!cache.join("big.txt.gz").exists());
+    }
+}
@@ -0,0 +1,117 @@
+use std::ops::RangeInclusive;
+
+/// What a `Range` header asks of a representation `size` bytes long.
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    /// One range that overlaps the content, clamped to it.
+    Single(RangeInclusive<u64>),
+    /// Several, clamped, in ascending order and apart from each other: a multipart response.
+    Multiple(Vec<RangeInclusive<u64>>),
+    /// Nothing that was asked for overlaps the content: 416.
+    Unsatisfiable,
+}
+
+/// Most ranges served in one multipart response; asking for more gets the whole content.
+const MAX_RANGES: usize = 16;
+
+/// Interprets a `Range` field (RFC 9110 section 14.2). `None` means it is to be ignored and the
+/// whole content served: a unit other than bytes, invalid syntax, or too many ranges.
+/// Overlapping and adjacent ranges are merged, so no byte is sent twice.
+pub fn resolve(field: &str, size: u64) -> Option<Ranges> {
+    let (unit, specs) = field.trim().split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let specs = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty())
+        .map(parse_spec)
+        .collect::<Option<Vec<_>>>()?;
+    if specs.is_empty() {
+        return None;
+    }
+
+    let mut satisfiable: Vec<_> = specs.into_iter().filter_map(|spec| satisfy(spec, size)).collect();
+    if satisfiable.len() > MAX_RANGES {
+        return None;
+    }
+    satisfiable.sort_by_key(|range| *range.start());
+    let mut merged: Vec<RangeInclusive<u64>> = Vec::new();
+    for range in satisfiable {
+        match merged.last_mut() {
+            Some(last) if *range.start() <= last.end() + 1 => *last = *last.start()..=*last.end().max(range.end()),
+            _ => merged.push(range),
+        }
+    }
+    match merged.len() {
+        0 => Some(Ranges::Unsatisfiable),
+        1 => merged.pop().map(Ranges::Single),
+        _ => Some(Ranges::Multiple(merged)),
+    }
+}
+
+/// A range spec as written: `first-last`, `first-` or the suffix form `-length`.
+#[derive(Debug, Clone, Copy)]
+enum Spec {
+    From(u64, Option<u64>),
+    Suffix(u64),
+}
+
+fn parse_spec(spec: &str) -> Option<Spec> {
+    let (first, last) = spec.split_once('-')?;
+    let number = |digits: &str| -> Option<u64> {
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    if first.is_empty() {
+        return Some(Spec::Suffix(number(last)?));
+    }
+    let first = number(first)?;
+    if last.is_empty() {
+        return Some(Spec::From(first, None));
+    }
+    let last = number(last)?;
+    // a last position before the first makes the whole field invalid
+    (last >= first).then_some(Spec::From(first, Some(last)))
+}
+
+fn satisfy(spec: Spec, size: u64) -> Option<RangeInclusive<u64>> {
+    match spec {
+        Spec::From(first, last) if first < size => Some(first..=last.map_or(size - 1, |last| last.min(size - 1))),
+        Spec::From(..) => None,
+        Spec::Suffix(0) => None,
+        Spec::Suffix(length) if size > 0 => Some(size.saturating_sub(length)..=size - 1),
+        Spec::Suffix(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_are_clamped_rejected_or_ignored() {
+        assert_eq!(resolve("bytes=0-4", 10), Some(Ranges::Single(0..=4)));
+        assert_eq!(resolve("bytes=5-", 10), Some(Ranges::Single(5..=9)));
+        assert_eq!(resolve("bytes=-3", 10), Some(Ranges::Single(7..=9)));
+        assert_eq!(resolve("bytes=8-100", 10), Some(Ranges::Single(8..=9)));
+        assert_eq!(resolve("bytes=-100", 10), Some(Ranges::Single(0..=9)));
+
+        assert_eq!(resolve("bytes=10-", 10), Some(Ranges::Unsatisfiable));
+        assert_eq!(resolve("bytes=20-30, -0", 10), Some(Ranges::Unsatisfiable));
+        assert_eq!(resolve("bytes=-5", 0), Some(Ranges::Unsatisfiable));
+
+        assert_eq!(resolve("bytes=5-1", 10), None);
+        assert_eq!(resolve("bytes=a-b", 10), None);
+        assert_eq!(resolve("bytes=", 10), None);
+        assert_eq!(resolve("items=0-1", 10), None);
+    }
+
+    #[test]
+    fn several_ranges_are_sorted_and_merged() {
+        assert_eq!(resolve("bytes=6-7, 0-1, 20-30", 10), Some(Ranges::Multiple(vec![0..=1, 6..=7])));
+        assert_eq!(resolve("bytes=0-4, 2-6", 10), Some(Ranges::Single(0..=6)));
+        assert_eq!(resolve("bytes=0-1, 2-3, -2", 10), Some(Ranges::Multiple(vec![0..=3, 8..=9])));
+        assert_eq!(resolve(&format!("bytes={}", vec!["0-0"; 17].join(",")), 10), None);
+    }
+}
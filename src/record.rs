@@ -0,0 +1,160 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWriteExt, ReadBuf};
+
+use crate::log::{log_error, log_info};
+use crate::request::{reader_request, rejection};
+use crate::Service;
+
+const REQUEST_SUFFIX: &str = ".request.http";
+const RESPONSE_SUFFIX: &str = ".response.http";
+
+#[derive(Debug, Clone)]
+pub struct RecordConfig {
+    pub directory: PathBuf,
+    pub responses: bool,
+}
+
+/// Wraps a buffered reader and keeps a copy of every byte the parser consumes, so the
+/// recording holds exactly one request even when more has been read ahead.
+pub struct RecordingReader<R> {
+    inner: R,
+    recorded: Option<Vec<u8>>,
+    // copy of the buffer last handed out, `consume` has no other way to see the bytes
+    filled: Vec<u8>,
+}
+
+impl<R> RecordingReader<R> {
+    pub fn new(inner: R, enabled: bool) -> Self {
+        RecordingReader {
+            inner,
+            recorded: enabled.then(Vec::new),
+            filled: Vec::new(),
+        }
+    }
+
+    /// Stops recording, for traffic that is no HTTP anymore, like an upgraded connection.
+    pub fn stop_recording(&mut self) {
+        self.recorded = None;
+    }
+
+    /// Hands out what was consumed since the last call, when recording is enabled.
+    pub fn take_recorded(&mut self) -> Option<Vec<u8>> {
+        self.recorded.as_mut().map(std::mem::take)
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for RecordingReader<R> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let available = match self.as_mut().poll_fill_buf(cx) {
+            Poll::Ready(Ok(available)) => available,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending,
+        };
+        let n = available.len().min(buf.remaining());
+        buf.put_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for RecordingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        let available = match Pin::new(&mut this.inner).poll_fill_buf(cx) {
+            Poll::Ready(Ok(available)) => available,
+            other => return other,
+        };
+        if this.recorded.is_some() {
+            this.filled.clear();
+            this.filled.extend_from_slice(available);
+        }
+        Poll::Ready(Ok(available))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        if let Some(recorded) = this.recorded.as_mut() {
+            recorded.extend_from_slice(&this.filled[..amt.min(this.filled.len())]);
+            this.filled.drain(..amt.min(this.filled.len()));
+        }
+        Pin::new(&mut this.inner).consume(amt)
+    }
+}
+
+/// Stores a recorded exchange as `<millis>-<sequence>.request.http` (and `.response.http`).
+/// Requests that failed to parse have no response, but are kept all the same.
+pub async fn save(config: &RecordConfig, request: &[u8], response: Option<&[u8]>, now: SystemTime) -> anyhow::Result<()> {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+    let millis = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+    let stem = format!("{millis:013}-{sequence:06}");
+
+    tokio::fs::create_dir_all(&config.directory).await
+        .with_context(|| format!("ERROR: creating record directory {}", config.directory.display()))?;
+    let request_path = config.directory.join(format!("{stem}{REQUEST_SUFFIX}"));
+    tokio::fs::write(&request_path, request).await
+        .with_context(|| format!("ERROR: recording request to {}", request_path.display()))?;
+
+    if let Some(response) = response.filter(|_| config.responses) {
+        let response_path = config.directory.join(format!("{stem}{RESPONSE_SUFFIX}"));
+        tokio::fs::write(&response_path, response).await
+            .with_context(|| format!("ERROR: recording response to {}", response_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Feeds every recorded request in `directory` through `service`, in recording order, and
+/// writes the responses to stdout. Where a response was recorded too, the new one must match.
+pub async fn replay(service: &Service, directory: &Path) -> anyhow::Result<()> {
+    let mut requests = Vec::new();
+    let mut entries = tokio::fs::read_dir(directory).await
+        .with_context(|| format!("ERROR: reading record directory {}", directory.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.to_string_lossy().ends_with(REQUEST_SUFFIX) {
+            requests.push(path);
+        }
+    }
+    requests.sort();
+
+    let mut stdout = tokio::io::stdout();
+    let mut mismatches = 0;
+    for request_path in &requests {
+        let raw = tokio::fs::read(request_path).await?;
+        let mut reader = raw.as_slice();
+        let response: Vec<u8> = match reader_request(&mut reader, &service.config.parser, &*service.config.clock).await {
+            Ok(request) => service.respond(&request).await.into(),
+            Err(err) => rejection(&err, service.config.clock.now()).ok_or(err)
+                .with_context(|| format!("ERROR: parsing {}", request_path.display()))?
+                .into(),
+        };
+
+        stdout.write_all(&response).await?;
+        stdout.write_all(b"\n").await?;
+
+        let name = request_path.to_string_lossy();
+        let response_path = PathBuf::from(format!("{}{RESPONSE_SUFFIX}", name.trim_end_matches(REQUEST_SUFFIX)));
+        match tokio::fs::read(&response_path).await {
+            Ok(recorded) if recorded != response => {
+                log_error!("response differs from {}", response_path.display());
+                mismatches += 1;
+            }
+            _ => {}
+        }
+    }
+    stdout.flush().await?;
+
+    log_info!("replayed {} requests, {mismatches} responses differ", requests.len());
+    if mismatches > 0 {
+        bail!("{mismatches} replayed responses differ from the recording");
+    }
+    Ok(())
+}
@@ -0,0 +1,619 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use nom::bytes::complete::{tag, take_while, take_while1};
+use nom::character::complete::{crlf, space0};
+use nom::IResult;
+use nom::multi::many0;
+use nom::sequence::{pair, terminated};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::clock::{self, Clock};
+use crate::log::log_debug;
+use crate::{forwarded, httpdate, percent, session, upload, Content, HeaderMap, HttpResponseBuilder, HttpStatusCode};
+
+#[derive(Debug, Clone)]
+pub enum HttpMethod {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Options,
+}
+
+impl HttpMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HttpMethod::Get => "GET",
+            HttpMethod::Head => "HEAD",
+            HttpMethod::Post => "POST",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Delete => "DELETE",
+            HttpMethod::Options => "OPTIONS",
+        }
+    }
+
+    /// Safe methods (RFC 9110 section 9.2.1) only read, so sending one twice does no harm.
+    pub fn is_safe(&self) -> bool {
+        matches!(self, HttpMethod::Get | HttpMethod::Head | HttpMethod::Options)
+    }
+
+    pub fn parse(method: &str) -> Option<Self> {
+        match method {
+            "GET" => Some(HttpMethod::Get),
+            "HEAD" => Some(HttpMethod::Head),
+            "POST" => Some(HttpMethod::Post),
+            "PUT" => Some(HttpMethod::Put),
+            "DELETE" => Some(HttpMethod::Delete),
+            "OPTIONS" => Some(HttpMethod::Options),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub route: String,
+    pub version: String,
+    pub headers: HeaderMap,
+    pub body: Option<Vec<u8>>,
+    pub remote_addr: Option<SocketAddr>,
+    /// Set when the peer is a trusted proxy that said who it forwarded the request for.
+    pub forwarded: Option<forwarded::Forwarded>,
+    /// The body of a large upload, which is then not in `body`.
+    pub spooled: Option<Arc<upload::SpooledBody>>,
+    /// The client's session, when sessions are enabled.
+    pub session: Option<Arc<session::Session>>,
+}
+
+impl HttpRequest {
+    /// The value of header `name`, whatever its case on the wire.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The body as text, for handlers that need it; `None` when there is none or it isn't utf8.
+    pub fn text(&self) -> Option<&str> {
+        std::str::from_utf8(self.body.as_deref()?).ok()
+    }
+
+    /// The value of cookie `name` from the `Cookie` header.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.header("Cookie")?.split(';')
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find_map(|(cookie, value)| (cookie == name).then_some(value))
+    }
+
+    /// The address of the client, as reported by trusted proxies or else of the peer.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        match &self.forwarded {
+            Some(forwarded) => forwarded.client_ip,
+            None => self.remote_addr.map(|addr| addr.ip()),
+        }
+    }
+
+    /// The scheme the client used, `http` unless a trusted proxy reported otherwise.
+    pub fn scheme(&self) -> &str {
+        self.forwarded.as_ref().and_then(|forwarded| forwarded.proto.as_deref()).unwrap_or("http")
+    }
+
+    /// The request as it would have come over the wire, for handlers that take raw requests.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = format!("{} {} {}\r\n", self.method.as_str(), self.route, self.version);
+        for (name, value) in &self.headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        raw.push_str("\r\n");
+        let mut raw = raw.into_bytes();
+        if let Some(body) = &self.body {
+            raw.extend_from_slice(body);
+        }
+        raw
+    }
+}
+
+/// How request heads may end their lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LineEndings {
+    /// Only CRLF, as RFC 9112 requires; a bare LF is answered with 400.
+    Strict,
+    /// Bare LF is accepted as a line ending too.
+    #[default]
+    Lenient,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParserConfig {
+    pub line_endings: LineEndings,
+    /// Header fields a request may carry before it is answered with 431.
+    pub max_headers: usize,
+    /// Longest line of the head, in bytes. A longer request line is answered with 414, a
+    /// longer header field with 431.
+    pub max_line_len: usize,
+    /// Most bytes all header fields together may take, line endings included, before the
+    /// request is answered with 431.
+    pub max_header_size: usize,
+    /// Longest a client may take to send the head of a request once it started, and then
+    /// its body; either is answered with 408 when exceeded. Waiting for the first request on
+    /// a connection is bounded by `header_timeout` as well.
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    /// Longest body accepted, in bytes, spooled or not; a longer one is answered with 413
+    /// before any of it is read.
+    pub max_body_size: u64,
+    /// File uploads with longer bodies are spooled to `spool_dir` instead of read into memory.
+    pub spool_threshold: usize,
+    pub spool_dir: PathBuf,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {
+            line_endings: LineEndings::default(),
+            max_headers: 100,
+            max_line_len: 8 * 1024,
+            max_header_size: 64 * 1024,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(60),
+            max_body_size: 100 * 1024 * 1024,
+            spool_threshold: 1024 * 1024,
+            spool_dir: std::env::temp_dir(),
+        }
+    }
+}
+
+/// A request the server refuses to handle. Unlike other read errors it is answered, with
+/// `status_code`, before the connection is closed.
+#[derive(Debug)]
+pub struct RejectedRequest {
+    status_code: HttpStatusCode,
+    reason: String,
+    headers: HeaderMap,
+}
+
+impl RejectedRequest {
+    pub fn new(status_code: HttpStatusCode, reason: impl Into<String>) -> Self {
+        RejectedRequest { status_code, reason: reason.into(), headers: HeaderMap::new() }
+    }
+
+    /// The same rejection, with `headers` added to its response.
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+}
+
+impl std::fmt::Display for RejectedRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rejected with {}: {}", self.status_code.code_and_phrase().0, self.reason)
+    }
+}
+
+impl std::error::Error for RejectedRequest {}
+
+/// The response to send for `err`, if it is a [`RejectedRequest`].
+pub fn rejection(err: &anyhow::Error, now: SystemTime) -> Option<HttpResponseBuilder> {
+    let rejected = err.downcast_ref::<RejectedRequest>()?;
+    log_debug!("request {rejected}");
+    let mut headers = rejected.headers.clone();
+    headers.insert("Date", httpdate::format(now));
+    headers.insert("Connection", "close");
+    Some(HttpResponseBuilder {
+        status_code: rejected.status_code.clone(),
+        version: "HTTP/1.1".to_string(),
+        headers,
+        content: Content::Empty,
+    })
+}
+
+/// Reads the request line and headers up to the empty line, normalizing line endings to CRLF
+/// for the parser. With [`LineEndings::Strict`], a line ending in a bare LF is rejected.
+async fn read_head<R: AsyncBufRead + Unpin>(reader: &mut R, config: &ParserConfig) -> anyhow::Result<String> {
+    let mut head = Vec::new();
+    let mut fields_size = 0;
+    // the first line is the request line, every other one a header field
+    for lines in 0.. {
+        let mut line = Vec::new();
+        // one more than allowed, so an overlong line can be told from one that just fits
+        let limit = config.max_line_len as u64 + 2;
+        if (&mut *reader).take(limit + 1).read_until(b'\n', &mut line).await? == 0 {
+            break;
+        }
+        if line.len() as u64 > limit {
+            let (status_code, what) = match lines {
+                0 => (HttpStatusCode::Other(414, "URI Too Long".to_string()), "request line"),
+                _ => (HttpStatusCode::Other(431, "Request Header Fields Too Large".to_string()), "header field"),
+            };
+            Err(RejectedRequest::new(status_code, format!("{what} longer than {} bytes", config.max_line_len)))?;
+        }
+        if line.ends_with(b"\n") && !line.ends_with(b"\r\n") {
+            if config.line_endings == LineEndings::Strict {
+                Err(RejectedRequest::new(HttpStatusCode::BadRequest400, "bare LF line ending"))?;
+            }
+            line.pop();
+            line.extend_from_slice(b"\r\n");
+        }
+        if line == b"\r\n" {
+            break;
+        }
+        if lines > config.max_headers {
+            let status_code = HttpStatusCode::Other(431, "Request Header Fields Too Large".to_string());
+            Err(RejectedRequest::new(status_code, format!("more than {} header fields", config.max_headers)))?;
+        }
+        if lines > 0 {
+            fields_size += line.len();
+            if fields_size > config.max_header_size {
+                let status_code = HttpStatusCode::Other(431, "Request Header Fields Too Large".to_string());
+                Err(RejectedRequest::new(status_code, format!("header fields longer than {} bytes", config.max_header_size)))?;
+            }
+        }
+        head.extend_from_slice(&line);
+    }
+    String::from_utf8(head)
+        .map_err(|_| RejectedRequest::new(HttpStatusCode::BadRequest400, "request head is not utf8").into())
+}
+
+/// Reads a request: the head within `config.header_timeout` of its first byte, then the body
+/// within `config.body_timeout`, measured on `clock`. A client too slow for either gets 408.
+pub async fn reader_request<R: AsyncBufRead + Unpin>(reader: &mut R, config: &ParserConfig, clock: &dyn Clock) -> anyhow::Result<HttpRequest> {
+    let request = read_request_head(reader, config, clock).await?;
+    read_request_body(reader, request, config, clock).await
+}
+
+fn timed_out(what: &str, timeout: Duration) -> RejectedRequest {
+    let status_code = HttpStatusCode::Other(408, "Request Timeout".to_string());
+    RejectedRequest::new(status_code, format!("{what} not received within {timeout:?}"))
+}
+
+/// Reads and checks the head of a request, leaving its body unread, so a request can be
+/// turned away before its body is.
+pub async fn read_request_head<R: AsyncBufRead + Unpin>(reader: &mut R, config: &ParserConfig, clock: &dyn Clock) -> anyhow::Result<HttpRequest> {
+    let request_content = clock::timeout(clock, config.header_timeout, read_head(reader, config)).await
+        .map_err(|_| timed_out("request head", config.header_timeout))??;
+
+    // parse request
+    let (left, request) = parse_http_request(&request_content)
+        .map_err(|err| match err {
+            nom::Err::Failure(err) => {
+                let status_code = HttpStatusCode::Other(501, "Not Implemented".to_string());
+                RejectedRequest::new(status_code, format!("unknown method {:?}", err.input))
+            }
+            err => RejectedRequest::new(HttpStatusCode::BadRequest400, format!("malformed request head, {err}")),
+        })?;
+    if !left.is_empty() {
+        Err(RejectedRequest::new(HttpStatusCode::BadRequest400, format!("malformed header line {left:?}")))?;
+    }
+    if let Some((name, value)) = request.headers.iter().find(|(name, value)| !is_token(name) || !is_field_value(value)) {
+        Err(RejectedRequest::new(HttpStatusCode::BadRequest400, format!("invalid header {name:?}: {value:?}")))?;
+    }
+    // a request with several Hosts is invalid (RFC 9112 section 3.2), and merging them as a
+    // list would hide that
+    let hosts = request_content.lines().skip(1)
+        .filter(|line| line.split_once(':').is_some_and(|(name, _)| name.eq_ignore_ascii_case("Host")))
+        .count();
+    if hosts > 1 {
+        Err(RejectedRequest::new(HttpStatusCode::BadRequest400, "more than one Host header"))?;
+    }
+    Ok(request)
+}
+
+/// Reads the body announced by the head of `request`.
+pub async fn read_request_body<R: AsyncBufRead + Unpin>(reader: &mut R, request: HttpRequest, config: &ParserConfig, clock: &dyn Clock) -> anyhow::Result<HttpRequest> {
+    clock::timeout(clock, config.body_timeout, read_body(reader, request, config)).await
+        .map_err(|_| timed_out("request content", config.body_timeout))?
+}
+
+async fn read_body<R: AsyncBufRead + Unpin>(reader: &mut R, mut request: HttpRequest, config: &ParserConfig) -> anyhow::Result<HttpRequest> {
+    if transfer_coding(&request)? {
+        return read_chunked(reader, request, config).await;
+    }
+    let body = if let Some(length) = content_length(&request)? {
+        log_debug!("content length - {length}");
+        if length as u64 > config.max_body_size {
+            Err(too_large(config))?;
+        }
+        if length > config.spool_threshold && is_file_upload(&request) {
+            let spooled = upload::SpooledBody::spool(reader, length as u64, &config.spool_dir).await?;
+            request.spooled = Some(Arc::new(spooled));
+            return Ok(request);
+        }
+
+        let mut buffer = vec![0; length];
+        reader.read_exact(&mut buffer).await
+            .context("ERROR: reading request content")?;
+
+        Some(buffer)
+    } else {
+        None
+    };
+
+    request.body = body;
+    Ok(request)
+}
+
+fn too_large(config: &ParserConfig) -> RejectedRequest {
+    let status_code = HttpStatusCode::Other(413, "Content Too Large".to_string());
+    RejectedRequest::new(status_code, format!("request content longer than {} bytes", config.max_body_size))
+}
+
+/// Whether the body is sent chunked. That is the only coding understood, and one that
+/// conflicts with a Content-Length could be read differently by a proxy in front, so it
+/// is refused (RFC 9112 section 6.3).
+fn transfer_coding(request: &HttpRequest) -> Result<bool, RejectedRequest> {
+    let Some(coding) = request.header("Transfer-Encoding") else {
+        return Ok(false);
+    };
+    if !coding.trim().eq_ignore_ascii_case("chunked") {
+        let status_code = HttpStatusCode::Other(501, "Not Implemented".to_string());
+        return Err(RejectedRequest::new(status_code, format!("unsupported Transfer-Encoding {coding:?}")));
+    }
+    if request.header("Content-Length").is_some() {
+        return Err(RejectedRequest::new(HttpStatusCode::BadRequest400, "both Transfer-Encoding and Content-Length"));
+    }
+    Ok(true)
+}
+
+/// Reads a chunk size or trailer line, at most `config.max_line_len` long like a header
+/// field. Empty once the client has closed.
+async fn read_chunk_line<R: AsyncBufRead + Unpin>(reader: &mut R, config: &ParserConfig, what: &str) -> anyhow::Result<String> {
+    let mut line = Vec::new();
+    let limit = config.max_line_len as u64 + 2;
+    (&mut *reader).take(limit + 1).read_until(b'\n', &mut line).await.with_context(|| format!("ERROR: reading {what}"))?;
+    if line.len() as u64 > limit {
+        let status_code = HttpStatusCode::Other(431, "Request Header Fields Too Large".to_string());
+        Err(RejectedRequest::new(status_code, format!("{what} longer than {} bytes", config.max_line_len)))?;
+    }
+    String::from_utf8(line).map_err(|_| RejectedRequest::new(HttpStatusCode::BadRequest400, format!("{what} is not utf8")).into())
+}
+
+/// Reads a chunked body (RFC 9112 section 7.1) into memory, or into a spool file once an
+/// upload grows past the spool threshold. The request then looks as if it had been sent
+/// with a Content-Length, for handlers passing it on.
+async fn read_chunked<R: AsyncBufRead + Unpin>(reader: &mut R, mut request: HttpRequest, config: &ParserConfig) -> anyhow::Result<HttpRequest> {
+    let malformed = |reason: String| RejectedRequest::new(HttpStatusCode::BadRequest400, reason);
+    let mut body = Vec::new();
+    let mut spooler = None;
+    let mut received = 0u64;
+    loop {
+        let line = read_chunk_line(reader, config, "chunk size line").await?;
+        // extensions after the size are allowed, and ignored
+        let size = line.split(';').next().unwrap().trim();
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            Err(malformed(format!("invalid chunk size line {line:?}")))?;
+        }
+        let size = usize::from_str_radix(size, 16).map_err(|_| malformed(format!("chunk size {size} is too large")))?;
+        if size == 0 {
+            break;
+        }
+        log_debug!("chunk of {size} bytes");
+        received += size as u64;
+        if received > config.max_body_size {
+            Err(too_large(config))?;
+        }
+
+        if spooler.is_none() && body.len() + size > config.spool_threshold && is_file_upload(&request) {
+            let mut started = upload::Spooler::create(&config.spool_dir).await?;
+            started.write(&body).await?;
+            body = Vec::new();
+            spooler = Some(started);
+        }
+        match &mut spooler {
+            Some(spooler) => spooler.copy(reader, size as u64).await?,
+            None => {
+                let start = body.len();
+                body.resize(start + size, 0);
+                reader.read_exact(&mut body[start..]).await.context("ERROR: reading request content")?;
+            }
+        }
+        let mut end = [0; 2];
+        reader.read_exact(&mut end).await.context("ERROR: reading request content")?;
+        if &end != b"\r\n" {
+            Err(malformed("chunk data not followed by CRLF".to_string()))?;
+        }
+    }
+    // trailer fields aren't used for anything, but still count against the header limit
+    let mut trailer_size = 0;
+    loop {
+        let line = read_chunk_line(reader, config, "trailer field").await?;
+        if line.trim_end().is_empty() {
+            break;
+        }
+        trailer_size += line.len();
+        if trailer_size > config.max_header_size {
+            let status_code = HttpStatusCode::Other(431, "Request Header Fields Too Large".to_string());
+            Err(RejectedRequest::new(status_code, format!("trailer fields longer than {} bytes", config.max_header_size)))?;
+        }
+    }
+
+    request.headers.remove("Transfer-Encoding");
+    let length = match spooler {
+        Some(spooler) => {
+            let spooled = spooler.finish().await?;
+            let length = spooled.len;
+            request.spooled = Some(Arc::new(spooled));
+            length
+        }
+        None => {
+            let length = body.len() as u64;
+            request.body = Some(body);
+            length
+        }
+    };
+    request.headers.insert("Content-Length", length.to_string());
+    Ok(request)
+}
+
+/// Whether `request` writes to /files or a file under it, the only routes that can take a
+/// spooled body.
+fn is_file_upload(request: &HttpRequest) -> bool {
+    let path = request.route.split(['?', '#']).next().unwrap_or_default();
+    matches!(request.method, HttpMethod::Post | HttpMethod::Put) && (path == "/files" || path.starts_with("/files/"))
+}
+
+/// A header field name is a token, RFC 9110 section 5.6.2.
+pub fn is_token(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Field values may hold anything but control characters, except for tabs.
+pub fn is_field_value(value: &str) -> bool {
+    value.bytes().all(|b| b == b'\t' || !(b.is_ascii_control()))
+}
+
+/// The body length the request declares. Repeated Content-Length fields are merged into a
+/// list by the parser, which is only acceptable when every entry agrees (RFC 9110 section
+/// 8.6); anything but plain digits that fit a usize is rejected.
+fn content_length(request: &HttpRequest) -> Result<Option<usize>, RejectedRequest> {
+    let Some(value) = request.header("Content-Length") else {
+        return Ok(None);
+    };
+    let invalid = || RejectedRequest::new(HttpStatusCode::BadRequest400, format!("invalid Content-Length {value:?}"));
+
+    let mut length = None;
+    for entry in value.split(',').map(str::trim) {
+        if entry.is_empty() || !entry.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let entry: usize = entry.parse().map_err(|_| invalid())?;
+        if length.is_some_and(|length| length != entry) {
+            return Err(RejectedRequest::new(HttpStatusCode::BadRequest400, format!("conflicting Content-Length {value:?}")));
+        }
+        length = Some(entry);
+    }
+    Ok(length)
+}
+
+fn non_whitespace(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| !c.is_whitespace())(input)
+}
+
+/// Everything up to the line ending, without the optional whitespace around it.
+fn header_value(input: &str) -> IResult<&str, &str> {
+    let (input, value) = take_while(|c: char| c != '\r' && c != '\n')(input)?;
+    Ok((input, value.trim_matches(|c| c == ' ' || c == '\t')))
+}
+
+pub fn parse_http_request(content: &str) -> IResult<&str, HttpRequest> {
+    let (input, method) = terminated(non_whitespace, space0)(content)?;
+    let (input, route) = terminated(non_whitespace, space0)(input)?;
+    let (input, version) = terminated(non_whitespace, crlf)(input)?;
+
+    let (input, headers) = many0(pair(
+        terminated(take_while1(|c: char| c != ':'), tag(":")),
+        terminated(header_value, crlf),
+    ))(input)?;
+
+    let Some(method) = HttpMethod::parse(method) else {
+        // a failure rather than an error, so it can be told apart from a malformed head
+        return Err(nom::Err::Failure(nom::error::Error::new(method, nom::error::ErrorKind::Tag)));
+    };
+
+    let headers = headers.into_iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+
+    Ok(
+        (input, HttpRequest {
+            method,
+            headers,
+            route: route.to_string(),
+            version: version.to_string(),
+            body: None,
+            remote_addr: None,
+            forwarded: None,
+            spooled: None,
+            session: None,
+        }
+        )
+    )
+}
+
+pub fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query.split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+/// The request `request` stands in for when it is a POST naming another method in
+/// `X-HTTP-Method-Override` or a `_method` form field, for clients behind proxies that only
+/// let GET and POST through. Only methods in `allowed` may be asked for.
+pub fn method_override(request: &HttpRequest, allowed: &[HttpMethod]) -> Option<HttpRequest> {
+    if allowed.is_empty() || !matches!(request.method, HttpMethod::Post) {
+        return None;
+    }
+    let is_form = request.header("Content-Type")
+        .is_some_and(|content_type| content_type.split(';').next().unwrap().trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+    let requested = match request.header("X-HTTP-Method-Override") {
+        Some(method) => method.trim().to_ascii_uppercase(),
+        None if is_form => percent::decode(query_param(request.text()?, "_method")?)?.to_ascii_uppercase(),
+        None => return None,
+    };
+    let method = HttpMethod::parse(&requested)
+        .filter(|method| allowed.iter().any(|allowed| allowed.as_str() == method.as_str()));
+    let Some(method) = method else {
+        log_debug!("ignoring method override to {requested}");
+        return None;
+    };
+    Some(HttpRequest { method, ..request.clone() })
+}
+
+/// Whether the client's Accept-Encoding allows `coding`, i.e. lists it without `q=0`.
+pub fn accepts_encoding(request: &HttpRequest, coding: &str) -> bool {
+    request.header("Accept-Encoding").is_some_and(|accept| {
+        accept.split(',').any(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let refused = params.any(|param| {
+                param.strip_prefix("q=").is_some_and(|q| q.parse::<f32>().is_ok_and(|q| q == 0.0))
+            });
+            name.eq_ignore_ascii_case(coding) && !refused
+        })
+    })
+}
+
+/// Whether the client wants the connection closed after this response: it said so, or it
+/// speaks HTTP/1.0 and didn't ask for keep-alive (RFC 9112 section 9.3).
+pub fn wants_close(request: &HttpRequest) -> bool {
+    let options = request.header("Connection").unwrap_or_default();
+    let has = |option: &str| options.split(',').any(|o| o.trim().eq_ignore_ascii_case(option));
+    has("close") || (request.version == "HTTP/1.0" && !has("keep-alive"))
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn header_values_keep_inner_spaces() {
+        let raw = "GET /user-agent HTTP/1.1\r\nUser-Agent: foobar/1.2 (X11; Linux)\r\nX-Padded:\t  spaced out \t\r\nX-Empty:\r\n";
+        let (_, request) = parse_http_request(raw).unwrap();
+        assert_eq!(request.headers["User-Agent"], "foobar/1.2 (X11; Linux)");
+        assert_eq!(request.headers["X-Padded"], "spaced out");
+        assert_eq!(request.headers["X-Empty"], "");
+    }
+
+    fn with_content_length(values: &[&str]) -> HttpRequest {
+        let mut raw = "POST /files/a HTTP/1.1\r\nHost: localhost\r\n".to_string();
+        for value in values {
+            raw.push_str(&format!("content-length: {value}\r\n"));
+        }
+        parse_http_request(&raw).unwrap().1
+    }
+
+    #[test]
+    fn content_length_is_validated() {
+        assert_eq!(content_length(&with_content_length(&[])).unwrap(), None);
+        assert_eq!(content_length(&with_content_length(&["5"])).unwrap(), Some(5));
+        assert_eq!(content_length(&with_content_length(&["5", "5"])).unwrap(), Some(5));
+        assert_eq!(content_length(&with_content_length(&["5, 5"])).unwrap(), Some(5));
+
+        for invalid in [&["5", "6"][..], &["-1"], &["+5"], &["abc"], &[""], &["99999999999999999999999"]] {
+            assert!(content_length(&with_content_length(invalid)).is_err(), "{invalid:?} was accepted");
+        }
+    }
+}
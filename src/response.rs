@@ -0,0 +1,341 @@
+use std::io::Write;
+
+use anyhow::Context;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::log::log_error;
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite};
+
+use crate::request::{accepts_encoding, is_field_value, is_token};
+use crate::storage::{BoxFuture, BoxReader};
+use crate::{template, HeaderMap, HttpRequest};
+
+pub enum Content {
+    Empty,
+    Text(String),
+    OctetStream(Vec<u8>),
+    Html(String),
+    Bytes(Vec<u8>),
+    /// The inner content, gzipped on the way out and sent with `Content-Encoding: gzip`.
+    Gzip(Box<Content>),
+    /// A body sent as it's read, of the given length when it's known. Otherwise it's chunked,
+    /// or sent to HTTP/1.0 clients until the connection closes.
+    Stream(BoxReader, Option<u64>),
+    /// A protocol taking over the connection once the head of a 101 response is out.
+    Upgrade(Upgrade),
+}
+
+/// Runs a protocol switched to with 101 on the rest of the connection, until either side
+/// closes it.
+pub type Upgrade = Box<
+    dyn for<'a> FnOnce(&'a mut (dyn AsyncBufRead + Send + Unpin), &'a mut (dyn AsyncWrite + Send + Unpin)) -> BoxFuture<'a, std::io::Result<()>>
+        + Send,
+>;
+
+impl Content {
+    /// The content in the coding `request` accepts: gzipped when it does, as is otherwise.
+    pub fn encoded_for(self, request: &HttpRequest) -> Self {
+        match accepts_encoding(request, "gzip") && !matches!(self, Content::Stream(..)) {
+            true => Content::Gzip(Box::new(self)),
+            false => self,
+        }
+    }
+
+    /// The content's media type, unless a handler sets its own, and its body.
+    fn into_body(self) -> (Option<&'static str>, Option<Vec<u8>>) {
+        match self {
+            Content::Empty => (None, None),
+            Content::Text(content) => (Some("text/plain"), Some(content.into_bytes())),
+            Content::OctetStream(content) => (Some("application/octet-stream"), Some(content)),
+            Content::Html(content) => (Some("text/html; charset=utf-8"), Some(content.into_bytes())),
+            Content::Bytes(content) => (None, Some(content)),
+            Content::Gzip(content) => {
+                let (content_type, body) = content.into_body();
+                (content_type, body.map(|body| gzip(&body)))
+            }
+            // sent after the head, see `HttpResponseBuilder::take_stream`
+            Content::Stream(..) => (None, None),
+            Content::Upgrade(_) => (None, None),
+        }
+    }
+}
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // writing to a Vec can't fail
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[derive(Debug, Clone)]
+pub enum HttpStatusCode {
+    Ok200,
+    Created201,
+    NoContent204,
+    BadRequest400,
+    Forbidden403,
+    NotFound404,
+    InternalError500,
+    BadGateway502,
+    GatewayTimeout504,
+    Other(u16, String),
+}
+
+impl HttpStatusCode {
+    pub fn code_and_phrase(&self) -> (u16, &str) {
+        match self {
+            HttpStatusCode::Ok200 => (200, "Ok"),
+            HttpStatusCode::Created201 => (201, "Created"),
+            HttpStatusCode::NoContent204 => (204, "No Content"),
+            HttpStatusCode::BadRequest400 => (400, "Bad Request"),
+            HttpStatusCode::Forbidden403 => (403, "Forbidden"),
+            HttpStatusCode::NotFound404 => (404, "NotFound"),
+            HttpStatusCode::InternalError500 => (500, "InternalError"),
+            HttpStatusCode::BadGateway502 => (502, "Bad Gateway"),
+            HttpStatusCode::GatewayTimeout504 => (504, "Gateway Timeout"),
+            HttpStatusCode::Other(code, phrase) => (*code, phrase),
+        }
+    }
+
+    /// 1xx, 204 and 304 responses end with their header section (RFC 9110 section 6.4.1).
+    fn allows_body(&self) -> bool {
+        let (code, _) = self.code_and_phrase();
+        !((100..200).contains(&code) || code == 204 || code == 304)
+    }
+}
+
+pub struct HttpResponseBuilder {
+    pub status_code: HttpStatusCode,
+    pub version: String,
+    pub headers: HeaderMap,
+    pub content: Content,
+}
+
+impl HttpResponseBuilder {
+    pub fn no_content(version: String) -> Self {
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::NoContent204,
+            version,
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        }
+    }
+
+    pub fn render(status_code: HttpStatusCode, version: String, template: &str, context: &template::Context) -> anyhow::Result<Self> {
+        let page = template::render(template, context).context("ERROR: rendering template")?;
+        Ok(HttpResponseBuilder {
+            status_code,
+            version,
+            headers: HeaderMap::new(),
+            content: Content::Html(page),
+        })
+    }
+
+    /// The response to HEAD, given the one to GET: the same headers, including the
+    /// Content-Length and Content-Type of the body that isn't sent.
+    pub fn without_body(self) -> Self {
+        let (status_code, version, headers, _) = self.into_parts();
+        HttpResponseBuilder { status_code, version, headers, content: Content::Empty }
+    }
+
+    /// Whether the body is streamed without a known length, and chunked so the client can
+    /// still tell where it ends; HTTP/1.0 clients don't know the coding.
+    pub fn sends_chunked(&self) -> bool {
+        matches!(self.content, Content::Stream(_, None)) && self.version != "HTTP/1.0"
+    }
+
+    /// Hands out the reader of a streamed body, and its length if known, for the caller to
+    /// send after the head, which still announces it.
+    pub fn take_stream(&mut self) -> Option<(BoxReader, Option<u64>)> {
+        match &mut self.content {
+            Content::Stream(reader, len) if self.status_code.allows_body() => {
+                Some((std::mem::replace(reader, Box::new(tokio::io::empty())), *len))
+            }
+            _ => None,
+        }
+    }
+
+    /// Hands out the protocol a 101 response switches to, for the caller to run once the head
+    /// is written.
+    pub fn take_upgrade(&mut self) -> Option<Upgrade> {
+        match std::mem::replace(&mut self.content, Content::Empty) {
+            Content::Upgrade(upgrade) => Some(upgrade),
+            content => {
+                self.content = content;
+                None
+            }
+        }
+    }
+
+    /// The same response with a streamed body read into memory, for callers that need the
+    /// whole body at once.
+    pub async fn buffered(self) -> std::io::Result<Self> {
+        let HttpResponseBuilder { status_code, version, headers, content: Content::Stream(reader, len) } = self else {
+            return Ok(self);
+        };
+        let mut body = Vec::new();
+        reader.take(len.unwrap_or(u64::MAX)).read_to_end(&mut body).await?;
+        Ok(HttpResponseBuilder { status_code, version, headers, content: Content::Bytes(body) })
+    }
+}
+
+impl HttpResponseBuilder {
+    /// Splits the response into its status, the full header list including the headers
+    /// derived from the content, and the body, if it has one. Statuses that can't carry a
+    /// body lose it here, along with any framing headers a handler set.
+    pub fn into_parts(self) -> (HttpStatusCode, String, HeaderMap, Option<Vec<u8>>) {
+        let chunked = self.sends_chunked();
+        let streamed_len = match self.content {
+            Content::Stream(_, len) => len,
+            _ => None,
+        };
+        let mut headers = self.headers;
+        if !self.status_code.allows_body() {
+            headers.retain(|(name, _)| {
+                !name.eq_ignore_ascii_case("Content-Length") && !name.eq_ignore_ascii_case("Transfer-Encoding")
+            });
+            return (self.status_code, self.version, headers, None);
+        }
+        let gzipped = matches!(self.content, Content::Gzip(_));
+        let framed = headers.contains_key("Content-Length") || headers.contains_key("Transfer-Encoding");
+        // an empty body still needs framing, or a kept-alive client waits for more
+        if matches!(self.content, Content::Empty) && !framed {
+            headers.insert("Content-Length", "0");
+        }
+        if chunked {
+            headers.insert("Transfer-Encoding", "chunked");
+        }
+        let (content_type, body) = self.content.into_body();
+        // a type the handler chose wins over the one its content implies
+        if let Some(content_type) = content_type.filter(|_| !headers.contains_key("Content-Type")) {
+            headers.insert("Content-Type", content_type);
+        }
+        if gzipped {
+            headers.append("Content-Encoding", "gzip");
+        }
+        if let Some(len) = streamed_len {
+            headers.insert("Content-Length", len.to_string());
+        }
+        if let Some(body) = &body {
+            headers.insert("Content-Length", body.len().to_string());
+        }
+        (self.status_code, self.version, headers, body)
+    }
+}
+
+impl From<HttpResponseBuilder> for Vec<u8> {
+    fn from(builder: HttpResponseBuilder) -> Self {
+        let (status_code, version, headers, body) = builder.into_parts();
+        let (code, phrase) = status_code.code_and_phrase();
+        let mut response = format!("{} {} {}\r\n", version, code, phrase);
+        for (name, value) in &headers {
+            // whatever ended up in a header, it must not be able to start a new one
+            if !is_token(name) || !is_field_value(value) {
+                log_error!("dropping invalid response header {name:?}: {value:?}");
+                continue;
+            }
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        response.push_str("\r\n");
+
+        let mut response = response.into_bytes();
+        if let Some(body) = body {
+            response.extend_from_slice(&body);
+        }
+        response
+    }
+}
+
+/// Storage failures as the client should see them: a missing file is the client's problem, a
+/// file the server may not touch or a failing disk is the operator's.
+pub fn status_for_io_error(err: &std::io::Error) -> HttpStatusCode {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => HttpStatusCode::NotFound404,
+        std::io::ErrorKind::PermissionDenied => HttpStatusCode::Forbidden403,
+        _ => HttpStatusCode::InternalError500,
+    }
+}
+
+/// `Allow` listing `methods`, and OPTIONS, which every route answers.
+pub fn allow_header(mut methods: Vec<&'static str>) -> (String, String) {
+    methods.push("OPTIONS");
+    ("Allow".to_string(), methods.join(", "))
+}
+
+const ERROR_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>{{ code }} {{ phrase }}</title></head>
+<body>
+<h1>{{ code }} {{ phrase }}</h1>
+<p>{{ route }}</p>
+</body>
+</html>
+";
+
+/// Gives bodiless error responses a small HTML page when the client is a browser.
+pub fn with_error_page(request: &HttpRequest, response: HttpResponseBuilder) -> HttpResponseBuilder {
+    let accepts_html = request.headers.get("Accept")
+        .is_some_and(|accept| accept.contains("text/html"));
+    let (code, phrase) = response.status_code.code_and_phrase();
+    if !accepts_html || code < 400 || !matches!(response.content, Content::Empty) {
+        return response;
+    }
+
+    let context = template::Context::from([
+        ("code".to_string(), code.to_string().into()),
+        ("phrase".to_string(), phrase.into()),
+        ("route".to_string(), request.route.as_str().into()),
+    ]);
+    match HttpResponseBuilder::render(response.status_code.clone(), response.version.clone(), ERROR_PAGE, &context) {
+        Ok(mut page) => {
+            page.headers = response.headers;
+            page
+        }
+        Err(err) => {
+            log_error!("{err:#}");
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn response_headers_cannot_be_injected() {
+        let response = HttpResponseBuilder {
+            status_code: HttpStatusCode::Other(302, "Found".to_string()),
+            version: "HTTP/1.1".to_string(),
+            headers: HeaderMap::from([
+                ("Location".to_string(), "/files/a\r\nSet-Cookie: session=evil".to_string()),
+                ("Bad Name".to_string(), "x".to_string()),
+                ("X-Tab".to_string(), "a\tb".to_string()),
+            ]),
+            content: Content::Empty,
+        };
+        let raw: Vec<u8> = response.into();
+        assert_eq!(String::from_utf8(raw).unwrap(), "HTTP/1.1 302 Found\r\nX-Tab: a\tb\r\nContent-Length: 0\r\n\r\n");
+    }
+
+    #[test]
+    fn bodiless_statuses_drop_content() {
+        let bytes: Vec<u8> = HttpResponseBuilder::no_content("HTTP/1.1".to_string()).into();
+        assert_eq!(bytes, b"HTTP/1.1 204 No Content\r\n\r\n");
+
+        for code in [103, 204, 304] {
+            let response = HttpResponseBuilder {
+                status_code: HttpStatusCode::Other(code, "Whatever".to_string()),
+                version: "HTTP/1.1".to_string(),
+                headers: HeaderMap::from([("Content-Length".to_string(), "5".to_string()), ("ETag".to_string(), "\"x\"".to_string())]),
+                content: Content::Text("hello".to_string()),
+            };
+            let (_, _, headers, body) = response.into_parts();
+            assert_eq!(headers, HeaderMap::from([("ETag".to_string(), "\"x\"".to_string())]), "{code}");
+            assert_eq!(body, None, "{code}");
+        }
+    }
+}
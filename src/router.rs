@@ -0,0 +1,1655 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::json::Json;
+use crate::log::{log_debug, log_error};
+use crate::openapi::{In, Operation, Param};
+use crate::request::{accepts_encoding, query_param};
+use crate::response::{allow_header, status_for_io_error};
+use crate::{cgi, digest, etag, httpdate, kv, markdown, mime, openapi, percent, precompress, range, signed_url, sse, storage, template, websocket};
+use crate::storage::BoxFuture;
+use crate::{Content, HeaderMap, HttpMethod, HttpRequest, HttpResponseBuilder, HttpStatusCode, ServerConfig};
+
+/// Lets every crawler in, like having no robots.txt does, minus the 404.
+pub const DEFAULT_ROBOTS_TXT: &str = "User-agent: *\nDisallow:\n";
+
+const MARKDOWN_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{{ title }}</title>
+<style>body { max-width: 48em; margin: 2em auto; padding: 0 1em; font-family: sans-serif; line-height: 1.5; }</style>
+</head>
+<body>
+{{ body | raw }}
+</body>
+</html>
+";
+
+/// Largest file gzipped on the fly for a download; bigger ones are streamed as they are.
+const GZIP_IN_MEMORY: u64 = 1024 * 1024;
+
+/// A multipart/byteranges body (RFC 9110 section 14.6) of `ranges` of the stored file `name`,
+/// which is `size` bytes long. The ranges are ascending and apart, so one pass over the file,
+/// on a task of its own, streams all of them.
+async fn multipart_stream(storage: &dyn storage::Storage, name: &str, ranges: Vec<RangeInclusive<u64>>, size: u64, boundary: &str) -> std::io::Result<Content> {
+    let parts: Vec<(String, RangeInclusive<u64>)> = ranges.into_iter()
+        .map(|range| {
+            let head = format!(
+                "--{boundary}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{size}\r\n\r\n",
+                mime::for_name(name),
+                range.start(),
+                range.end(),
+            );
+            (head, range)
+        })
+        .collect();
+    let closing = format!("--{boundary}--\r\n");
+    let len = parts.iter().map(|(head, range)| head.len() as u64 + range.end() - range.start() + 1 + 2).sum::<u64>()
+        + closing.len() as u64;
+
+    let mut reader = storage.open(name).await?;
+    let (mut body, received) = tokio::io::duplex(64 * 1024);
+    // a failure ends the body early, which the connection loop notices
+    tokio::spawn(async move {
+        let mut position = 0;
+        for (head, range) in parts {
+            body.write_all(head.as_bytes()).await?;
+            tokio::io::copy(&mut (&mut reader).take(range.start() - position), &mut tokio::io::sink()).await?;
+            tokio::io::copy(&mut (&mut reader).take(range.end() - range.start() + 1), &mut body).await?;
+            body.write_all(b"\r\n").await?;
+            position = range.end() + 1;
+        }
+        body.write_all(closing.as_bytes()).await
+    });
+    Ok(Content::Stream(Box::new(received), Some(len)))
+}
+
+/// Starts the names of uploads still being written, which listings leave out.
+const STAGED_PREFIX: &str = ".upload-";
+
+/// Tells apart the files uploads are staged in.
+static NEXT_STAGED: AtomicU64 = AtomicU64::new(0);
+
+/// Writes the body of an upload, whether it was read into memory or spooled, to a file of its
+/// own next to the stored ones and returns its name. Nothing is locked meanwhile, so a slow
+/// upload holds up no one; the file is renamed into place under `file_changes` after.
+async fn stage_body(request: &HttpRequest, storage: &dyn storage::Storage, content: Option<&[u8]>) -> std::io::Result<String> {
+    let staged = format!("{STAGED_PREFIX}{}-{}", std::process::id(), NEXT_STAGED.fetch_add(1, Ordering::Relaxed));
+    let written = match (content, &request.spooled) {
+        (Some(content), _) => {
+            log_debug!("writing file {staged}");
+            storage.write(&staged, content).await
+        }
+        (None, Some(spooled)) => {
+            log_debug!("streaming {} spooled bytes to file {staged}", spooled.len);
+            match spooled.open().await {
+                Ok(reader) => storage.write_stream(&staged, Box::new(reader)).await,
+                Err(err) => Err(err),
+            }
+        }
+        (None, None) => unreachable!("bodiless uploads are rejected before"),
+    };
+    match written {
+        Ok(()) => Ok(staged),
+        Err(err) => {
+            discard_staged(storage, &staged).await;
+            Err(err)
+        }
+    }
+}
+
+/// Removes a staged upload that won't be renamed into place.
+async fn discard_staged(storage: &dyn storage::Storage, staged: &str) {
+    match storage.delete(staged).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => log_error!("couldn't remove staged upload {staged}, error: {err}"),
+    }
+}
+
+/// The answer to an upload sent without a body, not even an empty one.
+fn length_required(request: &HttpRequest) -> HttpResponseBuilder {
+    HttpResponseBuilder {
+        status_code: HttpStatusCode::Other(411, "Length Required".to_string()),
+        version: request.version.clone(),
+        headers: HeaderMap::new(),
+        content: Content::Empty,
+    }
+}
+
+/// Regenerates or drops the gzipped variant of a file that was just written or deleted,
+/// off the request path.
+fn refresh_precompressed(config: &ServerConfig, name: &str) {
+    let Some(precompressed) = config.precompressed.clone() else {
+        return;
+    };
+    let name = name.to_string();
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = precompressed.refresh(&name) {
+            log_error!("couldn't refresh the gzipped variant of {name}, error: {err}");
+        }
+    });
+}
+
+/// Evaluates If-Match and If-Unmodified-Since (RFC 9110 section 13.2) against the stored
+/// file `name`, so clients editing the same file can't silently overwrite each other.
+async fn preconditions_hold(request: &HttpRequest, config: &ServerConfig, storage: &dyn storage::Storage, name: &str) -> std::io::Result<bool> {
+    let current = match storage.metadata(name).await {
+        Ok(metadata) if !metadata.is_dir => Some(metadata),
+        Ok(_) => None,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+
+    if let Some(if_match) = request.header("If-Match") {
+        let Some(metadata) = &current else {
+            return Ok(false);
+        };
+        if if_match.trim() != "*" && !etag::matches_strongly(if_match, &file_etag(config, storage, name, metadata).await?) {
+            return Ok(false);
+        }
+    } else {
+        let since = request.header("If-Unmodified-Since").and_then(httpdate::parse);
+        if let (Some(since), Some(modified)) = (since, current.as_ref().and_then(|metadata| metadata.modified)) {
+            if whole_seconds(modified) > since {
+                return Ok(false);
+            }
+        }
+    }
+
+    // e.g. If-None-Match: * so an upload can't replace a file someone else created meanwhile
+    if let (Some(if_none_match), Some(metadata)) = (request.header("If-None-Match"), &current) {
+        if if_none_match.trim() == "*" || etag::matches_weakly(if_none_match, &file_etag(config, storage, name, metadata).await?) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The answer to a change of `name` whose preconditions don't hold or can't be checked.
+async fn failed_precondition(request: &HttpRequest, config: &ServerConfig, storage: &dyn storage::Storage, name: &str) -> Option<HttpResponseBuilder> {
+    let status_code = match preconditions_hold(request, config, storage, name).await {
+        Ok(true) => return None,
+        Ok(false) => HttpStatusCode::Other(412, "Precondition Failed".to_string()),
+        Err(err) => {
+            log_error!("couldn't check preconditions on {name}, error: {err}");
+            status_for_io_error(&err)
+        }
+    };
+    Some(HttpResponseBuilder {
+        status_code,
+        version: request.version.clone(),
+        headers: HeaderMap::new(),
+        content: Content::Empty,
+    })
+}
+
+/// `time` as a client compares it with dates it got in `Last-Modified`, which only ever
+/// have whole seconds.
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+}
+
+/// The current `ETag` of the stored file `name`, reading it only if the tag is a hash.
+async fn file_etag(config: &ServerConfig, storage: &dyn storage::Storage, name: &str, metadata: &storage::Metadata) -> std::io::Result<String> {
+    let digest = match config.etags {
+        etag::Strategy::Strong => file_digest(config, storage, name, metadata).await?,
+        // not part of weak tags
+        etag::Strategy::Weak => [0; 32],
+    };
+    Ok(config.etags.tag(metadata, || digest))
+}
+
+/// The SHA-256 of the stored file `name`, streamed through the hash unless it is cached.
+async fn file_digest(config: &ServerConfig, storage: &dyn storage::Storage, name: &str, metadata: &storage::Metadata) -> std::io::Result<[u8; 32]> {
+    if let Some(digest) = config.digests.get(name, metadata) {
+        return Ok(digest);
+    }
+    let digest = digest::sha256_reader(storage.open(name).await?).await?;
+    config.digests.insert(name, metadata, digest);
+    Ok(digest)
+}
+
+/// `len` bytes of the stored file `name` from `start` on, as a streamed body.
+async fn file_stream(storage: &dyn storage::Storage, name: &str, start: u64, len: u64) -> std::io::Result<Content> {
+    let mut reader = storage.open(name).await?;
+    // storage readers can't seek, so what comes before the range is read and dropped
+    tokio::io::copy(&mut (&mut reader).take(start), &mut tokio::io::sink()).await?;
+    Ok(Content::Stream(reader, Some(len)))
+}
+
+/// What a handler gets to answer a request with: the request, the server's configuration
+/// and the parts of the path its pattern captured, still percent-encoded.
+pub struct Context<'a> {
+    pub request: &'a HttpRequest,
+    pub config: &'a ServerConfig,
+    /// The router dispatching the request.
+    pub router: &'a Router,
+    /// The request path, without the query.
+    pub path: &'a str,
+    pub query: &'a str,
+    params: Params<'a>,
+    rest: &'a [&'a str],
+}
+
+/// Names of a pattern's `:name` parameters with the segments they matched.
+type Params<'a> = Vec<(&'a str, &'a str)>;
+
+impl<'a> Context<'a> {
+    /// The path segment captured by `:name` in the pattern.
+    pub fn param(&self, name: &str) -> &'a str {
+        self.params.iter()
+            .find_map(|(param, value)| (*param == name).then_some(*value))
+            .unwrap_or_else(|| panic!("the route pattern has no :{name}"))
+    }
+
+    /// The segments matched by a trailing `*` in the pattern, none if it has none.
+    pub fn rest(&self) -> &'a [&'a str] {
+        self.rest
+    }
+}
+
+pub type Handler = Box<dyn for<'a> Fn(Context<'a>) -> BoxFuture<'a, anyhow::Result<HttpResponseBuilder>> + Send + Sync>;
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    /// `:name`, any one segment.
+    Param(String),
+    /// A trailing `*`, any number of segments including none.
+    Rest,
+}
+
+struct Route {
+    /// `None` for routes answering every method.
+    method: Option<&'static str>,
+    /// The pattern as registered, e.g. `/files/:name`.
+    source: String,
+    pattern: Vec<Segment>,
+    handler: Handler,
+    operation: Operation,
+}
+
+/// Dispatches requests to handlers registered for a method and a path pattern like
+/// `/files/:name` or `/echo/*`. The first registered route matching a request handles it.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, anyhow::Result<HttpResponseBuilder>> + Send + Sync + 'static,
+    {
+        self.add(Some(HttpMethod::Get), pattern, Box::new(handler))
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, anyhow::Result<HttpResponseBuilder>> + Send + Sync + 'static,
+    {
+        self.add(Some(HttpMethod::Post), pattern, Box::new(handler))
+    }
+
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, anyhow::Result<HttpResponseBuilder>> + Send + Sync + 'static,
+    {
+        self.add(Some(HttpMethod::Put), pattern, Box::new(handler))
+    }
+
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, anyhow::Result<HttpResponseBuilder>> + Send + Sync + 'static,
+    {
+        self.add(Some(HttpMethod::Delete), pattern, Box::new(handler))
+    }
+
+    /// Registers `handler` for every method.
+    pub fn any<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(Context<'a>) -> BoxFuture<'a, anyhow::Result<HttpResponseBuilder>> + Send + Sync + 'static,
+    {
+        self.add(None, pattern, Box::new(handler))
+    }
+
+    fn add(&mut self, method: Option<HttpMethod>, pattern: &str, handler: Handler) -> &mut Self {
+        let source = pattern.to_string();
+        let segments: Vec<&str> = pattern.split('/').skip(1).collect();
+        let pattern = segments.iter().enumerate()
+            .map(|(i, segment)| match (segment.strip_prefix(':'), *segment) {
+                (Some(name), _) => Segment::Param(name.to_string()),
+                (None, "*") if i == segments.len() - 1 => Segment::Rest,
+                (None, "*") => panic!("* may only end a route pattern"),
+                (None, literal) => Segment::Literal(literal.to_string()),
+            })
+            .collect();
+        let method = method.map(|method| method.as_str());
+        self.routes.push(Route { method, source, pattern, handler, operation: Operation::default() });
+        self
+    }
+
+    /// Describes the route registered last, for /openapi.json.
+    pub fn describe(&mut self, operation: Operation) -> &mut Self {
+        self.routes.last_mut().expect("describe follows a route").operation = operation;
+        self
+    }
+
+    /// Each route's method, lower-cased, its path as an OpenAPI template and its description.
+    /// Routes for every method are listed under each of them, and a trailing `*` is `{rest}`.
+    pub fn operations(&self) -> impl Iterator<Item = (String, String, &Operation)> {
+        const EVERY: &[&str] = &["get", "post", "put", "delete"];
+        self.routes.iter().flat_map(|route| {
+            let path: String = route.pattern.iter()
+                .map(|segment| match segment {
+                    Segment::Literal(literal) => format!("/{literal}"),
+                    Segment::Param(name) => format!("/{{{name}}}"),
+                    Segment::Rest => "/{rest}".to_string(),
+                })
+                .collect();
+            let methods = match route.method {
+                Some(method) => vec![method.to_ascii_lowercase()],
+                None => EVERY.iter().map(|method| method.to_string()).collect(),
+            };
+            methods.into_iter().map(move |method| (method, path.clone(), &route.operation))
+        })
+    }
+
+    /// Runs the handler of the first route matching `request`, if any does.
+    pub async fn dispatch(&self, request: &HttpRequest, config: &ServerConfig) -> Option<anyhow::Result<HttpResponseBuilder>> {
+        let (path, query) = request.route.split_once('?').unwrap_or((&request.route, ""));
+        let segments = path.split('/').skip(1).collect::<Vec<&str>>();
+        for route in &self.routes {
+            if route.method.is_some_and(|method| method != request.method.as_str()) {
+                continue;
+            }
+            let Some((params, rest)) = route.matches(&segments) else {
+                continue;
+            };
+            let cx = Context { request, config, router: self, path, query, params, rest };
+            return Some((route.handler)(cx).await);
+        }
+        None
+    }
+
+    /// The pattern of the route `request` is dispatched to, if any.
+    pub fn pattern(&self, request: &HttpRequest) -> Option<&str> {
+        let path = request.route.split_once('?').map_or(request.route.as_str(), |(path, _)| path);
+        let segments = path.split('/').skip(1).collect::<Vec<&str>>();
+        self.routes.iter()
+            .filter(|route| route.method.is_none_or(|method| method == request.method.as_str()))
+            .find(|route| route.matches(&segments).is_some())
+            .map(|route| route.source.as_str())
+    }
+
+    /// The methods some route answers at `path`, which is what belongs in `Allow`; empty when
+    /// no route has the path at all.
+    pub fn allowed_methods(&self, path: &str) -> Vec<&'static str> {
+        let segments = path.split('/').skip(1).collect::<Vec<&str>>();
+        methods_of(self.routes.iter().filter(|route| route.matches(&segments).is_some()))
+    }
+
+    /// Every method some route answers, for `OPTIONS *`.
+    pub fn methods(&self) -> Vec<&'static str> {
+        methods_of(self.routes.iter())
+    }
+
+    /// Answers `request`: asterisk-form OPTIONS, the configured gateways and plugins, then the
+    /// registered routes, and 404, 405 or the `Allow` list for everything else.
+    pub async fn handle(&self, request: &HttpRequest, config: &ServerConfig) -> anyhow::Result<HttpResponseBuilder> {
+        if request.route == "*" {
+            // OPTIONS * asks about the server as a whole
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Ok200,
+                version: request.version.clone(),
+                headers: HeaderMap::from([allow_header(self.methods())]),
+                content: Content::Bytes(Vec::new()),
+            });
+        }
+        let path = request.route.split_once('?').map_or(request.route.as_str(), |(path, _)| path);
+        if let Some(fastcgi) = config.fastcgi.as_ref().filter(|fastcgi| fastcgi.matches(path)) {
+            return Ok(fastcgi.handle(request, path).await);
+        }
+        if let Some(external) = config.external.iter().find(|external| external.matches(path)) {
+            return Ok(external.handle(request).await);
+        }
+        #[cfg(feature = "http")]
+        if let Some(mount) = config.mounts.iter().find(|mount| mount.matches(path)) {
+            return mount.handle(request).await;
+        }
+        #[cfg(feature = "wasm")]
+        if let Some(plugin) = config.plugins.iter().find(|plugin| plugin.matches(path)) {
+            return Ok(plugin.handle(request).await);
+        }
+        #[cfg(feature = "scripting")]
+        if let Some(script) = config.scripts.iter().find(|script| script.matches(path)) {
+            return Ok(script.handle(request).await);
+        }
+
+        // escapes that don't decode can't name anything this server has
+        if path.split('/').any(|segment| percent::decode(segment).is_none()) {
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::BadRequest400,
+                version: request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Empty,
+            });
+        }
+        if let Some(response) = self.dispatch(request, config).await {
+            return response;
+        }
+        let allowed = self.allowed_methods(path);
+        let (status_code, headers, content) = match (&request.method, allowed.is_empty()) {
+            (_, true) => (HttpStatusCode::NotFound404, HeaderMap::new(), Content::Empty),
+            (HttpMethod::Options, false) => (HttpStatusCode::Ok200, HeaderMap::from([allow_header(allowed)]), Content::Bytes(Vec::new())),
+            (_, false) => (HttpStatusCode::Other(405, "Method Not Allowed".to_string()), HeaderMap::from([allow_header(allowed)]), Content::Empty),
+        };
+        Ok(HttpResponseBuilder {
+            status_code,
+            version: request.version.clone(),
+            headers,
+            content,
+        })
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.routes.iter().map(|route| (route.method, &route.pattern))).finish()
+    }
+}
+
+/// The methods `routes` answer, upper-cased as on the wire.
+fn methods_of<'a>(routes: impl Iterator<Item = &'a Route>) -> Vec<&'static str> {
+    const EVERY: &[&str] = &["GET", "POST", "PUT", "DELETE"];
+    let mut methods: Vec<&'static str> = Vec::new();
+    for route in routes {
+        for &method in route.method.as_ref().map_or(EVERY, std::slice::from_ref) {
+            if !methods.contains(&method) {
+                methods.push(method);
+                // HEAD comes with every GET
+                if method == "GET" {
+                    methods.push("HEAD");
+                }
+            }
+        }
+    }
+    methods
+}
+
+impl Route {
+    /// The parameters and rest `segments` give this route's pattern, if they match it.
+    fn matches<'a>(&'a self, segments: &'a [&'a str]) -> Option<(Params<'a>, &'a [&'a str])> {
+        let mut params = Vec::new();
+        for (i, segment) in self.pattern.iter().enumerate() {
+            match segment {
+                Segment::Rest => return Some((params, &segments[i..])),
+                Segment::Param(name) => params.push((name.as_str(), *segments.get(i)?)),
+                Segment::Literal(literal) if segments.get(i) == Some(&literal.as_str()) => {}
+                Segment::Literal(_) => return None,
+            }
+        }
+        (segments.len() == self.pattern.len()).then_some((params, &[]))
+    }
+}
+
+/// The routes this server answers with `config`, each described for /openapi.json.
+pub fn routes(config: &ServerConfig) -> Router {
+    const NAME: Param = Param { name: "name", location: In::Path, description: "File name, percent-encoded" };
+    const IF_MATCH: Param = Param { name: "If-Match", location: In::Header, description: "ETags the current file must have, or *" };
+    const IF_UNMODIFIED_SINCE: Param = Param { name: "If-Unmodified-Since", location: In::Header, description: "Date the file must not have changed after" };
+    const CGI: Operation = Operation {
+        summary: "Run a CGI script, with the request body on stdin",
+        params: &[
+            Param { name: "script", location: In::Path, description: "Script name" },
+            Param { name: "rest", location: In::Path, description: "Path info passed to the script" },
+        ],
+        request_body: Some("application/octet-stream"),
+        responses: &[
+            (200, "Script output", None),
+            (404, "No such script", None),
+            (502, "Script failed", None),
+            (504, "Script timed out", None),
+        ],
+    };
+
+    let mut router = Router::default();
+    router
+        .get("/", |cx| Box::pin(root(cx)))
+        .describe(Operation {
+            summary: "Check that the server is up",
+            responses: &[(200, "Server is up", None)],
+            ..Default::default()
+        })
+        .get("/echo/*", |cx| Box::pin(echo(cx)))
+        .describe(Operation {
+            summary: "Echo the rest of the path back",
+            params: &[Param { name: "rest", location: In::Path, description: "Text to echo, may contain slashes" }],
+            responses: &[(200, "The echoed text", Some("text/plain"))],
+            ..Default::default()
+        })
+        .get("/user-agent", |cx| Box::pin(user_agent(cx)))
+        .describe(Operation {
+            summary: "Echo the User-Agent header back",
+            params: &[Param { name: "User-Agent", location: In::Header, description: "Client identification" }],
+            responses: &[(200, "The User-Agent header", Some("text/plain")), (404, "No User-Agent header was sent", None)],
+            ..Default::default()
+        })
+        .get("/favicon.ico", |cx| Box::pin(favicon(cx)))
+        .describe(Operation {
+            summary: "The site icon",
+            responses: &[(200, "The icon from --favicon", Some("image/x-icon")), (204, "No icon is configured", None)],
+            ..Default::default()
+        })
+        .get("/robots.txt", |cx| Box::pin(robots_txt(cx)))
+        .describe(Operation {
+            summary: "Crawler rules",
+            responses: &[(200, "The rules from --robots-txt, or allow everything", Some("text/plain"))],
+            ..Default::default()
+        })
+        .get("/health", |cx| Box::pin(health(cx)))
+        .describe(Operation {
+            summary: "Liveness check, answered even in maintenance mode",
+            responses: &[(200, "The server is up", Some("text/plain"))],
+            ..Default::default()
+        })
+        .get("/healthz", |cx| Box::pin(health(cx)))
+        .describe(Operation {
+            summary: "Liveness probe, the same as /health",
+            responses: &[(200, "The server is up", Some("text/plain"))],
+            ..Default::default()
+        })
+        .get("/readyz", |cx| Box::pin(ready(cx)))
+        .describe(Operation {
+            summary: "Readiness probe: whether the storage behind /files is there and readable",
+            responses: &[
+                (200, "The server can take requests", Some("text/plain")),
+                (503, "The storage can't be read, or the server is in maintenance mode", None),
+            ],
+            ..Default::default()
+        })
+        .get("/ip", |cx| Box::pin(client_ip(cx)))
+        .describe(Operation {
+            summary: "Show the client address, as reported by trusted proxies if any",
+            responses: &[(200, "The client IP address", Some("text/plain"))],
+            ..Default::default()
+        })
+        .get("/openapi.json", |cx| Box::pin(openapi_json(cx)))
+        .describe(Operation {
+            summary: "This document",
+            responses: &[(200, "OpenAPI 3 document", Some("application/json"))],
+            ..Default::default()
+        })
+        .get("/ws/echo", |cx| Box::pin(websocket_echo(cx)))
+        .describe(Operation {
+            summary: "Open a WebSocket that sends every message back",
+            params: &[Param { name: "Sec-WebSocket-Key", location: In::Header, description: "The client's handshake nonce" }],
+            responses: &[
+                (101, "Switched to the WebSocket protocol", None),
+                (400, "Malformed handshake", None),
+                (426, "Not a WebSocket handshake", None),
+            ],
+            ..Default::default()
+        })
+        .get("/events", |cx| Box::pin(events(cx)))
+        .describe(Operation {
+            summary: "Stream a tick event with the time every second, as server-sent events",
+            params: &[Param { name: "count", location: In::Query, description: "Ticks to send before ending the stream" }],
+            responses: &[(200, "Event stream", Some("text/event-stream")), (400, "count is not a number", None)],
+            ..Default::default()
+        });
+    if config.slow_log.is_some() {
+        router.get("/admin/slow-requests", |cx| Box::pin(slow_requests(cx))).describe(Operation {
+            summary: "The slowest requests over the --slow-request-ms threshold, with where their time went",
+            responses: &[(200, "The requests, slowest first", Some("application/json"))],
+            ..Default::default()
+        });
+    }
+    if config.metrics.is_some() {
+        router.get("/metrics", |cx| Box::pin(metrics(cx))).describe(Operation {
+            summary: "Request counts by route and status, open connections and bytes sent, for Prometheus to scrape",
+            responses: &[(200, "The metrics in Prometheus text format", Some("text/plain"))],
+            ..Default::default()
+        });
+    }
+    if config.swagger_ui {
+        router.get("/docs", |cx| Box::pin(docs(cx))).describe(Operation {
+            summary: "Swagger UI for this document",
+            responses: &[(200, "Swagger UI page", Some("text/html"))],
+            ..Default::default()
+        });
+    }
+    if config.storage.is_some() {
+        if config.content_addressed {
+            router.post("/files", |cx| Box::pin(upload_by_hash(cx))).describe(Operation {
+                summary: "Upload a file, named after its content",
+                request_body: Some("application/octet-stream"),
+                responses: &[
+                    (200, "The same content was stored before, its URL in Location", Some("text/plain")),
+                    (201, "File written under its SHA-256 in hex, returned in the body and Location", Some("text/plain")),
+                    (403, "The server may not write the file", None),
+                ],
+                ..Default::default()
+            });
+        }
+        if config.autoindex {
+            router.get("/files/", |cx| Box::pin(file_index(cx))).describe(Operation {
+                summary: "List the stored files with their sizes and modification dates",
+                params: &[Param { name: "Accept", location: In::Header, description: "application/json for a JSON listing" }],
+                responses: &[
+                    (200, "Listing page", Some("text/html")),
+                    (403, "The server may not read the storage", None),
+                ],
+                ..Default::default()
+            });
+        }
+        router
+            .get("/files/:name", |cx| Box::pin(download_file(cx)))
+            .describe(Operation {
+                summary: "Download a file",
+                params: &[
+                    NAME,
+                    Param { name: "raw", location: In::Query, description: "1 to get markdown source instead of rendered HTML" },
+                    Param { name: "If-None-Match", location: In::Header, description: "ETags of copies the client already has" },
+                    Param { name: "If-Modified-Since", location: In::Header, description: "Last-Modified of the client's copy, ignored with If-None-Match" },
+                ],
+                responses: &[
+                    (200, "File content, markdown rendered as HTML with --render-markdown", Some("application/octet-stream")),
+                    (304, "The client's copy is current", None),
+                    (400, "Not an acceptable file name", None),
+                    (403, "The server may not read the file", None),
+                    (404, "No such file", None),
+                ],
+                ..Default::default()
+            })
+            .post("/files/:name", |cx| Box::pin(upload_file(cx)))
+            .describe(Operation {
+                summary: "Upload a file",
+                params: &[NAME],
+                request_body: Some("application/octet-stream"),
+                responses: &[
+                    (201, "File written, its URL in Location and its SHA-256 in Repr-Digest", None),
+                    (400, "Not an acceptable file name, or the body doesn't match its Content-Digest", None),
+                    (403, "The server may not write the file", None),
+                    (412, "If-Match, If-Unmodified-Since or If-None-Match doesn't hold", None),
+                ],
+            })
+            .put("/files/:name", |cx| Box::pin(upload_file(cx)))
+            .describe(Operation {
+                summary: "Create or replace a file",
+                params: &[
+                    NAME,
+                    IF_MATCH,
+                    IF_UNMODIFIED_SINCE,
+                    Param { name: "If-None-Match", location: In::Header, description: "* to only create the file, never replace it" },
+                ],
+                request_body: Some("application/octet-stream"),
+                responses: &[
+                    (201, "File created", None),
+                    (204, "File replaced", None),
+                    (400, "Not an acceptable file name, or the body doesn't match its Content-Digest", None),
+                    (403, "The server may not write the file", None),
+                    (412, "If-Match, If-Unmodified-Since or If-None-Match doesn't hold", None),
+                ],
+            });
+        if config.allow_delete {
+            router.delete("/files/:name", |cx| Box::pin(delete_file(cx))).describe(Operation {
+                summary: "Delete a file",
+                params: &[NAME, IF_MATCH, IF_UNMODIFIED_SINCE],
+                responses: &[
+                    (204, "File deleted", None),
+                    (403, "The server may not delete the file", None),
+                    (404, "No such file", None),
+                    (412, "If-Match, If-Unmodified-Since or If-None-Match doesn't hold", None),
+                ],
+                ..Default::default()
+            });
+        }
+    }
+    if config.kv.is_some() {
+        const KEY: Param = Param { name: "key", location: In::Path, description: "Key, percent-encoded" };
+        router
+            .get("/kv/:key", |cx| Box::pin(kv_entry(cx)))
+            .describe(Operation {
+                summary: "Read a value from the key-value store",
+                params: &[KEY],
+                responses: &[(200, "The value", Some("application/octet-stream")), (404, "No such key, or it expired", None)],
+                ..Default::default()
+            })
+            .put("/kv/:key", |cx| Box::pin(kv_entry(cx)))
+            .describe(Operation {
+                summary: "Store a value in the key-value store",
+                params: &[KEY, Param { name: "Kv-Ttl", location: In::Header, description: "Seconds until the value expires" }],
+                request_body: Some("application/octet-stream"),
+                responses: &[
+                    (201, "Value stored under a new key", None),
+                    (204, "Value replaced", None),
+                    (400, "Empty key or malformed Kv-Ttl", None),
+                    (413, "The value is larger than the whole store", None),
+                    (507, "The store is full", None),
+                ],
+            })
+            .delete("/kv/:key", |cx| Box::pin(kv_entry(cx)))
+            .describe(Operation {
+                summary: "Remove a value from the key-value store",
+                params: &[KEY],
+                responses: &[(204, "Value removed", None), (404, "No such key", None)],
+                ..Default::default()
+            });
+    }
+    if config.sessions.is_some() {
+        const KEY: Param = Param { name: "key", location: In::Path, description: "Name of the value" };
+        router
+            .get("/session/:key", |cx| Box::pin(session_value(cx)))
+            .describe(Operation {
+                summary: "Read a value from the client's session",
+                params: &[KEY],
+                responses: &[(200, "The value", Some("text/plain")), (404, "The session holds no such value", None)],
+                ..Default::default()
+            })
+            .put("/session/:key", |cx| Box::pin(session_value(cx)))
+            .describe(Operation {
+                summary: "Store a value in the client's session, which sets the session cookie on first use",
+                params: &[KEY],
+                request_body: Some("text/plain"),
+                responses: &[(204, "Value stored", None)],
+            });
+    }
+    if config.cgi.is_some() {
+        router.any("/cgi-bin/:script/*", |cx| Box::pin(cgi_script(cx))).describe(CGI);
+    }
+    router
+}
+
+async fn root(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let request = cx.request;
+    let content = Content::Empty;
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content,
+        }
+    )
+}
+
+async fn echo(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let val = cx.rest().iter()
+        .map(|segment| percent::decode(segment))
+        .collect::<Option<Vec<_>>>()
+        .context("ERROR: undecodable echo path")?;
+    let request = cx.request;
+    let content = Content::Text(val.join("/")).encoded_for(request);
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::from([("Vary".to_string(), "Accept-Encoding".to_string())]),
+            content,
+        }
+    )
+}
+
+async fn user_agent(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let request = cx.request;
+    let user_agent = request.headers.get("User-Agent");
+    match user_agent {
+        Some(user_agent) => {
+            let user_agent = user_agent.to_string();
+            let content = Content::Text(user_agent);
+            Ok(
+                HttpResponseBuilder {
+                    status_code: HttpStatusCode::Ok200,
+                    version: request.version.clone(),
+                    headers: HeaderMap::new(),
+                    content,
+                }
+            )
+        }
+        None => {
+            Ok(
+                HttpResponseBuilder {
+                    status_code: HttpStatusCode::NotFound404,
+                    version: request.version.clone(),
+                    headers: HeaderMap::new(),
+                    content: Content::Empty,
+                }
+            )
+        }
+    }
+}
+
+async fn favicon(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, config, .. } = cx;
+    let Some(favicon) = &config.favicon else {
+        return Ok(HttpResponseBuilder::no_content(request.version.clone()));
+    };
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::from([
+                ("Content-Type".to_string(), "image/x-icon".to_string()),
+                ("Cache-Control".to_string(), "max-age=86400".to_string()),
+            ]),
+            content: Content::Bytes(favicon.clone()),
+        }
+    )
+}
+
+async fn robots_txt(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, config, .. } = cx;
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Text(config.robots_txt.clone()),
+        }
+    )
+}
+
+async fn health(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let request = cx.request;
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Text("ok".to_string()),
+        }
+    )
+}
+
+/// Whether the server can do its job, as opposed to just being up: the storage behind
+/// /files, if any, must be there and readable.
+async fn ready(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, config, .. } = cx;
+    let unavailable = match &config.storage {
+        Some(storage) => storage.list("").await.err(),
+        None => None,
+    };
+    let (status_code, text) = match unavailable {
+        None => (HttpStatusCode::Ok200, "ready".to_string()),
+        Some(err) => (HttpStatusCode::Other(503, "Service Unavailable".to_string()), format!("storage unavailable: {err}")),
+    };
+    Ok(
+        HttpResponseBuilder {
+            status_code,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Text(text),
+        }
+    )
+}
+
+async fn client_ip(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let request = cx.request;
+    let content = match request.client_ip() {
+        Some(ip) => Content::Text(ip.to_string()),
+        None => Content::Empty,
+    };
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content,
+        }
+    )
+}
+
+async fn slow_requests(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, config, .. } = cx;
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::from([("Content-Type".to_string(), "application/json".to_string())]),
+            content: Content::Bytes(config.slow_log.as_ref().unwrap().to_json().into_bytes()),
+        }
+    )
+}
+
+async fn metrics(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, config, .. } = cx;
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::from([("Content-Type".to_string(), "text/plain; version=0.0.4".to_string())]),
+            content: Content::Bytes(config.metrics.as_ref().unwrap().render().into_bytes()),
+        }
+    )
+}
+
+async fn openapi_json(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, router, .. } = cx;
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::from([("Content-Type".to_string(), "application/json".to_string())]),
+            content: Content::Bytes(openapi::document(router).into_bytes()),
+        }
+    )
+}
+
+async fn websocket_echo(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    Ok(websocket::handshake(cx.request, Box::new(|reader, writer| Box::pin(websocket::echo(reader, writer)))))
+}
+
+/// Server-sent events demo: a `tick` event with the time every second, `count` of them if
+/// the query gives one, for as long as the client listens otherwise.
+async fn events(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, config, query, .. } = cx;
+    let count = match query_param(query, "count").map(str::parse::<u64>) {
+        Some(Ok(count)) => Some(count),
+        Some(Err(_)) => {
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::BadRequest400,
+                version: request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Empty,
+            });
+        }
+        None => None,
+    };
+    let (mut sender, content) = sse::channel();
+    let clock = config.clock.clone();
+    tokio::spawn(async move {
+        for id in 1.. {
+            if count.is_some_and(|count| id > count) {
+                break;
+            }
+            if id > 1 {
+                clock.sleep(Duration::from_secs(1)).await;
+            }
+            // failing means the client is gone
+            if sender.send(Some("tick"), Some(&id.to_string()), &httpdate::rfc3339(clock.now())).await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(HttpResponseBuilder {
+        status_code: HttpStatusCode::Ok200,
+        version: request.version.clone(),
+        headers: sse::headers(),
+        content,
+    })
+}
+
+async fn docs(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let request = cx.request;
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Html(openapi::SWAGGER_UI_PAGE.to_string()),
+        }
+    )
+}
+
+const INDEX_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Files</title>
+</head>
+<body>
+<h1>Files</h1>
+<table>
+<tr><th>Name</th><th>Size</th><th>Modified</th></tr>
+{% for entry in entries %}<tr><td>{% if entry.directory %}{{ entry.name }}/{% else %}<a href=\"/files/{{ entry.href }}\">{{ entry.name }}</a>{% endif %}</td><td>{{ entry.size }}</td><td>{{ entry.modified }}</td></tr>
+{% endfor %}</table>
+</body>
+</html>
+";
+
+/// The listing of the storage root for --autoindex: an HTML page, or JSON for clients that
+/// ask for it.
+async fn file_index(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, config, .. } = cx;
+    let storage = config.storage.as_ref().context("ERROR: --autoindex without storage")?;
+    let failed = |err: std::io::Error| {
+        log_error!("couldn't list the stored files, error: {err}");
+        Ok(HttpResponseBuilder {
+            status_code: status_for_io_error(&err),
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        })
+    };
+    let names = match storage.list("").await {
+        Ok(names) => names,
+        Err(err) => return failed(err),
+    };
+    let mut entries = Vec::with_capacity(names.len());
+    for name in names.into_iter().filter(|name| !name.starts_with(STAGED_PREFIX)) {
+        match storage.metadata(&name).await {
+            Ok(metadata) => entries.push((name, metadata)),
+            // deleted since it was listed
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return failed(err),
+        }
+    }
+
+    let wants_json = request.header("Accept").is_some_and(|accept| accept.contains("application/json"));
+    if wants_json {
+        let entries = entries.into_iter()
+            .map(|(name, metadata)| {
+                let mut fields = vec![
+                    ("name".to_string(), Json::Str(name)),
+                    ("directory".to_string(), Json::Bool(metadata.is_dir)),
+                    ("size".to_string(), Json::Num(metadata.len)),
+                ];
+                if let Some(modified) = metadata.modified {
+                    fields.push(("modified".to_string(), Json::Str(httpdate::format(modified))));
+                }
+                Json::Obj(fields)
+            })
+            .collect();
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers: HeaderMap::from([("Content-Type".to_string(), "application/json".to_string())]),
+            content: Content::Bytes(Json::Arr(entries).to_string().into_bytes()),
+        });
+    }
+
+    let entries = entries.into_iter()
+        .map(|(name, metadata)| {
+            template::Value::Map(template::Context::from([
+                ("href".to_string(), percent::encode(&name).into()),
+                ("name".to_string(), name.into()),
+                ("directory".to_string(), metadata.is_dir.into()),
+                ("size".to_string(), if metadata.is_dir { String::new() } else { metadata.len.to_string() }.into()),
+                ("modified".to_string(), metadata.modified.map(httpdate::format).unwrap_or_default().into()),
+            ]))
+        })
+        .collect();
+    let context = template::Context::from([("entries".to_string(), template::Value::List(entries))]);
+    HttpResponseBuilder::render(HttpStatusCode::Ok200, request.version.clone(), INDEX_PAGE, &context)
+}
+
+async fn download_file(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let filename = cx.param("name");
+    let (path, query) = (cx.path, cx.query);
+    let Context { request, config, .. } = cx;
+    // `/files/` names no file; with autoindex it is the listing instead
+    let (Some(storage), false) = (&config.storage, filename.is_empty()) else {
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::NotFound404,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    };
+    let Some(filename) = percent::decode_file_name(filename) else {
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::BadRequest400,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    };
+    let filename = filename.as_str();
+
+    if let Some(signer) = &config.url_signer {
+        let verdict = signer.check(path, query, config.clock.now());
+        if verdict == signed_url::Verdict::Invalid || (verdict == signed_url::Verdict::Unsigned && signer.required) {
+            log_debug!("refusing {path}, link is {verdict:?}");
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Forbidden403,
+                version: request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Empty,
+            });
+        }
+    }
+
+    let metadata = match storage.metadata(filename).await {
+        Ok(metadata) if !metadata.is_dir => {
+            log_debug!("reading file {filename}, {} bytes, modified {:?}", metadata.len, metadata.modified);
+            metadata
+        }
+        Ok(_) => {
+            log_debug!("{filename} is a directory");
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::NotFound404,
+                version: request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Empty,
+            });
+        }
+        Err(err) => {
+            log_error!("{filename} is not a readable file, error: {err}");
+            return Ok(HttpResponseBuilder {
+                status_code: status_for_io_error(&err),
+                version: request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Empty,
+            });
+        }
+    };
+
+    let unreadable = |err: std::io::Error| {
+        log_error!("couldn't read file {filename}, error: {err}");
+        Ok(HttpResponseBuilder {
+            status_code: status_for_io_error(&err),
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        })
+    };
+
+    if config.render_markdown && filename.ends_with(".md") {
+        let file_content = match storage.read(filename).await {
+            Ok(file_content) => String::from_utf8_lossy(&file_content).into_owned(),
+            Err(err) => return unreadable(err),
+        };
+        if query_param(query, "raw") == Some("1") {
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Ok200,
+                version: request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Text(file_content),
+            });
+        }
+        let context = template::Context::from([
+            ("title".to_string(), filename.into()),
+            ("body".to_string(), markdown::to_html(&file_content).into()),
+        ]);
+        return HttpResponseBuilder::render(HttpStatusCode::Ok200, request.version.clone(), MARKDOWN_PAGE, &context);
+    }
+
+    let digest = match file_digest(config, &**storage, filename, &metadata).await {
+        Ok(digest) => digest,
+        Err(err) => return unreadable(err),
+    };
+    let last_modified = metadata.modified.map(httpdate::format);
+    let mut headers = HeaderMap::from([
+        ("Repr-Digest".to_string(), digest::header_value(&digest)),
+        ("Accept-Ranges".to_string(), "bytes".to_string()),
+    ]);
+    if let Some(last_modified) = &last_modified {
+        headers.append("Last-Modified", last_modified.clone());
+    }
+    // the name says what the content is, so it can never change
+    if config.content_addressed && digest::hex(&digest) == filename {
+        headers.append("Cache-Control", "public, max-age=31536000, immutable");
+    }
+
+    let tag = config.etags.tag(&metadata, || digest);
+    let gzip_tag = etag::variant(&tag, "gzip");
+    let varies = config.precompressed.is_some() || precompress::is_compressible(filename);
+    let not_modified = match request.header("If-None-Match") {
+        // the client's copy is current, whichever representation it has
+        Some(if_none_match) => etag::matches_weakly(if_none_match, &tag) || etag::matches_weakly(if_none_match, &gzip_tag),
+        // only consulted without If-None-Match, which is the more precise of the two
+        None => {
+            let since = request.header("If-Modified-Since").and_then(httpdate::parse);
+            match (since, metadata.modified) {
+                (Some(since), Some(modified)) => whole_seconds(modified) <= since,
+                _ => false,
+            }
+        }
+    };
+    if not_modified {
+        headers.append("ETag", tag);
+        if varies {
+            headers.append("Vary", "Accept-Encoding");
+        }
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::Other(304, "Not Modified".to_string()),
+            version: request.version.clone(),
+            headers,
+            content: Content::Empty,
+        });
+    }
+
+    // If-Range: a client resuming a download of an older version gets the whole new one
+    let range_applies = request.header("If-Range").is_none_or(|validator| {
+        (validator.starts_with('"') && etag::matches_strongly(validator, &tag)) || Some(validator) == last_modified.as_deref()
+    });
+    match request.header("Range").filter(|_| range_applies).and_then(|field| range::resolve(field, metadata.len)) {
+        Some(range::Ranges::Single(range)) => {
+            let content = match file_stream(&**storage, filename, *range.start(), range.end() - range.start() + 1).await {
+                Ok(content) => content,
+                Err(err) => return unreadable(err),
+            };
+            headers.append("ETag", tag);
+            headers.append("Content-Range", format!("bytes {}-{}/{}", range.start(), range.end(), metadata.len));
+            headers.append("Content-Type", mime::for_name(filename).to_string());
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Other(206, "Partial Content".to_string()),
+                version: request.version.clone(),
+                headers,
+                content,
+            });
+        }
+        Some(range::Ranges::Multiple(ranges)) => {
+            // a boundary must not occur in the parts, and a file is unlikely to hold its own hash
+            let boundary = format!("byteranges-{}", &digest::hex(&digest)[..32]);
+            let content = match multipart_stream(&**storage, filename, ranges, metadata.len, &boundary).await {
+                Ok(content) => content,
+                Err(err) => return unreadable(err),
+            };
+            headers.append("ETag", tag);
+            headers.append("Content-Type", format!("multipart/byteranges; boundary={boundary}"));
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Other(206, "Partial Content".to_string()),
+                version: request.version.clone(),
+                headers,
+                content,
+            });
+        }
+        Some(range::Ranges::Unsatisfiable) => {
+            headers.append("Content-Range", format!("bytes */{}", metadata.len));
+            return Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Other(416, "Range Not Satisfiable".to_string()),
+                version: request.version.clone(),
+                headers,
+                content: Content::Empty,
+            });
+        }
+        None => {}
+    }
+
+    let gzipped = match &config.precompressed {
+        Some(precompressed) if accepts_encoding(request, "gzip") => precompressed.gzipped(filename, metadata.modified).await,
+        _ => None,
+    };
+    let content = match gzipped {
+        Some(gzipped) => {
+            // a different representation, so it needs its own entity tag
+            headers.append("ETag", gzip_tag);
+            headers.append("Content-Type", mime::for_name(filename).to_string());
+            headers.append("Content-Encoding", "gzip");
+            Content::Bytes(gzipped)
+        }
+        // compressed here when there's no variant made ahead of time, if small enough to
+        // hold in memory
+        None if precompress::is_compressible(filename) && accepts_encoding(request, "gzip") && metadata.len <= GZIP_IN_MEMORY => {
+            let file_content = match storage.read(filename).await {
+                Ok(file_content) => file_content,
+                Err(err) => return unreadable(err),
+            };
+            headers.append("ETag", gzip_tag);
+            headers.append("Content-Type", mime::for_name(filename).to_string());
+            Content::Gzip(Box::new(Content::Bytes(file_content)))
+        }
+        None => {
+            let content = match file_stream(&**storage, filename, 0, metadata.len).await {
+                Ok(content) => content,
+                Err(err) => return unreadable(err),
+            };
+            headers.append("ETag", tag);
+            headers.append("Content-Type", mime::for_name(filename).to_string());
+            content
+        }
+    };
+    if varies {
+        headers.append("Vary", "Accept-Encoding");
+    }
+    Ok(
+        HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: request.version.clone(),
+            headers,
+            content,
+        }
+    )
+}
+
+async fn upload_by_hash(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let Context { request, config, .. } = cx;
+    let (content, digest) = match (&request.spooled, request.body.as_deref()) {
+        (Some(spooled), _) => (None, spooled.digest),
+        (None, Some(content)) => (Some(content), digest::sha256(content)),
+        (None, None) => return Ok(length_required(request)),
+    };
+    let Some(storage) = &config.storage else {
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::NotFound404,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    };
+    let filename = digest::hex(&digest);
+    let filename = filename.as_str();
+    let failed = |err: std::io::Error| {
+        log_error!("couldn't write file {filename}, error: {err}");
+        Ok(HttpResponseBuilder {
+            status_code: status_for_io_error(&err),
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        })
+    };
+
+    // identical uploads share one file, so one that is there already needn't be written
+    let staged = match storage.metadata(filename).await {
+        Ok(_) => None,
+        Err(_) => match stage_body(request, &**storage, content).await {
+            Ok(staged) => Some(staged),
+            Err(err) => return failed(err),
+        },
+    };
+    let change = config.file_changes.lock().await;
+    // checked again, someone may have stored the same content meanwhile
+    let existed = storage.metadata(filename).await.is_ok();
+    if let Some(staged) = &staged {
+        if existed {
+            discard_staged(&**storage, staged).await;
+        } else if let Err(err) = storage.rename(staged, filename).await {
+            discard_staged(&**storage, staged).await;
+            return failed(err);
+        }
+    }
+    if existed {
+        log_debug!("{filename} is already stored");
+    }
+    let mut headers = HeaderMap::from([
+        ("Location".to_string(), format!("/files/{filename}")),
+        ("Repr-Digest".to_string(), digest::header_value(&digest)),
+    ]);
+    if let Ok(metadata) = storage.metadata(filename).await {
+        config.digests.insert(filename, &metadata, digest);
+        headers.append("ETag", config.etags.tag(&metadata, || digest));
+    }
+    drop(change);
+    if !existed {
+        refresh_precompressed(config, filename);
+    }
+    Ok(
+        HttpResponseBuilder {
+            status_code: if existed { HttpStatusCode::Ok200 } else { HttpStatusCode::Created201 },
+            version: request.version.clone(),
+            headers,
+            content: Content::Text(filename.to_string()),
+        }
+    )
+}
+
+async fn upload_file(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let filename = cx.param("name");
+    let Context { request, config, .. } = cx;
+    let (content, digest) = match (&request.spooled, request.body.as_deref()) {
+        (Some(spooled), _) => (None, spooled.digest),
+        (None, Some(content)) => (Some(content), digest::sha256(content)),
+        (None, None) => return Ok(length_required(request)),
+    };
+    let Some(storage) = &config.storage else {
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::NotFound404,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    };
+    let Some(filename) = percent::decode_file_name(filename) else {
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::BadRequest400,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    };
+    let filename = filename.as_str();
+
+    let claimed = request.header("Content-Digest").or(request.header("Repr-Digest"));
+    if claimed.is_some_and(|claimed| !digest::verify(claimed, &digest)) {
+        log_debug!("digest of {filename} doesn't match {claimed:?}");
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::BadRequest400,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    }
+    // checked before the body is written too, so a doomed upload fails early
+    if let Some(failed) = failed_precondition(request, config, &**storage, filename).await {
+        return Ok(failed);
+    }
+    let staged = match stage_body(request, &**storage, content).await {
+        Ok(staged) => staged,
+        Err(err) => {
+            log_error!("couldn't write file {filename}, error: {err}");
+            return Ok(HttpResponseBuilder {
+                status_code: status_for_io_error(&err),
+                version: request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Empty,
+            });
+        }
+    };
+
+    let change = config.file_changes.lock().await;
+    if let Some(failed) = failed_precondition(request, config, &**storage, filename).await {
+        discard_staged(&**storage, &staged).await;
+        return Ok(failed);
+    }
+    let existed = storage.metadata(filename).await.is_ok();
+    if let Err(err) = storage.rename(&staged, filename).await {
+        log_error!("couldn't write file {filename}, error: {err}");
+        discard_staged(&**storage, &staged).await;
+        return Ok(HttpResponseBuilder {
+            status_code: status_for_io_error(&err),
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    }
+    let metadata = storage.metadata(filename).await.ok();
+    if let Some(metadata) = &metadata {
+        config.digests.insert(filename, metadata, digest);
+    }
+    drop(change);
+    refresh_precompressed(config, filename);
+    // POST always answers 201, PUT only when it created the file (RFC 9110 section 9.3.4)
+    let status_code = match request.method {
+        HttpMethod::Put if existed => HttpStatusCode::NoContent204,
+        _ => HttpStatusCode::Created201,
+    };
+    let mut headers = HeaderMap::from([
+        ("Location".to_string(), format!("/files/{}", percent::encode(filename))),
+        ("Repr-Digest".to_string(), digest::header_value(&digest)),
+    ]);
+    if let Some(metadata) = &metadata {
+        headers.append("ETag", config.etags.tag(metadata, || digest));
+    }
+    Ok(
+        HttpResponseBuilder {
+            status_code,
+            version: request.version.clone(),
+            headers,
+            content: Content::Empty,
+        }
+    )
+}
+
+async fn delete_file(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let filename = cx.param("name");
+    let Context { request, config, .. } = cx;
+    let Some(storage) = &config.storage else {
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::NotFound404,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    };
+    let Some(filename) = percent::decode_file_name(filename) else {
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::BadRequest400,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        });
+    };
+    let filename = filename.as_str();
+
+    let _change = config.file_changes.lock().await;
+    let result = match preconditions_hold(request, config, &**storage, filename).await {
+        Ok(true) => storage.delete(filename).await.map(|()| HttpStatusCode::NoContent204),
+        Ok(false) => Ok(HttpStatusCode::Other(412, "Precondition Failed".to_string())),
+        Err(err) => Err(err),
+    };
+    let status_code = result.unwrap_or_else(|err| {
+        log_error!("couldn't delete file {filename}, error: {err}");
+        status_for_io_error(&err)
+    });
+    refresh_precompressed(config, filename);
+    Ok(
+        HttpResponseBuilder {
+            status_code,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        }
+    )
+}
+
+async fn kv_entry(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let key = cx.param("key");
+    let Context { request, config, .. } = cx;
+    let store = config.kv.as_ref().unwrap();
+    let now = config.clock.now();
+    let response = |status_code, content| HttpResponseBuilder {
+        status_code,
+        version: request.version.clone(),
+        headers: HeaderMap::new(),
+        content,
+    };
+    let Some(key) = percent::decode(key).filter(|key| !key.is_empty()) else {
+        return Ok(response(HttpStatusCode::BadRequest400, Content::Empty));
+    };
+    Ok(match request.method {
+        HttpMethod::Put => {
+            let ttl = match request.header("Kv-Ttl").map(|ttl| ttl.trim().parse()) {
+                None => None,
+                Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                Some(Err(_)) => return Ok(response(HttpStatusCode::BadRequest400, Content::Empty)),
+            };
+            let value = request.body.clone().unwrap_or_default();
+            match store.put(&key, value, ttl, now) {
+                Ok(true) => response(HttpStatusCode::NoContent204, Content::Empty),
+                Ok(false) => response(HttpStatusCode::Created201, Content::Empty),
+                Err(kv::PutError::TooLarge) => response(HttpStatusCode::Other(413, "Content Too Large".to_string()), Content::Empty),
+                Err(kv::PutError::Full) => response(HttpStatusCode::Other(507, "Insufficient Storage".to_string()), Content::Empty),
+            }
+        }
+        HttpMethod::Delete if store.delete(&key, now) => response(HttpStatusCode::NoContent204, Content::Empty),
+        HttpMethod::Delete => response(HttpStatusCode::NotFound404, Content::Empty),
+        _ => match store.get(&key, now) {
+            Some(value) => response(HttpStatusCode::Ok200, Content::OctetStream(value)),
+            None => response(HttpStatusCode::NotFound404, Content::Empty),
+        },
+    })
+}
+
+async fn session_value(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let key = cx.param("key");
+    let request = cx.request;
+    let session = request.session.as_ref().unwrap();
+    let status_code = match &request.method {
+        HttpMethod::Put => match std::str::from_utf8(request.body.as_deref().unwrap_or_default()) {
+            Ok(value) => {
+                session.set(key, value.to_string());
+                HttpStatusCode::NoContent204
+            }
+            // session values are kept as text
+            Err(_) => HttpStatusCode::BadRequest400,
+        },
+        _ => match session.get(key) {
+            Some(value) => {
+                return Ok(HttpResponseBuilder {
+                    status_code: HttpStatusCode::Ok200,
+                    version: request.version.clone(),
+                    headers: HeaderMap::new(),
+                    content: Content::Text(value),
+                });
+            }
+            None => HttpStatusCode::NotFound404,
+        },
+    };
+    Ok(HttpResponseBuilder {
+        status_code,
+        version: request.version.clone(),
+        headers: HeaderMap::new(),
+        content: Content::Empty,
+    })
+}
+
+async fn cgi_script(cx: Context<'_>) -> anyhow::Result<HttpResponseBuilder> {
+    let (script, path_info) = (cx.param("script"), cx.rest());
+    let Context { request, config, .. } = cx;
+    let cgi_config = config.cgi.as_ref().unwrap();
+    Ok(cgi::handle(request, cgi_config, &*config.clock, script, path_info).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::request::parse_http_request;
+
+    #[tokio::test]
+    async fn handlers_get_what_their_pattern_captured() {
+        let mut router = Router::default();
+        router.get("/greet/:name/*", |cx| Box::pin(async move {
+            let greeting = format!("hello {} from {}", cx.param("name"), cx.rest().join("/"));
+            Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Ok200,
+                version: cx.request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Text(greeting),
+            })
+        }));
+        let config = ServerConfig::default();
+        let dispatch = |raw: &str| {
+            let (_, request) = parse_http_request(raw).unwrap();
+            let (router, config) = (&router, &config);
+            async move { router.dispatch(&request, config).await.map(|response| response.unwrap().into_parts().3) }
+        };
+
+        assert_eq!(dispatch("GET /greet/ada/a/b?x=1 HTTP/1.1\r\n").await, Some(Some(b"hello ada from a/b".to_vec())));
+        assert_eq!(dispatch("GET /greet/ada HTTP/1.1\r\n").await, Some(Some(b"hello ada from ".to_vec())));
+        assert_eq!(dispatch("GET /greet HTTP/1.1\r\n").await, None);
+        assert_eq!(dispatch("PUT /greet/ada HTTP/1.1\r\n").await, None);
+    }
+
+    #[test]
+    fn allowed_methods_follow_the_routes() {
+        let config = ServerConfig { storage: Some(Arc::new(storage::MemoryStorage::default())), ..Default::default() };
+        assert_eq!(routes(&config).allowed_methods("/files/notes.txt"), ["GET", "HEAD", "POST", "PUT"]);
+        let deletes = ServerConfig { allow_delete: true, ..config.clone() };
+        assert_eq!(routes(&deletes).allowed_methods("/files/notes.txt"), ["GET", "HEAD", "POST", "PUT", "DELETE"]);
+        assert_eq!(routes(&config).allowed_methods("/echo/a/b"), ["GET", "HEAD"]);
+        assert_eq!(routes(&config).allowed_methods("/"), ["GET", "HEAD"]);
+        assert!(routes(&config).allowed_methods("/nowhere").is_empty());
+        assert!(routes(&config).allowed_methods("/kv/x").is_empty());
+        assert!(routes(&ServerConfig::default()).allowed_methods("/files/notes.txt").is_empty());
+
+        let mut router = Router::default();
+        router.get("/a/:id", |_| unreachable!()).put("/a/:id", |_| unreachable!()).any("/b/*", |_| unreachable!());
+        assert_eq!(router.allowed_methods("/a/1"), ["GET", "HEAD", "PUT"]);
+        assert_eq!(router.allowed_methods("/b/c/d"), ["GET", "HEAD", "POST", "PUT", "DELETE"]);
+
+        let mut router = Router::default();
+        router.put("/a/:id", |_| unreachable!()).delete("/a/:id", |_| unreachable!());
+        assert_eq!(router.methods(), ["PUT", "DELETE"]);
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+
+use crate::log::log_error;
+use crate::{glob, Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+/// Operations a script may run per request before it is stopped.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// A rhai script handling the request paths matching `pattern`.
+///
+/// The script defines `fn handle(request)`, where `request` is a map of `method`, `path`,
+/// `query`, `version`, `headers` (lowercased names) and `body`. It returns either a string,
+/// served as 200 text/plain, or a map of `status`, `reason`, `headers` and `body`. Besides
+/// the language itself a script can only reach `read_file(name)`, `write_file(name, content)`
+/// and `file_exists(name)`, which are confined to the script root.
+#[derive(Clone)]
+pub struct Script {
+    pattern: String,
+    path: PathBuf,
+    engine: Arc<Engine>,
+    ast: Arc<AST>,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Script")
+            .field("pattern", &self.pattern)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Script {
+    pub fn load(pattern: &str, path: &Path, root: Option<&Path>) -> anyhow::Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("ERROR: reading script {}", path.display()))?;
+        Self::compile(pattern, path, &source, root)
+    }
+
+    fn compile(pattern: &str, path: &Path, source: &str, root: Option<&Path>) -> anyhow::Result<Self> {
+        let engine = engine(root.map(Path::to_path_buf));
+        let ast = engine.compile(source)
+            .map_err(|err| anyhow!("ERROR: compiling script {}: {err}", path.display()))?;
+        Ok(Script { pattern: pattern.to_string(), path: path.to_path_buf(), engine: Arc::new(engine), ast: Arc::new(ast) })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        glob::matches(&self.pattern, path)
+    }
+
+    /// Runs the script on a blocking thread; script errors are reported to the client as 500.
+    pub async fn handle(&self, request: &HttpRequest) -> HttpResponseBuilder {
+        let script = self.clone();
+        let input = request_map(request);
+        let response = tokio::task::spawn_blocking(move || script.run(input)).await
+            .context("script panicked")
+            .and_then(|response| response);
+
+        match response {
+            Ok(response) => HttpResponseBuilder { version: request.version.clone(), ..response },
+            Err(err) => {
+                log_error!("script {} failed, error: {err:#}", self.path.display());
+                HttpResponseBuilder {
+                    status_code: HttpStatusCode::InternalError500,
+                    version: request.version.clone(),
+                    headers: HeaderMap::new(),
+                    content: Content::Empty,
+                }
+            }
+        }
+    }
+
+    fn run(&self, request: Map) -> anyhow::Result<HttpResponseBuilder> {
+        let result = self.engine.call_fn::<Dynamic>(&mut Scope::new(), &self.ast, "handle", (request,))
+            .map_err(|err| anyhow!("{err}"))?;
+        into_response(result)
+    }
+}
+
+fn engine(root: Option<PathBuf>) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    let read_root = root.clone();
+    engine.register_fn("read_file", move |name: &str| -> Result<String, Box<EvalAltResult>> {
+        let path = confine(read_root.as_deref(), name)?;
+        std::fs::read_to_string(&path).map_err(|err| format!("read_file({name}): {err}").into())
+    });
+    let write_root = root.clone();
+    engine.register_fn("write_file", move |name: &str, content: &str| -> Result<(), Box<EvalAltResult>> {
+        let path = confine(write_root.as_deref(), name)?;
+        std::fs::write(&path, content).map_err(|err| format!("write_file({name}): {err}").into())
+    });
+    engine.register_fn("file_exists", move |name: &str| -> Result<bool, Box<EvalAltResult>> {
+        Ok(confine(root.as_deref(), name)?.is_file())
+    });
+    engine
+}
+
+/// Resolves `name` inside `root`, refusing anything that could climb out of it.
+fn confine(root: Option<&Path>, name: &str) -> Result<PathBuf, Box<EvalAltResult>> {
+    let root = root.ok_or("scripts have no file access without --script-root or --directory")?;
+    let relative = Path::new(name);
+    if name.is_empty() || !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(format!("{name:?} is not a plain relative file name").into());
+    }
+    Ok(root.join(relative))
+}
+
+fn request_map(request: &HttpRequest) -> Map {
+    let (path, query) = request.route.split_once('?').unwrap_or((&request.route, ""));
+    let headers: Map = request.headers.iter()
+        .map(|(name, value)| (name.to_lowercase().into(), value.clone().into()))
+        .collect();
+
+    let mut map = Map::new();
+    map.insert("method".into(), request.method.as_str().into());
+    map.insert("path".into(), path.into());
+    map.insert("query".into(), query.into());
+    map.insert("version".into(), request.version.clone().into());
+    map.insert("headers".into(), headers.into());
+    // scripts see the body as text
+    map.insert("body".into(), String::from_utf8_lossy(request.body.as_deref().unwrap_or_default()).into_owned().into());
+    map
+}
+
+fn into_response(result: Dynamic) -> anyhow::Result<HttpResponseBuilder> {
+    if result.is_string() {
+        return Ok(HttpResponseBuilder {
+            status_code: HttpStatusCode::Ok200,
+            version: String::new(),
+            headers: HeaderMap::new(),
+            content: Content::Text(result.into_string().unwrap_or_default()),
+        });
+    }
+
+    let type_name = result.type_name();
+    let Some(mut map) = result.try_cast::<Map>() else {
+        bail!("handle returned a {type_name}, expected a string or a map");
+    };
+
+    let status = match map.remove("status") {
+        None => 200,
+        Some(status) => status.as_int().ok()
+            .and_then(|status| u16::try_from(status).ok())
+            .filter(|status| (100..1000).contains(status))
+            .context("status is not a valid status code")?,
+    };
+    let reason = map.remove("reason").map(|reason| reason.to_string()).unwrap_or_default();
+    let headers = match map.remove("headers") {
+        None => HeaderMap::new(),
+        Some(headers) => headers.try_cast::<Map>().context("headers is not a map")?
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    };
+    let content = match map.remove("body") {
+        None => Content::Empty,
+        Some(body) => Content::Bytes(body.to_string().into_bytes()),
+    };
+
+    Ok(HttpResponseBuilder {
+        status_code: HttpStatusCode::Other(status, reason),
+        version: String::new(),
+        headers,
+        content,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_client::{temp_dir, TestClient};
+    use crate::ServerConfig;
+
+    const SCRIPT: &str = r#"
+        fn handle(request) {
+            if request.path == "/hello" {
+                return "hello " + request.headers["user-agent"];
+            }
+            if request.method == "POST" {
+                write_file("note.txt", request.body);
+                return #{ status: 201 };
+            }
+            #{ status: 200, headers: #{ "Content-Type": "text/plain" }, body: read_file("note.txt") }
+        }
+    "#;
+
+    fn client(source: &str, root: Option<&Path>) -> TestClient {
+        let script = Script::compile("/*", Path::new("test.rhai"), source, root).unwrap();
+        TestClient::new(ServerConfig { scripts: vec![script], ..Default::default() })
+    }
+
+    #[tokio::test]
+    async fn script_sees_the_request_and_confined_files() {
+        let root = temp_dir("scripting");
+        let client = client(SCRIPT, Some(&root));
+
+        let response = client.get("/hello").header("User-Agent", "rhai/1").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "hello rhai/1");
+
+        assert_eq!(client.post("/note").body("remember me").send().await.status, 201);
+        let response = client.get("/note").send().await;
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.text(), "remember me");
+    }
+
+    #[tokio::test]
+    async fn scripts_cannot_leave_their_root_or_run_forever() {
+        let root = temp_dir("scripting-confined");
+        let escape = client(r#"fn handle(request) { read_file("../etc/passwd") }"#, Some(&root));
+        assert_eq!(escape.get("/").send().await.status, 500);
+
+        let spin = client("fn handle(request) { loop {} }", Some(&root));
+        assert_eq!(spin.get("/").send().await.status, 500);
+    }
+}
@@ -0,0 +1,177 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, ensure, Context};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::storage::{BoxFuture, LocalStorage};
+use crate::server::serve;
+use crate::{ServerConfig, Service};
+
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn expect(&self, status: u16, body: Option<&[u8]>) -> anyhow::Result<()> {
+        ensure!(self.status == status, "expected status {status}, got {}", self.status);
+        if let Some(body) = body {
+            ensure!(
+                self.body == body,
+                "expected body {:?}, got {:?}", String::from_utf8_lossy(body), String::from_utf8_lossy(&self.body)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Serves `config` on an ephemeral port, runs every check against it over real sockets and
+/// fails if any check does. /files is pointed at a scratch directory for the duration.
+pub async fn run(config: ServerConfig, trace_wire: Option<usize>) -> anyhow::Result<()> {
+    let scratch = std::env::temp_dir().join(format!("http-server-self-test-{}", std::process::id()));
+    tokio::fs::create_dir_all(&scratch).await
+        .with_context(|| format!("ERROR: creating scratch directory {}", scratch.display()))?;
+
+    let service = Service::new(ServerConfig {
+        storage: Some(Arc::new(LocalStorage::new(&scratch))),
+        ..config
+    });
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let server = tokio::spawn(serve(listener, service, trace_wire));
+    println!("self-test: server listening on {addr}");
+
+    let checks: Vec<(&str, BoxFuture<'static, anyhow::Result<()>>)> = vec![
+        ("root", Box::pin(check_root(addr))),
+        ("echo", Box::pin(check_echo(addr))),
+        ("user-agent", Box::pin(check_user_agent(addr))),
+        ("files round trip", Box::pin(check_files(addr))),
+        ("missing file", Box::pin(check_missing_file(addr))),
+        ("unknown route", Box::pin(check_unknown_route(addr))),
+        ("date header", Box::pin(check_date(addr))),
+        ("survives malformed requests", Box::pin(check_malformed(addr))),
+    ];
+
+    let mut failures = 0;
+    for (name, check) in checks {
+        match check.await {
+            Ok(()) => println!("PASS {name}"),
+            Err(err) => {
+                println!("FAIL {name}: {err:#}");
+                failures += 1;
+            }
+        }
+    }
+
+    server.abort();
+    let _ = tokio::fs::remove_dir_all(&scratch).await;
+
+    if failures > 0 {
+        bail!("{failures} self-test checks failed");
+    }
+    println!("self-test: all checks passed");
+    Ok(())
+}
+
+async fn exchange(addr: SocketAddr, raw: &[u8]) -> anyhow::Result<Response> {
+    parse(&raw_exchange(addr, raw).await?)
+}
+
+/// Sends `raw` on a new connection, done sending once it's written, and reads until the
+/// server closes it.
+async fn raw_exchange(addr: SocketAddr, raw: &[u8]) -> anyhow::Result<Vec<u8>> {
+    tokio::time::timeout(EXCHANGE_TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(raw).await?;
+        stream.shutdown().await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        anyhow::Ok(response)
+    }).await.context("connection not closed in time")?
+}
+
+fn parse(raw: &[u8]) -> anyhow::Result<Response> {
+    let head_end = raw.windows(4).position(|w| w == b"\r\n\r\n")
+        .with_context(|| format!("response has no end of headers: {:?}", String::from_utf8_lossy(raw)))?;
+    let head = std::str::from_utf8(&raw[..head_end]).context("response head is not utf8")?;
+    let mut lines = head.split("\r\n");
+
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line.split(' ').nth(1)
+        .and_then(|code| code.parse().ok())
+        .with_context(|| format!("malformed status line {status_line:?}"))?;
+    let headers = lines
+        .map(|line| {
+            let (name, value) = line.split_once(':').with_context(|| format!("malformed header {line:?}"))?;
+            Ok((name.to_string(), value.trim().to_string()))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(Response { status, headers, body: raw[head_end + 4..].to_vec() })
+}
+
+async fn check_root(addr: SocketAddr) -> anyhow::Result<()> {
+    exchange(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?.expect(200, None)
+}
+
+async fn check_echo(addr: SocketAddr) -> anyhow::Result<()> {
+    let response = exchange(addr, b"GET /echo/abc HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+    response.expect(200, Some(b"abc"))?;
+    ensure!(response.header("Content-Type") == Some("text/plain"), "expected a text/plain Content-Type");
+    ensure!(response.header("Content-Length") == Some("3"), "expected Content-Length: 3");
+    Ok(())
+}
+
+async fn check_user_agent(addr: SocketAddr) -> anyhow::Result<()> {
+    let response = exchange(addr, b"GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: self-test/1.0\r\n\r\n").await?;
+    response.expect(200, Some(b"self-test/1.0"))
+}
+
+async fn check_files(addr: SocketAddr) -> anyhow::Result<()> {
+    let upload = b"POST /files/self-test.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 12\r\n\r\nhello, world";
+    exchange(addr, upload).await?.expect(201, None).context("upload")?;
+
+    let response = exchange(addr, b"GET /files/self-test.txt HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+    response.expect(200, Some(b"hello, world")).context("download")?;
+    ensure!(
+        response.header("Content-Type") == Some("application/octet-stream"),
+        "expected an application/octet-stream Content-Type"
+    );
+    Ok(())
+}
+
+async fn check_missing_file(addr: SocketAddr) -> anyhow::Result<()> {
+    exchange(addr, b"GET /files/does-not-exist HTTP/1.1\r\nHost: localhost\r\n\r\n").await?.expect(404, None)
+}
+
+async fn check_unknown_route(addr: SocketAddr) -> anyhow::Result<()> {
+    exchange(addr, b"GET /no/such/route HTTP/1.1\r\nHost: localhost\r\n\r\n").await?.expect(404, None)
+}
+
+async fn check_date(addr: SocketAddr) -> anyhow::Result<()> {
+    let response = exchange(addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await?;
+    let date = response.header("Date").context("no Date header")?;
+    ensure!(date.ends_with(" GMT") && date.len() == 29, "Date {date:?} is not an IMF-fixdate");
+    Ok(())
+}
+
+async fn check_malformed(addr: SocketAddr) -> anyhow::Result<()> {
+    for garbage in [&b"\x00\x01\x02\r\n\r\n"[..], b"GET\r\n\r\n", b"GET / HTTP/1.1\r\nno colon here\r\n\r\n"] {
+        // whatever the answer, the connection has to end rather than hang
+        raw_exchange(addr, garbage).await
+            .with_context(|| format!("sending {:?}", String::from_utf8_lossy(garbage)))?;
+    }
+    check_root(addr).await.context("server stopped answering after malformed requests")
+}
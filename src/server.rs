@@ -0,0 +1,1025 @@
+use std::borrow::Cow;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use tracing::Instrument;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use crate::log::{log_debug, log_error, log_info};
+use crate::middleware::{Chain, Next};
+use crate::request::{is_field_value, is_token, method_override, read_request_body, read_request_head, reader_request};
+use crate::request::{rejection, wants_close, ParserConfig, RejectedRequest};
+use crate::response::with_error_page;
+use crate::router::{self, Router, DEFAULT_ROBOTS_TXT};
+use crate::{basic_auth, cgi, client_limit, clock, digest, etag, external, fastcgi, forwarded, glob, httpdate, kv, log, maintenance, metrics};
+use crate::{precompress, record, session, signed_url, slowlog, statsd, storage, validate, wire};
+use crate::{Content, HeaderMap, HttpMethod, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+#[cfg(feature = "http")]
+use crate::http_compat;
+#[cfg(feature = "scripting")]
+use crate::scripting;
+#[cfg(feature = "tower")]
+use crate::tower_compat;
+#[cfg(feature = "wasm")]
+use crate::wasm;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub clock: Arc<dyn clock::Clock>,
+    pub parser: ParserConfig,
+    /// How long a client may take to accept a response before its connection is dropped.
+    pub write_timeout: Duration,
+    /// How long a connection may sit idle between requests before it is closed.
+    pub keep_alive_timeout: Duration,
+    /// How long any handler may run; the first matching route timeout takes precedence.
+    pub handler_timeout: Option<Duration>,
+    pub route_timeouts: Vec<RouteTimeout>,
+    pub storage: Option<Arc<dyn storage::Storage>>,
+    /// Gzipped variants of the files under /files, for clients that accept them.
+    pub precompressed: Option<precompress::Precompressed>,
+    /// SHA-256 of the files under /files, sent as `Repr-Digest`.
+    pub digests: Arc<digest::DigestCache>,
+    /// Per-client state for handlers, when enabled.
+    pub sessions: Option<session::Sessions>,
+    /// Backs /kv, when enabled.
+    pub kv: Option<Arc<kv::KvStore>>,
+    /// Credentials /files needs, when set.
+    pub basic_auth: Option<basic_auth::BasicAuth>,
+    /// Checks signed download links to /files.
+    pub url_signer: Option<signed_url::UrlSigner>,
+    /// How the `ETag` of files under /files is made.
+    pub etags: etag::Strategy,
+    /// Whether `POST /files` stores uploads under their SHA-256, in hex, and files named so are
+    /// served as immutable.
+    pub content_addressed: bool,
+    /// Whether `DELETE /files` is answered; read-only deployments leave it off.
+    pub allow_delete: bool,
+    /// Whether `GET /files/` lists the stored files.
+    pub autoindex: bool,
+    /// Held while a /files change is checked and made, so its preconditions still hold when
+    /// it happens. Uploads are written beforehand and only renamed into place under it.
+    pub file_changes: Arc<tokio::sync::Mutex<()>>,
+    pub cgi: Option<cgi::CgiConfig>,
+    pub fastcgi: Option<Arc<fastcgi::FastCgiClient>>,
+    pub external: Vec<Arc<external::ExternalHandler>>,
+    pub early_hints: Vec<EarlyHint>,
+    pub header_rules: Vec<HeaderRule>,
+    /// Checks on request bodies, the first matching the path applies.
+    pub body_validators: Vec<validate::BodyValidator>,
+    /// Methods a POST may ask to be handled as; none unless enabled.
+    pub method_overrides: Vec<HttpMethod>,
+    /// Toggled with SIGUSR1 while the server runs.
+    pub maintenance: Arc<maintenance::Maintenance>,
+    /// Peers whose `Forwarded` and `X-Forwarded-*` headers are believed.
+    pub trusted_proxies: Vec<forwarded::Cidr>,
+    /// Most connections served at once; more wait in the listen backlog until one closes.
+    pub max_connections: Option<usize>,
+    /// Most responses one client IP may have in flight at once.
+    pub client_limit: Option<Arc<client_limit::ClientLimit>>,
+    /// Served at /favicon.ico; without one browsers get a 204 rather than a logged 404.
+    pub favicon: Option<Vec<u8>>,
+    pub robots_txt: String,
+    pub render_markdown: bool,
+    pub swagger_ui: bool,
+    pub statsd: Option<Arc<statsd::StatsdClient>>,
+    /// Counts requests for `/metrics`, when enabled.
+    pub metrics: Option<Arc<metrics::Metrics>>,
+    /// Logs slow requests and keeps the slowest for `/admin/slow-requests`.
+    pub slow_log: Option<Arc<slowlog::SlowLog>>,
+    pub record: Option<record::RecordConfig>,
+    #[cfg(feature = "http")]
+    pub mounts: Vec<http_compat::Mount>,
+    #[cfg(feature = "wasm")]
+    pub plugins: Vec<wasm::Plugin>,
+    #[cfg(feature = "scripting")]
+    pub scripts: Vec<scripting::Script>,
+    #[cfg(feature = "tower")]
+    pub tower_layers: tower_compat::TowerLayers,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            clock: Arc::new(clock::SystemClock),
+            parser: ParserConfig::default(),
+            write_timeout: Duration::from_secs(30),
+            keep_alive_timeout: Duration::from_secs(5),
+            handler_timeout: None,
+            route_timeouts: Vec::new(),
+            storage: None,
+            precompressed: None,
+            digests: Arc::default(),
+            sessions: None,
+            kv: None,
+            basic_auth: None,
+            url_signer: None,
+            etags: etag::Strategy::default(),
+            content_addressed: false,
+            allow_delete: false,
+            autoindex: false,
+            file_changes: Arc::default(),
+            cgi: None,
+            fastcgi: None,
+            external: Vec::new(),
+            early_hints: Vec::new(),
+            header_rules: Vec::new(),
+            body_validators: Vec::new(),
+            method_overrides: Vec::new(),
+            maintenance: Arc::default(),
+            trusted_proxies: Vec::new(),
+            max_connections: None,
+            client_limit: None,
+            favicon: None,
+            robots_txt: DEFAULT_ROBOTS_TXT.to_string(),
+            render_markdown: false,
+            swagger_ui: false,
+            statsd: None,
+            metrics: None,
+            slow_log: None,
+            record: None,
+            #[cfg(feature = "http")]
+            mounts: Vec::new(),
+            #[cfg(feature = "wasm")]
+            plugins: Vec::new(),
+            #[cfg(feature = "scripting")]
+            scripts: Vec::new(),
+            #[cfg(feature = "tower")]
+            tower_layers: tower_compat::TowerLayers::default(),
+        }
+    }
+}
+
+/// A `Link` header announced in a 103 Early Hints response to requests for paths matching
+/// the glob `pattern`, so clients can start fetching e.g. stylesheets while the final
+/// response is still being produced.
+#[derive(Debug, Clone)]
+pub struct EarlyHint {
+    pub pattern: String,
+    pub link: String,
+}
+
+/// The 103 interim response for `request`, if any hints match its path. HTTP/1.0 clients
+/// don't expect interim responses, so they never get one.
+fn early_hints(request: &HttpRequest, hints: &[EarlyHint]) -> Option<HttpResponseBuilder> {
+    if request.version == "HTTP/1.0" {
+        return None;
+    }
+    let path = request.route.split_once('?').map_or(request.route.as_str(), |(path, _)| path);
+    let headers = hints.iter()
+        .filter(|hint| glob::matches(&hint.pattern, path))
+        .map(|hint| ("Link".to_string(), hint.link.clone()))
+        .collect::<HeaderMap>();
+    if headers.is_empty() {
+        return None;
+    }
+    Some(HttpResponseBuilder {
+        status_code: HttpStatusCode::Other(103, "Early Hints".to_string()),
+        version: request.version.clone(),
+        headers,
+        content: Content::Empty,
+    })
+}
+
+/// A header set on every response to a request path matching the glob `pattern`, replacing
+/// whatever value the handler gave it, e.g. CORS headers for `/assets/*`.
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub pattern: String,
+    pub name: String,
+    pub value: String,
+}
+
+impl HeaderRule {
+    /// Parses `PATTERN=Name: value`.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let (pattern, header) = spec.split_once('=').context("expected PATTERN=Name: value")?;
+        let (name, value) = header.split_once(':').context("expected PATTERN=Name: value")?;
+        let value = value.trim_matches([' ', '\t']);
+        anyhow::ensure!(is_token(name), "{name:?} is not a valid header name");
+        anyhow::ensure!(is_field_value(value), "{value:?} is not a valid header value");
+        Ok(HeaderRule { pattern: pattern.to_string(), name: name.to_string(), value: value.to_string() })
+    }
+}
+
+/// Applies the configured header rules to a finished response.
+fn add_rule_headers(request: &HttpRequest, rules: &[HeaderRule], response: &mut HttpResponseBuilder) {
+    let path = request.route.split_once('?').map_or(request.route.as_str(), |(path, _)| path);
+    for rule in rules.iter().filter(|rule| glob::matches(&rule.pattern, path)) {
+        response.headers.retain(|(name, _)| !name.eq_ignore_ascii_case(&rule.name));
+        response.headers.append(rule.name.clone(), rule.value.clone());
+    }
+}
+
+/// A time limit for handling request paths matching the glob `pattern`, overriding the
+/// default handler timeout.
+#[derive(Debug, Clone)]
+pub struct RouteTimeout {
+    pub pattern: String,
+    pub timeout: Duration,
+}
+
+/// Checks what the parser can't: rules that depend on the meaning of the request.
+fn validate_request(request: &HttpRequest) -> Result<(), HttpStatusCode> {
+    // RFC 9112 section 3.2, every HTTP/1.1 request carries a Host header
+    if request.version == "HTTP/1.1" && request.header("Host").is_none() {
+        log_debug!("rejecting HTTP/1.1 request without Host");
+        return Err(HttpStatusCode::BadRequest400);
+    }
+    // the asterisk-form target only means something to OPTIONS (RFC 9112 section 3.2.4)
+    if request.route == "*" && !matches!(request.method, HttpMethod::Options) {
+        log_debug!("rejecting {} *", request.method.as_str());
+        return Err(HttpStatusCode::BadRequest400);
+    }
+    Ok(())
+}
+
+/// Where the redirect listener leaves requests alone, so certificates can still be issued
+/// over plain HTTP (RFC 8555 section 8.3).
+const ACME_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+/// The 301 to the https:// equivalent of `request` on `port`, or `None` for requests that
+/// are served normally.
+fn https_redirect(request: &HttpRequest, port: u16) -> Option<HttpResponseBuilder> {
+    if request.route.starts_with(ACME_CHALLENGE_PREFIX) {
+        return None;
+    }
+    let response = |status_code, headers| HttpResponseBuilder {
+        status_code,
+        version: request.version.clone(),
+        headers,
+        content: Content::Empty,
+    };
+
+    // the host ends up in Location, so it may only be a host and an optional port
+    let host = request.header("Host")
+        .filter(|host| !host.is_empty() && !host.contains(['/', '?', '#', '@', '\\']));
+    let Some(host) = host else {
+        return Some(response(HttpStatusCode::BadRequest400, HeaderMap::new()));
+    };
+    let name = match host.strip_prefix('[') {
+        Some(rest) => &host[..rest.find(']').map_or(host.len(), |end| end + 2)],
+        None => host.split(':').next().unwrap_or(host),
+    };
+    let authority = if port == 443 { name.to_string() } else { format!("{name}:{port}") };
+    let location = format!("https://{authority}{}", request.route);
+    Some(response(HttpStatusCode::Other(301, "Moved Permanently".to_string()), HeaderMap::from([("Location".to_string(), location)])))
+}
+
+/// The request pipeline independent of any transport: routing, the 500 fallback and error pages.
+#[derive(Debug, Clone)]
+pub struct Service {
+    pub config: Arc<ServerConfig>,
+    router: Arc<Router>,
+    middleware: Chain,
+    /// Set on the plaintext listener that only sends clients over to HTTPS on this port.
+    https_redirect: Option<u16>,
+    /// Turns true once the server stops accepting connections, so the open ones close after
+    /// the response they are on.
+    draining: Arc<tokio::sync::watch::Sender<bool>>,
+    /// The pipeline wrapped in the configured tower middleware. A std Mutex keeps `Service`
+    /// Sync; it is only held long enough to clone the stack.
+    #[cfg(feature = "tower")]
+    stack: Option<Arc<std::sync::Mutex<tower_compat::HttpService>>>,
+}
+
+impl Service {
+    /// The service answering the routes `config` enables.
+    pub fn new(config: ServerConfig) -> Self {
+        let router = router::routes(&config);
+        Self::with_router(config, router)
+    }
+
+    /// The service answering the routes of `router` instead of the built-in ones.
+    pub fn with_router(config: ServerConfig, router: Router) -> Self {
+        Self::build(Arc::new(config), Arc::new(router), Chain::default(), None)
+    }
+
+    /// The same service with `middleware` wrapped around every handler, inside the
+    /// middleware added before it.
+    pub fn with_middleware<F>(&self, middleware: F) -> Self
+    where
+        F: for<'a> Fn(&'a HttpRequest, Next<'a>) -> storage::BoxFuture<'a, HttpResponseBuilder> + Send + Sync + 'static,
+    {
+        let mut chain = self.middleware.clone();
+        chain.push(Arc::new(middleware));
+        Self::build(self.config.clone(), self.router.clone(), chain, self.https_redirect)
+    }
+
+    fn build(config: Arc<ServerConfig>, router: Arc<Router>, middleware: Chain, https_redirect: Option<u16>) -> Self {
+        let service = Service {
+            config,
+            router,
+            middleware,
+            https_redirect,
+            draining: Arc::new(tokio::sync::watch::channel(false).0),
+            #[cfg(feature = "tower")]
+            stack: None,
+        };
+        #[cfg(feature = "tower")]
+        let service = {
+            let layers = &service.config.tower_layers;
+            let stack = (layers.timeout.is_some() || layers.concurrency_limit.is_some())
+                .then(|| Arc::new(std::sync::Mutex::new(tower_compat::layered(service.clone(), layers))));
+            Service { stack, ..service }
+        };
+        service
+    }
+
+    /// The same service, but answering everything except ACME challenges with a redirect to
+    /// HTTPS on `port`.
+    pub fn redirecting_to_https(&self, port: u16) -> Self {
+        // rebuilt rather than cloned, so the tower stack wraps the redirecting service
+        Self::build(self.config.clone(), self.router.clone(), self.middleware.clone(), Some(port))
+    }
+
+    /// Like `handle`, but through the tower middleware when any is configured.
+    pub async fn respond(&self, request: &HttpRequest) -> HttpResponseBuilder {
+        #[cfg(feature = "tower")]
+        if let Some(stack) = &self.stack {
+            return tower_compat::call(stack, request).await;
+        }
+        self.handle(request).await
+    }
+
+    /// The 401 for a request that needs credentials and lacks them. A valid signed download
+    /// link stands in for credentials, as handing those out is what it is for, and requests
+    /// only redirected to https aren't asked for them.
+    fn unauthorized(&self, request: &HttpRequest) -> Option<HttpResponseBuilder> {
+        let auth = self.config.basic_auth.as_ref()?;
+        if self.https_redirect.is_some_and(|port| https_redirect(request, port).is_some()) {
+            return None;
+        }
+        let (path, query) = request.route.split_once('?').unwrap_or((&request.route, ""));
+        let signed = matches!(request.method, HttpMethod::Get | HttpMethod::Head)
+            && self.config.url_signer.as_ref()
+                .is_some_and(|signer| signer.check(path, query, self.config.clock.now()) == signed_url::Verdict::Valid);
+        if signed {
+            return None;
+        }
+        auth.response(request)
+    }
+
+    /// `request` as the handlers see it, with its method overridden if allowed and HEAD
+    /// turned into GET, and whether it was a HEAD.
+    fn routed<'a>(&self, request: &'a HttpRequest) -> (Cow<'a, HttpRequest>, bool) {
+        let mut routed = match method_override(request, &self.config.method_overrides) {
+            Some(overridden) => Cow::Owned(overridden),
+            None => Cow::Borrowed(request),
+        };
+        // HEAD is answered by the GET handler, whose body is then dropped (RFC 9110 section 9.3.2)
+        let head = matches!(routed.method, HttpMethod::Head);
+        if head {
+            routed.to_mut().method = HttpMethod::Get;
+        }
+        (routed, head)
+    }
+
+    pub async fn handle(&self, request: &HttpRequest) -> HttpResponseBuilder {
+        let (routed, head) = self.routed(request);
+        let request = &*routed;
+        let response = match validate_request(request) {
+            Err(status_code) => HttpResponseBuilder {
+                status_code,
+                version: request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Empty,
+            },
+            Ok(()) => match self.https_redirect.and_then(|port| https_redirect(request, port))
+                .or_else(|| self.config.maintenance.response(request))
+                .or_else(|| self.unauthorized(request))
+            {
+                Some(response) => response,
+                None => self.route_in_session(request).await,
+            },
+        };
+        let mut response = with_error_page(request, response);
+        add_rule_headers(request, &self.config.header_rules, &mut response);
+        response.headers.insert("Date", httpdate::format(self.config.clock.now()));
+        if head {
+            response = response.without_body();
+        }
+        response
+    }
+
+    /// Runs `request` through the middleware with the client's session attached, when
+    /// sessions are enabled, and keeps what the handler put in it.
+    async fn route_in_session(&self, request: &HttpRequest) -> HttpResponseBuilder {
+        let Some(sessions) = &self.config.sessions else {
+            return self.middleware.run(self, request).await;
+        };
+        let session = Arc::new(sessions.open(request, self.config.clock.now()));
+        let request = HttpRequest { session: Some(session.clone()), ..request.clone() };
+        let mut response = self.middleware.run(self, &request).await;
+        if let Some(cookie) = sessions.close(&session, request.scheme() == "https", self.config.clock.now()) {
+            response.headers.append("Set-Cookie", cookie);
+        }
+        response
+    }
+
+    /// Runs the handler for `request` within its time limit. A handler that runs over is
+    /// dropped, cancelling whatever it was waiting on, and the client gets a 503.
+    pub(crate) async fn route(&self, request: &HttpRequest) -> HttpResponseBuilder {
+        let failure = |status_code| HttpResponseBuilder {
+            status_code,
+            version: request.version.clone(),
+            headers: HeaderMap::new(),
+            content: Content::Empty,
+        };
+
+        let path = request.route.split_once('?').map_or(request.route.as_str(), |(path, _)| path);
+        let rejection = self.config.body_validators.iter()
+            .find(|validator| validator.matches(path))
+            .and_then(|validator| validator.check(request));
+        if let Some(rejection) = rejection {
+            return rejection;
+        }
+        let limit = self.config.route_timeouts.iter()
+            .find(|route| glob::matches(&route.pattern, path))
+            .map(|route| route.timeout)
+            .or(self.config.handler_timeout);
+        let routed = match limit {
+            Some(limit) => match clock::timeout(&*self.config.clock, limit, self.router.handle(request, &self.config)).await {
+                Ok(routed) => routed,
+                Err(_) => {
+                    log_error!("handling {} {} took over {limit:?}, cancelled", request.method.as_str(), request.route);
+                    return failure(HttpStatusCode::Other(503, "Service Unavailable".to_string()));
+                }
+            },
+            None => self.router.handle(request, &self.config).await,
+        };
+        routed.unwrap_or_else(|err| {
+            log_error!("handling {} {} failed, error: {err:#}", request.method.as_str(), request.route);
+            failure(HttpStatusCode::InternalError500)
+        })
+    }
+}
+
+/// Writes a whole response, giving up on clients that don't take it within the write
+/// timeout; returning the error drops, and so closes, their connection.
+async fn write_response<W: AsyncWrite + Unpin>(writer: &mut W, response: &[u8], config: &ServerConfig) -> anyhow::Result<()> {
+    let write = async {
+        writer.write_all(response).await?;
+        writer.flush().await
+    };
+    match clock::timeout(&*config.clock, config.write_timeout, write).await {
+        Ok(result) => Ok(result?),
+        Err(_) => anyhow::bail!(
+            "client took more than {:?} to accept a {} byte response, dropping it", config.write_timeout, response.len()
+        ),
+    }
+}
+
+/// Sends a streamed body as it's read, each chunk within the write timeout. Returns how many
+/// bytes went out, framing included. A body ending before its announced length leaves the
+/// client waiting for the rest, so that is an error and drops the connection.
+async fn write_stream<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut body: storage::BoxReader,
+    chunked: bool,
+    len: Option<u64>,
+    config: &ServerConfig,
+) -> anyhow::Result<usize> {
+    let mut buffer = vec![0; 16 * 1024];
+    let mut sent = 0;
+    let mut remaining = len;
+    loop {
+        // never more than announced
+        let want = remaining.map_or(buffer.len(), |remaining| buffer.len().min(remaining.try_into().unwrap_or(usize::MAX)));
+        // the head is out, so all a failing body can still do is cut the response short
+        let n = body.read(&mut buffer[..want]).await.context("ERROR: reading streamed response body")?;
+        let chunk = match chunked {
+            true => [format!("{n:x}\r\n").as_bytes(), &buffer[..n], b"\r\n"].concat(),
+            false => buffer[..n].to_vec(),
+        };
+        if !chunk.is_empty() {
+            write_response(writer, &chunk, config).await?;
+            sent += chunk.len();
+        }
+        if n == 0 {
+            if let Some(remaining @ 1..) = remaining {
+                anyhow::bail!("streamed body ended {remaining} bytes short of its Content-Length");
+            }
+            return Ok(sent);
+        }
+        remaining = remaining.map(|remaining| remaining.saturating_sub(n as u64));
+    }
+}
+
+/// Serves a client over any byte stream: TCP in production, an in-memory duplex in tests.
+/// Requests are answered one after another on the same connection until the client closes
+/// it, asks for it to be closed, or stays idle past the keep-alive timeout.
+pub async fn stream_handler<S>(stream: S, remote_addr: Option<SocketAddr>, service: Service) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let record = service.config.record.as_ref();
+    let stream = slowlog::Counted::new(stream);
+    let totals = stream.totals();
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = record::RecordingReader::new(BufReader::new(reader), record.is_some());
+    let mut opened = service.config.clock.now();
+    let mut draining = service.draining.subscribe();
+    let _open = service.config.metrics.as_ref().map(|metrics| metrics.connection());
+    for served in 0usize.. {
+        // a connection that never sends anything is closed as soon as one too slow to finish
+        // its head would be
+        let idle = match served {
+            0 => service.config.parser.header_timeout,
+            _ => service.config.keep_alive_timeout,
+        };
+        let more = async { reader.fill_buf().await.map(|buffered| !buffered.is_empty()) };
+        let next = tokio::select! {
+            next = clock::timeout(&*service.config.clock, idle, more) => next,
+            // between requests there is nothing to finish
+            _ = draining.wait_for(|draining| *draining) => {
+                log_debug!("closing idle connection, the server is shutting down");
+                break;
+            }
+        };
+        match next {
+            Ok(Ok(false)) => break,
+            Ok(Ok(true)) => {}
+            Ok(Err(err)) => return Err(err.into()),
+            Err(_) => {
+                log_debug!("closing connection idle for {idle:?}");
+                break;
+            }
+        }
+
+        let read = async {
+            let mut request = read_request_head(&mut reader, &service.config.parser, &*service.config.clock).await?;
+            request.remote_addr = remote_addr;
+            request.forwarded = forwarded::resolve(&request, &service.config.trusted_proxies);
+            // turned away before a body nobody will look at is read, or spooled
+            if let Some(response) = service.unauthorized(&request) {
+                Err(RejectedRequest::new(response.status_code, "no valid credentials").with_headers(response.headers))?;
+            }
+            read_request_body(&mut reader, request, &service.config.parser, &*service.config.clock).await
+        };
+        let request = match read.await {
+            Ok(request) => request,
+            Err(err) => {
+                let now = service.config.clock.now();
+                // rejections close the connection, what follows a request that couldn't be
+                // read can't be framed
+                let Some(response) = rejection(&err, now) else {
+                    save_recording(record, reader.take_recorded(), None, now).await;
+                    return Err(err);
+                };
+                let response_bytes: Vec<u8> = response.into();
+                write_response(&mut writer, &response_bytes, &service.config).await?;
+                save_recording(record, reader.take_recorded(), Some(&response_bytes), now).await;
+                writer.shutdown().await?;
+                return Ok(());
+            }
+        };
+        if let Some(hints) = early_hints(&request, &service.config.early_hints) {
+            write_response(&mut writer, &Vec::<u8>::from(hints), &service.config).await?;
+        }
+
+        let started = service.config.clock.now();
+        // held until the response is written, so clients reading slowly use up their own slots
+        let slot = service.config.client_limit.as_ref().zip(request.client_ip()).map(|(limit, ip)| (limit, limit.acquire(ip)));
+        let mut response = match &slot {
+            Some((limit, None)) => limit.rejection(&request, started),
+            _ => {
+                let span = tracing::info_span!("request", method = request.method.as_str(), path = request.route.as_str());
+                service.respond(&request).instrument(span).await
+            }
+        };
+        let chunked = response.sends_chunked();
+        let stream = response.take_stream();
+        let upgrade = response.take_upgrade();
+        // a stream of unknown length that isn't chunked ends where the connection does
+        let close = wants_close(&request)
+            || stream.as_ref().is_some_and(|(_, len)| len.is_none() && !chunked)
+            || *draining.borrow();
+        if close {
+            response.headers.insert("Connection", "close");
+        } else if request.version == "HTTP/1.0" {
+            response.headers.insert("Connection", "keep-alive");
+        }
+        let status = response.status_code.code_and_phrase().0;
+        let response_bytes: Vec<u8> = response.into();
+        let finished = service.config.clock.now();
+        let request_line = format!("{} {} {}", request.method.as_str(), request.route, request.version);
+        if let Some(statsd) = &service.config.statsd {
+            statsd.request(request.method.as_str(), status, finished.duration_since(started).unwrap_or_default());
+        }
+
+        write_response(&mut writer, &response_bytes, &service.config).await?;
+        let streamed = match stream {
+            Some((stream, len)) => write_stream(&mut writer, stream, chunked, len, &service.config).await?,
+            None => 0,
+        };
+        if let Some(metrics) = &service.config.metrics {
+            // gateways and plugins answer outside the router's patterns
+            let route = service.router.pattern(&service.routed(&request).0).unwrap_or("other");
+            metrics.response(request.method.as_str(), route, status, response_bytes.len() + streamed);
+        }
+        // the head ends at the first empty line, header values can't hold one
+        let head_len = response_bytes.windows(4).position(|window| window == b"\r\n\r\n").map_or(0, |end| end + 4);
+        log::access(&log::Access {
+            client_ip: request.client_ip(),
+            request_line: &request_line,
+            status,
+            bytes: response_bytes.len() - head_len + streamed,
+            referer: request.header("Referer"),
+            user_agent: request.header("User-Agent"),
+            duration: service.config.clock.now().duration_since(started).unwrap_or_default(),
+        }, finished);
+        if close {
+            writer.shutdown().await?;
+        }
+        let written = service.config.clock.now();
+        if let Some(slow_log) = &service.config.slow_log {
+            let elapsed = |from: SystemTime, to: SystemTime| to.duration_since(from).unwrap_or_default();
+            slow_log.record(slowlog::SlowRequest {
+                request_line,
+                client: request.client_ip(),
+                status,
+                finished: written,
+                timing: slowlog::Timing {
+                    read: elapsed(opened, started),
+                    handle: elapsed(started, finished),
+                    write: elapsed(finished, written),
+                },
+                bytes_in: totals.read(),
+                bytes_out: totals.written(),
+            });
+        }
+        save_recording(record, reader.take_recorded(), Some(&response_bytes), service.config.clock.now()).await;
+        if close {
+            return Ok(());
+        }
+        // the connection is the new protocol's from here on
+        if let Some(upgrade) = upgrade {
+            reader.stop_recording();
+            upgrade(&mut reader, &mut writer).await.context("ERROR: upgraded connection")?;
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        opened = written;
+    }
+
+    Ok(())
+}
+
+async fn save_recording(config: Option<&record::RecordConfig>, request: Option<Vec<u8>>, response: Option<&[u8]>, now: SystemTime) {
+    let (Some(config), Some(request)) = (config, request) else {
+        return;
+    };
+    if let Err(err) = record::save(config, &request, response, now).await {
+        log_error!("{err:#}");
+    }
+}
+
+/// Accepts connections forever, serving each on its own task. With `trace_wire`, every
+/// connection's traffic is hexdumped up to that many bytes per direction.
+pub async fn serve(listener: TcpListener, service: Service, trace_wire: Option<usize>) -> anyhow::Result<()> {
+    serve_until(listener, service, trace_wire, std::future::pending(), Duration::ZERO).await
+}
+
+/// Like [`serve`], until `shutdown` completes. Then no more connections are accepted, idle
+/// ones are closed and busy ones get up to `grace` to finish their response; whatever is
+/// still open after that is dropped.
+pub async fn serve_until<F>(listener: TcpListener, service: Service, trace_wire: Option<usize>, shutdown: F, grace: Duration) -> anyhow::Result<()>
+where
+    F: Future<Output = ()>,
+{
+    let mut connection_id = 0;
+    let mut connections = tokio::task::JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let room = service.config.max_connections.is_none_or(|max| connections.len() < max);
+        let (stream, remote_addr) = tokio::select! {
+            // at the limit nothing is accepted, the kernel queues new connections meanwhile
+            accepted = listener.accept(), if room => accepted?,
+            // finished connections are reaped as they go, so the set only holds open ones
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            () = &mut shutdown => break,
+        };
+        let service = service.clone();
+        connection_id += 1;
+        let id = connection_id;
+        connections.spawn(
+            async move {
+                let result = match trace_wire {
+                    Some(limit) => {
+                        let stream = wire::WireTrace::new(stream, id, limit);
+                        stream_handler(stream, Some(remote_addr), service).await
+                    }
+                    None => stream_handler(stream, Some(remote_addr), service).await,
+                };
+                if let Err(err) = result {
+                    log_error!("connection ended with {err}")
+                }
+            }
+            .instrument(tracing::info_span!("connection", peer = %remote_addr))
+        );
+    }
+
+    drop(listener);
+    service.draining.send_replace(true);
+    if !connections.is_empty() {
+        log_info!("waiting up to {grace:?} for {} connections to finish", connections.len());
+    }
+    let drained = clock::timeout(&*service.config.clock, grace, async {
+        while connections.join_next().await.is_some() {}
+    }).await;
+    if drained.is_err() {
+        log_error!("dropping {} connections still open after {grace:?}", connections.len());
+        connections.abort_all();
+    }
+    Ok(())
+}
+
+/// Offline mode: parses a raw request from `input` (or stdin), runs it through `service` and
+/// writes the raw response to stdout, leaving stdout free of any diagnostics.
+pub async fn respond(service: &Service, input: Option<&String>) -> anyhow::Result<()> {
+    let raw = match input.map(String::as_str) {
+        None | Some("-") => {
+            let mut raw = Vec::new();
+            tokio::io::stdin().read_to_end(&mut raw).await
+                .context("ERROR: reading request from stdin")?;
+            raw
+        }
+        Some(path) => tokio::fs::read(path).await
+            .with_context(|| format!("ERROR: reading request from {path}"))?,
+    };
+
+    let mut reader = raw.as_slice();
+    let response: Vec<u8> = match reader_request(&mut reader, &service.config.parser, &*service.config.clock).await {
+        Ok(request) => service.respond(&request).await.into(),
+        Err(err) => rejection(&err, service.config.clock.now()).ok_or(err)?.into(),
+    };
+
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&response).await?;
+    stdout.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use crate::request::parse_http_request;
+    use crate::test_client::TestClient;
+
+    use super::*;
+
+    /// Storage where every operation fails with the same kind of error.
+    #[derive(Debug)]
+    struct FailingStorage(std::io::ErrorKind);
+
+    impl storage::Storage for FailingStorage {
+        fn open<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<storage::BoxReader>> {
+            Box::pin(async { Err(self.0.into()) })
+        }
+
+        fn read<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<Vec<u8>>> {
+            Box::pin(async { Err(self.0.into()) })
+        }
+
+        fn write<'a>(&'a self, _: &'a str, _: &'a [u8]) -> storage::BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(async { Err(self.0.into()) })
+        }
+
+        fn delete<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(async { Err(self.0.into()) })
+        }
+
+        fn metadata<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<storage::Metadata>> {
+            Box::pin(async { Err(self.0.into()) })
+        }
+
+        fn list<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<Vec<String>>> {
+            Box::pin(async { Err(self.0.into()) })
+        }
+
+        fn write_stream<'a>(&'a self, _: &'a str, _: storage::BoxReader) -> storage::BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(async { Err(self.0.into()) })
+        }
+    }
+
+    /// Storage where every operation waits forever, like a hung network mount.
+    #[derive(Debug)]
+    struct StalledStorage;
+
+    impl storage::Storage for StalledStorage {
+        fn open<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<storage::BoxReader>> {
+            Box::pin(std::future::pending())
+        }
+
+        fn read<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<Vec<u8>>> {
+            Box::pin(std::future::pending())
+        }
+
+        fn write<'a>(&'a self, _: &'a str, _: &'a [u8]) -> storage::BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(std::future::pending())
+        }
+
+        fn delete<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(std::future::pending())
+        }
+
+        fn metadata<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<storage::Metadata>> {
+            Box::pin(std::future::pending())
+        }
+
+        fn list<'a>(&'a self, _: &'a str) -> storage::BoxFuture<'a, std::io::Result<Vec<String>>> {
+            Box::pin(std::future::pending())
+        }
+
+        fn write_stream<'a>(&'a self, _: &'a str, _: storage::BoxReader) -> storage::BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(std::future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn stuck_handlers_time_out() {
+        let client = TestClient::new(ServerConfig {
+            storage: Some(Arc::new(StalledStorage)),
+            handler_timeout: Some(Duration::from_secs(3600)),
+            route_timeouts: vec![RouteTimeout { pattern: "/files/*".to_string(), timeout: Duration::from_millis(10) }],
+            ..Default::default()
+        });
+        assert_eq!(client.get("/files/a").send().await.status, 503);
+        assert_eq!(client.get("/echo/a").send().await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn storage_errors_map_to_statuses() {
+        use std::io::ErrorKind;
+
+        for (kind, status) in [(ErrorKind::NotFound, 404), (ErrorKind::PermissionDenied, 403), (ErrorKind::Other, 500)] {
+            let client = TestClient::new(ServerConfig {
+                storage: Some(Arc::new(FailingStorage(kind))),
+                ..Default::default()
+            });
+            assert_eq!(client.get("/files/a").send().await.status, status, "GET with {kind:?}");
+            assert_eq!(client.post("/files/a").body("x").send().await.status, status, "POST with {kind:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn redirect_listener_sends_clients_to_https() {
+        let service = Service::new(ServerConfig::default());
+        let redirect = |raw: &str, port| {
+            let (_, request) = parse_http_request(raw).unwrap();
+            let service = service.redirecting_to_https(port);
+            async move { service.handle(&request).await }
+        };
+
+        let response = redirect("GET /echo/a?b=c HTTP/1.1\r\nHost: example.com:8080\r\n", 443).await;
+        assert_eq!(response.status_code.code_and_phrase().0, 301);
+        assert_eq!(response.headers.get("Location"), Some("https://example.com/echo/a?b=c"));
+
+        let response = redirect("GET / HTTP/1.1\r\nHost: [::1]:8080\r\n", 8443).await;
+        assert_eq!(response.headers.get("Location"), Some("https://[::1]:8443/"));
+
+        let response = redirect("GET / HTTP/1.1\r\nHost: evil.com/x\r\n", 443).await;
+        assert_eq!(response.status_code.code_and_phrase().0, 400);
+
+        let response = redirect("GET /.well-known/acme-challenge/token HTTP/1.1\r\nHost: example.com\r\n", 443).await;
+        assert_eq!(response.status_code.code_and_phrase().0, 404);
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_chunked_for_http_1_1() {
+        let mut router = Router::default();
+        router.get("/stream", |cx| Box::pin(async move {
+            Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Ok200,
+                version: cx.request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Stream(Box::new(std::io::Cursor::new(b"hello world")), None),
+            })
+        }));
+        let service = Service::with_router(ServerConfig::default(), router);
+        let exchange = |raw: &'static [u8]| {
+            let service = service.clone();
+            async move {
+                let (mut client, server) = tokio::io::duplex(1024);
+                let handler = tokio::spawn(stream_handler(server, None, service));
+                client.write_all(raw).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut response = Vec::new();
+                client.read_to_end(&mut response).await.unwrap();
+                handler.await.unwrap().unwrap();
+                String::from_utf8(response).unwrap()
+            }
+        };
+
+        let response = exchange(b"GET /stream HTTP/1.1\r\nHost: a\r\n\r\nHEAD /stream HTTP/1.1\r\nHost: a\r\n\r\n").await;
+        let (get, head) = response.split_once("\r\n0\r\n\r\n").unwrap();
+        assert!(get.contains("Transfer-Encoding: chunked\r\n") && !get.contains("Content-Length"), "{get}");
+        assert!(get.ends_with("\r\n\r\nb\r\nhello world"), "{get}");
+        assert!(head.contains("Transfer-Encoding: chunked\r\n") && head.ends_with("\r\n\r\n"), "{head}");
+
+        let response = exchange(b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").await;
+        assert!(response.contains("Connection: close\r\n") && !response.contains("Transfer-Encoding"), "{response}");
+        assert!(response.ends_with("\r\n\r\nhello world"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn middleware_wraps_every_handler_in_order() {
+        let service = Service::new(ServerConfig::default())
+            .with_middleware(|request, next| Box::pin(async move {
+                if request.header("Authorization").is_none() {
+                    return HttpResponseBuilder {
+                        status_code: HttpStatusCode::Other(401, "Unauthorized".to_string()),
+                        version: request.version.clone(),
+                        headers: HeaderMap::new(),
+                        content: Content::Empty,
+                    };
+                }
+                let mut response = next.run(request).await;
+                response.headers.append("X-Outer", "1");
+                response
+            }))
+            .with_middleware(|request, next| Box::pin(async move {
+                let rewritten = HttpRequest { route: request.route.replace("/old/", "/echo/"), ..request.clone() };
+                let mut response = next.run(&rewritten).await;
+                response.headers.append("X-Inner", "1");
+                response
+            }));
+        let handle = |raw: &str| {
+            let (_, request) = parse_http_request(raw).unwrap();
+            let service = service.clone();
+            async move { service.handle(&request).await }
+        };
+
+        let response = handle("GET /old/hi HTTP/1.1\r\nHost: a\r\nAuthorization: Bearer x\r\n").await;
+        assert_eq!(response.status_code.code_and_phrase().0, 200);
+        let names: Vec<&str> = response.headers.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names[names.len() - 3..], ["X-Inner", "X-Outer", "Date"]);
+        assert_eq!(handle("GET /echo/hi HTTP/1.1\r\nHost: a\r\n").await.status_code.code_and_phrase().0, 401);
+    }
+
+    #[tokio::test]
+    async fn shutdown_lets_busy_connections_finish() {
+        let clock = Arc::new(crate::clock::MockClock::new(std::time::UNIX_EPOCH));
+        let service = Service::new(ServerConfig { clock: clock.clone(), ..Default::default() });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = async { stopped.await.unwrap_or_default() };
+        let server = tokio::spawn(serve_until(listener, service, None, shutdown, Duration::from_secs(60)));
+
+        let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+        idle.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap();
+        let mut busy = tokio::net::TcpStream::connect(addr).await.unwrap();
+        busy.write_all(b"GET /events?count=2 HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap();
+        let mut buffer = vec![0; 1024];
+        assert!(idle.read(&mut buffer).await.unwrap() > 0);
+        let mut read = 0;
+        while !String::from_utf8_lossy(&buffer[..read]).contains("id: 1\n") {
+            read += busy.read(&mut buffer[read..]).await.unwrap();
+        }
+
+        stop.send(()).unwrap();
+        // the idle connection is closed right away, the busy one is waited for
+        assert_eq!(idle.read(&mut buffer).await.unwrap(), 0);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!server.is_finished());
+        clock.advance(Duration::from_secs(1));
+        let mut rest = Vec::new();
+        busy.read_to_end(&mut rest).await.unwrap();
+        assert!(String::from_utf8_lossy(&rest).contains("id: 2\n"));
+        server.await.unwrap().unwrap();
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connections_over_the_limit_wait_their_turn() {
+        let service = Service::new(ServerConfig { max_connections: Some(1), ..Default::default() });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(listener, service, None));
+
+        let mut first = tokio::net::TcpStream::connect(addr).await.unwrap();
+        first.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n").await.unwrap();
+        let mut buffer = vec![0; 1024];
+        assert!(first.read(&mut buffer).await.unwrap() > 0);
+
+        let mut second = tokio::net::TcpStream::connect(addr).await.unwrap();
+        second.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(100), second.read(&mut buffer)).await;
+        assert!(waiting.is_err());
+
+        drop(first);
+        let mut response = Vec::new();
+        second.read_to_end(&mut response).await.unwrap();
+        assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 200"));
+        server.abort();
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::digest::{constant_time_eq, hex, hmac_sha256};
+use crate::HttpRequest;
+
+const COOKIE_NAME: &str = "sid";
+
+pub type SessionData = HashMap<String, String>;
+
+/// Where session data lives between requests. Entries past their expiry must not be returned.
+pub trait SessionStore: fmt::Debug + Send + Sync {
+    fn load(&self, id: &str, now: SystemTime) -> Option<SessionData>;
+    fn save(&self, id: &str, data: SessionData, expires: SystemTime);
+}
+
+/// Keeps sessions in memory; they are lost when the server restarts.
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    entries: Mutex<HashMap<String, (SessionData, SystemTime)>>,
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, id: &str, now: SystemTime) -> Option<SessionData> {
+        let mut entries = self.entries.lock().unwrap();
+        // expired sessions are dropped whenever any session is looked up
+        entries.retain(|_, (_, expires)| *expires > now);
+        entries.get(id).map(|(data, _)| data.clone())
+    }
+
+    fn save(&self, id: &str, data: SessionData, expires: SystemTime) {
+        self.entries.lock().unwrap().insert(id.to_string(), (data, expires));
+    }
+}
+
+/// The state of one client, for handlers to read and change while answering it.
+#[derive(Debug)]
+pub struct Session {
+    id: String,
+    data: Mutex<SessionData>,
+    /// Whether the client doesn't have the cookie yet.
+    fresh: bool,
+    changed: AtomicBool,
+}
+
+impl Session {
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.data.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn set(&self, key: &str, value: String) {
+        self.data.lock().unwrap().insert(key.to_string(), value);
+        self.changed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Issues session cookies signed with `key` and keeps what handlers put in sessions in `store`
+/// for `ttl` after the client was last seen.
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    key: Vec<u8>,
+    ttl: Duration,
+    store: Arc<dyn SessionStore>,
+    next: Arc<AtomicU64>,
+}
+
+impl Sessions {
+    pub fn new(key: impl Into<Vec<u8>>, ttl: Duration, store: Arc<dyn SessionStore>) -> Self {
+        Sessions { key: key.into(), ttl, store, next: Arc::default() }
+    }
+
+    /// The session `request` carries a valid cookie for, or a new, empty one.
+    pub fn open(&self, request: &HttpRequest, now: SystemTime) -> Session {
+        let existing = request.cookie(COOKIE_NAME)
+            .and_then(|cookie| self.verify(cookie))
+            .and_then(|id| Some((id.to_string(), self.store.load(id, now)?)));
+        let (id, data, fresh) = match existing {
+            Some((id, data)) => (id, data, false),
+            None => (self.new_id(now), SessionData::new(), true),
+        };
+        Session { id, data: Mutex::new(data), fresh, changed: AtomicBool::new(false) }
+    }
+
+    /// Stores `session` once the handler is done with it, returning the `Set-Cookie` value
+    /// if the client needs the cookie. Sessions nothing was put in are never stored, so
+    /// clients that don't use them don't get cookies.
+    pub fn close(&self, session: &Session, secure: bool, now: SystemTime) -> Option<String> {
+        let changed = session.changed.load(Ordering::SeqCst);
+        if session.fresh && !changed {
+            return None;
+        }
+        // saved even unchanged, so the session lives on while the client is active
+        self.store.save(&session.id, session.data.lock().unwrap().clone(), now + self.ttl);
+        session.fresh.then(|| {
+            let secure = if secure { "; Secure" } else { "" };
+            format!("{COOKIE_NAME}={}; Path=/; HttpOnly; SameSite=Lax{secure}", self.sign(&session.id))
+        })
+    }
+
+    /// An id no one without the key can predict.
+    fn new_id(&self, now: SystemTime) -> String {
+        let count = self.next.fetch_add(1, Ordering::Relaxed);
+        let nanos = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        hex(&hmac_sha256(&self.key, format!("session id {count} {nanos} {}", std::process::id()).as_bytes())[..16])
+    }
+
+    fn sign(&self, id: &str) -> String {
+        format!("{id}.{}", hex(&hmac_sha256(&self.key, id.as_bytes())))
+    }
+
+    fn verify<'a>(&self, cookie: &'a str) -> Option<&'a str> {
+        let (id, _) = cookie.split_once('.')?;
+        constant_time_eq(self.sign(id).as_bytes(), cookie.as_bytes()).then_some(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpMethod;
+
+    fn request(cookie: Option<&str>) -> HttpRequest {
+        HttpRequest {
+            method: HttpMethod::Get,
+            route: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: cookie.map(|cookie| ("Cookie".to_string(), cookie.to_string())).into_iter().collect(),
+            body: None,
+            remote_addr: None,
+            forwarded: None,
+            spooled: None,
+            session: None,
+        }
+    }
+
+    #[test]
+    fn sessions_survive_between_requests_until_they_expire() {
+        let sessions = Sessions::new("key", Duration::from_secs(60), Arc::new(MemorySessionStore::default()));
+        let now = UNIX_EPOCH + Duration::from_secs(1_000);
+
+        let untouched = sessions.open(&request(None), now);
+        assert_eq!(sessions.close(&untouched, false, now), None);
+
+        let session = sessions.open(&request(None), now);
+        session.set("user", "ada".to_string());
+        let cookie = sessions.close(&session, true, now).unwrap();
+        assert!(cookie.ends_with("; Path=/; HttpOnly; SameSite=Lax; Secure"), "{cookie}");
+        let cookie = cookie.split(';').next().unwrap().to_string();
+
+        let again = sessions.open(&request(Some(&format!("theme=dark; {cookie}"))), now + Duration::from_secs(30));
+        assert_eq!(again.get("user").as_deref(), Some("ada"));
+        // a returning client already has the cookie
+        assert_eq!(sessions.close(&again, false, now + Duration::from_secs(30)), None);
+
+        let forged = format!("{}0", cookie);
+        assert_eq!(sessions.open(&request(Some(&forged)), now).get("user"), None);
+        let late = sessions.open(&request(Some(&cookie)), now + Duration::from_secs(91));
+        assert_eq!(late.get("user"), None);
+    }
+}
@@ -0,0 +1,192 @@
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::httpdate;
+use crate::json::{obj, Json};
+use crate::log::log_error;
+
+/// Where the time answering one request went.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timing {
+    /// From the connection opening to the request being parsed, so it includes the client's
+    /// own slowness in sending it.
+    pub read: Duration,
+    pub handle: Duration,
+    pub write: Duration,
+}
+
+impl Timing {
+    pub fn total(&self) -> Duration {
+        self.read + self.handle + self.write
+    }
+}
+
+/// Bytes moved over one connection so far, shared with the [`Counted`] stream doing the moving.
+#[derive(Debug, Default)]
+pub struct ConnectionTotals {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl ConnectionTotals {
+    pub fn read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SlowRequest {
+    pub request_line: String,
+    pub client: Option<IpAddr>,
+    pub status: u16,
+    pub finished: SystemTime,
+    pub timing: Timing,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl SlowRequest {
+    fn to_json(&self) -> Json {
+        let millis = |duration: Duration| Json::Num(duration.as_millis() as u64);
+        obj([
+            ("request", self.request_line.as_str().into()),
+            ("client", self.client.map(|ip| ip.to_string()).unwrap_or_default().as_str().into()),
+            ("status", Json::Num(self.status.into())),
+            ("finished", httpdate::rfc3339(self.finished).as_str().into()),
+            ("total_ms", millis(self.timing.total())),
+            ("read_ms", millis(self.timing.read)),
+            ("handle_ms", millis(self.timing.handle)),
+            ("write_ms", millis(self.timing.write)),
+            ("connection", obj([("bytes_in", Json::Num(self.bytes_in)), ("bytes_out", Json::Num(self.bytes_out))])),
+        ])
+    }
+}
+
+/// Logs requests taking longer than `threshold` and keeps the `keep` slowest of them for
+/// `/admin/slow-requests`.
+#[derive(Debug)]
+pub struct SlowLog {
+    threshold: Duration,
+    keep: usize,
+    slowest: Mutex<Vec<SlowRequest>>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, keep: usize) -> Self {
+        SlowLog { threshold, keep, slowest: Mutex::default() }
+    }
+
+    pub fn record(&self, request: SlowRequest) {
+        let timing = request.timing;
+        if timing.total() < self.threshold {
+            return;
+        }
+        log_error!(
+            "slow request: {} from {} answered {} in {}ms (read {}ms, handle {}ms, write {}ms), {} bytes in, {} bytes out",
+            request.request_line,
+            request.client.map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_string()),
+            request.status,
+            timing.total().as_millis(),
+            timing.read.as_millis(),
+            timing.handle.as_millis(),
+            timing.write.as_millis(),
+            request.bytes_in,
+            request.bytes_out,
+        );
+        let mut slowest = self.slowest.lock().unwrap();
+        slowest.push(request);
+        slowest.sort_by_key(|request| std::cmp::Reverse(request.timing.total()));
+        slowest.truncate(self.keep);
+    }
+
+    /// The kept requests, slowest first, as a JSON array.
+    pub fn to_json(&self) -> String {
+        Json::Arr(self.slowest.lock().unwrap().iter().map(SlowRequest::to_json).collect()).to_string()
+    }
+}
+
+/// Counts the bytes read from and written to the wrapped stream into its [`ConnectionTotals`].
+pub struct Counted<S> {
+    inner: S,
+    totals: Arc<ConnectionTotals>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S) -> Self {
+        Counted { inner, totals: Arc::default() }
+    }
+
+    pub fn totals(&self) -> Arc<ConnectionTotals> {
+        self.totals.clone()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            this.totals.read.fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.totals.written.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(millis: u64) -> SlowRequest {
+        SlowRequest {
+            request_line: format!("GET /{millis} HTTP/1.1"),
+            client: None,
+            status: 200,
+            finished: SystemTime::UNIX_EPOCH,
+            timing: Timing { read: Duration::ZERO, handle: Duration::from_millis(millis), write: Duration::ZERO },
+            bytes_in: 0,
+            bytes_out: 0,
+        }
+    }
+
+    #[test]
+    fn only_the_slowest_requests_over_the_threshold_are_kept() {
+        let log = SlowLog::new(Duration::from_millis(100), 2);
+        for millis in [50, 300, 150, 200] {
+            log.record(request(millis));
+        }
+        let json = log.to_json();
+        assert!(json.starts_with(r#"[{"request":"GET /300 HTTP/1.1""#), "{json}");
+        assert!(json.contains("GET /200") && !json.contains("GET /150") && !json.contains("GET /50 "), "{json}");
+    }
+}
@@ -0,0 +1,65 @@
+use std::io;
+
+use tokio::io::{AsyncWriteExt, DuplexStream};
+
+use crate::{Content, HeaderMap};
+
+/// The sending end of a `text/event-stream` body (server-sent events, HTML Living Standard
+/// section 9.2). The handler returns the body right away and keeps pushing events through
+/// this, typically from a task of its own, until sending fails because the client left.
+pub struct EventSender {
+    writer: DuplexStream,
+}
+
+/// A sender and the body it feeds, a stream of unknown length, so sent chunked.
+pub fn channel() -> (EventSender, Content) {
+    let (writer, reader) = tokio::io::duplex(16 * 1024);
+    (EventSender { writer }, Content::Stream(Box::new(reader), None))
+}
+
+/// The headers a response carrying events needs besides its framing.
+pub fn headers() -> HeaderMap {
+    HeaderMap::from([
+        ("Content-Type".to_string(), "text/event-stream".to_string()),
+        ("Cache-Control".to_string(), "no-cache".to_string()),
+    ])
+}
+
+impl EventSender {
+    /// Sends one event, named `event` unless it's a plain message. Lines of `data` become
+    /// `data:` fields of their own.
+    pub async fn send(&mut self, event: Option<&str>, id: Option<&str>, data: &str) -> io::Result<()> {
+        self.writer.write_all(format_event(event, id, data).as_bytes()).await
+    }
+
+    /// Sends a comment, which clients ignore; it keeps idle connections from timing out.
+    pub async fn comment(&mut self, text: &str) -> io::Result<()> {
+        self.writer.write_all(format!(": {text}\n\n").as_bytes()).await
+    }
+}
+
+pub fn format_event(event: Option<&str>, id: Option<&str>, data: &str) -> String {
+    let mut formatted = String::new();
+    if let Some(event) = event {
+        formatted.push_str(&format!("event: {event}\n"));
+    }
+    if let Some(id) = id {
+        formatted.push_str(&format!("id: {id}\n"));
+    }
+    for line in data.split('\n') {
+        formatted.push_str(&format!("data: {line}\n"));
+    }
+    formatted.push('\n');
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_framed_line_by_line() {
+        assert_eq!(format_event(None, None, "hello"), "data: hello\n\n");
+        assert_eq!(format_event(Some("tick"), Some("7"), "a\nb"), "event: tick\nid: 7\ndata: a\ndata: b\n\n");
+    }
+}
@@ -0,0 +1,101 @@
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use anyhow::Context;
+
+#[derive(Debug, Clone)]
+pub struct StatsdConfig {
+    /// host:port of the StatsD agent.
+    pub address: String,
+    /// Prepended to every metric name, followed by a dot.
+    pub prefix: String,
+    /// Sent with every metric, `key:value` or bare `key`. Tags are a DogStatsD extension, so
+    /// they (and the per-request method and status tags) are only sent with `dogstatsd` set.
+    pub tags: Vec<String>,
+    pub dogstatsd: bool,
+}
+
+/// Pushes request metrics to a StatsD agent over UDP, for setups that don't scrape.
+#[derive(Debug)]
+pub struct StatsdClient {
+    config: StatsdConfig,
+    socket: UdpSocket,
+}
+
+impl StatsdClient {
+    pub fn new(config: StatsdConfig) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0").context("ERROR: binding statsd socket")?;
+        socket.connect(&config.address)
+            .with_context(|| format!("ERROR: connecting to statsd at {}", config.address))?;
+        // a slow agent must never hold up a response
+        socket.set_nonblocking(true)?;
+        Ok(StatsdClient { config, socket })
+    }
+
+    /// Counts a request and times it: `requests`, `responses.<class>` and `request_time`.
+    pub fn request(&self, method: &str, status: u16, elapsed: Duration) {
+        let tags = [format!("method:{method}"), format!("status:{status}")];
+        let lines = [
+            self.line("requests", "1|c", &tags),
+            self.line(&format!("responses.{}xx", status / 100), "1|c", &tags),
+            self.line("request_time", &format!("{}|ms", elapsed.as_millis()), &tags),
+        ];
+        // one metric per datagram suits agents that don't split packets on newlines;
+        // a dropped datagram is a dropped sample, which StatsD is designed to tolerate
+        for line in lines {
+            let _ = self.socket.send(line.as_bytes());
+        }
+    }
+
+    fn line(&self, name: &str, value: &str, tags: &[String]) -> String {
+        let mut line = format!("{}.{name}:{value}", self.config.prefix);
+        if self.config.dogstatsd {
+            let tags = self.config.tags.iter().chain(tags).map(String::as_str).collect::<Vec<_>>();
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(receiver: &UdpSocket, dogstatsd: bool) -> StatsdClient {
+        StatsdClient::new(StatsdConfig {
+            address: receiver.local_addr().unwrap().to_string(),
+            prefix: "web".to_string(),
+            tags: vec!["env:test".to_string()],
+            dogstatsd,
+        }).unwrap()
+    }
+
+    fn receive(receiver: &UdpSocket, count: usize) -> Vec<String> {
+        let mut buf = [0; 512];
+        (0..count)
+            .map(|_| {
+                let n = receiver.recv(&mut buf).unwrap();
+                String::from_utf8_lossy(&buf[..n]).into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plain_statsd_has_no_tags() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client(&receiver, false).request("GET", 404, Duration::from_millis(12));
+        assert_eq!(receive(&receiver, 3), ["web.requests:1|c", "web.responses.4xx:1|c", "web.request_time:12|ms"]);
+    }
+
+    #[test]
+    fn dogstatsd_appends_tags() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client(&receiver, true).request("POST", 201, Duration::from_millis(3));
+        assert_eq!(receive(&receiver, 3), [
+            "web.requests:1|c|#env:test,method:POST,status:201",
+            "web.responses.2xx:1|c|#env:test,method:POST,status:201",
+            "web.request_time:3|ms|#env:test,method:POST,status:201",
+        ]);
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::pin::Pin;
+use std::time::SystemTime;
+
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub type BoxReader = Box<dyn AsyncRead + Send + Unpin>;
+
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub is_dir: bool,
+}
+
+/// Where the /files routes keep their content. Names are relative to the storage root and
+/// use `/` as separator; implementations report missing names as `ErrorKind::NotFound`.
+pub trait Storage: fmt::Debug + Send + Sync {
+    fn open<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<BoxReader>>;
+
+    fn read<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>>;
+
+    fn write<'a>(&'a self, name: &'a str, content: &'a [u8]) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Like `write`, but takes the content from `reader` a buffer at a time.
+    fn write_stream<'a>(&'a self, name: &'a str, reader: BoxReader) -> BoxFuture<'a, io::Result<()>>;
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<()>>;
+
+    /// Moves `from` to `to`, replacing whatever `to` was. Storages that can't rename in place
+    /// copy and delete.
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let content = self.read(from).await?;
+            self.write(to, &content).await?;
+            self.delete(from).await
+        })
+    }
+
+    fn metadata<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Metadata>>;
+
+    /// Names of the entries directly inside directory `name`, `""` being the root.
+    fn list<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<String>>>;
+}
+
+/// Files on the local disk below `root`.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    /// The path of `name`, which must stay below the root: no `..` or absolute parts, and no
+    /// symlink leading out of it. Anything else is refused as `PermissionDenied`.
+    async fn path(&self, name: &str) -> io::Result<PathBuf> {
+        let escapes = || io::Error::new(io::ErrorKind::PermissionDenied, format!("{name:?} is outside the storage root"));
+        let relative = Path::new(name);
+        if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(escapes());
+        }
+        let path = self.root.join(relative);
+        let root = tokio::fs::canonicalize(&self.root).await?;
+        // a file about to be created has no canonical path yet, its directory does
+        let resolved = match tokio::fs::canonicalize(&path).await {
+            Ok(resolved) => resolved,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => match path.parent() {
+                Some(parent) => tokio::fs::canonicalize(parent).await?.join(path.file_name().unwrap_or_default()),
+                None => return Err(err),
+            },
+            Err(err) => return Err(err),
+        };
+        if !resolved.starts_with(&root) {
+            return Err(escapes());
+        }
+        Ok(path)
+    }
+}
+
+impl Storage for LocalStorage {
+    fn open<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<BoxReader>> {
+        Box::pin(async move {
+            let file = tokio::fs::File::open(self.path(name).await?).await?;
+            Ok(Box::new(file) as BoxReader)
+        })
+    }
+
+    fn read<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        Box::pin(async move { tokio::fs::read(self.path(name).await?).await })
+    }
+
+    fn write<'a>(&'a self, name: &'a str, content: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut file = tokio::fs::File::create(self.path(name).await?).await?;
+            file.write_all(content).await?;
+            file.flush().await
+        })
+    }
+
+    fn write_stream<'a>(&'a self, name: &'a str, mut reader: BoxReader) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut file = tokio::fs::File::create(self.path(name).await?).await?;
+            tokio::io::copy(&mut reader, &mut file).await?;
+            file.flush().await
+        })
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { tokio::fs::remove_file(self.path(name).await?).await })
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move { tokio::fs::rename(self.path(from).await?, self.path(to).await?).await })
+    }
+
+    fn metadata<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Metadata>> {
+        Box::pin(async move {
+            let metadata = tokio::fs::metadata(self.path(name).await?).await?;
+            Ok(Metadata {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+                is_dir: metadata.is_dir(),
+            })
+        })
+    }
+
+    fn list<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<String>>> {
+        Box::pin(async move {
+            let mut entries = tokio::fs::read_dir(self.path(name).await?).await?;
+            let mut names = Vec::new();
+            while let Some(entry) = entries.next_entry().await? {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+            names.sort();
+            Ok(names)
+        })
+    }
+}
+
+/// Files kept in memory, for tests and throwaway servers; they are lost when the server stops.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    files: std::sync::RwLock<std::collections::BTreeMap<String, (Vec<u8>, SystemTime)>>,
+}
+
+impl MemoryStorage {
+    fn not_found(name: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, format!("{name} not found"))
+    }
+
+    fn get(&self, name: &str) -> io::Result<(Vec<u8>, SystemTime)> {
+        self.files.read().unwrap().get(name).cloned().ok_or_else(|| Self::not_found(name))
+    }
+
+    fn is_dir(&self, name: &str) -> bool {
+        let prefix = format!("{name}/");
+        name.is_empty() || self.files.read().unwrap().keys().any(|file| file.starts_with(&prefix))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn open<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<BoxReader>> {
+        Box::pin(async move {
+            let (content, _) = self.get(name)?;
+            Ok(Box::new(io::Cursor::new(content)) as BoxReader)
+        })
+    }
+
+    fn read<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<u8>>> {
+        Box::pin(async move { self.get(name).map(|(content, _)| content) })
+    }
+
+    fn write<'a>(&'a self, name: &'a str, content: &'a [u8]) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.files.write().unwrap().insert(name.to_string(), (content.to_vec(), SystemTime::now()));
+            Ok(())
+        })
+    }
+
+    fn write_stream<'a>(&'a self, name: &'a str, mut reader: BoxReader) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut content = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut content).await?;
+            self.write(name, &content).await
+        })
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            self.files.write().unwrap().remove(name).map(|_| ()).ok_or_else(|| Self::not_found(name))
+        })
+    }
+
+    fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, io::Result<()>> {
+        Box::pin(async move {
+            let mut files = self.files.write().unwrap();
+            let file = files.remove(from).ok_or_else(|| Self::not_found(from))?;
+            files.insert(to.to_string(), file);
+            Ok(())
+        })
+    }
+
+    fn metadata<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Metadata>> {
+        Box::pin(async move {
+            if self.is_dir(name) {
+                return Ok(Metadata { len: 0, modified: None, is_dir: true });
+            }
+            let (content, modified) = self.get(name)?;
+            Ok(Metadata {
+                len: content.len() as u64,
+                modified: Some(modified),
+                is_dir: false,
+            })
+        })
+    }
+
+    fn list<'a>(&'a self, name: &'a str) -> BoxFuture<'a, io::Result<Vec<String>>> {
+        Box::pin(async move {
+            if !self.is_dir(name) {
+                return Err(Self::not_found(name));
+            }
+            let prefix = if name.is_empty() { String::new() } else { format!("{name}/") };
+            let mut names = self.files.read().unwrap().keys()
+                .filter_map(|file| file.strip_prefix(&prefix))
+                .map(|rest| rest.split('/').next().unwrap_or(rest).to_string())
+                .collect::<Vec<_>>();
+            names.dedup();
+            Ok(names)
+        })
+    }
+}
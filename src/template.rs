@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context as _};
+
+/// Values a template can refer to. Maps are addressed with dots, `{{ entry.name }}`.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Text(String),
+    Bool(bool),
+    List(Vec<Value>),
+    Map(Context),
+}
+
+pub type Context = HashMap<String, Value>;
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl Value {
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(text) => !text.is_empty(),
+            Value::Bool(value) => *value,
+            Value::List(items) => !items.is_empty(),
+            Value::Map(map) => !map.is_empty(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node<'a> {
+    Text(&'a str),
+    Var { path: &'a str, escape: bool },
+    For { var: &'a str, list: &'a str, body: Vec<Node<'a>> },
+    If { condition: &'a str, negate: bool, then: Vec<Node<'a>>, otherwise: Vec<Node<'a>> },
+}
+
+/// Renders `template` against `context`.
+///
+/// Supported syntax is deliberately small: `{{ path }}` (HTML-escaped), `{{ path | raw }}`,
+/// `{% for item in path %}...{% endfor %}` and `{% if [not] path %}...{% else %}...{% endif %}`.
+pub fn render(template: &str, context: &Context) -> anyhow::Result<String> {
+    let mut input = template;
+    let (nodes, end) = parse(&mut input)?;
+    if let Some(tag) = end {
+        bail!("unexpected {{% {tag} %}}");
+    }
+
+    let mut out = String::with_capacity(template.len());
+    let mut scopes = vec![context.clone()];
+    render_nodes(&nodes, &mut scopes, &mut out)?;
+    Ok(out)
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Parses nodes until the input ends or a block-closing tag is met; the closing tag is returned.
+fn parse<'a>(input: &mut &'a str) -> anyhow::Result<(Vec<Node<'a>>, Option<&'a str>)> {
+    let mut nodes = Vec::new();
+    loop {
+        let Some(start) = input.find("{{").into_iter().chain(input.find("{%")).min() else {
+            if !input.is_empty() {
+                nodes.push(Node::Text(input));
+            }
+            *input = "";
+            return Ok((nodes, None));
+        };
+        if start > 0 {
+            nodes.push(Node::Text(&input[..start]));
+        }
+
+        let is_var = input[start..].starts_with("{{");
+        let close = if is_var { "}}" } else { "%}" };
+        let rest = &input[start + 2..];
+        let end = rest.find(close).with_context(|| format!("unterminated tag at {:?}", &input[start..]))?;
+        let tag = rest[..end].trim();
+        *input = &rest[end + 2..];
+
+        if is_var {
+            let (path, escape) = match tag.split_once('|') {
+                Some((path, filter)) if filter.trim() == "raw" => (path.trim(), false),
+                Some((_, filter)) => bail!("unknown filter {:?}", filter.trim()),
+                None => (tag, true),
+            };
+            nodes.push(Node::Var { path, escape });
+            continue;
+        }
+
+        let words = tag.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["for", var, "in", list] => {
+                let (body, end) = parse(input)?;
+                if end != Some("endfor") {
+                    bail!("{{% for %}} without {{% endfor %}}");
+                }
+                nodes.push(Node::For { var, list, body });
+            }
+            ["if", rest @ ..] => {
+                let (negate, condition) = match rest {
+                    ["not", condition] => (true, *condition),
+                    [condition] => (false, *condition),
+                    _ => bail!("malformed {{% {tag} %}}"),
+                };
+                let (then, mut end) = parse(input)?;
+                let mut otherwise = Vec::new();
+                if end == Some("else") {
+                    (otherwise, end) = parse(input)?;
+                }
+                if end != Some("endif") {
+                    bail!("{{% if %}} without {{% endif %}}");
+                }
+                nodes.push(Node::If { condition, negate, then, otherwise });
+            }
+            ["endfor"] | ["endif"] | ["else"] => return Ok((nodes, Some(tag))),
+            _ => bail!("unknown tag {{% {tag} %}}"),
+        }
+    }
+}
+
+fn render_nodes(nodes: &[Node], scopes: &mut Vec<Context>, out: &mut String) -> anyhow::Result<()> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Var { path, escape } => {
+                let text = match lookup(scopes, path).with_context(|| format!("unknown variable {path:?}"))? {
+                    Value::Text(text) => text.clone(),
+                    Value::Bool(value) => value.to_string(),
+                    _ => bail!("{path:?} can't be printed"),
+                };
+                if *escape {
+                    out.push_str(&escape_html(&text));
+                } else {
+                    out.push_str(&text);
+                }
+            }
+            Node::For { var, list, body } => {
+                let items = match lookup(scopes, list) {
+                    Some(Value::List(items)) => items.clone(),
+                    Some(_) => bail!("{list:?} is not a list"),
+                    None => bail!("unknown variable {list:?}"),
+                };
+                for item in items {
+                    scopes.push(Context::from([(var.to_string(), item)]));
+                    let rendered = render_nodes(body, scopes, out);
+                    scopes.pop();
+                    rendered?;
+                }
+            }
+            Node::If { condition, negate, then, otherwise } => {
+                let truthy = lookup(scopes, condition).is_some_and(Value::is_truthy);
+                let branch = if truthy != *negate { then } else { otherwise };
+                render_nodes(branch, scopes, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn lookup<'c>(scopes: &'c [Context], path: &str) -> Option<&'c Value> {
+    let mut parts = path.split('.');
+    let first = parts.next()?;
+    let mut value = scopes.iter().rev().find_map(|scope| scope.get(first))?;
+    for part in parts {
+        match value {
+            Value::Map(map) => value = map.get(part)?,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Context {
+        let entry = |name: &str| Value::Map(Context::from([("name".to_string(), name.into())]));
+        Context::from([
+            ("title".to_string(), "Tom & \"Jerry\" <3".into()),
+            ("html".to_string(), "<b>bold</b>".into()),
+            ("admin".to_string(), false.into()),
+            ("entries".to_string(), Value::List(vec![entry("a.txt"), entry("<b>.txt")])),
+        ])
+    }
+
+    #[test]
+    fn variables_are_substituted_and_escaped() {
+        let rendered = render("<h1>{{ title }}</h1>{{html|raw}} {{ admin }}", &context()).unwrap();
+        assert_eq!(rendered, "<h1>Tom &amp; &quot;Jerry&quot; &lt;3</h1><b>bold</b> false");
+        assert_eq!(escape_html("'&'"), "&#39;&amp;&#39;");
+    }
+
+    #[test]
+    fn blocks_loop_and_branch() {
+        let template = "{% for entry in entries %}[{{ entry.name }}]{% endfor %}\
+                        {% if admin %}admin{% else %}guest{% endif %}{% if not missing %}!{% endif %}";
+        assert_eq!(render(template, &context()).unwrap(), "[a.txt][&lt;b&gt;.txt]guest!");
+    }
+
+    #[test]
+    fn mistakes_are_errors() {
+        for (template, error) in [
+            ("{{ missing }}", "unknown variable \"missing\""),
+            ("{{ entries }}", "\"entries\" can't be printed"),
+            ("{{ title | upper }}", "unknown filter \"upper\""),
+            ("{{ title", "unterminated tag at \"{{ title\""),
+            ("{% for entry in title %}{% endfor %}", "\"title\" is not a list"),
+            ("{% for entry in entries %}", "{% for %} without {% endfor %}"),
+            ("{% if admin %}", "{% if %} without {% endif %}"),
+            ("{% if admin extra %}{% endif %}", "malformed {% if admin extra %}"),
+            ("{% endif %}", "unexpected {% endif %}"),
+            ("{% include x %}", "unknown tag {% include x %}"),
+        ] {
+            assert_eq!(render(template, &context()).unwrap_err().to_string(), error, "{template}");
+        }
+    }
+}
@@ -0,0 +1,1462 @@
+use std::path::PathBuf;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::log::log_error;
+use crate::server::stream_handler;
+use crate::{HeaderMap, HttpMethod, HttpRequest, Service, ServerConfig};
+
+/// Drives a [`Service`] in-process, so endpoints can be tested without binding a socket.
+///
+/// ```ignore
+/// let response = client.get("/echo/abc").header("Accept", "text/plain").send().await;
+/// assert_eq!(response.status, 200);
+/// ```
+pub struct TestClient {
+    service: Service,
+}
+
+pub struct TestRequest<'a> {
+    service: &'a Service,
+    request: HttpRequest,
+}
+
+/// A response as a client sees it, parsed back from the serialized bytes.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestClient {
+    pub fn new(config: ServerConfig) -> Self {
+        TestClient { service: Service::new(config) }
+    }
+
+    pub fn get(&self, route: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::Get, route)
+    }
+
+    pub fn head(&self, route: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::Head, route)
+    }
+
+    pub fn post(&self, route: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::Post, route)
+    }
+
+    pub fn put(&self, route: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::Put, route)
+    }
+
+    pub fn delete(&self, route: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::Delete, route)
+    }
+
+    pub fn options(&self, route: &str) -> TestRequest<'_> {
+        self.request(HttpMethod::Options, route)
+    }
+
+    /// Opens an in-memory connection served by the full connection loop, for end-to-end tests
+    /// of framing that the request-level API skips.
+    pub fn connect(&self) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let service = self.service.clone();
+        tokio::spawn(async move {
+            if let Err(err) = stream_handler(server, None, service).await {
+                log_error!("connection ended with {err}")
+            }
+        });
+        client
+    }
+
+    /// Writes `raw` on a fresh connection, closes its sending side and returns everything
+    /// the server sent back.
+    pub async fn send_raw(&self, raw: &[u8]) -> Vec<u8> {
+        let mut connection = self.connect();
+        connection.write_all(raw).await.unwrap();
+        connection.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        connection.read_to_end(&mut response).await.unwrap();
+        response
+    }
+
+    fn request(&self, method: HttpMethod, route: &str) -> TestRequest<'_> {
+        TestRequest {
+            service: &self.service,
+            request: HttpRequest {
+                method,
+                route: route.to_string(),
+                version: "HTTP/1.1".to_string(),
+                headers: HeaderMap::from([("Host".to_string(), "localhost".to_string())]),
+                body: None,
+                remote_addr: None,
+                forwarded: None,
+                spooled: None,
+                session: None,
+            },
+        }
+    }
+}
+
+impl TestRequest<'_> {
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.request.headers.insert(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl AsRef<[u8]>) -> Self {
+        let body = body.as_ref();
+        self.request.headers.insert("Content-Length", body.len().to_string());
+        self.request.body = Some(body.to_vec());
+        self
+    }
+
+    pub async fn send(self) -> TestResponse {
+        let response = self.service.handle(&self.request).await.buffered().await.unwrap();
+        TestResponse::parse(&Vec::<u8>::from(response))
+    }
+}
+
+impl TestResponse {
+    pub fn parse(raw: &[u8]) -> Self {
+        let head_end = raw.windows(4).position(|w| w == b"\r\n\r\n")
+            .expect("response has no end of headers");
+        let head = std::str::from_utf8(&raw[..head_end]).expect("response head is not utf8");
+        let mut lines = head.split("\r\n");
+
+        let status_line = lines.next().unwrap();
+        let status = status_line.split(' ').nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or_else(|| panic!("malformed status line {status_line:?}"));
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(": ").unwrap_or_else(|| panic!("malformed header {line:?}"));
+                (name.to_string(), value.to_string())
+            })
+            .collect();
+
+        TestResponse {
+            status,
+            headers,
+            body: raw[head_end + 4..].to_vec(),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).expect("response body is not utf8")
+    }
+}
+
+/// A scratch directory unique to this test run.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("http-server-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use pretty_assertions::assert_eq;
+
+    use crate::clock::MockClock;
+    use crate::storage::{BoxFuture, BoxReader, LocalStorage, MemoryStorage, Storage};
+    use crate::request::{LineEndings, ParserConfig};
+
+    use super::*;
+
+    fn config(directory: Option<String>) -> ServerConfig {
+        ServerConfig {
+            storage: directory.map(|dir| Arc::new(LocalStorage::new(dir)) as Arc<dyn Storage>),
+            ..Default::default()
+        }
+    }
+
+    fn memory_config() -> (ServerConfig, Arc<MemoryStorage>) {
+        let storage = Arc::new(MemoryStorage::default());
+        let config = ServerConfig {
+            storage: Some(storage.clone()),
+            allow_delete: true,
+            ..Default::default()
+        };
+        (config, storage)
+    }
+
+    #[tokio::test]
+    async fn root_is_ok() {
+        let client = TestClient::new(config(None));
+        let response = client.get("/").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"");
+    }
+
+    #[tokio::test]
+    async fn echo_returns_the_rest_of_the_path() {
+        let client = TestClient::new(config(None));
+        let response = client.get("/echo/abc/def").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.header("Content-Length"), Some("7"));
+        assert_eq!(response.text(), "abc/def");
+    }
+
+    #[tokio::test]
+    async fn paths_are_percent_decoded() {
+        let client = TestClient::new(memory_config().0);
+        assert_eq!(client.get("/echo/hello%20world/%C3%BCber").send().await.text(), "hello world/über");
+        // an encoded separator stays part of its segment
+        assert_eq!(client.get("/echo/a%2Fb").send().await.text(), "a/b");
+
+        for path in ["/echo/100%", "/echo/%zz", "/echo/%FF", "/files/a%2"] {
+            assert_eq!(client.get(path).send().await.status, 400, "{path}");
+        }
+
+        client.put("/files/with%20space.txt").body("x").send().await;
+        assert_eq!(client.get("/files/with%20space.txt").send().await.text(), "x");
+    }
+
+    #[tokio::test]
+    async fn websocket_messages_are_echoed() {
+        let client = TestClient::new(config(None));
+        let mut connection = client.connect();
+        connection.write_all(
+            b"GET /ws/echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        ).await.unwrap();
+        // the masked "Hello" of RFC 6455 section 5.7, then a close with status 1000
+        connection.write_all(&[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]).await.unwrap();
+        connection.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8]).await.unwrap();
+        let mut raw = Vec::new();
+        connection.read_to_end(&mut raw).await.unwrap();
+
+        let response = TestResponse::parse(&raw);
+        assert_eq!(response.status, 101);
+        assert_eq!(response.header("Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+        assert_eq!(response.body, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o', 0x88, 0x02, 0x03, 0xe8]);
+
+        assert_eq!(client.get("/ws/echo").send().await.status, 426);
+        let response = client.get("/ws/echo")
+            .header("Upgrade", "websocket")
+            .header("Connection", "Upgrade")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "short")
+            .send().await;
+        assert_eq!(response.status, 400);
+    }
+
+    #[tokio::test]
+    async fn user_agent_is_echoed() {
+        let client = TestClient::new(config(None));
+        let response = client.get("/user-agent").header("User-Agent", "foobar/1.2.3").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "foobar/1.2.3");
+
+        let response = client.get("/user-agent").send().await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[tokio::test]
+    async fn header_names_are_case_insensitive() {
+        let client = TestClient::new(memory_config().0);
+        let raw = client.send_raw(b"GET /user-agent HTTP/1.1\r\nhost: localhost\r\nuser-agent: lower/1.0\r\n\r\n").await;
+        assert_eq!(TestResponse::parse(&raw).text(), "lower/1.0");
+
+        let raw = client.send_raw(b"PUT /files/doc HTTP/1.1\r\nHOST: localhost\r\nCONTENT-LENGTH: 2\r\n\r\nhi").await;
+        assert_eq!(TestResponse::parse(&raw).status, 201);
+        assert_eq!(client.get("/files/doc").send().await.text(), "hi");
+    }
+
+    #[tokio::test]
+    async fn user_agent_with_spaces_survives_the_parser() {
+        let client = TestClient::new(config(None));
+        let raw = client.send_raw(b"GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: foobar/1.2 (X11; Linux)\r\n\r\n").await;
+        let response = TestResponse::parse(&raw);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "foobar/1.2 (X11; Linux)");
+    }
+
+    #[tokio::test]
+    async fn requests_without_headers_parse() {
+        let client = TestClient::new(config(None));
+        let response = TestResponse::parse(&client.send_raw(b"GET / HTTP/1.0\r\n\r\n").await);
+        assert_eq!(response.status, 200);
+
+        // HTTP/1.1 parses too, but has to name its Host
+        let response = TestResponse::parse(&client.send_raw(b"GET / HTTP/1.1\r\n\r\n").await);
+        assert_eq!(response.status, 400);
+    }
+
+    #[tokio::test]
+    async fn bare_lf_depends_on_line_ending_mode() {
+        let raw = b"GET /echo/lf HTTP/1.1\nHost: localhost\n\n";
+
+        let lenient = TestClient::new(config(None));
+        let response = TestResponse::parse(&lenient.send_raw(raw).await);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "lf");
+
+        let strict = TestClient::new(ServerConfig {
+            parser: ParserConfig { line_endings: LineEndings::Strict, ..Default::default() },
+            ..config(None)
+        });
+        let response = TestResponse::parse(&strict.send_raw(raw).await);
+        assert_eq!(response.status, 400);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn too_many_header_fields_are_rejected() {
+        let client = TestClient::new(ServerConfig {
+            parser: ParserConfig { max_headers: 3, ..Default::default() },
+            ..config(None)
+        });
+
+        let at_limit = b"GET / HTTP/1.1\r\nHost: localhost\r\nA: 1\r\nB: 2\r\n\r\n";
+        assert_eq!(TestResponse::parse(&client.send_raw(at_limit).await).status, 200);
+
+        let over_limit = b"GET / HTTP/1.1\r\nHost: localhost\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert_eq!(TestResponse::parse(&client.send_raw(over_limit).await).status, 431);
+    }
+
+    #[tokio::test]
+    async fn oversized_header_blocks_are_rejected() {
+        let client = TestClient::new(ServerConfig {
+            parser: ParserConfig { max_header_size: 48, ..Default::default() },
+            ..config(None)
+        });
+
+        // 17 + 15 + 16 bytes of fields, each line short enough on its own
+        let at_limit = b"GET / HTTP/1.1\r\nHost: localhost\r\nA: 1234567890\r\nB: 12345678901\r\n\r\n";
+        assert_eq!(TestResponse::parse(&client.send_raw(at_limit).await).status, 200);
+
+        let over_limit = b"GET / HTTP/1.1\r\nHost: localhost\r\nA: 1234567890\r\nB: 123456789012\r\n\r\n";
+        let response = TestResponse::parse(&client.send_raw(over_limit).await);
+        assert_eq!(response.status, 431);
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn overlong_lines_are_rejected() {
+        let client = &TestClient::new(ServerConfig {
+            parser: ParserConfig { max_line_len: 32, ..Default::default() },
+            ..memory_config().0
+        });
+        let status = |raw: Vec<u8>| async move { TestResponse::parse(&client.send_raw(&raw).await).status };
+
+        let at_limit = format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", "a".repeat(13));
+        assert_eq!(status(at_limit.into_bytes()).await, 200);
+        let long_target = format!("GET /echo/{} HTTP/1.1\r\nHost: localhost\r\n\r\n", "a".repeat(14));
+        assert_eq!(status(long_target.into_bytes()).await, 414);
+        let long_field = format!("GET / HTTP/1.1\r\nHost: localhost\r\nX-Long: {}\r\n\r\n", "a".repeat(32));
+        assert_eq!(status(long_field.into_bytes()).await, 431);
+    }
+
+    #[tokio::test]
+    async fn binary_uploads_are_stored_as_sent() {
+        let (config, storage) = memory_config();
+        let client = TestClient::new(config);
+
+        let raw = b"PUT /files/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\n\r\n\xff\xfe\x00\x01";
+        assert_eq!(TestResponse::parse(&client.send_raw(raw).await).status, 201);
+        assert_eq!(storage.read("a").await.unwrap(), b"\xff\xfe\x00\x01");
+
+        let chunked = b"PUT /files/b HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n\x89P\r\n0\r\n\r\n";
+        assert_eq!(TestResponse::parse(&client.send_raw(chunked).await).status, 201);
+        assert_eq!(storage.read("b").await.unwrap(), b"\x89P");
+    }
+
+    #[tokio::test]
+    async fn chunked_framing_lines_are_bounded() {
+        let client = &TestClient::new(ServerConfig {
+            parser: ParserConfig { max_line_len: 32, max_header_size: 48, ..Default::default() },
+            ..memory_config().0
+        });
+        let status = |chunks: String| async move {
+            let raw = format!("POST /files/a HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{chunks}");
+            TestResponse::parse(&client.send_raw(raw.as_bytes()).await).status
+        };
+
+        assert_eq!(status(format!("1;{}\r\na\r\n0\r\n\r\n", "x".repeat(30))).await, 201);
+        assert_eq!(status(format!("1;{}\r\na\r\n0\r\n\r\n", "x".repeat(31))).await, 431);
+        assert_eq!(status(format!("1\r\na\r\n0\r\nX-Long: {}\r\n\r\n", "a".repeat(32))).await, 431);
+        // each trailer line fits, together they don't
+        let trailers = "X-A: 12345678901234567890\r\n".repeat(2);
+        assert_eq!(status(format!("1\r\na\r\n0\r\n{trailers}\r\n")).await, 431);
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let client = &TestClient::new(ServerConfig {
+            parser: ParserConfig { max_body_size: 8, ..Default::default() },
+            ..memory_config().0
+        });
+        let response = |raw: &'static [u8]| async move { TestResponse::parse(&client.send_raw(raw).await) };
+
+        assert_eq!(client.post("/files/a").body("12345678").send().await.status, 201);
+        let huge = response(b"POST /files/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10000000000\r\n\r\n").await;
+        assert_eq!(huge.status, 413);
+        assert_eq!(huge.header("Connection"), Some("close"));
+        let chunked = b"POST /files/a HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n";
+        assert_eq!(response(chunked).await.status, 413);
+    }
+
+    #[tokio::test]
+    async fn conflicting_content_length_is_a_bad_request() {
+        let client = TestClient::new(memory_config().0);
+        let raw = b"POST /files/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!";
+        assert_eq!(TestResponse::parse(&client.send_raw(raw).await).status, 400);
+    }
+
+    #[tokio::test]
+    async fn control_characters_in_headers_are_rejected() {
+        let client = TestClient::new(config(None));
+        for raw in [
+            &b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Nul: a\0b\r\n\r\n"[..],
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nX-Cr: a\rb\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\nBad Name: x\r\n\r\n",
+        ] {
+            let response = TestResponse::parse(&client.send_raw(raw).await);
+            assert_eq!(response.status, 400, "{:?}", String::from_utf8_lossy(raw));
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_close_is_echoed() {
+        let client = TestClient::new(config(None));
+        let cases: [(&[u8], Option<&str>); 4] = [
+            (b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", Some("close")),
+            (b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", None),
+            (b"GET / HTTP/1.0\r\n\r\n", Some("close")),
+            (b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n", Some("keep-alive")),
+        ];
+        for (raw, connection) in cases {
+            let response = TestResponse::parse(&client.send_raw(raw).await);
+            assert_eq!(response.header("Connection"), connection, "{:?}", String::from_utf8_lossy(raw));
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_are_kept_alive_between_requests() {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let service = Service::new(ServerConfig { clock: clock.clone(), ..config(None) });
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let handler = tokio::spawn(stream_handler(server, None, service));
+
+        // pipelined, the second answered after the first
+        client.write_all(b"GET /echo/a HTTP/1.1\r\nHost: localhost\r\n\r\nGET /echo/bc HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut responses = vec![0; 1024];
+        let mut read = 0;
+        while !String::from_utf8_lossy(&responses[..read]).ends_with("bc") {
+            read += client.read(&mut responses[read..]).await.unwrap();
+        }
+        let responses = String::from_utf8_lossy(&responses[..read]).into_owned();
+        assert_eq!(responses.matches("HTTP/1.1 200 Ok").count(), 2, "{responses}");
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!handler.is_finished());
+
+        clock.advance(Duration::from_secs(5));
+        handler.await.unwrap().unwrap();
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_need_exactly_one_host() {
+        let client = TestClient::new(config(None));
+        let status = |raw: &'static [u8]| {
+            let client = &client;
+            async move { TestResponse::parse(&client.send_raw(raw).await).status }
+        };
+
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await, 200);
+        assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n").await, 400);
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: localhost\r\nhost: evil.example\r\n\r\n").await, 400);
+    }
+
+    #[tokio::test]
+    async fn empty_responses_are_framed() {
+        let client = TestClient::new(config(None));
+        let mut connection = client.connect();
+        // neither response has a body, and the connection stays open after both
+        connection.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\nGET /nope HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut raw = vec![0; 1024];
+        let mut read = 0;
+        while String::from_utf8_lossy(&raw[..read]).matches("\r\n\r\n").count() < 2 {
+            read += connection.read(&mut raw[read..]).await.unwrap();
+        }
+        let raw = String::from_utf8_lossy(&raw[..read]).into_owned();
+        let (first, second) = raw.split_once("\r\n\r\n").unwrap();
+        assert!(first.starts_with("HTTP/1.1 200") && first.contains("Content-Length: 0"), "{first}");
+        assert!(second.starts_with("HTTP/1.1 404") && second.contains("Content-Length: 0"), "{second}");
+    }
+
+    #[tokio::test]
+    async fn events_are_pushed_as_they_happen() {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let client = TestClient::new(ServerConfig { clock: clock.clone(), ..config(None) });
+        let mut connection = client.connect();
+        connection.write_all(b"GET /events?count=2 HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+
+        let mut raw = vec![0; 1024];
+        let mut read = 0;
+        // the first event arrives before any time passes
+        while !String::from_utf8_lossy(&raw[..read]).contains("00:00:00.000Z\n\n") {
+            read += connection.read(&mut raw[read..]).await.unwrap();
+        }
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        clock.advance(Duration::from_secs(1));
+        let mut rest = Vec::new();
+        connection.read_to_end(&mut rest).await.unwrap();
+        raw.truncate(read);
+        raw.extend(rest);
+
+        let response = TestResponse::parse(&raw);
+        assert_eq!(response.header("Content-Type"), Some("text/event-stream"));
+        assert_eq!(response.header("Transfer-Encoding"), Some("chunked"));
+        let body = response.text();
+        assert!(body.contains("event: tick\nid: 1\ndata: 1970-01-01T00:00:00.000Z\n\n"), "{body}");
+        assert!(body.contains("event: tick\nid: 2\ndata: 1970-01-01T00:00:01.000Z\n\n"), "{body}");
+        assert!(body.ends_with("\r\n0\r\n\r\n"), "{body}");
+
+        assert_eq!(client.get("/events?count=many").send().await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn stalled_clients_are_dropped() {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let service = Service::new(ServerConfig {
+            clock: clock.clone(),
+            write_timeout: Duration::from_secs(10),
+            ..config(None)
+        });
+        // too small for the response, and the client never reads
+        let (mut client, server) = tokio::io::duplex(16);
+        let handler = tokio::spawn(stream_handler(server, None, service));
+
+        client.write_all(b"GET /echo/abc HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert!(!handler.is_finished());
+
+        clock.advance(Duration::from_secs(10));
+        let result = handler.await.unwrap();
+        assert!(result.unwrap_err().to_string().contains("dropping it"));
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out() {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let service = Service::new(ServerConfig {
+            clock: clock.clone(),
+            parser: ParserConfig {
+                header_timeout: Duration::from_secs(10),
+                body_timeout: Duration::from_secs(30),
+                ..Default::default()
+            },
+            ..config(None)
+        });
+        let timed_out = |partial: &'static [u8], after: u64| {
+            let (clock, service) = (clock.clone(), service.clone());
+            async move {
+                let (mut client, server) = tokio::io::duplex(64 * 1024);
+                let handler = tokio::spawn(stream_handler(server, None, service));
+                client.write_all(partial).await.unwrap();
+                for _ in 0..10 {
+                    tokio::task::yield_now().await;
+                }
+                clock.advance(Duration::from_secs(after - 1));
+                for _ in 0..10 {
+                    tokio::task::yield_now().await;
+                }
+                assert!(!handler.is_finished());
+                clock.advance(Duration::from_secs(1));
+                handler.await.unwrap().unwrap();
+                let mut response = Vec::new();
+                client.read_to_end(&mut response).await.unwrap();
+                response
+            }
+        };
+
+        let head = timed_out(b"GET / HTTP/1.1\r\nHost: local", 10).await;
+        assert_eq!(TestResponse::parse(&head).status, 408);
+        let body = timed_out(b"POST /echo/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhe", 30).await;
+        assert_eq!(TestResponse::parse(&body).status, 408);
+        // a connection that never sends a request is closed without an answer
+        assert!(timed_out(b"", 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn percent_encoded_file_names() {
+        let (config, storage) = memory_config();
+        let client = TestClient::new(config);
+
+        let response = client.post("/files/%C3%BCber%20notes.txt").body("hi").send().await;
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Location"), Some("/files/%C3%BCber%20notes.txt"));
+        assert_eq!(storage.read("über notes.txt").await.unwrap(), b"hi");
+
+        assert_eq!(client.get("/files/%C3%BCber%20notes.txt").send().await.text(), "hi");
+        assert_eq!(client.get("/files/..").send().await.status, 400);
+        assert_eq!(client.post("/files/a%2F..%2F..%2Fetc%2Fpasswd").body("x").send().await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn early_hints_precede_matching_responses() {
+        let client = TestClient::new(ServerConfig {
+            early_hints: vec![crate::server::EarlyHint {
+                pattern: "/echo/*".to_string(),
+                link: "</style.css>; rel=preload; as=style".to_string(),
+            }],
+            ..Default::default()
+        });
+
+        let raw = client.send_raw(b"GET /echo/hi HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let hints = TestResponse::parse(&raw);
+        assert_eq!(hints.status, 103);
+        assert_eq!(hints.header("Link"), Some("</style.css>; rel=preload; as=style"));
+        let response = TestResponse::parse(&hints.body);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "hi");
+
+        let raw = client.send_raw(b"GET /echo/hi HTTP/1.0\r\n\r\n").await;
+        assert_eq!(TestResponse::parse(&raw).status, 200);
+        let raw = client.send_raw(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(TestResponse::parse(&raw).status, 200);
+    }
+
+    #[tokio::test]
+    async fn file_digests_are_sent_and_checked() {
+        const HELLO_DIGEST: &str = "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:";
+        let client = TestClient::new(memory_config().0);
+
+        let response = client.post("/files/a").header("Content-Digest", HELLO_DIGEST).body("hello").send().await;
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Repr-Digest"), Some(HELLO_DIGEST));
+        assert_eq!(client.get("/files/a").send().await.header("Repr-Digest"), Some(HELLO_DIGEST));
+
+        let response = client.post("/files/b").header("Content-Digest", HELLO_DIGEST).body("tampered").send().await;
+        assert_eq!(response.status, 400);
+        assert_eq!(client.get("/files/b").send().await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn file_changes_honor_preconditions() {
+        let client = TestClient::new(memory_config().0);
+        assert_eq!(client.put("/files/doc").body("v1").send().await.status, 201);
+        let etag = client.get("/files/doc").send().await.header("ETag").unwrap().to_string();
+
+        let response = client.put("/files/doc").header("If-Match", &etag).body("v2").send().await;
+        assert_eq!(response.status, 204);
+        // a second writer still holding the first version loses
+        let response = client.put("/files/doc").header("If-Match", &etag).body("v3").send().await;
+        assert_eq!(response.status, 412);
+        assert_eq!(client.get("/files/doc").send().await.text(), "v2");
+
+        let response = client.delete("/files/doc").header("If-Unmodified-Since", "Thu, 01 Jan 1970 00:00:00 GMT").send().await;
+        assert_eq!(response.status, 412);
+        assert_eq!(client.put("/files/new").header("If-Match", "*").body("x").send().await.status, 412);
+
+        assert_eq!(client.delete("/files/doc").header("If-Match", "*").send().await.status, 204);
+        assert_eq!(client.delete("/files/doc").send().await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn deletes_are_opt_in() {
+        let client = TestClient::new(ServerConfig { allow_delete: false, ..memory_config().0 });
+        client.put("/files/kept").body("x").send().await;
+        let response = client.delete("/files/kept").send().await;
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, PUT, OPTIONS"));
+        assert_eq!(client.get("/files/kept").send().await.text(), "x");
+    }
+
+    #[tokio::test]
+    async fn metrics_count_requests_by_route() {
+        // counted by the connection loop, which the request-level API skips
+        let client = TestClient::new(ServerConfig { metrics: Some(Arc::default()), ..memory_config().0 });
+        client.send_raw(b"PUT /files/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello").await;
+        client.send_raw(b"GET /files/a HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        client.send_raw(b"GET /files/b HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        client.send_raw(b"HEAD /files/a HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+
+        let response = TestResponse::parse(&client.send_raw(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain; version=0.0.4"));
+        let text = response.text();
+        for series in [
+            r#"http_requests_total{method="PUT",route="/files/:name",status="201"} 1"#,
+            r#"http_requests_total{method="GET",route="/files/:name",status="200"} 1"#,
+            r#"http_requests_total{method="GET",route="/files/:name",status="404"} 1"#,
+            // answered by the GET route
+            r#"http_requests_total{method="HEAD",route="/files/:name",status="200"} 1"#,
+        ] {
+            assert!(text.contains(series), "{series} missing from {text}");
+        }
+        assert_eq!(TestClient::new(config(None)).get("/metrics").send().await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn stored_files_are_listed_with_autoindex() {
+        let (config, storage) = memory_config();
+        storage.write("a b.txt", b"hello").await.unwrap();
+        storage.write("<z>", b"").await.unwrap();
+        assert_eq!(TestClient::new(config.clone()).get("/files/").send().await.status, 404);
+
+        let client = TestClient::new(ServerConfig { autoindex: true, ..config });
+        let response = client.get("/files/").send().await;
+        assert_eq!(response.status, 200);
+        assert!(response.text().contains("<a href=\"/files/a%20b.txt\">a b.txt</a></td><td>5</td>"), "{}", response.text());
+        assert!(response.text().contains("&lt;z&gt;"));
+
+        let response = client.get("/files/").header("Accept", "application/json").send().await;
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        assert!(response.text().starts_with("[{\"name\":\"<z>\",\"directory\":false,\"size\":0,\"modified\":"), "{}", response.text());
+        assert!(response.text().contains("{\"name\":\"a b.txt\",\"directory\":false,\"size\":5,"));
+    }
+
+    #[tokio::test]
+    async fn weak_etags_only_satisfy_weak_comparisons() {
+        let client = TestClient::new(ServerConfig { etags: crate::etag::Strategy::Weak, ..memory_config().0 });
+        assert_eq!(client.put("/files/doc").body("v1").send().await.status, 201);
+        let etag = client.get("/files/doc").send().await.header("ETag").unwrap().to_string();
+        assert!(etag.starts_with("W/\""), "{etag}");
+
+        assert_eq!(client.get("/files/doc").header("If-None-Match", &etag).send().await.status, 304);
+        // If-Match compares strongly, which a weak tag never passes
+        assert_eq!(client.put("/files/doc").header("If-Match", &etag).body("v2").send().await.status, 412);
+        assert_eq!(client.put("/files/doc").header("If-None-Match", "*").body("v2").send().await.status, 412);
+        assert_eq!(client.put("/files/fresh").header("If-None-Match", "*").body("v1").send().await.status, 201);
+    }
+
+    #[tokio::test]
+    async fn unmodified_files_are_not_sent_again() {
+        let client = TestClient::new(memory_config().0);
+        client.put("/files/doc").body("v1").send().await;
+        let response = client.get("/files/doc").send().await;
+        let last_modified = response.header("Last-Modified").unwrap().to_string();
+
+        let response = client.get("/files/doc").header("If-Modified-Since", &last_modified).send().await;
+        assert_eq!((response.status, response.body.len()), (304, 0));
+        let response = client.get("/files/doc").header("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT").send().await;
+        assert_eq!(response.text(), "v1");
+        // a date that doesn't parse is ignored
+        assert_eq!(client.get("/files/doc").header("If-Modified-Since", "yesterday").send().await.status, 200);
+        // If-None-Match takes precedence
+        let response = client.get("/files/doc").header("If-Modified-Since", &last_modified).header("If-None-Match", "\"other\"").send().await;
+        assert_eq!(response.status, 200);
+    }
+
+    #[tokio::test]
+    async fn signed_links_grant_downloads() {
+        let signer = crate::signed_url::UrlSigner::new("secret", true);
+        let (config, _) = memory_config();
+        let client = TestClient::new(ServerConfig { url_signer: Some(signer.clone()), ..config });
+        client.put("/files/report").body("numbers").send().await;
+
+        assert_eq!(client.get("/files/report").send().await.status, 403);
+        let link = signer.sign("/files/report", SystemTime::now() + Duration::from_secs(60));
+        assert_eq!(client.get(&link).send().await.text(), "numbers");
+        let expired = signer.sign("/files/report", SystemTime::now() - Duration::from_secs(1));
+        assert_eq!(client.get(&expired).send().await.status, 403);
+    }
+
+    #[tokio::test]
+    async fn kv_values_live_until_their_ttl() {
+        let clock = Arc::new(MockClock::new(UNIX_EPOCH));
+        let client = TestClient::new(ServerConfig {
+            clock: clock.clone(),
+            kv: Some(Arc::new(crate::kv::KvStore::new(1024))),
+            ..Default::default()
+        });
+        assert_eq!(client.put("/kv/token").header("Kv-Ttl", "30").body("abc").send().await.status, 201);
+        assert_eq!(client.put("/kv/name").body("x").send().await.status, 201);
+        assert_eq!(client.put("/kv/name").body("y").send().await.status, 204);
+        assert_eq!(client.get("/kv/token").send().await.text(), "abc");
+
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(client.get("/kv/token").send().await.status, 404);
+        assert_eq!(client.delete("/kv/name").send().await.status, 204);
+        assert_eq!(client.get("/kv/name").send().await.status, 404);
+        assert_eq!(client.put("/kv/big").body("x".repeat(2000)).send().await.status, 413);
+    }
+
+    #[tokio::test]
+    async fn sessions_follow_their_cookie() {
+        let sessions = crate::session::Sessions::new("key", Duration::from_secs(60), Arc::new(crate::session::MemorySessionStore::default()));
+        let client = TestClient::new(ServerConfig { sessions: Some(sessions), ..Default::default() });
+        assert_eq!(client.get("/").send().await.header("Set-Cookie"), None);
+
+        let response = client.put("/session/cart").body("3 apples").send().await;
+        let cookie = response.header("Set-Cookie").unwrap().split(';').next().unwrap().to_string();
+        assert_eq!(client.get("/session/cart").header("Cookie", &cookie).send().await.text(), "3 apples");
+        assert_eq!(client.get("/session/cart").send().await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn bodies_are_validated_before_handling() {
+        let client = TestClient::new(ServerConfig {
+            kv: Some(Arc::new(crate::kv::KvStore::new(1024))),
+            body_validators: vec![crate::validate::BodyValidator::parse("/kv/*=max-size:32,content-type:application/json,required:id").unwrap()],
+            ..Default::default()
+        });
+        let put = |body: &str| client.put("/kv/item").header("Content-Type", "application/json").body(body);
+
+        let response = put(r#"{"name": "x"}"#).send().await;
+        assert_eq!(response.status, 422);
+        assert_eq!(response.header("Content-Type"), Some("application/json"));
+        assert_eq!(response.text(), r#"{"status":422,"error":"body has no \"id\" field"}"#);
+        assert_eq!(put("{").send().await.status, 400);
+        assert_eq!(put(&format!(r#"{{"id": "{}"}}"#, "x".repeat(32))).send().await.status, 413);
+        assert_eq!(client.put("/kv/item").body(r#"{"id": 1}"#).send().await.status, 415);
+        assert_eq!(put(r#"{"id": 1}"#).send().await.status, 201);
+    }
+
+    #[tokio::test]
+    async fn maintenance_spares_health_checks() {
+        let maintenance = Arc::new(crate::maintenance::Maintenance::new(
+            true,
+            Duration::from_secs(120),
+            Some("<p>back soon</p>".to_string()),
+        ));
+        let client = TestClient::new(ServerConfig { maintenance: maintenance.clone(), ..Default::default() });
+
+        let response = client.get("/echo/hi").send().await;
+        assert_eq!(response.status, 503);
+        assert_eq!(response.header("Retry-After"), Some("120"));
+        assert_eq!(response.text(), "<p>back soon</p>");
+        assert_eq!(client.get("/health").send().await.status, 200);
+        assert_eq!(client.get("/healthz").send().await.status, 200);
+        assert_eq!(client.get("/readyz").send().await.status, 503);
+
+        assert!(!maintenance.toggle());
+        assert_eq!(client.get("/echo/hi").send().await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn files_need_credentials_with_auth() {
+        let client = TestClient::new(ServerConfig {
+            basic_auth: Some(crate::basic_auth::BasicAuth::new(vec!["alice:secret".to_string()]).unwrap()),
+            ..memory_config().0
+        });
+        let response = client.put("/files/a").body("hello").send().await;
+        assert_eq!(response.status, 401);
+        assert_eq!(response.header("WWW-Authenticate"), Some(r#"Basic realm="files", charset="UTF-8""#));
+
+        // alice:secret
+        let credentials = "Basic YWxpY2U6c2VjcmV0";
+        assert_eq!(client.put("/files/a").header("Authorization", credentials).body("hello").send().await.status, 201);
+        assert_eq!(client.get("/files/a").header("Authorization", credentials).send().await.text(), "hello");
+        let wrong = client.get("/files/a").header("Authorization", "Basic YWxpY2U6d3Jvbmc=").send().await;
+        assert_eq!(wrong.status, 401);
+        assert_eq!(client.get("/echo/hi").send().await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn signed_links_stand_in_for_credentials() {
+        let signer = crate::signed_url::UrlSigner::new("secret", false);
+        let client = TestClient::new(ServerConfig {
+            basic_auth: Some(crate::basic_auth::BasicAuth::new(vec!["alice:secret".to_string()]).unwrap()),
+            url_signer: Some(signer.clone()),
+            ..memory_config().0
+        });
+        client.put("/files/report").header("Authorization", "Basic YWxpY2U6c2VjcmV0").body("numbers").send().await;
+
+        let link = signer.sign("/files/report", SystemTime::now() + Duration::from_secs(60));
+        assert_eq!(client.get(&link).send().await.text(), "numbers");
+        let expired = signer.sign("/files/report", SystemTime::now() - Duration::from_secs(1));
+        assert_eq!(client.get(&expired).send().await.status, 401);
+        assert_eq!(client.put(&link).body("overwritten").send().await.status, 401);
+    }
+
+    #[tokio::test]
+    async fn credentials_are_checked_before_the_body_is_read() {
+        let client = TestClient::new(ServerConfig {
+            basic_auth: Some(crate::basic_auth::BasicAuth::new(vec!["alice:secret".to_string()]).unwrap()),
+            ..memory_config().0
+        });
+        // the body never arrives; reading it would end the connection without an answer
+        let raw = b"PUT /files/a HTTP/1.1\r\nHost: localhost\r\nContent-Length: 1000000\r\n\r\nhello";
+        let response = TestResponse::parse(&client.send_raw(raw).await);
+        assert_eq!(response.status, 401);
+        assert_eq!(response.header("WWW-Authenticate"), Some(r#"Basic realm="files", charset="UTF-8""#));
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+
+    #[tokio::test]
+    async fn readiness_needs_readable_storage() {
+        assert_eq!(TestClient::new(config(None)).get("/readyz").send().await.status, 200);
+
+        let dir = temp_dir("readyz");
+        let client = TestClient::new(config(Some(dir.display().to_string())));
+        let response = client.get("/readyz").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "ready");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        let response = client.get("/readyz").send().await;
+        assert_eq!(response.status, 503);
+        assert!(response.text().starts_with("storage unavailable"), "{}", response.text());
+        assert_eq!(client.get("/healthz").send().await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn header_rules_apply_to_matching_paths() {
+        let client = TestClient::new(ServerConfig {
+            header_rules: vec![
+                crate::server::HeaderRule::parse("/echo/*=Access-Control-Allow-Origin: *").unwrap(),
+                crate::server::HeaderRule::parse("*=X-Served-By:  web1 ").unwrap(),
+            ],
+            ..Default::default()
+        });
+
+        let response = client.get("/echo/hi").send().await;
+        assert_eq!(response.header("Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(response.header("X-Served-By"), Some("web1"));
+        let response = client.get("/nothing-here").send().await;
+        assert_eq!(response.header("Access-Control-Allow-Origin"), None);
+        assert_eq!(response.header("X-Served-By"), Some("web1"));
+
+        assert!(crate::server::HeaderRule::parse("*=Bad Name: x").is_err());
+        assert!(crate::server::HeaderRule::parse("*=X-Missing-Colon").is_err());
+    }
+
+    #[tokio::test]
+    async fn large_uploads_are_spooled() {
+        let spool_dir = temp_dir("spool");
+        let (config, storage) = memory_config();
+        let client = TestClient::new(ServerConfig {
+            parser: ParserConfig { spool_threshold: 4, spool_dir: spool_dir.clone(), ..Default::default() },
+            ..config
+        });
+
+        // binary, as uploads may be
+        let body = [0xff, 0xfe, b'b', b'i', b'n', b'a', b'r', b'y'];
+        let mut raw = b"POST /files/blob HTTP/1.1\r\nHost: localhost\r\nContent-Length: 8\r\n\r\n".to_vec();
+        raw.extend_from_slice(&body);
+        let response = TestResponse::parse(&client.send_raw(&raw).await);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("Repr-Digest").map(str::to_string), Some(crate::digest::header_value(&crate::digest::sha256(&body))));
+        assert_eq!(storage.read("blob").await.unwrap(), body);
+        assert_eq!(std::fs::read_dir(&spool_dir).unwrap().count(), 0, "spool file left behind");
+    }
+
+    #[tokio::test]
+    async fn unknown_methods_are_not_implemented() {
+        let client = TestClient::new(config(None));
+        let response = TestResponse::parse(&client.send_raw(b"PATCH /files/a HTTP/1.1\r\nHost: localhost\r\n\r\n").await);
+        assert_eq!(response.status, 501);
+
+        let raw = client.send_raw(b"HEAD /echo/abc HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        let response = TestResponse::parse(&raw);
+        assert_eq!((response.status, response.header("Content-Length")), (200, Some("3")));
+        assert!(response.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn chunked_uploads_are_decoded() {
+        let spool_dir = temp_dir("chunked-spool");
+        let (config, storage) = memory_config();
+        let client = TestClient::new(ServerConfig {
+            parser: ParserConfig { spool_threshold: 8, spool_dir: spool_dir.clone(), ..Default::default() },
+            ..config
+        });
+        let upload = |name: &str, chunks: &str| {
+            format!("POST /files/{name} HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n{chunks}").into_bytes()
+        };
+
+        let raw = upload("small", "4;ext=1\r\nhell\r\n1\r\no\r\n0\r\nX-Trailer: t\r\n\r\n");
+        assert_eq!(TestResponse::parse(&client.send_raw(&raw).await).status, 201);
+        assert_eq!(storage.read("small").await.unwrap(), b"hello");
+
+        // grows past the threshold halfway
+        let raw = upload("large", "5\r\nhello\r\nA\r\n, world!!!\r\n0\r\n\r\n");
+        assert_eq!(TestResponse::parse(&client.send_raw(&raw).await).status, 201);
+        assert_eq!(storage.read("large").await.unwrap(), b"hello, world!!!");
+        assert_eq!(std::fs::read_dir(&spool_dir).unwrap().count(), 0, "spool file left behind");
+
+        for (raw, status) in [
+            (upload("bad", "zz\r\nhello\r\n0\r\n\r\n"), 400),
+            (upload("bad", "2\r\nhello\r\n0\r\n\r\n"), 400),
+            (b"POST /files/bad HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\n".to_vec(), 400),
+            (b"POST /files/bad HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n".to_vec(), 501),
+        ] {
+            assert_eq!(TestResponse::parse(&client.send_raw(&raw).await).status, status, "{}", String::from_utf8_lossy(&raw));
+        }
+        assert!(storage.read("bad").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn favicon_and_robots_txt_are_built_in() {
+        let client = TestClient::new(Default::default());
+        assert_eq!(client.get("/favicon.ico").send().await.status, 204);
+        assert_eq!(client.get("/robots.txt").send().await.text(), "User-agent: *\nDisallow:\n");
+
+        let client = TestClient::new(ServerConfig {
+            favicon: Some(vec![0, 0, 1, 0]),
+            robots_txt: "User-agent: *\nDisallow: /files/\n".to_string(),
+            ..Default::default()
+        });
+        let response = client.get("/favicon.ico").send().await;
+        assert_eq!(response.header("Content-Type"), Some("image/x-icon"));
+        assert_eq!(response.body, [0, 0, 1, 0]);
+        assert_eq!(client.get("/robots.txt").send().await.text(), "User-agent: *\nDisallow: /files/\n");
+    }
+
+    #[tokio::test]
+    async fn precompressed_variants_are_served_to_gzip_clients() {
+        let root = temp_dir("precompressed");
+        std::fs::write(root.join("page.html"), "<p>hello</p>".repeat(200)).unwrap();
+        let precompressed = crate::precompress::Precompressed::new(&root, root.join(".gz"), 100);
+        precompressed.build_all().unwrap();
+        let client = TestClient::new(ServerConfig {
+            storage: Some(Arc::new(LocalStorage::new(&root))),
+            precompressed: Some(precompressed),
+            ..Default::default()
+        });
+
+        let response = client.get("/files/page.html").header("Accept-Encoding", "br, gzip;q=0.5").send().await;
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert!(response.body.len() < 2400);
+
+        let response = client.get("/files/page.html").header("Accept-Encoding", "gzip;q=0").send().await;
+        assert_eq!(response.header("Content-Encoding"), None);
+        assert_eq!(response.body.len(), 2400);
+    }
+
+    #[tokio::test]
+    async fn responses_are_gzipped_for_clients_accepting_it() {
+        use std::io::Read;
+
+        let gunzip = |body: &[u8]| {
+            let mut text = String::new();
+            flate2::read::GzDecoder::new(body).read_to_string(&mut text).unwrap();
+            text
+        };
+        let client = TestClient::new(memory_config().0);
+        client.put("/files/notes.txt").body("notes ".repeat(100)).send().await;
+        client.put("/files/photo.jpg").body("jpeg").send().await;
+
+        let response = client.get("/echo/abc").header("Accept-Encoding", "invalid, gzip").send().await;
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.header("Content-Length"), Some(response.body.len().to_string().as_str()));
+        assert_eq!(gunzip(&response.body), "abc");
+        let response = client.get("/echo/abc").header("Accept-Encoding", "invalid").send().await;
+        assert_eq!((response.header("Content-Encoding"), response.text()), (None, "abc"));
+
+        let response = client.get("/files/notes.txt").header("Accept-Encoding", "gzip").send().await;
+        assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+        assert!(response.body.len() < 600);
+        assert_eq!(gunzip(&response.body), "notes ".repeat(100));
+        let head = client.head("/files/notes.txt").header("Accept-Encoding", "gzip").send().await;
+        assert_eq!(head.header("Content-Length"), Some(response.body.len().to_string().as_str()));
+
+        // already compressed
+        let response = client.get("/files/photo.jpg").header("Accept-Encoding", "gzip").send().await;
+        assert_eq!((response.header("Content-Encoding"), response.text()), (None, "jpeg"));
+    }
+
+    #[tokio::test]
+    async fn file_downloads_are_streamed_with_their_length() {
+        let (config, storage) = memory_config();
+        let client = TestClient::new(config);
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        storage.write("big.bin", &content).await.unwrap();
+
+        let raw = client.send_raw(b"GET /files/big.bin HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        let response = TestResponse::parse(&raw);
+        assert_eq!(response.header("Content-Length"), Some("200000"));
+        assert_eq!(response.header("Transfer-Encoding"), None);
+        assert!(response.body == content);
+
+        let raw = client.send_raw(b"GET /files/big.bin HTTP/1.1\r\nHost: localhost\r\nRange: bytes=150000-150009\r\n\r\n").await;
+        let response = TestResponse::parse(&raw);
+        assert_eq!((response.status, response.header("Content-Length")), (206, Some("10")));
+        assert_eq!(response.body, content[150_000..150_010]);
+    }
+
+    #[tokio::test]
+    async fn file_ranges() {
+        let client = TestClient::new(memory_config().0);
+        client.put("/files/digits").body("0123456789").send().await;
+
+        let response = client.get("/files/digits").header("Range", "bytes=2-4").send().await;
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), Some("bytes 2-4/10"));
+        assert_eq!(response.text(), "234");
+
+        let response = client.get("/files/digits").header("Range", "bytes=10-").send().await;
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+
+        let response = client.get("/files/digits").header("Range", "bytes=4-2").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "0123456789");
+
+        // the client's copy is of another version, so it gets all of this one
+        let response = client.get("/files/digits").header("Range", "bytes=-2").header("If-Range", "\"stale\"").send().await;
+        assert_eq!(response.status, 200);
+    }
+
+    #[tokio::test]
+    async fn several_ranges_get_a_multipart_response() {
+        let client = TestClient::new(memory_config().0);
+        client.put("/files/digits").body("0123456789").send().await;
+
+        let raw = client.send_raw(b"GET /files/digits HTTP/1.1\r\nHost: localhost\r\nRange: bytes=6-7, 0-1\r\n\r\n").await;
+        let response = TestResponse::parse(&raw);
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("Content-Range"), None);
+        let boundary = response.header("Content-Type").and_then(|value| value.strip_prefix("multipart/byteranges; boundary=")).unwrap();
+        assert_eq!(response.header("Content-Length"), Some(response.body.len().to_string().as_str()));
+        assert_eq!(response.text(), format!(
+            "--{boundary}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{boundary}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 6-7/10\r\n\r\n67\r\n\
+             --{boundary}--\r\n"
+        ));
+    }
+
+    #[tokio::test]
+    async fn content_addressed_uploads() {
+        let client = TestClient::new(ServerConfig { content_addressed: true, ..memory_config().0 });
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        let response = client.post("/files").body("hello").send().await;
+        assert_eq!(response.status, 201);
+        assert_eq!(response.text(), hash);
+        assert_eq!(response.header("Location"), Some(format!("/files/{hash}").as_str()));
+        assert_eq!(client.post("/files").body("hello").send().await.status, 200);
+
+        let response = client.get(&format!("/files/{hash}")).send().await;
+        assert_eq!(response.text(), "hello");
+        assert_eq!(response.header("Cache-Control"), Some("public, max-age=31536000, immutable"));
+        // a name that isn't the content's hash promises nothing
+        client.put("/files/greeting").body("hello").send().await;
+        assert_eq!(client.get("/files/greeting").send().await.header("Cache-Control"), None);
+
+        assert_eq!(TestClient::new(memory_config().0).post("/files").body("hello").send().await.status, 404);
+    }
+
+    /// Memory storage whose streamed writes notify `started`, then wait until `release` is
+    /// notified.
+    #[derive(Debug, Default)]
+    struct StalledStorage {
+        inner: MemoryStorage,
+        started: tokio::sync::Notify,
+        release: tokio::sync::Notify,
+    }
+
+    impl Storage for StalledStorage {
+        fn open<'a>(&'a self, name: &'a str) -> BoxFuture<'a, std::io::Result<BoxReader>> {
+            self.inner.open(name)
+        }
+
+        fn read<'a>(&'a self, name: &'a str) -> BoxFuture<'a, std::io::Result<Vec<u8>>> {
+            self.inner.read(name)
+        }
+
+        fn write<'a>(&'a self, name: &'a str, content: &'a [u8]) -> BoxFuture<'a, std::io::Result<()>> {
+            self.inner.write(name, content)
+        }
+
+        fn write_stream<'a>(&'a self, name: &'a str, reader: BoxReader) -> BoxFuture<'a, std::io::Result<()>> {
+            Box::pin(async move {
+                self.started.notify_one();
+                self.release.notified().await;
+                self.inner.write_stream(name, reader).await
+            })
+        }
+
+        fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, std::io::Result<()>> {
+            self.inner.delete(name)
+        }
+
+        fn rename<'a>(&'a self, from: &'a str, to: &'a str) -> BoxFuture<'a, std::io::Result<()>> {
+            self.inner.rename(from, to)
+        }
+
+        fn metadata<'a>(&'a self, name: &'a str) -> BoxFuture<'a, std::io::Result<crate::storage::Metadata>> {
+            self.inner.metadata(name)
+        }
+
+        fn list<'a>(&'a self, name: &'a str) -> BoxFuture<'a, std::io::Result<Vec<String>>> {
+            self.inner.list(name)
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_uploads_hold_up_no_one() {
+        let storage = Arc::new(StalledStorage::default());
+        let client = Arc::new(TestClient::new(ServerConfig {
+            storage: Some(storage.clone()),
+            parser: ParserConfig { spool_threshold: 4, spool_dir: temp_dir("stalled-spool"), ..Default::default() },
+            ..Default::default()
+        }));
+
+        let slow = tokio::spawn({
+            let client = client.clone();
+            async move { client.send_raw(b"PUT /files/slow HTTP/1.1\r\nHost: localhost\r\nContent-Length: 8\r\n\r\nlarge!!!").await }
+        });
+        storage.started.notified().await;
+        // answered while the slow upload is still being written
+        let response = tokio::time::timeout(Duration::from_secs(5), client.put("/files/quick").body("hi").send()).await;
+        assert_eq!(response.expect("blocked behind the slow upload").status, 201);
+
+        storage.release.notify_one();
+        assert_eq!(TestResponse::parse(&slow.await.unwrap()).status, 201);
+        assert_eq!(storage.inner.read("slow").await.unwrap(), b"large!!!");
+        assert_eq!(storage.inner.list("").await.unwrap(), ["quick", "slow"], "staged upload left behind");
+    }
+
+    #[tokio::test]
+    async fn bodiless_uploads_need_a_length() {
+        let client = TestClient::new(ServerConfig { content_addressed: true, ..memory_config().0 });
+        assert_eq!(client.put("/files/a").send().await.status, 411);
+        assert_eq!(client.post("/files").send().await.status, 411);
+        let raw = client.send_raw(b"POST /files/a HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(TestResponse::parse(&raw).status, 411);
+        // an empty body is still a body
+        assert_eq!(client.put("/files/a").body("").send().await.status, 201);
+    }
+
+    #[tokio::test]
+    async fn large_content_addressed_uploads_are_spooled() {
+        let spool_dir = temp_dir("hash-spool");
+        let (config, storage) = memory_config();
+        let client = TestClient::new(ServerConfig {
+            content_addressed: true,
+            parser: ParserConfig { spool_threshold: 4, spool_dir: spool_dir.clone(), ..Default::default() },
+            ..config
+        });
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+        let raw = client.send_raw(b"POST /files HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello").await;
+        let response = TestResponse::parse(&raw);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.text(), hash);
+        assert_eq!(storage.read(hash).await.unwrap(), b"hello");
+        assert_eq!(std::fs::read_dir(&spool_dir).unwrap().count(), 0, "spool file left behind");
+    }
+
+    #[tokio::test]
+    async fn options_asterisk_lists_server_methods() {
+        let client = TestClient::new(memory_config().0);
+        let raw = client.send_raw(b"OPTIONS * HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+        let response = TestResponse::parse(&raw);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, PUT, DELETE, OPTIONS"));
+        assert_eq!(response.header("Content-Length"), Some("0"));
+
+        assert_eq!(TestClient::new(ServerConfig::default()).options("*").send().await.header("Allow"), Some("GET, HEAD, OPTIONS"));
+        // a router of its own is asked, not the built-in routes
+        let mut router = crate::router::Router::default();
+        router.put("/things/:id", |_| unreachable!());
+        let custom = TestClient { service: Service::with_router(memory_config().0, router) };
+        assert_eq!(custom.options("*").send().await.header("Allow"), Some("PUT, OPTIONS"));
+        assert_eq!(client.get("*").send().await.status, 400);
+    }
+
+    #[tokio::test]
+    async fn wrong_methods_are_told_what_is_allowed() {
+        let client = TestClient::new(memory_config().0);
+        let response = client.post("/echo/hi").body("x").send().await;
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, OPTIONS"));
+
+        let response = client.options("/files/notes.txt").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Allow"), Some("GET, HEAD, POST, PUT, DELETE, OPTIONS"));
+        assert_eq!(client.options("/nowhere").send().await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn posts_may_override_allowed_methods() {
+        let (config, storage) = memory_config();
+        let client = TestClient::new(ServerConfig { method_overrides: vec![HttpMethod::Delete], ..config });
+        client.put("/files/doomed").body("x").send().await;
+
+        let response = client.post("/files/doomed").header("X-HTTP-Method-Override", "PUT").body("y").send().await;
+        // PUT isn't allowed, so that was an ordinary upload
+        assert_eq!(response.status, 201);
+        let response = client.post("/files/doomed")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("_method=delete")
+            .send().await;
+        assert_eq!(response.status, 204);
+        assert!(storage.read("doomed").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn head_is_get_without_the_body() {
+        let client = TestClient::new(memory_config().0);
+        client.put("/files/page").body("0123456789").send().await;
+        let get = client.get("/files/page").send().await;
+        let head = client.head("/files/page").send().await;
+        assert_eq!(head.status, 200);
+        assert_eq!(head.header("Content-Length"), Some("10"));
+        assert_eq!(head.header("ETag"), get.header("ETag"));
+        assert!(head.body.is_empty());
+
+        let missing = client.head("/files/missing").send().await;
+        assert_eq!(missing.status, 404);
+        assert!(missing.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_route_is_not_found() {
+        let client = TestClient::new(config(None));
+        let response = client.get("/nope").send().await;
+        assert_eq!(response.status, 404);
+
+        let response = client.get("/nope").header("Accept", "text/html").send().await;
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn files_cannot_escape_the_directory() {
+        let dir = temp_dir("escape");
+        let outside = temp_dir("escape-outside");
+        std::fs::write(outside.join("secret"), "secret").unwrap();
+        std::os::unix::fs::symlink(outside.join("secret"), dir.join("link")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("out")).unwrap();
+        let client = TestClient::new(config(Some(dir.display().to_string())));
+
+        assert_eq!(client.get("/files/..%2F..%2Fetc%2Fpasswd").send().await.status, 400);
+        assert_eq!(client.get("/files/..").send().await.status, 400);
+        assert_eq!(client.get("/files/link").send().await.status, 403);
+        assert_eq!(client.put("/files/link").body("overwritten").send().await.status, 403);
+        assert_eq!(std::fs::read_to_string(outside.join("secret")).unwrap(), "secret");
+
+        let storage = LocalStorage::new(&dir);
+        assert_eq!(storage.read("../secret").await.unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        assert_eq!(storage.write("out/planted", b"x").await.unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(!outside.join("planted").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[tokio::test]
+    async fn files_round_trip() {
+        let dir = temp_dir("files");
+        let client = TestClient::new(config(Some(dir.display().to_string())));
+
+        let response = client.post("/files/hello.txt").body("hello world").send().await;
+        assert_eq!(response.status, 201);
+        assert_eq!(std::fs::read_to_string(dir.join("hello.txt")).unwrap(), "hello world");
+
+        let response = client.get("/files/hello.txt").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(response.text(), "hello world");
+
+        let response = client.get("/files/missing.txt").send().await;
+        assert_eq!(response.status, 404);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn files_in_memory() {
+        let (config, storage) = memory_config();
+        storage.write("notes.txt", b"\x00binary\xff").await.unwrap();
+        let client = TestClient::new(config);
+
+        let response = client.get("/files/notes.txt").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"\x00binary\xff");
+
+        let response = client.post("/files/new.txt").body("fresh").send().await;
+        assert_eq!(response.status, 201);
+        assert_eq!(storage.read("new.txt").await.unwrap(), b"fresh");
+
+        let response = client.get("/files/missing.txt").send().await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[tokio::test]
+    async fn files_without_directory_are_not_found() {
+        let client = TestClient::new(config(None));
+        let response = client.get("/files/hello.txt").send().await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[tokio::test]
+    async fn raw_request_over_duplex() {
+        let client = TestClient::new(ServerConfig {
+            clock: Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(784_111_777))),
+            ..Default::default()
+        });
+        let raw = client.send_raw(b"GET /echo/abc HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert_eq!(
+            String::from_utf8(raw).unwrap(),
+            "HTTP/1.1 200 Ok\r\nVary: Accept-Encoding\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\nabc"
+        );
+    }
+
+    #[tokio::test]
+    async fn request_body_is_framed_by_content_length() {
+        let dir = temp_dir("duplex");
+        let client = TestClient::new(config(Some(dir.display().to_string())));
+
+        let raw = client.send_raw(
+            b"POST /files/upload.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello"
+        ).await;
+        assert_eq!(TestResponse::parse(&raw).status, 201);
+        assert_eq!(std::fs::read_to_string(dir.join("upload.txt")).unwrap(), "hello");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn recorded_requests_replay_to_the_same_responses() {
+        let dir = temp_dir("record");
+        let client = TestClient::new(ServerConfig {
+            clock: Arc::new(MockClock::new(UNIX_EPOCH)),
+            record: Some(crate::record::RecordConfig { directory: dir.clone(), responses: true }),
+            ..Default::default()
+        });
+
+        let first = b"GET /echo/abc HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let second = b"GET /user-agent HTTP/1.1\r\nHost: localhost\r\nUser-Agent: curl/8.0\r\n\r\n";
+        let raw = client.send_raw(&[&first[..], &second[..]].concat()).await;
+        assert_eq!(String::from_utf8_lossy(&raw).matches("HTTP/1.1 200 Ok").count(), 2);
+
+        let mut recorded: Vec<_> = std::fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        recorded.sort();
+        assert_eq!(recorded.len(), 4, "{recorded:?}");
+        // one request per file, even though both arrived in the same read
+        assert_eq!(std::fs::read(&recorded[0]).unwrap(), first);
+        assert_eq!(std::fs::read(&recorded[2]).unwrap(), second);
+
+        crate::record::replay(&client.service, &dir).await.unwrap();
+
+        let response = recorded.iter().find(|path| path.to_string_lossy().ends_with(".response.http")).unwrap();
+        std::fs::write(response, b"HTTP/1.1 500 Internal Server Error\r\n\r\n").unwrap();
+        assert!(crate::record::replay(&client.service, &dir).await.is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
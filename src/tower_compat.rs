@@ -0,0 +1,160 @@
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tower::limit::ConcurrencyLimitLayer;
+use tower::timeout::TimeoutLayer;
+use tower::util::BoxCloneService;
+use tower::{BoxError, ServiceBuilder, ServiceExt};
+
+use crate::storage::BoxFuture;
+use crate::log::log_error;
+use crate::{Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode, Service};
+
+pub type HttpService = BoxCloneService<http::Request<Bytes>, http::Response<Bytes>, BoxError>;
+
+/// The tower middleware the server knows how to configure from the command line.
+#[derive(Debug, Clone, Default)]
+pub struct TowerLayers {
+    pub timeout: Option<Duration>,
+    pub concurrency_limit: Option<usize>,
+}
+
+/// The request pipeline as a `tower::Service`, so tower middleware can wrap it.
+impl tower::Service<http::Request<Bytes>> for Service {
+    type Response = http::Response<Bytes>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Bytes>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move {
+            let request = HttpRequest::try_from(request)?;
+            // streams and upgrades pass through the stack as they are, see `http_compat`
+            let response = service.handle(&request).await;
+            Ok(http::Response::try_from(response)?)
+        })
+    }
+}
+
+pub fn layered(service: Service, layers: &TowerLayers) -> HttpService {
+    let stack = ServiceBuilder::new()
+        .option_layer(layers.concurrency_limit.map(ConcurrencyLimitLayer::new))
+        .option_layer(layers.timeout.map(TimeoutLayer::new))
+        .service(service);
+    BoxCloneService::new(stack)
+}
+
+/// Runs `request` through the middleware stack, turning middleware errors into responses.
+pub async fn call(stack: &Mutex<HttpService>, request: &HttpRequest) -> HttpResponseBuilder {
+    let failure = |status_code| HttpResponseBuilder {
+        status_code,
+        version: request.version.clone(),
+        headers: HeaderMap::new(),
+        content: Content::Empty,
+    };
+
+    let http_request = match http::Request::try_from(request.clone()) {
+        Ok(http_request) => http_request,
+        Err(err) => {
+            log_error!("request can't be passed to tower middleware, error: {err:#}");
+            return failure(HttpStatusCode::InternalError500);
+        }
+    };
+
+    // the stack isn't Sync, every call works on its own clone like tower intends
+    let stack = stack.lock().unwrap().clone();
+    match stack.oneshot(http_request).await {
+        Ok(response) => response.into(),
+        Err(err) if err.is::<tower::timeout::error::Elapsed>() => {
+            log_error!("{} {} timed out", request.method.as_str(), request.route);
+            failure(HttpStatusCode::Other(503, "Service Unavailable".to_string()))
+        }
+        Err(err) => {
+            log_error!("tower middleware failed, error: {err}");
+            failure(HttpStatusCode::InternalError500)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::request::{parse_http_request, ParserConfig};
+    use crate::router::Router;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::test_client::{temp_dir, TestClient, TestResponse};
+    use crate::{forwarded, ServerConfig};
+
+    fn layers() -> TowerLayers {
+        TowerLayers { timeout: Some(Duration::from_secs(5)), concurrency_limit: None }
+    }
+
+    #[tokio::test]
+    async fn layered_stack_serves_http_requests() {
+        let layers = TowerLayers { timeout: Some(Duration::from_secs(5)), concurrency_limit: Some(1) };
+        let stack = layered(Service::new(ServerConfig::default()), &layers);
+
+        let request = http::Request::get("/echo/tower").header("Host", "localhost").body(Bytes::new()).unwrap();
+        let response = stack.oneshot(request).await.unwrap();
+
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.body().as_ref(), b"tower");
+    }
+
+    #[tokio::test]
+    async fn requests_and_responses_keep_what_bytes_cant_hold() {
+        let mut router = Router::default();
+        router.get("/stream", |cx| Box::pin(async move {
+            Ok(HttpResponseBuilder {
+                status_code: HttpStatusCode::Ok200,
+                version: cx.request.version.clone(),
+                headers: HeaderMap::new(),
+                content: Content::Stream(Box::new(&b"streamed"[..]), None),
+            })
+        }));
+        let service = Service::with_router(ServerConfig { tower_layers: layers(), ..Default::default() }, router);
+
+        let (_, request) = parse_http_request("GET /stream HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let response = service.respond(&request).await;
+        assert!(matches!(response.content, Content::Stream(_, None)));
+        assert!(response.sends_chunked());
+
+        let service = Service::new(ServerConfig { tower_layers: layers(), ..Default::default() });
+        let (_, request) = parse_http_request(
+            "GET /ws/echo HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        ).unwrap();
+        let mut response = service.respond(&request).await;
+        assert_eq!(response.status_code.code_and_phrase().0, 101);
+        assert!(response.take_upgrade().is_some());
+
+        let (_, mut request) = parse_http_request("GET /ip HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        request.forwarded = Some(forwarded::Forwarded { client_ip: Some("203.0.113.7".parse().unwrap()), proto: None });
+        let (_, _, _, body) = service.respond(&request).await.into_parts();
+        assert_eq!(body.as_deref(), Some(&b"203.0.113.7"[..]));
+    }
+
+    #[tokio::test]
+    async fn spooled_uploads_pass_through_the_stack() {
+        let storage = Arc::new(MemoryStorage::default());
+        let client = TestClient::new(ServerConfig {
+            storage: Some(storage.clone()),
+            parser: ParserConfig { spool_threshold: 4, spool_dir: temp_dir("tower-spool"), ..Default::default() },
+            tower_layers: layers(),
+            ..Default::default()
+        });
+        let raw = b"PUT /files/big HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\n0123456789";
+        assert_eq!(TestResponse::parse(&client.send_raw(raw).await).status, 201);
+        assert_eq!(storage.read("big").await.unwrap(), b"0123456789");
+    }
+}
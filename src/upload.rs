@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{ensure, Context};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+
+use crate::digest::Sha256;
+
+/// How much of an upload is held in memory at a time while it is spooled.
+const WINDOW: usize = 64 * 1024;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A request body written to a temporary file as it arrived, so uploads of any size are
+/// received with constant memory. Its SHA-256 is computed on the way through; the file is
+/// removed once the last reference to it is dropped.
+#[derive(Debug)]
+pub struct SpooledBody {
+    path: PathBuf,
+    pub len: u64,
+    pub digest: [u8; 32],
+}
+
+impl SpooledBody {
+    /// Copies exactly `len` bytes from `reader` into a new file in `dir`.
+    pub async fn spool<R: AsyncRead + Unpin>(reader: &mut R, len: u64, dir: &Path) -> anyhow::Result<Self> {
+        let mut spooler = Spooler::create(dir).await?;
+        spooler.copy(reader, len).await?;
+        spooler.finish().await
+    }
+
+    pub async fn open(&self) -> std::io::Result<tokio::fs::File> {
+        tokio::fs::File::open(&self.path).await
+    }
+}
+
+/// A [`SpooledBody`] being written, for bodies that arrive in pieces.
+pub struct Spooler {
+    file: tokio::fs::File,
+    spooled: SpooledBody,
+    hasher: Sha256,
+}
+
+impl Spooler {
+    pub async fn create(dir: &Path) -> anyhow::Result<Self> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("upload-{}-{id}", std::process::id()));
+        let file = tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await
+            .with_context(|| format!("ERROR: creating upload spool file {}", path.display()))?;
+        // from here on the file is cleaned up however spooling ends
+        Ok(Spooler { file, spooled: SpooledBody { path, len: 0, digest: [0; 32] }, hasher: Sha256::default() })
+    }
+
+    pub async fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.hasher.update(bytes);
+        self.file.write_all(bytes).await.context("ERROR: writing upload spool file")?;
+        self.spooled.len += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Appends exactly `len` bytes from `reader`.
+    pub async fn copy<R: AsyncRead + Unpin>(&mut self, reader: &mut R, len: u64) -> anyhow::Result<()> {
+        let mut window = vec![0; WINDOW];
+        let mut remaining = len;
+        while remaining > 0 {
+            let want = WINDOW.min(usize::try_from(remaining).unwrap_or(WINDOW));
+            let read = reader.read(&mut window[..want]).await.context("ERROR: reading request content")?;
+            ensure!(read > 0, "ERROR: connection closed {remaining} bytes before the end of the request content");
+            self.write(&window[..read]).await?;
+            remaining -= read as u64;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> anyhow::Result<SpooledBody> {
+        self.file.flush().await.context("ERROR: writing upload spool file")?;
+        self.spooled.digest = self.hasher.finalize();
+        Ok(self.spooled)
+    }
+}
+
+impl Drop for SpooledBody {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,127 @@
+use anyhow::{bail, Context};
+
+use crate::json::{self, obj, Json};
+use crate::log::log_debug;
+use crate::{glob, Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+/// One check a request body has to pass.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rule {
+    /// At most this many bytes, else 413.
+    MaxSize(u64),
+    /// This media type, parameters aside, else 415.
+    ContentType(String),
+    /// Well-formed JSON, else 400.
+    Json,
+    /// A JSON object with this key, else 422.
+    RequiredKey(String),
+}
+
+/// Rules for the bodies of requests to paths matching the glob `pattern`, checked before
+/// the handler runs. Requests without a body aren't checked.
+#[derive(Debug, Clone)]
+pub struct BodyValidator {
+    pattern: String,
+    rules: Vec<Rule>,
+}
+
+impl BodyValidator {
+    /// Parses `PATTERN=RULE,RULE...` where a rule is `max-size:BYTES`, `content-type:TYPE`,
+    /// `json` or `required:KEY`; `required` implies `json`.
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let (pattern, rules) = spec.split_once('=').context("expected PATTERN=RULE,RULE...")?;
+        let rules = rules.split(',')
+            .map(|rule| {
+                let rule = rule.trim();
+                Ok(match rule.split_once(':') {
+                    Some(("max-size", bytes)) => Rule::MaxSize(bytes.parse().with_context(|| format!("{bytes:?} is not a byte count"))?),
+                    Some(("content-type", media_type)) => Rule::ContentType(media_type.trim().to_ascii_lowercase()),
+                    Some(("required", key)) => Rule::RequiredKey(key.to_string()),
+                    None if rule == "json" => Rule::Json,
+                    _ => bail!("{rule:?} is not max-size:BYTES, content-type:TYPE, json or required:KEY"),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(BodyValidator { pattern: pattern.to_string(), rules })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        glob::matches(&self.pattern, path)
+    }
+
+    /// The response rejecting `request`, if its body breaks a rule.
+    pub fn check(&self, request: &HttpRequest) -> Option<HttpResponseBuilder> {
+        let size = match (&request.body, &request.spooled) {
+            (Some(body), _) => body.len() as u64,
+            (None, Some(spooled)) => spooled.len,
+            (None, None) => return None,
+        };
+        let reject = |code: u16, phrase: &str, message: String| {
+            log_debug!("rejecting body of {} {}: {message}", request.method.as_str(), request.route);
+            let body = obj([("status", Json::Num(code.into())), ("error", message.as_str().into())]);
+            Some(HttpResponseBuilder {
+                status_code: HttpStatusCode::Other(code, phrase.to_string()),
+                version: request.version.clone(),
+                headers: HeaderMap::from([("Content-Type".to_string(), "application/json".to_string())]),
+                content: Content::Bytes(body.to_string().into_bytes()),
+            })
+        };
+
+        for rule in &self.rules {
+            match rule {
+                Rule::MaxSize(max) if size > *max => {
+                    return reject(413, "Content Too Large", format!("body is {size} bytes, at most {max} are accepted"));
+                }
+                Rule::ContentType(expected) => {
+                    let actual = request.header("Content-Type").map(|content_type| content_type.split(';').next().unwrap().trim());
+                    if !actual.is_some_and(|actual| actual.eq_ignore_ascii_case(expected)) {
+                        return reject(415, "Unsupported Media Type", format!("Content-Type must be {expected}"));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let wants_json = self.rules.iter().any(|rule| matches!(rule, Rule::Json | Rule::RequiredKey(_)));
+        if !wants_json {
+            return None;
+        }
+        // spooled bodies are too large to be worth parsing here
+        let Some(body) = &request.body else {
+            return reject(413, "Content Too Large", "body is too large to check as JSON".to_string());
+        };
+        let Ok(body) = std::str::from_utf8(body) else {
+            return reject(400, "Bad Request", "body is not valid JSON: not utf8".to_string());
+        };
+        let keys = match json::top_level_keys(body) {
+            Ok(keys) => keys,
+            Err(err) => return reject(400, "Bad Request", format!("body is not valid JSON: {err}")),
+        };
+        let missing = self.rules.iter().find_map(|rule| match rule {
+            Rule::RequiredKey(key) if !keys.contains(key) => Some(key),
+            _ => None,
+        });
+        match missing {
+            Some(key) => reject(422, "Unprocessable Content", format!("body has no {key:?} field")),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn specs_parse_into_rules() {
+        let validator = BodyValidator::parse("/api/*=max-size:1024, content-type:Application/JSON, required:name").unwrap();
+        assert!(validator.matches("/api/users"));
+        assert_eq!(validator.rules, [
+            Rule::MaxSize(1024),
+            Rule::ContentType("application/json".to_string()),
+            Rule::RequiredKey("name".to_string()),
+        ]);
+        assert!(BodyValidator::parse("/api/*=max-size:lots").is_err());
+        assert!(BodyValidator::parse("/api/*=xml").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use wasmtime::{Config, Engine, Instance, Module, Store};
+
+use crate::cgi;
+use crate::log::log_error;
+use crate::{Content, HeaderMap, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+/// A WebAssembly module serving every request under `prefix`.
+///
+/// The ABI is deliberately small. The module exports its `memory`, an `alloc(len: i32) -> i32`
+/// that reserves `len` bytes for the request, and `handle(ptr: i32, len: i32) -> i64` which
+/// receives the raw HTTP request and returns where its response lives as `ptr << 32 | len`.
+/// The response is written like CGI output: header lines (`Status:` sets the status), a blank
+/// line, then the body. Each request gets a fresh instance and a fuel budget, so a module can
+/// neither keep state between requests nor spin forever.
+#[derive(Clone)]
+pub struct Plugin {
+    prefix: String,
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugin")
+            .field("prefix", &self.prefix)
+            .field("module", &self.module.name())
+            .field("fuel", &self.fuel)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Plugin {
+    /// Loads a `.wasm` (or `.wat`) module from `path`, to be mounted at `prefix`.
+    pub fn load(prefix: &str, path: &Path, fuel: u64) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("ERROR: reading wasm plugin {}", path.display()))?;
+        Self::from_bytes(prefix, &bytes, fuel).with_context(|| format!("ERROR: loading wasm plugin {}", path.display()))
+    }
+
+    fn from_bytes(prefix: &str, bytes: &[u8], fuel: u64) -> anyhow::Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config)?;
+        let module = Module::new(&engine, bytes)?;
+        Ok(Plugin { prefix: prefix.trim_end_matches('/').to_string(), engine, module, fuel })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        path.strip_prefix(&self.prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Runs the module on a blocking thread; traps, running out of fuel and malformed output
+    /// are all reported to the client as 502.
+    pub async fn handle(&self, request: &HttpRequest) -> HttpResponseBuilder {
+        let plugin = self.clone();
+        let input = request.to_bytes();
+        let output = tokio::task::spawn_blocking(move || plugin.run(&input)).await
+            .context("wasm plugin panicked")
+            .and_then(|output| output)
+            .and_then(|output| cgi::parse_output(&output));
+
+        match output {
+            Ok(output) => output.into_response(request.version.clone()),
+            Err(err) => {
+                log_error!("wasm plugin at {} failed, error: {err:#}", self.prefix);
+                HttpResponseBuilder {
+                    status_code: HttpStatusCode::BadGateway502,
+                    version: request.version.clone(),
+                    headers: HeaderMap::new(),
+                    content: Content::Empty,
+                }
+            }
+        }
+    }
+
+    fn run(&self, input: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(self.fuel)?;
+        let instance = Instance::new(&mut store, &self.module, &[])?;
+        let memory = instance.get_memory(&mut store, "memory").context("module exports no memory")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let handle = instance.get_typed_func::<(i32, i32), i64>(&mut store, "handle")?;
+
+        let len = i32::try_from(input.len()).context("request too large for wasm")?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+
+        let packed = handle.call(&mut store, (ptr, len))? as u64;
+        let (ptr, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let Some(output) = memory.data(&store).get(ptr..ptr + len) else {
+            bail!("response at {ptr}+{len} is outside the module's memory");
+        };
+        Ok(output.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_client::TestClient;
+    use crate::ServerConfig;
+
+    /// Answers every request with its own request line as the body.
+    const ECHO_REQUEST_LINE: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (data (i32.const 0) "Status: 200 OK\0d\0aContent-Type: text/plain\0d\0a\0d\0a")
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "handle") (param $ptr i32) (param $len i32) (result i64)
+            (local $i i32)
+            ;; copy the request up to the first CR behind the 44 header bytes at 0
+            (block $done
+              (loop $copy
+                (br_if $done (i32.ge_u (local.get $i) (local.get $len)))
+                (br_if $done (i32.eq (i32.load8_u (i32.add (local.get $ptr) (local.get $i))) (i32.const 13)))
+                (i32.store8 (i32.add (i32.const 44) (local.get $i))
+                            (i32.load8_u (i32.add (local.get $ptr) (local.get $i))))
+                (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                (br $copy)))
+            (i64.extend_i32_u (i32.add (i32.const 44) (local.get $i)))))
+    "#;
+
+    const SPIN: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 0))
+          (func (export "handle") (param i32 i32) (result i64)
+            (loop $forever (br $forever))
+            (i64.const 0)))
+    "#;
+
+    fn client(wat: &str) -> TestClient {
+        let plugin = Plugin::from_bytes("/wasm", wat.as_bytes(), 1_000_000).unwrap();
+        TestClient::new(ServerConfig { plugins: vec![plugin], ..Default::default() })
+    }
+
+    #[tokio::test]
+    async fn plugin_answers_under_its_prefix() {
+        let client = client(ECHO_REQUEST_LINE);
+
+        let response = client.get("/wasm/hello?x=1").send().await;
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.text(), "GET /wasm/hello?x=1 HTTP/1.1");
+
+        assert_eq!(client.get("/wasmer").send().await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn runaway_plugin_is_a_bad_gateway() {
+        assert_eq!(client(SPIN).get("/wasm").send().await.status, 502);
+    }
+}
@@ -0,0 +1,79 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use notify::{EventKind, RecursiveMode, Watcher};
+
+use crate::digest::DigestCache;
+use crate::log::log_error;
+use crate::precompress::Precompressed;
+
+/// Keeps what the server derived from files in the served directory in step with changes
+/// made behind its back, by editors, deploys or `rsync`, instead of only with its own uploads.
+/// Events stop once the returned watcher is dropped.
+pub fn watch(root: &Path, digests: Arc<DigestCache>, precompressed: Option<Precompressed>) -> anyhow::Result<notify::RecommendedWatcher> {
+    let root = root.canonicalize().with_context(|| format!("ERROR: watching {}", root.display()))?;
+    let handler_root = root.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                log_error!("watching {}, error: {err}", handler_root.display());
+                return;
+            }
+        };
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        for name in changed_names(&handler_root, &event.paths) {
+            digests.forget(&name);
+            if let Some(precompressed) = &precompressed {
+                if let Err(err) = precompressed.refresh(&name) {
+                    log_error!("couldn't refresh the gzipped variant of {name}, error: {err}");
+                }
+            }
+        }
+    }).context("ERROR: starting the file watcher")?;
+    watcher.watch(&root, RecursiveMode::NonRecursive)
+        .with_context(|| format!("ERROR: watching {}", root.display()))?;
+    Ok(watcher)
+}
+
+/// Names of the files directly in `root` among `paths`; only those are served.
+fn changed_names(root: &Path, paths: &[PathBuf]) -> Vec<String> {
+    paths.iter()
+        .filter(|path| path.parent() == Some(root))
+        .filter_map(|path| path.file_name()?.to_str().map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::test_client::temp_dir;
+
+    #[test]
+    fn outside_changes_refresh_gzipped_variants() {
+        let root = temp_dir("watch-root");
+        let cache = temp_dir("watch-cache");
+        let precompressed = Precompressed::new(&root, &cache, 10);
+        let _watcher = watch(&root, Arc::default(), Some(precompressed)).unwrap();
+
+        let variant = cache.join("notes.txt.gz");
+        std::fs::write(root.join("notes.txt"), "written by someone else ".repeat(10)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !variant.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(variant.exists());
+
+        std::fs::remove_file(root.join("notes.txt")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while variant.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!variant.exists());
+    }
+}
@@ -0,0 +1,197 @@
+use std::io;
+
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::response::Upgrade;
+use crate::log::log_debug;
+use crate::{digest, Content, HeaderMap, HttpMethod, HttpRequest, HttpResponseBuilder, HttpStatusCode};
+
+/// Appended to the client's key before hashing it into `Sec-WebSocket-Accept` (RFC 6455 section 1.3).
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest message payload accepted; a bigger frame closes the connection with 1009.
+const MAX_PAYLOAD: u64 = 1024 * 1024;
+
+pub const CONTINUATION: u8 = 0x0;
+pub const TEXT: u8 = 0x1;
+pub const BINARY: u8 = 0x2;
+pub const CLOSE: u8 = 0x8;
+pub const PING: u8 = 0x9;
+pub const PONG: u8 = 0xa;
+
+/// Close status codes (RFC 6455 section 7.4.1).
+const PROTOCOL_ERROR: u16 = 1002;
+const TOO_BIG: u16 = 1009;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Why a frame couldn't be read.
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    /// The frame broke the protocol; the connection is closed with this status code.
+    Protocol(u16, &'static str),
+}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        FrameError::Io(err)
+    }
+}
+
+/// The `Sec-WebSocket-Accept` answering `key`.
+pub fn accept_key(key: &str) -> String {
+    digest::base64_encode(&digest::sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// Answers an opening handshake (RFC 6455 section 4.2) with 101, handing the connection to
+/// `session`, or explains what is wrong with it: 426 for a request that isn't one, 400 for
+/// one that is malformed.
+pub fn handshake(request: &HttpRequest, session: Upgrade) -> HttpResponseBuilder {
+    let response = |status_code, headers: HeaderMap, content| HttpResponseBuilder {
+        status_code,
+        version: request.version.clone(),
+        headers,
+        content,
+    };
+    let has_token = |name: &str, token: &str| {
+        request.header(name).is_some_and(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") || request.header("Sec-WebSocket-Version") != Some("13") {
+        let headers = HeaderMap::from([
+            ("Upgrade".to_string(), "websocket".to_string()),
+            ("Connection".to_string(), "Upgrade".to_string()),
+            ("Sec-WebSocket-Version".to_string(), "13".to_string()),
+        ]);
+        return response(HttpStatusCode::Other(426, "Upgrade Required".to_string()), headers, Content::Empty);
+    }
+    // a nonce of 16 bytes, base64-encoded
+    let key = request.header("Sec-WebSocket-Key").filter(|key| {
+        key.len() == 24 && key.ends_with("==") && key[..22].bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+    });
+    let Some(key) = key.filter(|_| matches!(request.method, HttpMethod::Get) && request.version == "HTTP/1.1") else {
+        return response(HttpStatusCode::BadRequest400, HeaderMap::new(), Content::Empty);
+    };
+    let headers = HeaderMap::from([
+        ("Upgrade".to_string(), "websocket".to_string()),
+        ("Connection".to_string(), "Upgrade".to_string()),
+        ("Sec-WebSocket-Accept".to_string(), accept_key(key)),
+    ]);
+    response(HttpStatusCode::Other(101, "Switching Protocols".to_string()), headers, Content::Upgrade(session))
+}
+
+/// Reads the next frame a client sent, unmasked. `None` means the client closed the stream
+/// between frames.
+pub async fn read_frame(reader: &mut (dyn AsyncBufRead + Send + Unpin)) -> Result<Option<Frame>, FrameError> {
+    let mut head = [0u8; 2];
+    match reader.read_exact(&mut head).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[0] & 0x70 != 0 {
+        return Err(FrameError::Protocol(PROTOCOL_ERROR, "reserved bits set without an extension"));
+    }
+    if head[1] & 0x80 == 0 {
+        return Err(FrameError::Protocol(PROTOCOL_ERROR, "client frames must be masked"));
+    }
+    let len = match head[1] & 0x7f {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        len => u64::from(len),
+    };
+    if opcode >= CLOSE && (!fin || len > 125) {
+        return Err(FrameError::Protocol(PROTOCOL_ERROR, "control frames are short and unfragmented"));
+    }
+    if len > MAX_PAYLOAD {
+        return Err(FrameError::Protocol(TOO_BIG, "frame too big"));
+    }
+
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok(Some(Frame { fin, opcode, payload }))
+}
+
+/// Writes a frame as a server does, unmasked.
+pub async fn write_frame(writer: &mut (dyn AsyncWrite + Send + Unpin), frame: &Frame) -> io::Result<()> {
+    let mut head = vec![u8::from(frame.fin) << 7 | frame.opcode];
+    match frame.payload.len() {
+        len @ 0..=125 => head.push(len as u8),
+        len @ 126..=0xffff => {
+            head.push(126);
+            head.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            head.push(127);
+            head.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    writer.write_all(&head).await?;
+    writer.write_all(&frame.payload).await?;
+    writer.flush().await
+}
+
+/// The demo session behind `/ws/echo`: sends every message back as it came, fragments
+/// included, answers pings, and returns the client's close frame.
+pub async fn echo(reader: &mut (dyn AsyncBufRead + Send + Unpin), writer: &mut (dyn AsyncWrite + Send + Unpin)) -> io::Result<()> {
+    loop {
+        let frame = match read_frame(reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ok(()),
+            Err(FrameError::Io(err)) => return Err(err),
+            Err(FrameError::Protocol(code, reason)) => {
+                log_debug!("closing websocket, {reason}");
+                let payload = [&code.to_be_bytes()[..], reason.as_bytes()].concat();
+                return write_frame(writer, &Frame { fin: true, opcode: CLOSE, payload }).await;
+            }
+        };
+        match frame.opcode {
+            CONTINUATION | TEXT | BINARY => write_frame(writer, &frame).await?,
+            PING => write_frame(writer, &Frame { fin: true, opcode: PONG, payload: frame.payload }).await?,
+            PONG => {}
+            CLOSE => return write_frame(writer, &frame).await,
+            _ => {
+                let payload = PROTOCOL_ERROR.to_be_bytes().to_vec();
+                return write_frame(writer, &Frame { fin: true, opcode: CLOSE, payload }).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accept_key_matches_rfc_6455() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[tokio::test]
+    async fn masked_frames_are_read_and_unmasked_written() {
+        // "Hello" masked, from RFC 6455 section 5.7
+        let mut raw: &[u8] = &[0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+        let frame = read_frame(&mut raw).await.unwrap().unwrap();
+        assert_eq!(frame, Frame { fin: true, opcode: TEXT, payload: b"Hello".to_vec() });
+        assert!(read_frame(&mut raw).await.unwrap().is_none());
+
+        let mut written = Vec::new();
+        write_frame(&mut written, &frame).await.unwrap();
+        assert_eq!(written, [0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+
+        let mut unmasked: &[u8] = &[0x81, 0x05, b'H', b'e', b'l', b'l', b'o'];
+        assert!(matches!(read_frame(&mut unmasked).await, Err(FrameError::Protocol(PROTOCOL_ERROR, _))));
+    }
+}
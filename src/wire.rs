@@ -0,0 +1,91 @@
+use std::fmt::Write as _;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Logs hexdumps of everything read from and written to the wrapped stream at trace level,
+/// in a `wire` span carrying the connection id. Each direction stops being dumped after
+/// `limit` bytes.
+pub struct WireTrace<S> {
+    inner: S,
+    span: tracing::Span,
+    limit: usize,
+    read: usize,
+    written: usize,
+}
+
+impl<S> WireTrace<S> {
+    pub fn new(inner: S, id: u64, limit: usize) -> Self {
+        let span = tracing::trace_span!("wire", conn = id);
+        span.in_scope(|| tracing::trace!("opened"));
+        WireTrace { inner, span, limit, read: 0, written: 0 }
+    }
+}
+
+impl<S> Drop for WireTrace<S> {
+    fn drop(&mut self) {
+        self.span.in_scope(|| tracing::trace!("closed after {} bytes in, {} bytes out", self.read, self.written));
+    }
+}
+
+/// Dumps `bytes`, of which `seen` bytes in this direction came before, honoring the cap.
+fn trace(span: &tracing::Span, arrow: &str, bytes: &[u8], seen: usize, limit: usize) {
+    if bytes.is_empty() || span.is_disabled() {
+        return;
+    }
+    let shown = bytes.len().min(limit.saturating_sub(seen));
+    let mut dump = format!("{arrow} {} bytes", bytes.len());
+    for (i, line) in bytes[..shown].chunks(BYTES_PER_LINE).enumerate() {
+        let _ = write!(dump, "\n  {:08x}  ", seen + i * BYTES_PER_LINE);
+        for column in 0..BYTES_PER_LINE {
+            match line.get(column) {
+                Some(byte) => { let _ = write!(dump, "{byte:02x} "); }
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push(' ');
+        dump.extend(line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+    }
+    if shown < bytes.len() {
+        let _ = write!(dump, "\n  ... {} bytes over the trace limit not shown", bytes.len() - shown);
+    }
+    span.in_scope(|| tracing::trace!("{dump}"));
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for WireTrace<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let bytes = &buf.filled()[before..];
+            trace(&this.span, "<-", bytes, this.read, this.limit);
+            this.read += bytes.len();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for WireTrace<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            trace(&this.span, "->", &buf[..n], this.written, this.limit);
+            this.written += n;
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}